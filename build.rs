@@ -0,0 +1,32 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    // release tarballs don't contain .git, packagers can set GIT_SHA explicitly
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+
+    let sha = env::var("GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(git_sha)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_SHA={}", sha);
+}
+
+fn git_sha() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let sha = String::from_utf8(output.stdout).ok()?;
+    Some(sha.trim().to_string())
+}
@@ -37,6 +37,12 @@ _drbd-reactorctl() {
             ls)
                 cmd+="__ls"
                 ;;
+            migrate)
+                cmd+="__migrate"
+                ;;
+            promote)
+                cmd+="__promote"
+                ;;
             restart)
                 cmd+="__restart"
                 ;;
@@ -56,7 +62,7 @@ _drbd-reactorctl() {
 
     case "${cmd}" in
         drbd-reactorctl)
-            opts=" -h -V -c  --local --help --version --config --context --nodes   disable enable status restart edit rm evict cat ls start-until generate-completion help"
+            opts=" -h -V -c  --local --help --version --config --context --nodes   disable enable status restart edit migrate rm evict promote cat ls start-until generate-completion help"
             if [[ ${cur} == -* || ${COMP_CWORD} -eq 1 ]] ; then
                 COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
                 return 0
@@ -287,6 +293,60 @@ _drbd-reactorctl() {
             COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
             return 0
             ;;
+        drbd__reactorctl__migrate)
+            opts=" -h -V  --disabled --help --version --context --nodes  <configs>... "
+            if [[ ${cur} == -* || ${COMP_CWORD} -eq 2 ]] ; then
+                COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
+                return 0
+            fi
+            case "${prev}" in
+                
+                --context)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
+                --nodes)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
+                *)
+                    COMPREPLY=()
+                    ;;
+            esac
+            COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
+            return 0
+            ;;
+        drbd__reactorctl__promote)
+            opts=" -h -V -d  --help --version --delay --context --nodes  <resources>... "
+            if [[ ${cur} == -* || ${COMP_CWORD} -eq 2 ]] ; then
+                COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
+                return 0
+            fi
+            case "${prev}" in
+                
+                --delay)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
+                    -d)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
+                --context)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
+                --nodes)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
+                *)
+                    COMPREPLY=()
+                    ;;
+            esac
+            COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
+            return 0
+            ;;
         drbd__reactorctl__restart)
             opts=" -h -V  --with-targets --help --version --context --nodes  <configs>... "
             if [[ ${cur} == -* || ${COMP_CWORD} -eq 2 ]] ; then
@@ -357,7 +417,7 @@ _drbd-reactorctl() {
             return 0
             ;;
         drbd__reactorctl__status)
-            opts=" -v -h -V -r  --verbose --help --version --resource --context --nodes  <configs>... "
+            opts=" -v -h -V -r  --verbose --json --fail-on-degraded --help --version --resource --context --nodes  <configs>... "
             if [[ ${cur} == -* || ${COMP_CWORD} -eq 2 ]] ; then
                 COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
                 return 0
@@ -391,3 +451,48 @@ _drbd-reactorctl() {
 }
 
 complete -F _drbd-reactorctl -o bashdefault -o default drbd-reactorctl
+
+_drbd-reactorctl_dynamic() {
+    local i w cur prev sub="" disabled="" skip="" what=""
+    cur="${COMP_WORDS[COMP_CWORD]}"
+    prev="${COMP_WORDS[COMP_CWORD-1]}"
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        w="${COMP_WORDS[i]}"
+        if [ -n "${skip}" ]; then
+            skip=""
+            continue
+        fi
+        case "${w}" in
+            -c|--config|--context|--nodes) skip="yes" ;;
+            --disabled) disabled="yes" ;;
+            -*) ;;
+            *) [ -z "${sub}" ] && sub="${w}" ;;
+        esac
+    done
+
+    case "${prev}" in
+        -r|--resource) what="resources" ;;
+        -d|--delay) ;;
+        *)
+            if [[ "${cur}" != -* ]]; then
+                case "${sub}" in
+                    enable) what="disabled" ;;
+                    disable|status|restart|edit|migrate|rm|evict|cat|ls|start-until)
+                        what="snippets"
+                        [ -n "${disabled}" ] && what="disabled"
+                        ;;
+                    promote) what="resources" ;;
+                esac
+            fi
+            ;;
+    esac
+
+    if [ -n "${what}" ]; then
+        COMPREPLY=( $(compgen -W "$(drbd-reactorctl __complete "${what}" 2>/dev/null)" -- "${cur}") )
+        return 0
+    fi
+    _drbd-reactorctl
+}
+
+complete -F _drbd-reactorctl_dynamic -o bashdefault -o default drbd-reactorctl
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::env;
 use std::fmt;
 use std::fs;
@@ -11,7 +11,7 @@ use std::process::{Command, Output, Stdio};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
@@ -27,6 +27,7 @@ use drbd_reactor::drbd;
 use drbd_reactor::drbd::PrimaryOn;
 use drbd_reactor::plugin;
 use drbd_reactor::plugin::promoter;
+use drbd_reactor::runner::SystemRunner;
 use drbd_reactor::systemd;
 use drbd_reactor::systemd::UnitActiveState;
 use drbd_reactor::utils;
@@ -35,6 +36,8 @@ static TERMINATE: AtomicBool = AtomicBool::new(false);
 
 const REACTOR_RELOAD_PATH: &str = "drbd-reactor-reload.path";
 const REACTOR_SERVICE: &str = "drbd-reactor.service";
+// exit code of 'status --fail-on-degraded' if a unit failed or an endpoint is unreachable
+const EXIT_DEGRADED: i32 = 3;
 
 struct ClusterConf<'a> {
     context: &'a str,
@@ -59,14 +62,23 @@ fn main() -> Result<()> {
                 .expect("expected to have a default"),
         )
         .expect("expected shell to be parsable"); // this has to be one of its variants.
-        get_app().gen_completions_to("drbd-reactorctl", shell, &mut io::stdout());
+        get_public_app().gen_completions_to("drbd-reactorctl", shell, &mut io::stdout());
+        // clap only knows static completions, snippet and resource names are completed via '__complete'
+        match shell {
+            Shell::Bash => print!("{}", BASH_DYNAMIC_COMPLETION),
+            Shell::Fish => print!("{}", FISH_DYNAMIC_COMPLETION),
+            _ => (),
+        }
         return Ok(());
     }
 
     let config_file = matches
         .value_of("config")
         .expect("expected to have a default");
-    let snippets_path = get_snippets_path(&PathBuf::from(config_file))
+    let snippets = get_snippets(&PathBuf::from(config_file))
+        .with_context(|| "Could not get snippets from config file")?;
+    let snippets_path = snippets
+        .directory()
         .with_context(|| "Could not get snippets path from config file")?;
 
     let context = matches
@@ -88,30 +100,46 @@ fn main() -> Result<()> {
     };
 
     match matches.subcommand() {
+        ("__complete", Some(complete_matches)) => {
+            let what = complete_matches
+                .value_of("what")
+                .expect("expected to be checked by parser");
+            complete(&snippets_path, what)
+        }
         ("cat", Some(cat_matches)) => cat(
-            expand_snippets(&snippets_path, cat_matches, false),
+            expand_snippets(&snippets, cat_matches, false),
+            cat_matches.is_present("plain"),
             &cluster,
         ),
         ("disable", Some(disable_matches)) => {
             let now = disable_matches.is_present("now");
+            let confirm = disable_matches.is_present("all") && !disable_matches.is_present("force");
             disable(
-                expand_snippets(&snippets_path, disable_matches, false),
+                expand_snippets(&snippets, disable_matches, false),
                 now,
+                confirm,
+                &cluster,
+            )
+        }
+        ("enable", Some(enable_matches)) => {
+            let confirm = enable_matches.is_present("all") && !enable_matches.is_present("force");
+            enable(
+                expand_snippets(&snippets, enable_matches, true),
+                confirm,
                 &cluster,
             )
         }
-        ("enable", Some(enable_matches)) => enable(
-            expand_snippets(&snippets_path, enable_matches, true),
-            &cluster,
-        ),
         ("edit", Some(edit_matches)) => {
             let disabled = edit_matches.is_present("disabled");
             let force = edit_matches.is_present("force");
+            if edit_matches.is_present("main") {
+                return edit_main(Path::new(config_file), &snippets_path, force, &cluster);
+            }
             let type_opt = edit_matches
                 .value_of("type")
                 .expect("expected to have a default");
             edit(
-                expand_snippets(&snippets_path, edit_matches, disabled),
+                expand_snippets(&snippets, edit_matches, disabled),
                 &snippets_path,
                 type_opt,
                 force,
@@ -127,33 +155,100 @@ fn main() -> Result<()> {
                 .expect("expected to have a default");
             let delay = delay.parse().expect("expected to be checked by parser");
             evict(
-                expand_snippets(&snippets_path, evict_matches, false),
+                expand_snippets(&snippets, evict_matches, false),
                 force,
                 keep_masked,
                 unmask,
                 delay,
             )
         }
+        ("promote", Some(promote_matches)) => {
+            let delay = promote_matches
+                .value_of("delay")
+                .expect("expected to have a default");
+            let delay = delay.parse().expect("expected to be checked by parser");
+            let resources = promote_matches
+                .values_of("resources")
+                .expect("expected to be checked by parser")
+                .map(String::from)
+                .collect::<Vec<_>>();
+            let args: ArgMatches = Default::default();
+            promote(expand_snippets(&snippets, &args, false), &resources, delay)
+        }
+        ("gc", Some(gc_matches)) => {
+            let force = gc_matches.is_present("force");
+            gc(Path::new(config_file), force, &cluster)
+        }
+        ("logs", Some(logs_matches)) => {
+            let follow = logs_matches.is_present("follow");
+            let lines = logs_matches
+                .value_of("lines")
+                .expect("expected to have a default");
+            let lines = lines.parse().expect("expected to be checked by parser");
+            let resources = logs_matches
+                .values_of("resources")
+                .expect("expected to be checked by parser")
+                .map(String::from)
+                .collect::<Vec<_>>();
+            logs(&resources, lines, follow)
+        }
+        ("stats", Some(stats_matches)) => {
+            let interval = stats_matches
+                .value_of("interval")
+                .expect("expected to have a default");
+            let interval = interval.parse().expect("expected to be checked by parser");
+            let resources = stats_matches
+                .values_of("resources")
+                .expect("expected to be checked by parser")
+                .map(String::from)
+                .collect::<Vec<_>>();
+            stats(&resources, interval)
+        }
         ("ls", Some(ls_matches)) => {
             let disabled = ls_matches.is_present("disabled");
+            let output = ls_matches
+                .value_of("output")
+                .expect("expected to have a default");
             ls(
-                expand_snippets(&snippets_path, ls_matches, disabled),
+                expand_snippets(&snippets, ls_matches, disabled),
+                output,
                 &cluster,
             )
         }
         ("restart", Some(restart_matches)) => {
             let with_targets = restart_matches.is_present("with_targets");
+            let all = restart_matches.is_present("all");
             let configs = match restart_matches.values_of("configs") {
-                None => Vec::new(),
-                Some(_) => expand_snippets(&snippets_path, restart_matches, false),
+                None if !all => Vec::new(),
+                _ => expand_snippets(&snippets, restart_matches, false),
             };
-            restart(configs, with_targets, &cluster)
+            // without snippets the daemon itself would be restarted
+            if all && configs.is_empty() {
+                return Err(anyhow::anyhow!("No snippets to restart"));
+            }
+            let confirm = restart_matches.is_present("all") && !restart_matches.is_present("force");
+            restart(configs, with_targets, confirm, &cluster)
+        }
+        ("migrate", Some(migrate_matches)) => {
+            let disabled = migrate_matches.is_present("disabled");
+            migrate(
+                expand_snippets(&snippets, migrate_matches, disabled),
+                &snippets_path,
+                &cluster,
+            )
+        }
+        ("validate", Some(validate_matches)) => {
+            let disabled = validate_matches.is_present("disabled");
+            validate(
+                expand_snippets(&snippets, validate_matches, disabled),
+                &cluster,
+            )
         }
         ("rm", Some(rm_matches)) => {
             let force = rm_matches.is_present("force");
             let disabled = rm_matches.is_present("disabled");
             rm(
-                expand_snippets(&snippets_path, rm_matches, disabled),
+                expand_snippets(&snippets, rm_matches, disabled),
                 force,
                 &cluster,
             )
@@ -162,15 +257,28 @@ fn main() -> Result<()> {
             let until = until_matches
                 .value_of("until")
                 .expect("expected to be checked by parser");
-            start_until(expand_snippets(&snippets_path, until_matches, true), until)
+            start_until(expand_snippets(&snippets, until_matches, true), until)
         }
         ("status", Some(status_matches)) => {
             let verbose = status_matches.is_present("verbose");
+            let json = status_matches.is_present("json");
+            let fail_on_degraded = status_matches.is_present("fail_on_degraded");
             let resources = status_matches.values_of("resource").unwrap_or_default();
             let resources: Vec<String> = resources.map(String::from).collect::<Vec<_>>();
+            let follow = if status_matches.is_present("follow") {
+                let interval = status_matches
+                    .value_of("interval")
+                    .expect("expected to have a default");
+                Some(interval.parse().expect("expected to be checked by parser"))
+            } else {
+                None
+            };
             status(
-                expand_snippets(&snippets_path, status_matches, false),
+                expand_snippets(&snippets, status_matches, false),
                 verbose,
+                json,
+                fail_on_degraded,
+                follow,
                 &resources,
                 &cluster,
             )
@@ -179,8 +287,11 @@ fn main() -> Result<()> {
             // pretend it is status
             let args: ArgMatches = Default::default();
             status(
-                expand_snippets(&snippets_path, &args, false),
+                expand_snippets(&snippets, &args, false),
                 false,
+                false,
+                false,
+                None,
                 &vec![],
                 &cluster,
             )
@@ -235,7 +346,7 @@ fn edit_editor(tmppath: &Path, editor: &str, type_opt: &str, force: bool) -> Res
         }
         for promoter in plugins.promoter {
             for config in promoter.resources.values() {
-                if let Some(last) = config.start.last() {
+                if let Some(last) = config.start_services().last() {
                     if last.ends_with(".mount") {
                         let err = "Mount unit should not be the topmost unit, consider using an \
                                    OCF file system RA";
@@ -246,6 +357,26 @@ fn edit_editor(tmppath: &Path, editor: &str, type_opt: &str, force: bool) -> Res
                         }
                     }
                 }
+                // shell runners execute arbitrary commands, nothing we could check
+                if config.runner != promoter::Runner::Systemd {
+                    continue;
+                }
+                if let Err(e) = config.ocf_root() {
+                    if force {
+                        warn(&e.to_string());
+                    } else {
+                        return Err(e);
+                    }
+                }
+                for start in &config.start_services() {
+                    if let Some(err) = missing_start_unit(start)? {
+                        if force {
+                            warn(&err);
+                        } else {
+                            return Err(anyhow::anyhow!(err));
+                        }
+                    }
+                }
             }
         }
     } else if type_opt == "prometheus" {
@@ -271,6 +402,26 @@ fn edit_editor(tmppath: &Path, editor: &str, type_opt: &str, force: bool) -> Res
     Ok(())
 }
 
+// returns an error message if the unit of a start entry does not exist
+// OCF agents are checked when looking up their OCF root
+fn missing_start_unit(start: &str) -> Result<Option<String>> {
+    let ocf_pattern = Regex::new(plugin::promoter::OCF_PATTERN)?;
+    let start = start.trim();
+    if ocf_pattern.is_match(start) {
+        return Ok(None);
+    }
+
+    let exists = Command::new("systemctl")
+        .arg("cat")
+        .arg("--no-pager")
+        .arg(start)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success());
+    Ok((!exists).then(|| format!("systemd unit '{}' does not exist", start)))
+}
+
 fn add_header(tmppath: &Path, last_result: &Result<()>) -> Result<()> {
     let was = fs::read_to_string(tmppath)?;
     let mut f = fs::File::create(tmppath)?;
@@ -308,6 +459,103 @@ fn rm_header(tmppath: &Path) -> Result<()> {
     Ok(())
 }
 
+// edits tmppath until the changes pass the validation, returns false if the edit was aborted
+fn edit_loop<F>(tmppath: &Path, mut from_template: bool, validate: F) -> Result<bool>
+where
+    F: Fn(&Path) -> Result<()>,
+{
+    let mut result: Result<()> = Ok(());
+    loop {
+        // be careful on first iteration, consider it to be empty
+        let was = if from_template {
+            from_template = false;
+            "".to_string()
+        } else {
+            fs::read_to_string(tmppath)?
+        };
+        let was = was.trim();
+        add_header(tmppath, &result)?;
+        result = validate(tmppath);
+        rm_header(tmppath)?;
+        let is = fs::read_to_string(tmppath)?;
+        let is = is.trim();
+
+        if is.is_empty() {
+            warn("Edit aborted, empty file saved");
+            return Ok(false);
+        } else if was == is {
+            warn("Edit aborted, no new changes have been made");
+            return Ok(false);
+        }
+
+        if result.is_ok() {
+            return Ok(true);
+        }
+    }
+}
+
+fn edit_main_editor(
+    tmppath: &Path,
+    editor: &str,
+    snippets_path: &PathBuf,
+    force: bool,
+) -> Result<()> {
+    plugin::map_status(Command::new(editor).arg(tmppath).status())?;
+
+    let content = fs::read_to_string(tmppath)?;
+    let config: config::Config = toml::from_str(&content)?;
+    if config.snippets.is_none() {
+        let snippets = config::files_with_extension_in(snippets_path, "toml").unwrap_or_default();
+        if !snippets.is_empty() {
+            let err = format!(
+                "Removing 'snippets' would ignore the {} snippet(s) in '{}'",
+                snippets.len(),
+                snippets_path.display()
+            );
+            if force {
+                warn(&err);
+            } else {
+                return Err(anyhow::anyhow!(err));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn edit_main(
+    config_file: &Path,
+    snippets_path: &PathBuf,
+    force: bool,
+    cluster: &ClusterConf,
+) -> Result<()> {
+    if do_remote(cluster)? {
+        return Ok(());
+    }
+
+    let editor = env::var("EDITOR").unwrap_or("vi".to_string());
+
+    // same as for snippets: keep the temporary file on the same mount point for .persist()
+    let dir = config_file
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let tmpfile = NamedTempFile::new_in(dir)?;
+    fs::copy(config_file, tmpfile.path())?;
+
+    let edited = edit_loop(tmpfile.path(), false, |tmppath| {
+        edit_main_editor(tmppath, &editor, snippets_path, force)
+    })?;
+
+    if edited {
+        tmpfile.persist(config_file)?;
+        // the reload path unit only watches the snippets directory
+        reload_service()?;
+    }
+
+    Ok(())
+}
+
 fn edit(
     snippets_paths: Vec<PathBuf>,
     snippets_path: &PathBuf,
@@ -344,41 +592,123 @@ fn edit(
             from_template = true;
         }
 
-        let mut aborted = false;
-        let mut result: Result<()> = Ok(());
-        loop {
-            // be careful on first iteration, consider it to be empty
-            let was = if from_template {
-                from_template = false;
-                "".to_string()
-            } else {
-                fs::read_to_string(tmpfile.path())?
-            };
-            let was = was.trim();
-            add_header(tmpfile.path(), &result)?;
-            result = edit_editor(tmpfile.path(), &editor, type_opt, force);
-            rm_header(tmpfile.path())?;
-            let is = fs::read_to_string(tmpfile.path())?;
-            let is = is.trim();
-
-            if is.is_empty() {
-                warn("Edit aborted, empty file saved");
-                aborted = true;
-                break;
-            } else if was == is {
-                warn("Edit aborted, no new changes have been made");
-                aborted = true;
-                break;
+        let edited = edit_loop(tmpfile.path(), from_template, |tmppath| {
+            edit_editor(tmppath, &editor, type_opt, force)
+        })?;
+
+        if edited {
+            tmpfile.persist(snippet)?;
+            persisted += 1;
+        }
+    }
+
+    if persisted > 0 && !has_autoload()? {
+        reload_service()?;
+    }
+
+    Ok(())
+}
+
+fn validate(snippets_paths: Vec<PathBuf>, cluster: &ClusterConf) -> Result<()> {
+    if do_remote(cluster)? {
+        return Ok(());
+    }
+
+    let mut problems = 0;
+    let mut problem = |snippet: &Path, text: &str| {
+        problems += 1;
+        red_prefix("ERROR:", &format!("{}: {}", snippet.display(), text));
+    };
+
+    // promoter resource name -> snippet that defined it first
+    let mut promoted: HashMap<String, PathBuf> = HashMap::new();
+    for snippet in &snippets_paths {
+        let content = match fs::read_to_string(snippet) {
+            Ok(content) => content,
+            Err(e) => {
+                problem(snippet, &format!("could not read file: {}", e));
+                continue;
+            }
+        };
+        let conf: config::Config = match toml::from_str(&content) {
+            Ok(conf) => conf,
+            Err(e) => {
+                problem(snippet, &format!("could not parse file: {}", e));
+                continue;
             }
+        };
+        let plugins = conf.plugins;
 
-            if result.is_ok() {
-                break;
+        let nr_promoters = plugins.promoter.len();
+        if nr_promoters > 0 && nr_plugins(&plugins) != nr_promoters {
+            problem(snippet, "contains mixed promoter and other plugins");
+        }
+        for promoter in plugins.promoter {
+            for (name, res) in promoter.resources {
+                if res.start.is_empty() {
+                    problem(
+                        snippet,
+                        &format!("promoter resource '{}' has no start", name),
+                    );
+                }
+                match promoted.get(&name) {
+                    Some(first) => problem(
+                        snippet,
+                        &format!(
+                            "promoter resource '{}' already defined in '{}'",
+                            name,
+                            first.display()
+                        ),
+                    ),
+                    None => {
+                        promoted.insert(name, snippet.clone());
+                    }
+                }
             }
         }
+    }
 
-        if !aborted {
-            tmpfile.persist(snippet)?;
-            persisted += 1;
+    if problems > 0 {
+        return Err(anyhow::anyhow!(
+            "Found {} problem(s) in {} config file(s)",
+            problems,
+            snippets_paths.len()
+        ));
+    }
+    green(&format!("{} config file(s) valid", snippets_paths.len()));
+    Ok(())
+}
+
+fn migrate(
+    snippets_paths: Vec<PathBuf>,
+    snippets_path: &PathBuf,
+    cluster: &ClusterConf,
+) -> Result<()> {
+    if do_remote(cluster)? {
+        return Ok(());
+    }
+
+    let mut persisted = 0;
+    for snippet in &snippets_paths {
+        let content = fs::read_to_string(snippet)
+            .with_context(|| format!("Could not read '{}'", snippet.display()))?;
+        let migrated = match config::migrate(&content)
+            .with_context(|| format!("Could not migrate '{}'", snippet.display()))?
+        {
+            Some(migrated) => migrated,
+            None => continue,
+        };
+
+        // same as in edit(), keep the tmpfile on the same mount point
+        let mut tmpfile = NamedTempFile::new_in(snippets_path)?;
+        tmpfile.write_all(migrated.as_bytes())?;
+        tmpfile.flush()?;
+        tmpfile.persist(snippet)?;
+        persisted += 1;
+
+        info(&format!("Migrated '{}':", snippet.display()));
+        for note in migrated.lines().take_while(|l| l.starts_with('#')) {
+            println!("{}", note);
         }
     }
 
@@ -416,12 +746,13 @@ fn rm(snippets_paths: Vec<PathBuf>, force: bool, cluster: &ClusterConf) -> Resul
 }
 
 fn start_until_list(config: promoter::PromoterOptResource, until: &str) -> Result<Vec<String>> {
+    let start = config.start_services();
     match until.parse::<usize>() {
-        Ok(n) => Ok(config.start.into_iter().take(n).collect()),
+        Ok(n) => Ok(start.into_iter().take(n).collect()),
         Err(_) => {
             // assume it it is a service name
-            match config.start.iter().position(|s| s == until) {
-                Some(n) => Ok(config.start.into_iter().take(n + 1).collect()),
+            match start.iter().position(|s| s == until) {
+                Some(n) => Ok(start.into_iter().take(n + 1).collect()),
                 None => Err(anyhow::anyhow!(
                     "Could not find unit '{}' in start list",
                     until
@@ -465,8 +796,11 @@ fn start_until(snippets_paths: Vec<PathBuf>, until: &str) -> Result<()> {
     Ok(())
 }
 
-fn enable(snippets_paths: Vec<PathBuf>, cluster: &ClusterConf) -> Result<()> {
-    if do_remote(cluster)? {
+fn enable(snippets_paths: Vec<PathBuf>, confirm: bool, cluster: &ClusterConf) -> Result<()> {
+    if confirm {
+        confirm_resources("Enable", &snippets_paths)?;
+    }
+    if do_remote_confirmed(cluster, confirm)? {
         return Ok(());
     }
 
@@ -513,8 +847,16 @@ fn stop_targets(snippets_paths: Vec<PathBuf>) -> Result<()> {
     Ok(())
 }
 
-fn disable(snippets_paths: Vec<PathBuf>, with_targets: bool, cluster: &ClusterConf) -> Result<()> {
-    if do_remote(cluster)? {
+fn disable(
+    snippets_paths: Vec<PathBuf>,
+    with_targets: bool,
+    confirm: bool,
+    cluster: &ClusterConf,
+) -> Result<()> {
+    if confirm {
+        confirm_resources("Disable", &snippets_paths)?;
+    }
+    if do_remote_confirmed(cluster, confirm)? {
         return Ok(());
     }
 
@@ -544,6 +886,43 @@ fn disable(snippets_paths: Vec<PathBuf>, with_targets: bool, cluster: &ClusterCo
     Ok(())
 }
 
+// asks before acting on snippets that affect more than one resource (i.e., of promoter plugins),
+// declining (or a stdin that is not interactive) is an error
+fn confirm_resources(action: &str, snippets_paths: &[PathBuf]) -> Result<()> {
+    let mut resources = BTreeSet::new();
+    for snippet in snippets_paths {
+        // broken snippets are reported by the action itself
+        if let Ok(conf) = read_config(snippet) {
+            for promoter in conf.plugins.promoter {
+                resources.extend(promoter.resources.into_keys());
+            }
+        }
+    }
+    if resources.len() <= 1 {
+        return Ok(());
+    }
+    for snippet in snippets_paths {
+        println!("{}", snippet.display());
+    }
+    let confirmed = ask(
+        &format!(
+            "{} these {} snippet(s) affecting {} resources ({})?",
+            action,
+            snippets_paths.len(),
+            resources.len(),
+            resources.into_iter().collect::<Vec<_>>().join(", ")
+        ),
+        false,
+    )?;
+    match confirmed {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!(
+            "{} not confirmed, use --force to skip the confirmation",
+            action
+        )),
+    }
+}
+
 fn get_disabled_path(snippet_path: &Path) -> PathBuf {
     snippet_path.with_extension("toml.disabled")
 }
@@ -578,13 +957,58 @@ fn reload_service() -> Result<()> {
 fn status(
     snippets_paths: Vec<PathBuf>,
     verbose: bool,
+    json: bool,
+    fail_on_degraded: bool,
+    follow: Option<u32>,
     resources: &Vec<String>,
     cluster: &ClusterConf,
 ) -> Result<()> {
     if do_remote(cluster)? {
         return Ok(());
     }
+    if let Some(interval) = follow {
+        return status_follow(snippets_paths, verbose, resources, interval);
+    }
+    let degraded = if json {
+        status_json(snippets_paths, resources)?
+    } else {
+        status_text(snippets_paths, verbose, resources)?
+    };
+    if fail_on_degraded && degraded {
+        std::process::exit(EXIT_DEGRADED);
+    }
+    Ok(())
+}
+
+// re-renders the text status every interval seconds until SIGINT/SIGTERM
+fn status_follow(
+    snippets_paths: Vec<PathBuf>,
+    verbose: bool,
+    resources: &[String],
+    interval: u32,
+) -> Result<()> {
+    TERMINATE.store(false, Ordering::Relaxed);
+    loop {
+        // clear screen and move cursor to the top left
+        print!("\x1B[2J\x1B[H");
+        println!("Every {}s: drbd-reactorctl status\n", interval);
+        status_text(snippets_paths.clone(), verbose, resources)?;
+        io::stdout().flush()?;
+        for _ in 0..interval {
+            if TERMINATE.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+        if TERMINATE.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+    }
+}
 
+// returns true if any promoter unit failed or (in verbose mode) a prometheus address was unreachable
+fn status_text(snippets_paths: Vec<PathBuf>, verbose: bool, resources: &[String]) -> Result<bool> {
+    let mut degraded = false;
     for snippet in snippets_paths {
         println!("{}:", snippet.display());
         let conf = read_config(&snippet)?;
@@ -596,38 +1020,48 @@ fn status(
                     continue;
                 }
                 let target = systemd::escaped_services_target(&drbd_res);
-                let primary = match drbd::get_primary(&drbd_res)? {
+                let primary = match drbd::get_primary(&SystemRunner, &drbd_res)? {
                     PrimaryOn::Local => "this node".to_string(),
                     PrimaryOn::Remote(r) => format!("node '{}'", r),
                     PrimaryOn::None => "<unknown>".to_string(),
                 };
                 println!("Promoter: Currently active on {}", primary);
+                if let Some(adjust) = adjust_state(&drbd_res) {
+                    println!("Adjust: {}", adjust.bold().yellow());
+                }
                 // target itself and the implicit one
                 let promote_service = promote_service(&drbd_res);
+                let target_state = active_state(&target)?;
+                let promote_state = active_state(&promote_service)?;
+                degraded |= target_state == UnitActiveState::Failed
+                    || promote_state == UnitActiveState::Failed;
                 if verbose {
                     // systemctl status in this case returns != 0 if service not started
                     // but we expect that on n-1 nodes and we don't want to fail in this case
                     let _ = systemctl(vec!["status".into(), "--no-pager".into(), target]);
                     let _ = systemctl(vec!["status".into(), "--no-pager".into(), promote_service]);
                 } else {
-                    println!("{} {}", status_dot(&target)?, target);
-                    println!("{} ├─ {}", status_dot(&promote_service)?, promote_service);
+                    println!("{} {}", target_state, target);
+                    println!("{} ├─ {}", promote_state, promote_service);
                 }
-                for (i, start) in config.start.iter().enumerate() {
+                let starts = config.start_services();
+                for (i, start) in starts.iter().enumerate() {
                     let service_name = service_name(start, &drbd_res)?;
+                    let state = active_state(&service_name)?;
+                    degraded |= state == UnitActiveState::Failed;
                     if verbose {
                         // systemctl status in this case returns != 0 if service not started
                         // but we expect that on n-1 nodes and we don't want to fail in this case
                         let _ = systemctl(vec!["status".into(), "--no-pager".into(), service_name]);
                     } else {
-                        let sep = if i == config.start.len() - 1 {
+                        let sep = if i == starts.len() - 1 {
                             "└─"
                         } else {
                             "├─"
                         };
                         println!(
                             "{} {} {} {}",
-                            status_dot(&service_name)?,
+                            state,
                             sep,
                             service_name,
                             freezer_state(&service_name)?
@@ -637,17 +1071,23 @@ fn status(
             }
         }
         for prometheus in plugins.prometheus {
-            println!(
-                "Prometheus: listening on {}",
-                prometheus.address.to_string().bold().green()
-            );
-            if verbose {
-                for addr in prometheus.address.to_socket_addrs()? {
-                    let status = match TcpStream::connect_timeout(&addr, Duration::from_secs(2)) {
-                        Ok(_) => format!("{}", "success".bold().green()),
-                        Err(e) => format!("{} ({})", "failed".bold().red(), e),
-                    };
-                    println!("TCP Connect ({}): {}", addr, status);
+            for address in prometheus.listen_addresses() {
+                println!(
+                    "Prometheus: listening on {}",
+                    address.to_string().bold().green()
+                );
+                if verbose {
+                    for addr in address.to_socket_addrs()? {
+                        let status = match TcpStream::connect_timeout(&addr, Duration::from_secs(2))
+                        {
+                            Ok(_) => format!("{}", "success".bold().green()),
+                            Err(e) => {
+                                degraded = true;
+                                format!("{} ({})", "failed".bold().red(), e)
+                            }
+                        };
+                        println!("TCP Connect ({}): {}", addr, status);
+                    }
                 }
             }
         }
@@ -664,13 +1104,157 @@ fn status(
             );
         }
     }
-    Ok(())
+    Ok(degraded)
+}
+
+#[derive(Serialize)]
+struct UnitStatus {
+    name: String,
+    active_state: UnitActiveState,
+    freezer_state: Option<UnitFreezerState>,
+}
+
+impl UnitStatus {
+    fn new(name: String) -> Result<Self> {
+        let active_state = active_state(&name)?;
+        // see freezer_state() for why this is optional
+        let freezer_state = match systemd::show_property(&SystemRunner, &name, "FreezerState") {
+            Ok(prop) => Some(UnitFreezerState::from_str(&prop)?),
+            Err(_) => None,
+        };
+        Ok(Self {
+            name,
+            active_state,
+            freezer_state,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct PromoterResourceStatus {
+    name: String,
+    primary: Option<String>,
+    primary_local: bool,
+    // e.g., still waiting for a backing device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    adjust: Option<String>,
+    units: Vec<UnitStatus>,
+}
+
+#[derive(Serialize)]
+struct PluginStatus {
+    snippet: PathBuf,
+    #[serde(rename = "type")]
+    kind: String,
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resources: Option<Vec<PromoterResourceStatus>>,
+}
+
+impl PluginStatus {
+    fn new(snippet: &Path, kind: &str, id: Option<String>) -> Self {
+        Self {
+            snippet: snippet.to_path_buf(),
+            kind: kind.to_string(),
+            id,
+            address: None,
+            resources: None,
+        }
+    }
 }
 
-fn cat(snippets_paths: Vec<PathBuf>, cluster: &ClusterConf) -> Result<()> {
+// returns true if any promoter unit failed
+fn status_json(snippets_paths: Vec<PathBuf>, resources: &[String]) -> Result<bool> {
+    let mut status = Vec::new();
+    let mut degraded = false;
+    for snippet in snippets_paths {
+        let conf = read_config(&snippet)?;
+        let plugins = conf.plugins;
+        for promoter in plugins.promoter {
+            let mut res_status = Vec::new();
+            for (drbd_res, config) in promoter.resources {
+                if !resources.is_empty() && !resources.contains(&drbd_res) {
+                    continue;
+                }
+                let (primary, primary_local) = match drbd::get_primary(&SystemRunner, &drbd_res)? {
+                    PrimaryOn::Local => (Some(utils::uname_n()?), true),
+                    PrimaryOn::Remote(r) => (Some(r), false),
+                    PrimaryOn::None => (None, false),
+                };
+                let mut units = vec![
+                    UnitStatus::new(systemd::escaped_services_target(&drbd_res))?,
+                    UnitStatus::new(promote_service(&drbd_res))?,
+                ];
+                for start in &config.start_services() {
+                    units.push(UnitStatus::new(service_name(start, &drbd_res)?)?);
+                }
+                degraded |= units
+                    .iter()
+                    .any(|u| u.active_state == UnitActiveState::Failed);
+                let adjust = adjust_state(&drbd_res);
+                res_status.push(PromoterResourceStatus {
+                    name: drbd_res,
+                    primary,
+                    primary_local,
+                    adjust,
+                    units,
+                });
+            }
+            let mut p = PluginStatus::new(&snippet, "promoter", promoter.id);
+            p.resources = Some(res_status);
+            status.push(p);
+        }
+        for prometheus in plugins.prometheus {
+            let address = prometheus.addresses_string();
+            let mut p = PluginStatus::new(&snippet, "prometheus", prometheus.id);
+            p.address = Some(address);
+            status.push(p);
+        }
+        for debugger in plugins.debugger {
+            status.push(PluginStatus::new(&snippet, "debugger", debugger.id));
+        }
+        for umh in plugins.umh {
+            status.push(PluginStatus::new(&snippet, "umh", umh.id));
+        }
+        for agentx in plugins.agentx {
+            let mut p = PluginStatus::new(&snippet, "agentx", None);
+            p.address = Some(agentx.address);
+            status.push(p);
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    Ok(degraded)
+}
+
+// the pager from the environment (if any), then the pretty printers, plain cat as last resort
+fn catters(plain: bool) -> Result<Vec<Vec<String>>> {
+    let mut catters = Vec::new();
+    if !plain {
+        for var in ["REACTOR_PAGER", "PAGER"] {
+            match env::var(var) {
+                Ok(pager) if !pager.trim().is_empty() => {
+                    let pager = shell_words::split(&pager)
+                        .with_context(|| format!("Could not parse ${}", var))?;
+                    catters.push(pager);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        catters.push(vec!["bat".to_string()]);
+        catters.push(vec!["batcat".to_string()]);
+    }
+    catters.push(vec!["cat".to_string()]);
+    Ok(catters)
+}
+
+fn cat(snippets_paths: Vec<PathBuf>, plain: bool, cluster: &ClusterConf) -> Result<()> {
     if do_remote(cluster)? {
         return Ok(());
     }
+    let catters = catters(plain)?;
     for snippet in snippets_paths {
         if !snippet.exists() {
             warn(&format!(
@@ -680,11 +1264,34 @@ fn cat(snippets_paths: Vec<PathBuf>, cluster: &ClusterConf) -> Result<()> {
             continue;
         }
         eprintln!("Displaying {}...", snippet.display());
-        for catter in vec!["bat", "batcat", "cat"] {
-            if plugin::map_status(Command::new(catter).arg(&snippet).status()).is_ok() {
-                break;
+        let mut displayed = false;
+        for catter in &catters {
+            let status = Command::new(&catter[0])
+                .args(&catter[1..])
+                .arg(&snippet)
+                .status();
+            match status {
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                status => {
+                    plugin::map_status(status).with_context(|| {
+                        format!("'{}' could not display '{}'", catter[0], snippet.display())
+                    })?;
+                    displayed = true;
+                    break;
+                }
             }
         }
+        if !displayed {
+            return Err(anyhow::anyhow!(
+                "None of {} found to display '{}'",
+                catters
+                    .iter()
+                    .map(|c| format!("'{}'", c[0]))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                snippet.display()
+            ));
+        }
     }
     Ok(())
 }
@@ -719,7 +1326,7 @@ fn evict_unmask_and_start(drbd_resources: &Vec<String>) -> Result<()> {
 
 fn evict_resource(drbd_resource: &str, delay: u32) -> Result<()> {
     println!("Evicting {}", drbd_resource);
-    match drbd::get_primary(drbd_resource)? {
+    match drbd::get_primary(&SystemRunner, drbd_resource)? {
         PrimaryOn::None => {
             println!(
                 "Sorry, resource state for '{}' unknown, ignoring",
@@ -731,18 +1338,88 @@ fn evict_resource(drbd_resource: &str, delay: u32) -> Result<()> {
             println!("Active on '{}', nothing to do on this node, ignoring", r,);
             return Ok(());
         }
-        PrimaryOn::Local => (), // we continue
-    };
+        PrimaryOn::Local => (), // we continue
+    };
+
+    let target = systemd::escaped_services_target(drbd_resource);
+    systemctl(vec!["mask".into(), "--runtime".into(), target.clone()])?;
+    systemctl(vec!["daemon-reload".into()])?;
+    systemctl_out_err(vec!["stop".into(), target], Stdio::inherit(), Stdio::null())?;
+
+    let mut needs_newline = false;
+    for i in (0..=delay).rev() {
+        // a know host/peer?
+        if let PrimaryOn::Remote(_r) = drbd::get_primary(&SystemRunner, drbd_resource)? {
+            break;
+        }
+
+        let s = if i != 0 {
+            i.to_string() + ".."
+        } else {
+            i.to_string()
+        };
+        print!("{}", s);
+        io::stdout().flush()?;
+        needs_newline = true;
+        if i != 0 {
+            // no need to sleep on last iteration
+            if TERMINATE.load(Ordering::Relaxed) {
+                break;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+    if needs_newline {
+        println!();
+    }
+
+    match drbd::get_primary(&SystemRunner, drbd_resource)? {
+        PrimaryOn::Local => {
+            println!("Local node still DRBD Primary, not all services stopped in time locally");
+        }
+        PrimaryOn::Remote(r) => println!("Node '{}' took over", r),
+        PrimaryOn::None => {
+            println!("Unfortunately no other node took over, resource in unknown state")
+        }
+    };
+
+    Ok(())
+}
+
+fn evict_resources(drbd_resources: &Vec<String>, keep_masked: bool, delay: u32) -> Result<()> {
+    TERMINATE.store(false, Ordering::Relaxed);
+    for drbd_res in drbd_resources {
+        let result = evict_resource(drbd_res, delay);
+        if !keep_masked {
+            evict_unmask_and_start(&vec![drbd_res.clone()])?;
+        }
+        result?;
+
+        if TERMINATE.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn promote_resource(drbd_resource: &str, delay: u32) -> Result<()> {
+    println!("Promoting {}", drbd_resource);
+    if drbd::get_primary(&SystemRunner, drbd_resource)? == PrimaryOn::Local {
+        println!("Already active on this node, nothing to do, ignoring");
+        return Ok(());
+    }
 
+    // fails intentional if Primary on other node, but that node might currently give it up
     let target = systemd::escaped_services_target(drbd_resource);
-    systemctl(vec!["mask".into(), "--runtime".into(), target.clone()])?;
-    systemctl(vec!["daemon-reload".into()])?;
-    systemctl_out_err(vec!["stop".into(), target], Stdio::inherit(), Stdio::null())?;
+    let _ = systemctl_out_err(
+        vec!["start".into(), target],
+        Stdio::inherit(),
+        Stdio::null(),
+    );
 
     let mut needs_newline = false;
     for i in (0..=delay).rev() {
-        // a know host/peer?
-        if let PrimaryOn::Remote(_r) = drbd::get_primary(drbd_resource)? {
+        if drbd::get_primary(&SystemRunner, drbd_resource)? == PrimaryOn::Local {
             break;
         }
 
@@ -766,27 +1443,41 @@ fn evict_resource(drbd_resource: &str, delay: u32) -> Result<()> {
         println!();
     }
 
-    match drbd::get_primary(drbd_resource)? {
-        PrimaryOn::Local => {
-            println!("Local node still DRBD Primary, not all services stopped in time locally");
+    match drbd::get_primary(&SystemRunner, drbd_resource)? {
+        PrimaryOn::Local => println!("Local node took over"),
+        PrimaryOn::Remote(r) => {
+            println!(
+                "Node '{}' still active, consider evicting it there first",
+                r
+            )
         }
-        PrimaryOn::Remote(r) => println!("Node '{}' took over", r),
         PrimaryOn::None => {
-            println!("Unfortunately no other node took over, resource in unknown state")
+            println!("Unfortunately the local node did not take over, resource in unknown state")
         }
     };
 
     Ok(())
 }
 
-fn evict_resources(drbd_resources: &Vec<String>, keep_masked: bool, delay: u32) -> Result<()> {
+fn promote(snippets_paths: Vec<PathBuf>, drbd_resources: &[String], delay: u32) -> Result<()> {
+    let mut managed = Vec::new();
+    for snippet in snippets_paths {
+        let conf = read_config(&snippet)?;
+        for promoter in conf.plugins.promoter {
+            managed.extend(promoter.resources.into_keys());
+        }
+    }
+
     TERMINATE.store(false, Ordering::Relaxed);
     for drbd_res in drbd_resources {
-        let result = evict_resource(drbd_res, delay);
-        if !keep_masked {
-            evict_unmask_and_start(&vec![drbd_res.clone()])?;
+        if !managed.contains(drbd_res) {
+            warn(&format!(
+                "'{}' is not managed by an enabled promoter plugin, ignoring",
+                drbd_res
+            ));
+            continue;
         }
-        result?;
+        promote_resource(drbd_res, delay)?;
 
         if TERMINATE.load(Ordering::Relaxed) {
             break;
@@ -795,6 +1486,179 @@ fn evict_resources(drbd_resources: &Vec<String>, keep_masked: bool, delay: u32)
     Ok(())
 }
 
+// removes the drop-ins of promoter resources that are not configured anymore (e.g., removed while the daemon was down)
+fn gc(config_file: &Path, force: bool, cluster: &ClusterConf) -> Result<()> {
+    if do_remote(cluster)? {
+        return Ok(());
+    }
+
+    // the effective configuration, i.e., the main file and all enabled snippets
+    let main = read_config(config_file)?;
+    let mut resources = BTreeSet::new();
+    let mut configs = vec![main.clone()];
+    if let Some(snippets) = &main.snippets {
+        for snippet in snippets.files()? {
+            configs.push(read_config(&snippet)?);
+        }
+    }
+    for conf in configs {
+        for promoter in conf.plugins.promoter {
+            resources.extend(promoter.resources.into_keys());
+        }
+    }
+
+    let prefix = Path::new(promoter::SYSTEMD_PREFIX);
+    let orphaned = promoter::orphaned_unit_dirs(prefix, &resources)?;
+    if orphaned.is_empty() {
+        println!("No orphaned units in '{}'", prefix.display());
+        return Ok(());
+    }
+
+    let mut removed = 0;
+    for dir in orphaned {
+        if force || ask(&format!("Remove orphaned '{}'?", dir.display()), false)? {
+            fs::remove_dir_all(&dir)?;
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        systemctl(vec!["daemon-reload".into()])?;
+    }
+    Ok(())
+}
+
+fn logs(drbd_resources: &[String], lines: u32, follow: bool) -> Result<()> {
+    let mut units: Vec<String> = Vec::new();
+    for drbd_res in drbd_resources {
+        let target = systemd::escaped_services_target(drbd_res);
+        let deps = systemd::list_dependencies(&SystemRunner, &target).unwrap_or_else(|e| {
+            warn(&format!(
+                "Could not list dependencies of '{}', only showing the target: {}",
+                target, e
+            ));
+            vec![target, promote_service(drbd_res)]
+        });
+        for unit in deps {
+            if !units.contains(&unit) {
+                units.push(unit);
+            }
+        }
+    }
+
+    let mut args = vec![
+        "--no-pager".to_string(),
+        "-n".to_string(),
+        lines.to_string(),
+    ];
+    if follow {
+        args.push("-f".to_string());
+    }
+    for unit in units {
+        args.push("-u".to_string());
+        args.push(unit);
+    }
+
+    let status = Command::new("journalctl").args(&args).status()?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("'journalctl {}' failed", args.join(" ")));
+    }
+    Ok(())
+}
+
+fn stats(drbd_resources: &[String], interval: u32) -> Result<()> {
+    let sample = || -> Result<Vec<drbd::DeviceStatistics>> {
+        let mut devices = Vec::new();
+        for drbd_res in drbd_resources {
+            devices.extend(drbd::get_device_statistics(&SystemRunner, drbd_res)?);
+        }
+        Ok(devices)
+    };
+
+    let before = sample()?;
+    let started = Instant::now();
+    TERMINATE.store(false, Ordering::Relaxed);
+    for _ in 0..interval {
+        if TERMINATE.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    let after = sample()?;
+    let elapsed = started.elapsed();
+
+    println!(
+        "{:<24} {:>6} {:>6} {:>12} {:>12}",
+        "RESOURCE", "VOLUME", "MINOR", "READ/s", "WRITTEN/s"
+    );
+    for now in &after {
+        // devices that appeared in between have no rate yet
+        let (read, written) = match before
+            .iter()
+            .find(|b| b.resource == now.resource && b.volume == now.volume)
+        {
+            Some(prev) => prev.rates(now, elapsed),
+            None => continue,
+        };
+        println!(
+            "{:<24} {:>6} {:>6} {:>12} {:>12}",
+            now.resource,
+            now.volume,
+            now.minor,
+            kib_rate(read),
+            kib_rate(written)
+        );
+    }
+
+    Ok(())
+}
+
+fn kib_rate(kib_per_sec: f64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    let mut rate = kib_per_sec;
+    let mut unit = 0;
+    while rate >= 1024.0 && unit < UNITS.len() - 1 {
+        rate /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}/s", rate, UNITS[unit])
+}
+
+// used by the dynamic part of the tab completion, so errors are ignored and only lead to less candidates
+fn complete(snippets_path: &PathBuf, what: &str) -> Result<()> {
+    let extension = match what {
+        "disabled" => "toml.disabled",
+        _ => "toml",
+    };
+    let snippets = config::files_with_extension_in(snippets_path, extension).unwrap_or_default();
+
+    let mut candidates = Vec::new();
+    if what == "resources" {
+        for snippet in snippets {
+            if let Ok(conf) = read_config(&snippet) {
+                for promoter in conf.plugins.promoter {
+                    candidates.extend(promoter.resources.into_keys());
+                }
+            }
+        }
+    } else {
+        let suffix = format!(".{}", extension);
+        candidates.extend(snippets.iter().filter_map(|snippet| {
+            snippet
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(&suffix))
+                .map(String::from)
+        }));
+    }
+    candidates.sort();
+    candidates.dedup();
+
+    for candidate in candidates {
+        println!("{}", candidate);
+    }
+    Ok(())
+}
+
 fn nr_plugins(plugins: &plugin::PluginConfig) -> usize {
     plugins.promoter.len()
         + plugins.umh.len()
@@ -861,10 +1725,80 @@ fn evict(
     }
 }
 
-fn ls(snippets_paths: Vec<PathBuf>, cluster: &ClusterConf) -> Result<()> {
+#[derive(Serialize)]
+struct PluginSummary {
+    path: PathBuf,
+    #[serde(rename = "type")]
+    kind: String,
+    id: Option<String>,
+    resources: Vec<String>,
+}
+
+impl PluginSummary {
+    fn new(path: &Path, kind: &str, id: Option<String>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            kind: kind.to_string(),
+            id,
+            resources: Vec::new(),
+        }
+    }
+}
+
+fn ls_structured(snippets_paths: Vec<PathBuf>, output: &str) -> Result<()> {
+    let mut summary = Vec::new();
+    for snippet in snippets_paths {
+        if !snippet.exists() {
+            eprintln!("'{}' does not exist, ignoring", snippet.display());
+            continue;
+        }
+        let plugins = read_config(&snippet)?.plugins;
+        for promoter in plugins.promoter {
+            let mut p = PluginSummary::new(&snippet, "promoter", promoter.id);
+            p.resources = promoter.resources.into_keys().collect();
+            summary.push(p);
+        }
+        for prometheus in plugins.prometheus {
+            summary.push(PluginSummary::new(&snippet, "prometheus", prometheus.id));
+        }
+        for debugger in plugins.debugger {
+            summary.push(PluginSummary::new(&snippet, "debugger", debugger.id));
+        }
+        for umh in plugins.umh {
+            let mut p = PluginSummary::new(&snippet, "umh", umh.id.clone());
+            p.resources = umh
+                .resource_names()
+                .unwrap_or_default()
+                .into_iter()
+                .collect();
+            summary.push(p);
+        }
+        for _ in plugins.agentx {
+            summary.push(PluginSummary::new(&snippet, "agentx", None));
+        }
+    }
+
+    match output {
+        "toml" => {
+            // toml has no top level arrays
+            #[derive(Serialize)]
+            struct Plugins {
+                plugin: Vec<PluginSummary>,
+            }
+            print!("{}", toml::to_string(&Plugins { plugin: summary })?);
+        }
+        _ => println!("{}", serde_json::to_string_pretty(&summary)?),
+    }
+    Ok(())
+}
+
+fn ls(snippets_paths: Vec<PathBuf>, output: &str, cluster: &ClusterConf) -> Result<()> {
     if do_remote(cluster)? {
         return Ok(());
     }
+    if output != "text" {
+        return ls_structured(snippets_paths, output);
+    }
 
     for snippet in snippets_paths {
         println!("{}:", snippet.display());
@@ -879,7 +1813,7 @@ fn ls(snippets_paths: Vec<PathBuf>, cluster: &ClusterConf) -> Result<()> {
         let plugins = conf.plugins;
         for promoter in plugins.promoter {
             for (drbd_res, resource) in promoter.resources {
-                let mut start = resource.start.clone();
+                let mut start = resource.start_services();
                 if start.len() > 3 {
                     start.truncate(3);
                     start.push("...".into());
@@ -899,7 +1833,7 @@ fn ls(snippets_paths: Vec<PathBuf>, cluster: &ClusterConf) -> Result<()> {
             }
         }
         for prometheus in plugins.prometheus {
-            println!("- Prometheus: {}", prometheus.address);
+            println!("- Prometheus: {}", prometheus.addresses_string());
         }
         for _ in plugins.debugger {
             println!("- Debugger");
@@ -915,16 +1849,29 @@ fn ls(snippets_paths: Vec<PathBuf>, cluster: &ClusterConf) -> Result<()> {
     Ok(())
 }
 
-fn restart(snippets_paths: Vec<PathBuf>, with_targets: bool, cluster: &ClusterConf) -> Result<()> {
+fn restart(
+    snippets_paths: Vec<PathBuf>,
+    with_targets: bool,
+    confirm: bool,
+    cluster: &ClusterConf,
+) -> Result<()> {
     if snippets_paths.is_empty() {
         systemctl(vec!["restart".into(), REACTOR_SERVICE.into()])
     } else {
-        disable(snippets_paths.clone(), with_targets, cluster)?;
+        if confirm {
+            confirm_resources("Restart", &snippets_paths)?;
+        }
+        // once for both steps, disable and enable then only act locally
+        if do_remote_confirmed(cluster, confirm)? {
+            return Ok(());
+        }
+        disable(snippets_paths.clone(), with_targets, false, cluster)?;
         enable(
             snippets_paths
                 .into_iter()
                 .map(|p| get_disabled_path(&p))
                 .collect(),
+            false,
             cluster,
         )
     }
@@ -943,15 +1890,17 @@ fn read_config(snippet_path: &Path) -> Result<config::Config> {
     Ok(config)
 }
 
-fn get_snippets_path(path: &PathBuf) -> Option<PathBuf> {
+fn get_snippets(path: &PathBuf) -> Option<config::Snippets> {
     let content = fs::read_to_string(path).ok()?;
 
-    toml::from_str::<config::Config>(&content)
-        .map(|c| c.snippets)
-        .ok()?
+    toml::from_str::<config::Config>(&content).ok()?.snippets
 }
 
-fn expand_snippets(snippets_path: &PathBuf, matches: &ArgMatches, disabled: bool) -> Vec<PathBuf> {
+fn expand_snippets(
+    snippets: &config::Snippets,
+    matches: &ArgMatches,
+    disabled: bool,
+) -> Vec<PathBuf> {
     let expected_extension = match disabled {
         true => "toml.disabled",
         false => "toml",
@@ -960,20 +1909,22 @@ fn expand_snippets(snippets_path: &PathBuf, matches: &ArgMatches, disabled: bool
     let configs: Vec<PathBuf> = match matches.values_of("configs") {
         Some(configs) => configs.map(PathBuf::from).collect::<Vec<_>>(), // process them in the next stage
         None => {
-            // "glob expand"
-            match config::files_with_extension_in(snippets_path, expected_extension) {
+            // all snippets, i.e., all files of the directory or all files matching the globs
+            let files = match disabled {
+                true => snippets.disabled_files(),
+                false => snippets.files(),
+            };
+            match files {
                 Ok(paths) => return paths,
                 Err(e) => {
-                    eprintln!(
-                        "Error reading files in '{}': {}",
-                        snippets_path.display(),
-                        e
-                    );
+                    eprintln!("Error reading snippets: {}", e);
                     return Vec::new();
                 }
             }
         }
     };
+    // relative names are looked up where new snippets are created
+    let snippets_path = snippets.directory().unwrap_or_default();
 
     let mut paths = Vec::new();
     for config in configs {
@@ -1000,14 +1951,16 @@ fn expand_snippets(snippets_path: &PathBuf, matches: &ArgMatches, disabled: bool
             }
         };
 
-        let mut abspath = PathBuf::from(snippets_path);
-        abspath.push(config);
-        paths.push(abspath);
+        paths.push(snippets_path.join(config));
     }
 
     paths
 }
 
+fn adjust_state(drbd_res: &str) -> Option<String> {
+    promoter::adjust_state(Path::new(promoter::ADJUST_STATE_DIR), drbd_res)
+}
+
 fn promote_service(drbd_res: &str) -> String {
     format!("drbd-promote@{}.service", systemd::escape_name(drbd_res))
 }
@@ -1018,7 +1971,14 @@ fn service_name(start_entry: &str, drbd_res: &str) -> Result<String> {
     let (service_name, _) = match ocf_pattern.captures(start) {
         Some(ocf) => {
             let (vendor, agent, args) = (&ocf[1], &ocf[2], &ocf[3]);
-            systemd::escaped_ocf_parse_to_env(drbd_res, vendor, agent, args)?
+            // only the name is needed, which does not depend on the OCF root
+            systemd::escaped_ocf_parse_to_env(
+                drbd_res,
+                vendor,
+                agent,
+                args,
+                Path::new(systemd::OCF_ROOT),
+            )?
         }
         _ => (start.to_string(), Vec::new()),
     };
@@ -1145,6 +2105,20 @@ fn pexec(cmds: &[Vec<String>]) -> Result<Vec<Output>> {
 }
 
 fn do_remote(cluster: &ClusterConf) -> Result<bool> {
+    do_remote_with(cluster, &[])
+}
+
+// the user already confirmed the action on this node, the nodes must not ask again
+fn do_remote_confirmed(cluster: &ClusterConf, confirmed: bool) -> Result<bool> {
+    match confirmed {
+        true => do_remote_with(cluster, &["--force"]),
+        false => do_remote(cluster),
+    }
+}
+
+// executes the command with the additional arguments on all nodes, the arguments are appended to the
+// original ones, so they have to be options of the subcommand
+fn do_remote_with(cluster: &ClusterConf, extra_args: &[&str]) -> Result<bool> {
     if cluster.local {
         return Ok(false);
     }
@@ -1192,6 +2166,7 @@ fn do_remote(cluster: &ClusterConf) -> Result<bool> {
         node_args.push("--local".to_string());
         let mut args = orig_args.clone();
         node_args.append(&mut args);
+        node_args.extend(extra_args.iter().map(|a| a.to_string()));
         cmds.push(node_args);
     }
     let results = pexec(&cmds)?;
@@ -1210,6 +2185,20 @@ fn do_remote(cluster: &ClusterConf) -> Result<bool> {
 }
 
 fn get_app() -> App<'static, 'static> {
+    get_public_app().subcommand(
+        SubCommand::with_name("__complete")
+            .setting(AppSettings::Hidden)
+            .about("List candidates for tab completion")
+            .arg(Arg::with_name("what").required(true).possible_values(&[
+                "snippets",
+                "disabled",
+                "resources",
+            ])),
+    )
+}
+
+// without internal helpers, clap can not generate bash completions for subcommands containing "__"
+fn get_public_app() -> App<'static, 'static> {
     App::new("drbd-reactorctl")
         .author(crate_authors!("\n"))
         .version(crate_version!())
@@ -1248,6 +2237,17 @@ fn get_app() -> App<'static, 'static> {
                         .long("now")
                         .help("In case of promoter plugin stop the drbd-resources target"),
                 )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Disable all snippets")
+                        .conflicts_with("configs"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Do not ask for confirmation"),
+                )
                 .arg(
                     Arg::with_name("configs")
                         .help("Configs to disable")
@@ -1256,12 +2256,25 @@ fn get_app() -> App<'static, 'static> {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("enable").about("enable plugin").arg(
-                Arg::with_name("configs")
-                    .help("Configs to enable")
-                    .required(false)
-                    .multiple(true),
-            ),
+            SubCommand::with_name("enable")
+                .about("enable plugin")
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Enable all disabled snippets")
+                        .conflicts_with("configs"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Do not ask for confirmation"),
+                )
+                .arg(
+                    Arg::with_name("configs")
+                        .help("Configs to enable")
+                        .required(false)
+                        .multiple(true),
+                ),
         )
         .subcommand(
             SubCommand::with_name("status")
@@ -1272,6 +2285,34 @@ fn get_app() -> App<'static, 'static> {
                         .short("v")
                         .long("verbose"),
                 )
+                .arg(
+                    Arg::with_name("json")
+                        .help("Print status as JSON")
+                        .long("json")
+                        .conflicts_with("verbose"),
+                )
+                .arg(
+                    Arg::with_name("fail_on_degraded")
+                        .help(
+                            "Exit with 3 if a promoter unit failed or, in verbose mode, a \
+                             prometheus address is unreachable",
+                        )
+                        .long("fail-on-degraded"),
+                )
+                .arg(
+                    Arg::with_name("follow")
+                        .help("Re-render the status every interval until interrupted")
+                        .short("f")
+                        .long("follow")
+                        .conflicts_with_all(&["json", "fail_on_degraded"]),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .help("Positive number of seconds between renders in follow mode")
+                        .long("interval")
+                        .default_value("2")
+                        .validator(has_positive_u32),
+                )
                 .arg(
                     Arg::with_name("resource")
                         .help("In case of a promoter plugin limit to these DRBD resources")
@@ -1294,6 +2335,17 @@ fn get_app() -> App<'static, 'static> {
                     "also stop the drbd-service@.target for promoter plugins, might get started \
                      on different node.",
                 ))
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Restart all snippets instead of the daemon")
+                        .conflicts_with("configs"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Do not ask for confirmation"),
+                )
                 .arg(
                     Arg::with_name("configs")
                         .help("Configs to restart")
@@ -1324,6 +2376,12 @@ fn get_app() -> App<'static, 'static> {
                         .long("disabled")
                         .help("Edit a disabled file"),
                 )
+                .arg(
+                    Arg::with_name("main")
+                        .long("main")
+                        .help("Edit the main configuration file instead of a snippet")
+                        .conflicts_with_all(&["configs", "disabled"]),
+                )
                 .arg(
                     Arg::with_name("configs")
                         .help("Configs to edit/add")
@@ -1331,6 +2389,34 @@ fn get_app() -> App<'static, 'static> {
                         .multiple(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Migrate deprecated settings in plugin configurations")
+                .arg(
+                    Arg::with_name("disabled")
+                        .long("disabled")
+                        .help("Migrate a disabled file"),
+                )
+                .arg(
+                    Arg::with_name("configs")
+                        .help("Configs to migrate")
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Validate plugin configurations")
+                .arg(
+                    Arg::with_name("disabled")
+                        .long("disabled")
+                        .help("Validate disabled files"),
+                )
+                .arg(
+                    Arg::with_name("configs")
+                        .help("Configs to validate")
+                        .multiple(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("rm")
                 .about("Remove plugin configuration")
@@ -1392,9 +2478,81 @@ It is used to clear previous '--keep-masked' operations",
                         .multiple(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("promote")
+                .about("Promote a promoter plugin controlled resource on this node")
+                .arg(
+                    Arg::with_name("delay")
+                        .short("d")
+                        .long("delay")
+                        .default_value("20")
+                        .validator(has_positive_u32)
+                        .help("Positive number of seconds to wait for the local node to take over"),
+                )
+                .arg(
+                    Arg::with_name("resources")
+                        .help("DRBD resources to promote")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Remove generated systemd units of promoter resources that are not configured anymore")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Remove without asking for confirmation"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("logs")
+                .about("Show the journal of the units of promoter resources")
+                .arg(
+                    Arg::with_name("lines")
+                        .short("n")
+                        .long("lines")
+                        .default_value("50")
+                        .validator(has_positive_u32)
+                        .help("Positive number of journal entries to show"),
+                )
+                .arg(
+                    Arg::with_name("follow")
+                        .short("f")
+                        .long("follow")
+                        .help("Follow the journal"),
+                )
+                .arg(
+                    Arg::with_name("resources")
+                        .help("DRBD resources to show the logs for")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Show read/write throughput of the devices of DRBD resources")
+                .arg(
+                    Arg::with_name("interval")
+                        .short("i")
+                        .long("interval")
+                        .default_value("1")
+                        .validator(has_positive_u32)
+                        .help("Positive number of seconds between the two samples"),
+                )
+                .arg(
+                    Arg::with_name("resources")
+                        .help("DRBD resources to show the throughput for")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("cat")
                 .about("(Pretty) print config files")
+                .arg(Arg::with_name("plain").long("plain").help(
+                    "Always use plain 'cat', ignore $REACTOR_PAGER/$PAGER and pretty printers",
+                ))
                 .arg(
                     Arg::with_name("configs")
                         .help("Configs to cat")
@@ -1409,6 +2567,14 @@ It is used to clear previous '--keep-masked' operations",
                         .long("disabled")
                         .help("show disabled plugins"),
                 )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .possible_values(&["text", "json", "toml"])
+                        .default_value("text")
+                        .help("Output format, json and toml list path, type, id, and resources per plugin"),
+                )
                 .arg(
                     Arg::with_name("configs")
                         .help("Configs to list")
@@ -1445,6 +2611,61 @@ It is used to clear previous '--keep-masked' operations",
         )
 }
 
+const BASH_DYNAMIC_COMPLETION: &str = r###"
+_drbd-reactorctl_dynamic() {
+    local i w cur prev sub="" disabled="" skip="" what=""
+    cur="${COMP_WORDS[COMP_CWORD]}"
+    prev="${COMP_WORDS[COMP_CWORD-1]}"
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        w="${COMP_WORDS[i]}"
+        if [ -n "${skip}" ]; then
+            skip=""
+            continue
+        fi
+        case "${w}" in
+            -c|--config|--context|--nodes) skip="yes" ;;
+            --disabled) disabled="yes" ;;
+            -*) ;;
+            *) [ -z "${sub}" ] && sub="${w}" ;;
+        esac
+    done
+
+    case "${prev}" in
+        -r|--resource) what="resources" ;;
+        -d|--delay|-n|--lines) ;;
+        *)
+            if [[ "${cur}" != -* ]]; then
+                case "${sub}" in
+                    enable) what="disabled" ;;
+                    disable|status|restart|edit|migrate|rm|evict|cat|ls|start-until)
+                        what="snippets"
+                        [ -n "${disabled}" ] && what="disabled"
+                        ;;
+                    promote|logs|stats) what="resources" ;;
+                esac
+            fi
+            ;;
+    esac
+
+    if [ -n "${what}" ]; then
+        COMPREPLY=( $(compgen -W "$(drbd-reactorctl __complete "${what}" 2>/dev/null)" -- "${cur}") )
+        return 0
+    fi
+    _drbd-reactorctl
+}
+
+complete -F _drbd-reactorctl_dynamic -o bashdefault -o default drbd-reactorctl
+"###;
+
+const FISH_DYNAMIC_COMPLETION: &str = r###"
+complete -c drbd-reactorctl -n "__fish_seen_subcommand_from enable" -f -a "(drbd-reactorctl __complete disabled 2>/dev/null)"
+complete -c drbd-reactorctl -n "__fish_seen_subcommand_from disable status restart edit migrate rm evict cat ls start-until; and not __fish_contains_opt disabled" -f -a "(drbd-reactorctl __complete snippets 2>/dev/null)"
+complete -c drbd-reactorctl -n "__fish_seen_subcommand_from edit migrate rm cat ls; and __fish_contains_opt disabled" -f -a "(drbd-reactorctl __complete disabled 2>/dev/null)"
+complete -c drbd-reactorctl -n "__fish_seen_subcommand_from promote logs stats" -f -a "(drbd-reactorctl __complete resources 2>/dev/null)"
+complete -c drbd-reactorctl -n "__fish_seen_subcommand_from status" -s r -l resource -x -a "(drbd-reactorctl __complete resources 2>/dev/null)"
+"###;
+
 fn has_positive_u32(s: String) -> Result<(), String> {
     match s.parse::<u32>() {
         Ok(i) => {
@@ -1472,16 +2693,15 @@ fn systemctl(args: Vec<String>) -> Result<()> {
     systemctl_out_err(args, Stdio::inherit(), Stdio::inherit())
 }
 
-fn status_dot(unit: &str) -> Result<String> {
-    let prop = systemd::show_property(unit, "ActiveState")?;
-    let state = UnitActiveState::from_str(&prop)?;
-    Ok(format!("{}", state))
+fn active_state(unit: &str) -> Result<UnitActiveState> {
+    let prop = systemd::show_property(&SystemRunner, unit, "ActiveState")?;
+    Ok(UnitActiveState::from_str(&prop)?)
 }
 
 fn freezer_state(unit: &str) -> Result<String> {
     // we can not always expect a value on older systemd that did not have freeze support
     // in that case we get an Err() which we discard.
-    let prop = match systemd::show_property(unit, "FreezerState") {
+    let prop = match systemd::show_property(&SystemRunner, unit, "FreezerState") {
         Ok(x) => x,
         Err(_) => return Ok("".into()),
     };
@@ -1490,6 +2710,8 @@ fn freezer_state(unit: &str) -> Result<String> {
 }
 
 // most of that inspired by systemc/src/basic/unit-def.c
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
 enum UnitFreezerState {
     Running,
     Freezing,
@@ -1532,6 +2754,10 @@ fn yellow_prefix(prefix: &str, text: &str) {
     println!("{} {}", prefix.bold().yellow(), text)
 }
 
+fn red_prefix(prefix: &str, text: &str) {
+    println!("{} {}", prefix.bold().red(), text)
+}
+
 fn warn(text: &str) {
     yellow_prefix("WARN:", text)
 }
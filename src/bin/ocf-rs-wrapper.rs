@@ -11,6 +11,8 @@ use anyhow::{Context, Result};
 use log::{error, info};
 use signal_hook::iterator::Signals;
 
+use drbd_reactor::utils;
+
 const EXIT_CODE_SUCCESS: i32 = 0;
 const EXIT_CODE_FAILURE: i32 = 1;
 const OCF_SUCCESS: i32 = EXIT_CODE_SUCCESS;
@@ -55,6 +57,8 @@ fn _main() -> Result<ExitCode> {
     let _ = env::var("OCF_ROOT").context("'OCF_ROOT' has to be set")?;
     let ocf_resource_instance =
         env::var("OCF_RESOURCE_INSTANCE").context("'OCF_RESOURCE_INSTANCE' has to be set")?;
+    // just cosmetics for ps, not worth failing for
+    let _ = utils::set_process_title(&utils::process_title("ocf-rs", &ocf_resource_instance));
 
     let key = "NOTIFY_SOCKET";
     let notify_socket = env::var(key).ok();
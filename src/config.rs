@@ -1,5 +1,8 @@
+use std::collections::HashSet;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 use std::{fmt, fs};
 
 use anyhow::Result;
@@ -20,17 +23,203 @@ pub struct Config {
     pub statistics_poll_interval: u64,
 
     #[serde(default)]
-    pub snippets: Option<PathBuf>,
+    pub snippets: Option<Snippets>,
+
+    // name of the main thread as shown by ps, truncated to 15 bytes
+    #[serde(default)]
+    pub process_title: Option<String>,
+
+    // give up if "drbdsetup events2" failed more than that often within the window (seconds), 0 never gives up
+    #[serde(default = "default_events2_max_restarts")]
+    pub events2_max_restarts: u32,
+    #[serde(default = "default_events2_restart_window")]
+    pub events2_restart_window_secs: u64,
+    // restarts are delayed exponentially, starting at 1s up to this
+    #[serde(default = "default_events2_max_restart_delay")]
+    pub events2_max_restart_delay_secs: u64,
+
+    // version of the configuration format the config was written for, 0 if not declared
+    #[serde(default)]
+    pub config_version: u32,
+
+    // glob patterns of DRBD resources drbd-reactor does not track at all
+    #[serde(default)]
+    pub ignore_resources: Vec<String>,
+    // glob patterns of DRBD resources drbd-reactor tracks, empty tracks all
+    #[serde(default)]
+    pub include_resources: Vec<String>,
 
     #[serde(flatten)]
     pub plugins: plugin::PluginConfig,
 }
 
+/// The current version of the configuration format. Configurations declaring an older
+/// `config-version` get a summary of the deprecated settings they still use.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Deprecated settings still used in a configuration that declares an older `config-version`.
+pub fn deprecated_settings(cfg: &Config) -> Vec<String> {
+    let mut found = Vec::new();
+    if cfg.config_version >= CONFIG_VERSION {
+        return found;
+    }
+
+    let mut id = |kind: &str, id: &Option<String>| {
+        if let Some(id) = id {
+            found.push(format!("{} plugin 'id = \"{}\"'", kind, id));
+        }
+    };
+    let plugins = &cfg.plugins;
+    plugins.debugger.iter().for_each(|p| id("debugger", &p.id));
+    plugins.umh.iter().for_each(|p| id("umh", &p.id));
+    plugins
+        .prometheus
+        .iter()
+        .for_each(|p| id("prometheus", &p.id));
+    plugins.promoter.iter().for_each(|p| id("promoter", &p.id));
+
+    for promoter in &plugins.promoter {
+        for (name, res) in &promoter.resources {
+            if !res.on_stop_failure.is_empty() {
+                found.push(format!(
+                    "promoter resource '{}' 'on-stop-failure = \"{}\"'",
+                    name, res.on_stop_failure
+                ));
+            }
+        }
+    }
+
+    found
+}
+
+/// Where config snippets are read from: all "*.toml" files in a directory, or the files matching a
+/// list of glob patterns (e.g., "/etc/drbd-reactor.d/tier1/*.toml").
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Snippets {
+    Directory(PathBuf),
+    Globs(Vec<String>),
+}
+
+impl Snippets {
+    /// The snippet files, sorted per directory/pattern and without duplicates
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        match self {
+            Snippets::Directory(path) => files_with_extension_in(path, "toml"),
+            Snippets::Globs(patterns) => files_matching_globs(patterns),
+        }
+    }
+
+    /// The disabled snippet files (i.e., "*.toml.disabled"), that would be returned by `files()` once
+    /// enabled
+    pub fn disabled_files(&self) -> Result<Vec<PathBuf>> {
+        match self {
+            Snippets::Directory(path) => files_with_extension_in(path, "toml.disabled"),
+            Snippets::Globs(patterns) => files_matching_globs(
+                &patterns
+                    .iter()
+                    .map(|p| format!("{}.disabled", p))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+
+    /// The directory new snippets are created in: the directory itself or the one of the first pattern
+    pub fn directory(&self) -> Option<PathBuf> {
+        match self {
+            Snippets::Directory(path) => Some(path.clone()),
+            Snippets::Globs(patterns) => patterns
+                .first()
+                .and_then(|p| Path::new(p).parent())
+                .map(Path::to_path_buf),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
 pub struct LogConfig {
     #[serde(default = "default_level")]
     pub level: LevelFilter,
     pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub format: LogFormat,
+    // None: only for files, stderr usually ends up in the journal, which has its own timestamps
+    pub timestamps: Option<bool>,
+    // log to the local syslog instead of 'file' or stderr
+    #[serde(default)]
+    pub syslog: bool,
+    #[serde(default)]
+    pub syslog_facility: SyslogFacility,
+}
+
+impl LogConfig {
+    /// If text log lines get prefixed with a timestamp
+    pub fn with_timestamps(&self) -> bool {
+        self.timestamps.unwrap_or(self.file.is_some())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// "LEVEL [target] message", the traditional format
+    #[default]
+    Text,
+    /// One JSON object per line with "timestamp", "level", "target", "message", and "context" if set
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    User,
+    Mail,
+    #[default]
+    Daemon,
+    Auth,
+    Authpriv,
+    Syslog,
+    Lpr,
+    News,
+    Uucp,
+    Cron,
+    Ftp,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    /// The facility as expected by syslog(3)
+    pub fn code(self) -> libc::c_int {
+        match self {
+            Self::User => libc::LOG_USER,
+            Self::Mail => libc::LOG_MAIL,
+            Self::Daemon => libc::LOG_DAEMON,
+            Self::Auth => libc::LOG_AUTH,
+            Self::Authpriv => libc::LOG_AUTHPRIV,
+            Self::Syslog => libc::LOG_SYSLOG,
+            Self::Lpr => libc::LOG_LPR,
+            Self::News => libc::LOG_NEWS,
+            Self::Uucp => libc::LOG_UUCP,
+            Self::Cron => libc::LOG_CRON,
+            Self::Ftp => libc::LOG_FTP,
+            Self::Local0 => libc::LOG_LOCAL0,
+            Self::Local1 => libc::LOG_LOCAL1,
+            Self::Local2 => libc::LOG_LOCAL2,
+            Self::Local3 => libc::LOG_LOCAL3,
+            Self::Local4 => libc::LOG_LOCAL4,
+            Self::Local5 => libc::LOG_LOCAL5,
+            Self::Local6 => libc::LOG_LOCAL6,
+            Self::Local7 => libc::LOG_LOCAL7,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Debug, Clone)]
@@ -97,6 +286,19 @@ fn default_statistics() -> u64 {
     60
 }
 
+fn default_events2_max_restarts() -> u32 {
+    // like before there was a limit, e.g., the DRBD module might not be loaded yet at boot
+    0
+}
+
+fn default_events2_restart_window() -> u64 {
+    60
+}
+
+fn default_events2_max_restart_delay() -> u64 {
+    30
+}
+
 fn default_level() -> LevelFilter {
     LevelFilter::Info
 }
@@ -105,6 +307,10 @@ fn default_log() -> Vec<LogConfig> {
     vec![LogConfig {
         level: default_level(),
         file: None,
+        format: LogFormat::Text,
+        timestamps: None,
+        syslog: false,
+        syslog_facility: SyslogFacility::Daemon,
     }]
 }
 
@@ -118,6 +324,169 @@ pub fn read_snippets(path: impl IntoIterator<Item = impl AsRef<Path>>) -> Result
     Ok(s)
 }
 
+const PLUGIN_KINDS: [&str; 5] = ["promoter", "debugger", "umh", "prometheus", "agentx"];
+
+/// Rewrites deprecated settings in a configuration (snippet) to their current equivalents.
+///
+/// Returns `None` if there was nothing to migrate. The content gets re-serialized, so comments of the original
+/// are lost; what was changed is documented in comments at the top of the result.
+pub fn migrate(content: &str) -> Result<Option<String>> {
+    let mut cfg: toml::value::Table = toml::from_str(content)?;
+    let mut notes = Vec::new();
+
+    for kind in PLUGIN_KINDS {
+        let instances = match cfg.get_mut(kind).and_then(toml::Value::as_array_mut) {
+            Some(instances) => instances,
+            None => continue,
+        };
+        for instance in instances.iter_mut().filter_map(toml::Value::as_table_mut) {
+            if let Some(id) = instance.remove("id") {
+                notes.push(format!("removed deprecated {} plugin 'id = {}'", kind, id));
+            }
+            if kind != "promoter" {
+                continue;
+            }
+            let resources = instance
+                .get_mut("resources")
+                .and_then(toml::Value::as_table_mut);
+            for (name, res) in resources.into_iter().flatten() {
+                if let Some(res) = res.as_table_mut() {
+                    migrate_on_stop_failure(name, res, &mut notes);
+                }
+            }
+        }
+    }
+
+    if notes.is_empty() {
+        return Ok(None);
+    }
+
+    let mut migrated = String::new();
+    for note in notes {
+        migrated.push_str(&format!("# drbd-reactorctl migrate: {}\n", note));
+    }
+    migrated.push_str(&toml::to_string(&cfg)?);
+    Ok(Some(migrated))
+}
+
+fn migrate_on_stop_failure(name: &str, res: &mut toml::value::Table, notes: &mut Vec<String>) {
+    let cmd = match res.remove("on-stop-failure") {
+        Some(cmd) => cmd,
+        None => return,
+    };
+    let cmd = cmd.as_str().unwrap_or_default().trim().to_string();
+    if cmd.is_empty() {
+        notes.push(format!(
+            "removed empty 'on-stop-failure' from resource '{}'",
+            name
+        ));
+        return;
+    }
+
+    // the usual commands were writing to sysrq-trigger, these have a systemd equivalent
+    let action = if !cmd.contains("/proc/sysrq-trigger") {
+        None
+    } else if cmd.starts_with("echo b") {
+        Some(plugin::promoter::SystemdFailureAction::RebootImmediate)
+    } else if cmd.starts_with("echo o") {
+        Some(plugin::promoter::SystemdFailureAction::PoweroffImmediate)
+    } else {
+        None
+    };
+
+    match action {
+        Some(action) if !res.contains_key("on-drbd-demote-failure") => {
+            res.insert(
+                "on-drbd-demote-failure".to_string(),
+                toml::Value::String(action.to_string()),
+            );
+            notes.push(format!(
+                "replaced 'on-stop-failure = \"{}\"' of resource '{}' by 'on-drbd-demote-failure = \"{}\"'",
+                cmd, name, action
+            ));
+        }
+        _ => notes.push(format!(
+            "removed 'on-stop-failure = \"{}\"' from resource '{}', it was ignored, \
+             consider setting 'on-drbd-demote-failure'",
+            cmd, name
+        )),
+    }
+}
+
+static CONFIG_HASH: Mutex<Option<String>> = Mutex::new(None);
+static RELOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a stable digest of the given configuration.
+///
+/// The configuration is serialized with sorted keys, so equivalent configurations (e.g., different key order,
+/// explicitly set default values) result in the same hash, independent of the build or the node.
+pub fn hash(config: &Config) -> Result<String> {
+    let value = serde_json::to_value(config)?;
+    let mut canonical = String::new();
+    write_canonical(&value, &mut canonical)?;
+
+    // FNV-1a, we only need it to be stable, not to be cryptographically secure
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in canonical.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+fn write_canonical(value: &serde_json::Value, out: &mut String) -> Result<()> {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                write_canonical(&map[key], out)?;
+            }
+            out.push('}');
+        }
+        serde_json::Value::Array(values) => {
+            out.push('[');
+            for (i, v) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(v, out)?;
+            }
+            out.push(']');
+        }
+        v => out.push_str(&serde_json::to_string(v)?),
+    }
+    Ok(())
+}
+
+/// Sets the hash of the currently active configuration.
+pub fn set_config_hash(hash: String) {
+    if let Ok(mut h) = CONFIG_HASH.lock() {
+        *h = Some(hash);
+    }
+}
+
+/// Returns the hash of the currently active configuration, if it was set.
+pub fn config_hash() -> Option<String> {
+    CONFIG_HASH.lock().ok().and_then(|h| h.clone())
+}
+
+/// Counts a requested reload of the configuration (e.g., SIGHUP).
+pub fn count_reload() {
+    RELOADS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of configuration reloads since start.
+pub fn reloads() -> u64 {
+    RELOADS.load(Ordering::Relaxed)
+}
+
 pub fn files_with_extension_in(path: &PathBuf, extension: &str) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
     let extension = ".".to_owned() + extension;
@@ -143,6 +512,98 @@ pub fn files_with_extension_in(path: &PathBuf, extension: &str) -> Result<Vec<Pa
     Ok(files)
 }
 
+/// Files matching any of the patterns. Results of a pattern are sorted, and files matched by an
+/// earlier pattern are skipped. Wildcards ('*', '?') are only supported in the file name.
+pub fn files_matching_globs(patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    for pattern in patterns {
+        let pattern = Path::new(pattern);
+        let (dir, name) = match (pattern.parent(), pattern.file_name()) {
+            (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+            _ => return Err(anyhow::anyhow!("Invalid pattern '{}'", pattern.display())),
+        };
+        if dir.to_string_lossy().contains(['*', '?']) {
+            return Err(anyhow::anyhow!(
+                "Wildcards are only supported in the file name: '{}'",
+                pattern.display()
+            ));
+        }
+        // like in a shell a pattern that does not match anything is not an error
+        if !dir.is_dir() {
+            continue;
+        }
+
+        let name: Vec<char> = name.chars().collect();
+        let mut matched = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = match entry {
+                Ok(e) => e.path(),
+                _ => continue,
+            };
+            if !path.is_file() {
+                continue;
+            }
+            let file_name: Vec<char> = match path.file_name() {
+                Some(f) => f.to_string_lossy().chars().collect(),
+                None => continue,
+            };
+            if glob_match(&name, &file_name) {
+                matched.push(path);
+            }
+        }
+        matched.sort();
+        files.extend(matched.into_iter().filter(|f| seen.insert(f.clone())));
+    }
+
+    Ok(files)
+}
+
+/// Returns true if the resource is tracked according to the glob patterns of 'include-resources'
+/// (empty includes all) and 'ignore-resources'.
+pub fn tracks_resource(include: &[String], ignore: &[String], name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let matches = |patterns: &[String]| {
+        patterns
+            .iter()
+            .any(|p| glob_match(&p.chars().collect::<Vec<_>>(), &name))
+    };
+    (include.is_empty() || matches(include)) && !matches(ignore)
+}
+
+// '*' matches any sequence, '?' any single character, but neither matches a leading '.'
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    if name.first() == Some(&'.') && pattern.first() != Some(&'.') {
+        return false;
+    }
+
+    let (mut p, mut n) = (0, 0);
+    // position after the last '*' in the pattern and the name position it currently covers
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last '*' cover one more character
+                Some((bp, bn)) => {
+                    p = bp;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 #[cfg(test)]
 mod tests {
     use toml;
@@ -159,6 +620,188 @@ mod tests {
     const LOCAL_ADDRESS_UNSPECIFIED: &str = "address = \":9999\"";
     const LOCAL_ADDRESS_ERR: &str = "address = \"::9999\"";
 
+    #[test]
+    fn test_hash() {
+        let a: Config = toml::from_str(
+            r#"
+            [[prometheus]]
+            enums = true
+            address = ":9942"
+
+            [[promoter]]
+            [promoter.resources.foo]
+            start = ["foo.service"]
+            [promoter.resources.bar]
+            start = ["bar.service"]
+            "#,
+        )
+        .expect("cfg must parse");
+        // different order, explicit defaults
+        let b: Config = toml::from_str(
+            r#"
+            statistics-poll-interval = 60
+
+            [[promoter]]
+            [promoter.resources.bar]
+            start = ["bar.service"]
+            [promoter.resources.foo]
+            start = ["foo.service"]
+
+            [[prometheus]]
+            address = ":9942"
+            enums = true
+            "#,
+        )
+        .expect("cfg must parse");
+        let c: Config = toml::from_str(
+            r#"
+            [[prometheus]]
+            enums = true
+            address = ":9942"
+
+            [[promoter]]
+            [promoter.resources.foo]
+            start = ["foo.service"]
+            [promoter.resources.bar]
+            start = ["bar.service", "baz.service"]
+            "#,
+        )
+        .expect("cfg must parse");
+
+        let hash_a = hash(&a).expect("hash must succeed");
+        assert_eq!(hash_a.len(), 16);
+        assert_eq!(hash_a, hash(&b).expect("hash must succeed"));
+        assert_ne!(hash_a, hash(&c).expect("hash must succeed"));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let m = |p: &str, n: &str| {
+            glob_match(
+                &p.chars().collect::<Vec<_>>(),
+                &n.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(m("*.toml", "foo.toml"));
+        assert!(!m("*.toml", ".toml"));
+        assert!(m(".*.toml", ".foo.toml"));
+        assert!(!m("*.toml", "foo.toml.disabled"));
+        assert!(m("*", "foo"));
+        assert!(m("f?o*.toml", "foo-bar.toml"));
+        assert!(!m("f?o*.toml", "fo.toml"));
+        assert!(m("*a*b", "xaxxab"));
+        assert!(!m("*a*b", "xaxxa"));
+        assert!(m("foo.toml", "foo.toml"));
+        assert!(!m("foo.toml", "bar.toml"));
+    }
+
+    #[test]
+    fn test_tracks_resource() {
+        let cfg: Config = toml::from_str(r#"ignore-resources = ["backup-*", "scratch"]"#)
+            .expect("cfg must parse");
+        let tracks = |name| tracks_resource(&cfg.include_resources, &cfg.ignore_resources, name);
+        assert!(!tracks("backup-1"));
+        assert!(!tracks("scratch"));
+        assert!(tracks("scratch2"));
+        assert!(tracks("db"));
+
+        let cfg: Config = toml::from_str(
+            r#"include-resources = ["db*", "web"]
+ignore-resources = ["db-test"]"#,
+        )
+        .expect("cfg must parse");
+        let tracks = |name| tracks_resource(&cfg.include_resources, &cfg.ignore_resources, name);
+        assert!(tracks("db1"));
+        assert!(tracks("web"));
+        assert!(!tracks("db-test"));
+        assert!(!tracks("backup-1"));
+    }
+
+    #[test]
+    fn test_snippets() {
+        let dir = tempfile::tempdir().expect("tempdir must work");
+        let tier1 = dir.path().join("tier1");
+        fs::create_dir(&tier1).expect("mkdir must work");
+        for f in [
+            dir.path().join("b.toml"),
+            dir.path().join("a.toml"),
+            dir.path().join("c.toml.disabled"),
+            tier1.join("a.toml"),
+        ] {
+            fs::write(f, "").expect("write must work");
+        }
+
+        let cfg: Config = toml::from_str(&format!("snippets = \"{}\"", dir.path().display()))
+            .expect("cfg must parse");
+        let snippets = cfg.snippets.expect("snippets must be set");
+        assert_eq!(snippets, Snippets::Directory(dir.path().to_path_buf()));
+        assert_eq!(
+            snippets.files().expect("must list files"),
+            vec![dir.path().join("a.toml"), dir.path().join("b.toml")]
+        );
+        assert_eq!(
+            snippets.disabled_files().expect("must list files"),
+            vec![dir.path().join("c.toml.disabled")]
+        );
+
+        let cfg: Config = toml::from_str(&format!(
+            "snippets = [\"{0}/tier1/*.toml\", \"{0}/*.toml\", \"{0}/?.toml\", \"{0}/nope/*\"]",
+            dir.path().display()
+        ))
+        .expect("cfg must parse");
+        let snippets = cfg.snippets.expect("snippets must be set");
+        assert_eq!(snippets.directory(), Some(tier1.clone()));
+        assert_eq!(
+            snippets.files().expect("must list files"),
+            vec![
+                tier1.join("a.toml"),
+                dir.path().join("a.toml"),
+                dir.path().join("b.toml")
+            ]
+        );
+        assert_eq!(
+            snippets.disabled_files().expect("must list files"),
+            vec![dir.path().join("c.toml.disabled")]
+        );
+
+        let snippets = Snippets::Globs(vec![format!("{}/*/*.toml", dir.path().display())]);
+        assert!(snippets.files().is_err());
+    }
+
+    #[test]
+    fn test_deprecated_settings() {
+        let deprecated = r#"
+            [[prometheus]]
+            id = "prom"
+
+            [[promoter]]
+            [promoter.resources.foo]
+            start = ["foo.service"]
+            on-stop-failure = "reboot"
+            [promoter.resources.bar]
+            start = ["bar.service"]
+            "#;
+        let cfg: Config = toml::from_str(deprecated).expect("cfg must parse");
+        assert_eq!(cfg.config_version, 0);
+        assert_eq!(
+            deprecated_settings(&cfg),
+            vec![
+                "prometheus plugin 'id = \"prom\"'".to_string(),
+                "promoter resource 'foo' 'on-stop-failure = \"reboot\"'".to_string(),
+            ]
+        );
+
+        let cfg: Config = toml::from_str(&format!(
+            "config-version = {}\n{}",
+            CONFIG_VERSION, deprecated
+        ))
+        .expect("cfg must parse");
+        assert!(deprecated_settings(&cfg).is_empty());
+
+        let cfg: Config = toml::from_str(EMPTY_CFG).expect("cfg must parse");
+        assert!(deprecated_settings(&cfg).is_empty());
+    }
+
     #[test]
     fn test_default_cfg() {
         let cfg: Config = toml::from_str(EMPTY_CFG).expect("cfg must parse");
@@ -176,6 +819,20 @@ mod tests {
             cfg.log[0].file,
             Some(PathBuf::from("/var/log/drbd-reactor.log"))
         );
+        assert_eq!(cfg.log[0].format, LogFormat::Text);
+        assert!(cfg.log[0].with_timestamps());
+        assert!(!default_log()[0].with_timestamps());
+
+        let cfg: Config = toml::from_str("[[log]]\nformat = \"json\"").expect("cfg must parse");
+        assert_eq!(cfg.log[0].format, LogFormat::Json);
+        assert!(toml::from_str::<Config>("[[log]]\nformat = \"xml\"").is_err());
+
+        let cfg: Config = toml::from_str("[[log]]\nsyslog = true\nsyslog-facility = \"local3\"")
+            .expect("cfg must parse");
+        assert!(cfg.log[0].syslog);
+        assert_eq!(cfg.log[0].syslog_facility.code(), libc::LOG_LOCAL3);
+        assert!(!cfg.log[0].with_timestamps());
+        assert!(!default_log()[0].syslog);
     }
 
     #[derive(Deserialize)]
@@ -212,4 +869,52 @@ mod tests {
         let addr: Result<AddressTest, _> = toml::from_str(LOCAL_ADDRESS_ERR);
         assert!(addr.is_err());
     }
+
+    #[test]
+    fn test_migrate() {
+        let old = r#"
+        [[promoter]]
+        id = "foo"
+        [promoter.resources.foo]
+        start = ["foo.service"]
+        on-stop-failure = "echo b > /proc/sysrq-trigger"
+        [promoter.resources.bar]
+        start = ["bar.service"]
+        on-stop-failure = "/usr/local/bin/fence-me"
+
+        [[prometheus]]
+        id = "prom"
+        address = ":9942"
+        "#;
+
+        let migrated = migrate(old)
+            .expect("must migrate")
+            .expect("must have changes");
+        let settings: Vec<_> = migrated.lines().filter(|l| !l.starts_with('#')).collect();
+        assert!(!settings.iter().any(|l| l.starts_with("on-stop-failure")));
+        assert!(!settings.iter().any(|l| l.starts_with("id")));
+        assert!(migrated.contains(
+            "# drbd-reactorctl migrate: removed 'on-stop-failure = \"/usr/local/bin/fence-me\"'"
+        ));
+
+        let cfg: Config = toml::from_str(&migrated).expect("migrated cfg must parse");
+        let promoter = &cfg.plugins.promoter[0];
+        assert_eq!(promoter.id, None);
+        let foo = promoter.resources.get("foo").expect("foo must exist");
+        assert_eq!(foo.start_services(), vec!["foo.service".to_string()]);
+        assert!(foo.on_stop_failure.is_empty());
+        assert_eq!(
+            foo.on_drbd_demote_failure,
+            plugin::promoter::SystemdFailureAction::RebootImmediate
+        );
+        let bar = promoter.resources.get("bar").expect("bar must exist");
+        assert_eq!(
+            bar.on_drbd_demote_failure,
+            plugin::promoter::SystemdFailureAction::None
+        );
+        assert_eq!(cfg.plugins.prometheus[0].id, None);
+
+        // current configs stay as they are
+        assert!(migrate(&migrated).expect("must migrate").is_none());
+    }
 }
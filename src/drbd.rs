@@ -1,14 +1,17 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::io::{Error, ErrorKind};
-use std::process::{Command, Stdio};
 use std::slice::Iter;
 use std::str::FromStr;
+use std::time::Duration;
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-common_matchable![Vec<Connection>, Vec<Device>];
+use crate::matchable::{BasicPattern, PartialMatchable};
+use crate::runner::CommandRunner;
+
+common_matchable![BackingDevice, Option<i32>];
 make_matchable![
     #[derive(Default, Debug, Serialize, Clone, PartialEq, Deserialize)]
     #[serde(rename_all = "kebab-case")]
@@ -23,10 +26,11 @@ make_matchable![
         pub devices: Vec<Device>,
         pub connections: Vec<Connection>,
     },
-    ResourcePattern
+    ResourcePattern,
+    #[derive(Eq, Hash)]
 ];
 
-#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct BackingDevice(pub Option<String>);
 
 impl FromStr for BackingDevice {
@@ -48,27 +52,31 @@ impl fmt::Display for BackingDevice {
     }
 }
 
-#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
-#[serde(rename_all = "kebab-case")]
-pub struct Device {
-    pub name: String,
-    pub volume: i32,
-    pub minor: i32,
-    pub disk_state: DiskState,
-    pub backing_dev: BackingDevice,
-    pub client: bool,
-    pub quorum: bool,
-    pub size: u64,
-    pub read: u64,
-    pub written: u64,
-    pub al_writes: u64,
-    pub bm_writes: u64,
-    pub upper_pending: u64,
-    pub lower_pending: u64,
-    pub al_suspended: bool,
-    pub blocked: String,
-    pub open: bool,
-}
+make_matchable![
+    #[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    pub struct Device {
+        pub name: String,
+        pub volume: i32,
+        pub minor: i32,
+        pub disk_state: DiskState,
+        pub backing_dev: BackingDevice,
+        pub client: bool,
+        pub quorum: bool,
+        pub size: u64,
+        pub read: u64,
+        pub written: u64,
+        pub al_writes: u64,
+        pub bm_writes: u64,
+        pub upper_pending: u64,
+        pub lower_pending: u64,
+        pub al_suspended: bool,
+        pub blocked: String,
+        pub open: bool,
+    },
+    DevicePattern,
+    #[derive(Eq, Hash)]
+];
 
 #[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "kebab-case")]
@@ -86,6 +94,9 @@ pub struct PeerDevice {
     pub out_of_sync: u64,
     pub pending: u64,
     pub unacked: u64,
+    pub resync_done: f64, // percent
+    pub resync_eta: u64,  // seconds
+    pub resync_rate: f64, // KiB/s
     pub has_sync_details: bool,
     pub has_online_verify_details: bool,
 }
@@ -105,6 +116,34 @@ pub struct Connection {
     pub paths: Vec<Path>,
 }
 
+/// Pattern for the state of a connection, peer devices and paths can not be matched
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConnectionPattern {
+    pub name: Option<BasicPattern<String>>,
+    pub peer_node_id: Option<BasicPattern<i32>>,
+    pub conn_name: Option<BasicPattern<String>>,
+    pub connection: Option<BasicPattern<ConnectionState>>,
+    pub peer_role: Option<BasicPattern<Role>>,
+    pub congested: Option<BasicPattern<bool>>,
+}
+
+impl PartialMatchable for Connection {
+    type Pattern = Option<ConnectionPattern>;
+    fn matches(&self, pattern: &Self::Pattern) -> bool {
+        let pattern = match pattern {
+            Some(p) => p,
+            None => return true,
+        };
+        self.name.matches(&pattern.name)
+            && self.peer_node_id.matches(&pattern.peer_node_id)
+            && self.conn_name.matches(&pattern.conn_name)
+            && self.connection.matches(&pattern.connection)
+            && self.peer_role.matches(&pattern.peer_role)
+            && self.congested.matches(&pattern.congested)
+    }
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Path {
@@ -116,6 +155,19 @@ pub struct Path {
     pub established: bool,
 }
 
+/// A "call helper"/"response helper" event, DRBD executing one of its user mode helpers.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Helper {
+    pub name: String,
+    pub volume: Option<i32>,
+    pub peer_node_id: Option<i32>,
+    pub conn_name: String,
+    pub helper: String,
+    // exit status, only set for responses
+    pub status: Option<i32>,
+}
+
 make_matchable![
     #[derive(Serialize, Deserialize, Hash, Debug, PartialEq, Eq, Clone)]
     pub enum Role {
@@ -422,6 +474,9 @@ make_matchable![
         pub role: Role,
         pub may_promote: bool,
         pub promotion_score: i32,
+        // derived from the full resource, see Resource::is_healthy()
+        #[serde(default)]
+        pub healthy: bool,
     },
     ResourceUpdateStatePattern
 ];
@@ -463,6 +518,15 @@ make_matchable![
     ConnectionUpdateStatePattern
 ];
 
+make_matchable![
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub struct HealthUpdateState {
+        pub healthy: bool,
+    },
+    HealthUpdateStatePattern
+];
+
 #[derive(Debug, PartialEq)]
 pub enum EventUpdate {
     Resource(EventType, Resource),
@@ -470,9 +534,26 @@ pub enum EventUpdate {
     PeerDevice(EventType, PeerDevice),
     Connection(EventType, Connection),
     Path(EventType, Path),
+    Helper(EventType, Helper),
     Stop,
     Reload,
     Flush,
+    DumpState,
+}
+
+impl EventUpdate {
+    /// Name of the resource the update is about, None for control updates (e.g., Stop)
+    pub fn resource_name(&self) -> Option<&str> {
+        match self {
+            Self::Resource(_, r) => Some(&r.name),
+            Self::Device(_, d) => Some(&d.name),
+            Self::PeerDevice(_, pd) => Some(&pd.name),
+            Self::Connection(_, c) => Some(&c.name),
+            Self::Path(_, p) => Some(&p.name),
+            Self::Helper(_, h) => Some(&h.name),
+            Self::Stop | Self::Reload | Self::Flush | Self::DumpState => None,
+        }
+    }
 }
 
 make_matchable![
@@ -503,6 +584,8 @@ impl ResourcePluginUpdate {
             "DRBD_NEW_MAY_PROMOTE".to_string(),
             self.new.may_promote.to_string(),
         );
+        env.insert("DRBD_OLD_HEALTHY".to_string(), self.old.healthy.to_string());
+        env.insert("DRBD_NEW_HEALTHY".to_string(), self.new.healthy.to_string());
 
         env
     }
@@ -702,13 +785,99 @@ impl ConnectionPluginUpdate {
     }
 }
 
+make_matchable![
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub struct HelperPluginUpdate {
+        pub event_type: EventType,
+        pub resource_name: String,
+        pub volume: Option<i32>,
+        pub peer_node_id: Option<i32>,
+        pub helper: String,
+        pub status: Option<i32>,
+        pub resource: Resource,
+    },
+    HelperPluginUpdatePattern
+];
+
+impl HelperPluginUpdate {
+    pub fn new(event_type: EventType, helper: &Helper, resource: Resource) -> Self {
+        Self {
+            event_type,
+            resource_name: helper.name.clone(),
+            volume: helper.volume,
+            peer_node_id: helper.peer_node_id,
+            helper: helper.helper.clone(),
+            status: helper.status,
+            resource,
+        }
+    }
+
+    pub fn get_env(&self) -> HashMap<String, String> {
+        let mut env = HashMap::new();
+
+        env.insert("DRBD_RES_NAME".to_string(), self.resource_name.clone());
+        env.insert("DRBD_HELPER".to_string(), self.helper.clone());
+        if let Some(volume) = self.volume {
+            env.insert("DRBD_VOLUME".to_string(), volume.to_string());
+        }
+        if let Some(peer_node_id) = self.peer_node_id {
+            env.insert("DRBD_PEER_NODE_ID".to_string(), peer_node_id.to_string());
+        }
+        if let Some(status) = self.status {
+            env.insert("DRBD_HELPER_STATUS".to_string(), status.to_string());
+        }
+
+        env
+    }
+}
+
+make_matchable![
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub struct HealthPluginUpdate {
+        pub event_type: EventType,
+        pub resource_name: String,
+        pub old: HealthUpdateState,
+        pub new: HealthUpdateState,
+        pub resource: Resource,
+    },
+    HealthPluginUpdatePattern
+];
+
+impl HealthPluginUpdate {
+    pub fn get_env(&self) -> HashMap<String, String> {
+        let mut env = HashMap::new();
+
+        env.insert("DRBD_RES_NAME".to_string(), self.resource_name.clone());
+        env.insert("DRBD_OLD_HEALTHY".to_string(), self.old.healthy.to_string());
+        env.insert("DRBD_NEW_HEALTHY".to_string(), self.new.healthy.to_string());
+
+        env
+    }
+}
+
+/// The kind of DRBD object an event was about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventObject {
+    Resource,
+    Device,
+    PeerDevice,
+    Connection,
+    Path,
+}
+
 #[derive(Debug, Clone)]
 pub enum PluginUpdate {
     Resource(ResourcePluginUpdate),
     Device(DevicePluginUpdate),
     PeerDevice(PeerDevicePluginUpdate),
     Connection(ConnectionPluginUpdate),
-    ResourceOnly(EventType, Resource),
+    Health(HealthPluginUpdate),
+    Helper(HelperPluginUpdate),
+    /// The current state of a resource after an event of the given type for the given kind of object
+    /// (e.g., a destroyed device of a still existing resource)
+    ResourceOnly(EventType, EventObject, Resource),
 }
 
 impl PluginUpdate {
@@ -718,7 +887,9 @@ impl PluginUpdate {
             Self::Device(u) => u.resource_name == name,
             Self::PeerDevice(u) => u.resource_name == name,
             Self::Connection(u) => u.resource_name == name,
-            Self::ResourceOnly(_, r) => r.name == name,
+            Self::Health(u) => u.resource_name == name,
+            Self::Helper(u) => u.resource_name == name,
+            Self::ResourceOnly(_, _, r) => r.name == name,
         }
     }
 
@@ -728,7 +899,9 @@ impl PluginUpdate {
             Self::Device(u) => u.event_type == *search,
             Self::PeerDevice(u) => u.event_type == *search,
             Self::Connection(u) => u.event_type == *search,
-            Self::ResourceOnly(t, _) => *t == *search,
+            Self::Health(u) => u.event_type == *search,
+            Self::Helper(u) => u.event_type == *search,
+            Self::ResourceOnly(t, _, _) => *t == *search,
         }
     }
 
@@ -738,7 +911,9 @@ impl PluginUpdate {
             Self::Device(u) => u.resource_name.to_string(),
             Self::PeerDevice(u) => u.resource_name.to_string(),
             Self::Connection(u) => u.resource_name.to_string(),
-            Self::ResourceOnly(_, r) => r.name.to_string(),
+            Self::Health(u) => u.resource_name.to_string(),
+            Self::Helper(u) => u.resource_name.to_string(),
+            Self::ResourceOnly(_, _, r) => r.name.to_string(),
         }
     }
 
@@ -748,7 +923,9 @@ impl PluginUpdate {
             Self::Device(u) => u.get_env(),
             Self::PeerDevice(u) => u.get_env(),
             Self::Connection(u) => u.get_env(),
-            Self::ResourceOnly(_, _) => HashMap::new(),
+            Self::Health(u) => u.get_env(),
+            Self::Helper(u) => u.get_env(),
+            Self::ResourceOnly(_, _, _) => HashMap::new(),
         }
     }
 
@@ -758,7 +935,9 @@ impl PluginUpdate {
             Self::Device(u) => u.resource.clone(),
             Self::PeerDevice(u) => u.resource.clone(),
             Self::Connection(u) => u.resource.clone(),
-            Self::ResourceOnly(_, r) => r.clone(),
+            Self::Health(u) => u.resource.clone(),
+            Self::Helper(u) => u.resource.clone(),
+            Self::ResourceOnly(_, _, r) => r.clone(),
         }
     }
 }
@@ -913,6 +1092,8 @@ impl Resource {
                 // does not contain any peerdevices or paths
                 // we want to preserve the existing pds/paths in the existing connection
                 // conn is just an update for the rest of the struct fields.
+                // store it even if the update state did not change, statistics (e.g., ap_in_flight)
+                // are not part of it, but event plugins expect them to be current.
                 let mut conn = conn.clone();
                 conn.peerdevices = existing.peerdevices.to_vec();
                 conn.paths = existing.paths.to_vec();
@@ -1121,21 +1302,25 @@ impl Resource {
         et: &EventType,
         update: &Resource,
     ) -> Option<PluginUpdate> {
-        let new = ResourceUpdateState {
-            may_promote: update.may_promote,
-            promotion_score: update.promotion_score,
-            role: update.role.clone(),
-        };
-
         let old = ResourceUpdateState {
             may_promote: self.may_promote,
             promotion_score: self.promotion_score,
             role: self.role.clone(),
+            healthy: self.is_healthy(),
         };
 
         if *et != EventType::Destroy {
             self.update(update);
         }
+
+        // health depends on devices and connections as well, so it is calculated on the updated resource
+        let new = ResourceUpdateState {
+            may_promote: update.may_promote,
+            promotion_score: update.promotion_score,
+            role: update.role.clone(),
+            healthy: *et != EventType::Destroy && self.is_healthy(),
+        };
+
         if old == new && *et != EventType::Destroy {
             return None;
         }
@@ -1149,6 +1334,40 @@ impl Resource {
         }))
     }
 
+    /// A resource is healthy if it is Primary, not suspended, all of its devices are UpToDate and
+    /// have quorum, and all of its peers are connected.
+    pub fn is_healthy(&self) -> bool {
+        self.role == Role::Primary
+            && !self.suspended
+            && !self.devices.is_empty()
+            && self
+                .devices
+                .iter()
+                .all(|d| d.disk_state == DiskState::UpToDate && d.quorum)
+            && self
+                .connections
+                .iter()
+                .all(|c| c.connection == ConnectionState::Connected)
+    }
+
+    /// Returns an update if the health changed compared to `was_healthy`.
+    pub fn get_health_update(&self, was_healthy: bool) -> Option<PluginUpdate> {
+        let healthy = self.is_healthy();
+        if healthy == was_healthy {
+            return None;
+        }
+
+        Some(PluginUpdate::Health(HealthPluginUpdate {
+            event_type: EventType::Change,
+            resource_name: self.name.clone(),
+            old: HealthUpdateState {
+                healthy: was_healthy,
+            },
+            new: HealthUpdateState { healthy },
+            resource: self.clone(),
+        }))
+    }
+
     pub fn to_plugin_updates(&self) -> Vec<PluginUpdate> {
         let mut updates = vec![];
         let mut r = Resource::with_name(&self.name);
@@ -1160,11 +1379,13 @@ impl Resource {
                 role: Role::Unknown,
                 promotion_score: 0,
                 may_promote: false,
+                healthy: false,
             },
             new: ResourceUpdateState {
                 role: Role::Unknown,
                 promotion_score: 0,
                 may_promote: false,
+                healthy: false,
             },
             resource: r.clone(),
             resource_name: r.name.clone(),
@@ -1209,6 +1430,8 @@ make_matchable![
         Create,
         Destroy,
         Change,
+        Call,
+        Response,
     }
 ];
 
@@ -1221,6 +1444,8 @@ impl FromStr for EventType {
             "create" => Ok(Self::Create),
             "destroy" => Ok(Self::Destroy),
             "change" => Ok(Self::Change),
+            "call" => Ok(Self::Call),
+            "response" => Ok(Self::Response),
             _ => Err(Error::new(ErrorKind::InvalidData, "unknown event")),
         }
     }
@@ -1244,12 +1469,15 @@ pub struct DRBDVersion {
     pub utils: Version,
 }
 
-pub fn get_drbd_versions() -> anyhow::Result<DRBDVersion> {
-    let version = match Command::new("drbdadm")
-        .stdin(Stdio::null())
-        .arg("--version")
-        .output()
-    {
+// "secondary --force" is the first thing that needs a recent kernel module
+pub const MIN_KMOD_SECONDARY_FORCE: Version = Version {
+    major: 9,
+    minor: 1,
+    patch: 7,
+};
+
+pub fn get_drbd_versions(runner: &dyn CommandRunner) -> anyhow::Result<DRBDVersion> {
+    let version = match runner.output("drbdadm", &["--version"]) {
         Ok(x) => x,
         Err(e) => return Err(anyhow::anyhow!("failed running drbdadm --version: {}", e)),
     };
@@ -1294,51 +1522,79 @@ fn split_version(pattern: regex::Regex, stdout: Vec<u8>) -> anyhow::Result<Versi
     })
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Debug)]
 pub enum PrimaryOn {
     Local,
     Remote(String),
     None,
 }
 
-pub fn get_primary(drbd_resource: &str) -> anyhow::Result<PrimaryOn> {
-    let output = Command::new("drbdsetup")
-        .arg("status")
-        .arg("--json")
-        .arg(drbd_resource)
-        .output()?;
+pub fn get_primary(runner: &dyn CommandRunner, drbd_resource: &str) -> anyhow::Result<PrimaryOn> {
+    let output = runner.output("drbdsetup", &["status", "--json", drbd_resource])?;
     if !output.status.success() {
         return Err(anyhow::anyhow!(
-            "'drbdsetup show' not executed successfully"
+            "'drbdsetup status' not executed successfully"
         ));
     }
 
+    parse_primary(&output.stdout, drbd_resource)
+}
+
+/// Parses the output of "drbdsetup status --json" for the node `drbd_resource` is Primary on.
+///
+/// Unknown fields and roles are tolerated. If the output contains multiple resources, the one
+/// named `drbd_resource` is used.
+pub fn parse_primary(json: &[u8], drbd_resource: &str) -> anyhow::Result<PrimaryOn> {
     #[derive(Deserialize)]
     #[serde(rename_all = "kebab-case")]
     struct Resource {
+        #[serde(default)]
+        name: String,
+        #[serde(default, deserialize_with = "lenient_role")]
         role: Role,
+        #[serde(default)]
         connections: Vec<Connection>,
     }
     #[derive(Deserialize)]
     #[serde(rename_all = "kebab-case")]
     struct Connection {
+        #[serde(default)]
         name: String,
+        #[serde(default, deserialize_with = "lenient_role")]
         peer_role: Role,
     }
-    let resources: Vec<Resource> = serde_json::from_slice(&output.stdout)?;
-    if resources.len() != 1 {
-        return Err(anyhow::anyhow!(
-            "resources length from drbdsetup status not exactly 1"
-        ));
-    }
+    // a role newer DRBD versions might introduce is not Primary
+    fn lenient_role<'de, D>(deserializer: D) -> Result<Role, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let role = Option::<String>::deserialize(deserializer)?;
+        Ok(role
+            .and_then(|role| Role::from_str(&role).ok())
+            .unwrap_or_default())
+    }
+
+    let resources: Vec<Resource> = serde_json::from_slice(json)?;
+    let resource = match resources.iter().find(|r| r.name == drbd_resource) {
+        Some(resource) => resource,
+        // older versions might not report the name, a single resource has to be the one we asked for
+        None if resources.len() == 1 && resources[0].name.is_empty() => &resources[0],
+        None => {
+            return Err(anyhow::anyhow!(
+                "resource '{}' not found in drbdsetup status ({} resources)",
+                drbd_resource,
+                resources.len()
+            ))
+        }
+    };
 
     // is it me?
-    if resources[0].role == Role::Primary {
+    if resource.role == Role::Primary {
         return Ok(PrimaryOn::Local);
     }
 
     // a peer?
-    for conn in &resources[0].connections {
+    for conn in &resource.connections {
         if conn.peer_role == Role::Primary {
             return Ok(PrimaryOn::Remote(conn.name.clone()));
         }
@@ -1346,3 +1602,80 @@ pub fn get_primary(drbd_resource: &str) -> anyhow::Result<PrimaryOn> {
 
     Ok(PrimaryOn::None)
 }
+
+/// Read/written counters of a DRBD device as reported by "drbdsetup status --statistics"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceStatistics {
+    pub resource: String,
+    pub volume: i32,
+    pub minor: i32,
+    pub read: u64,    // KiB
+    pub written: u64, // KiB
+}
+
+impl DeviceStatistics {
+    /// Read and written KiB/s between `self` and a later sample `now` taken `elapsed` later.
+    ///
+    /// Counters that went backwards (e.g., a re-attached device) count as 0.
+    pub fn rates(&self, now: &DeviceStatistics, elapsed: Duration) -> (f64, f64) {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return (0.0, 0.0);
+        }
+        (
+            now.read.saturating_sub(self.read) as f64 / secs,
+            now.written.saturating_sub(self.written) as f64 / secs,
+        )
+    }
+}
+
+/// Parses the output of "drbdsetup status --json --statistics" into per device statistics.
+pub fn parse_device_statistics(json: &[u8]) -> anyhow::Result<Vec<DeviceStatistics>> {
+    #[derive(Deserialize)]
+    struct Resource {
+        name: String,
+        #[serde(default)]
+        devices: Vec<Device>,
+    }
+    #[derive(Deserialize)]
+    struct Device {
+        volume: i32,
+        minor: i32,
+        #[serde(default)]
+        read: u64,
+        #[serde(default)]
+        written: u64,
+    }
+
+    let resources: Vec<Resource> = serde_json::from_slice(json)?;
+    Ok(resources
+        .into_iter()
+        .flat_map(|r| {
+            let name = r.name;
+            r.devices.into_iter().map(move |d| DeviceStatistics {
+                resource: name.clone(),
+                volume: d.volume,
+                minor: d.minor,
+                read: d.read,
+                written: d.written,
+            })
+        })
+        .collect())
+}
+
+pub fn get_device_statistics(
+    runner: &dyn CommandRunner,
+    drbd_resource: &str,
+) -> anyhow::Result<Vec<DeviceStatistics>> {
+    let output = runner.output(
+        "drbdsetup",
+        &["status", "--json", "--statistics", drbd_resource],
+    )?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "'drbdsetup status' not executed successfully"
+        ));
+    }
+
+    parse_device_statistics(&output.stdout)
+}
@@ -1,23 +1,163 @@
+use crate::config;
 use crate::drbd::{
-    BackingDevice, Connection, ConnectionState, Device, DiskState, EventType, EventUpdate, Path,
-    PeerDevice, ReplicationState, Resource, Role,
+    BackingDevice, Connection, ConnectionState, Device, DiskState, EventType, EventUpdate, Helper,
+    Path, PeerDevice, ReplicationState, Resource, Role,
 };
 use anyhow::{Context, Result};
 use crossbeam_channel::{SendError, Sender};
 use log::{debug, warn};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Write;
 use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-pub fn events2(tx: Sender<EventUpdate>, statistics_poll: Duration) -> Result<()> {
+static EVENTS_PROCESSED: AtomicU64 = AtomicU64::new(0);
+static EVENTS_PARSE_ERRORS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+static EVENTS2_RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts an event that got processed by the core.
+pub fn count_processed() {
+    EVENTS_PROCESSED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of events processed by the core since start.
+pub fn events_processed() -> u64 {
+    EVENTS_PROCESSED.load(Ordering::Relaxed)
+}
+
+fn count_parse_error(line: &str) {
+    // only known categories, every unknown one would be a new time series
+    let what = line
+        .split_whitespace()
+        .nth(1)
+        .filter(|w| {
+            matches!(
+                *w,
+                "resource" | "device" | "peer-device" | "connection" | "path" | "helper"
+            )
+        })
+        .unwrap_or("unknown");
+    if let Ok(mut errors) = EVENTS_PARSE_ERRORS.lock() {
+        *errors.entry(what.to_string()).or_default() += 1;
+    }
+}
+
+/// Returns the number of "drbdsetup events2" lines that could not be parsed, by category (e.g.,
+/// "resource", "device").
+pub fn events_parse_errors() -> BTreeMap<String, u64> {
+    EVENTS_PARSE_ERRORS
+        .lock()
+        .map(|errors| errors.clone())
+        .unwrap_or_default()
+}
+
+/// Returns how often "drbdsetup events2" got restarted after a failure since start.
+pub fn events2_restarts() -> u64 {
+    EVENTS2_RESTARTS.load(Ordering::Relaxed)
+}
+
+/// Limits the resources "drbdsetup events2" reports on.
+///
+/// The filter is shared between the events2 thread and the main thread. If the set of resources
+/// changes (e.g., on reload), a running "drbdsetup events2" gets killed, which restarts event
+/// processing with the new filter. Such an intentional restart is not counted as failure.
+#[derive(Default)]
+pub struct ResourceFilter {
+    resources: Option<BTreeSet<String>>,
+    // glob patterns of 'include-resources' and 'ignore-resources'
+    included: Vec<String>,
+    ignored: Vec<String>,
+    child: Option<u32>,
+    restart_requested: bool,
+}
+
+impl ResourceFilter {
+    pub fn new(resources: Option<BTreeSet<String>>) -> Self {
+        Self {
+            resources,
+            included: Vec::new(),
+            ignored: Vec::new(),
+            child: None,
+            restart_requested: false,
+        }
+    }
+
+    pub fn set(&mut self, resources: Option<BTreeSet<String>>) {
+        if self.resources == resources {
+            return;
+        }
+        debug!("set: events2 resource filter changed to {:?}", resources);
+        self.resources = resources;
+        self.restart_child();
+    }
+
+    /// Sets the glob patterns of resources that are tracked (empty tracks all) and of resources
+    /// that are ignored completely.
+    pub fn set_patterns(&mut self, included: Vec<String>, ignored: Vec<String>) {
+        if self.included == included && self.ignored == ignored {
+            return;
+        }
+        debug!(
+            "set_patterns: included resources changed to {:?}, ignored to {:?}",
+            included, ignored
+        );
+        self.included = included;
+        self.ignored = ignored;
+        self.restart_child();
+    }
+
+    fn restart_child(&mut self) {
+        if let Some(pid) = self.child {
+            self.restart_requested = true;
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGTERM);
+            }
+        }
+    }
+
+    /// Returns true if "drbdsetup events2" got killed on purpose since the last call.
+    fn take_restart_requested(&mut self) -> bool {
+        std::mem::take(&mut self.restart_requested)
+    }
+
+    /// Returns true if events for the given resource should be processed
+    pub fn wants(&self, name: &str) -> bool {
+        if !config::tracks_resource(&self.included, &self.ignored, name) {
+            return false;
+        }
+        match &self.resources {
+            Some(resources) => resources.contains(name),
+            None => true,
+        }
+    }
+
+    // drbdsetup events2 only allows to filter for a single resource, the rest is filtered by us
+    fn drbdsetup_arg(&self) -> Option<String> {
+        match &self.resources {
+            Some(resources) if resources.len() == 1 => resources.iter().next().cloned(),
+            _ => None,
+        }
+    }
+}
+
+pub fn events2(
+    tx: Sender<EventUpdate>,
+    statistics_poll: Duration,
+    filter: Arc<Mutex<ResourceFilter>>,
+    mut restarts: RestartBudget,
+) -> Result<()> {
     let mut send_flush = false;
     loop {
         debug!("events2_loop: starting process_events2 loop");
-        let result = process_events2(&tx, statistics_poll, send_flush);
+        let started = Instant::now();
+        let result = process_events2(&tx, statistics_poll, &filter, send_flush);
         send_flush = true;
         match result {
             Ok(()) => break,
@@ -26,7 +166,27 @@ pub fn events2(tx: Sender<EventUpdate>, statistics_poll: Duration) -> Result<()>
                     debug!("events2_loop: send error on chanel, bye");
                     return Err(e);
                 }
-                thread::sleep(Duration::from_secs(2));
+                // killed to apply a new filter, the state of the remaining resources is still valid
+                if lock_filter(&filter)?.take_restart_requested() {
+                    debug!("events2_loop: restarting with new resource filter");
+                    send_flush = false;
+                    continue;
+                }
+                if restarts.failed(started, Instant::now()) {
+                    return Err(e.context(format!(
+                        "events2_loop: giving up, failed more than {} times within {}s",
+                        restarts.max,
+                        restarts.window.as_secs()
+                    )));
+                }
+                let delay = restarts.next_delay();
+                warn!(
+                    "events2_loop: restarting in {}s after failure: {}",
+                    delay.as_secs(),
+                    e
+                );
+                EVENTS2_RESTARTS.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(delay);
             }
         }
     }
@@ -34,6 +194,56 @@ pub fn events2(tx: Sender<EventUpdate>, statistics_poll: Duration) -> Result<()>
     Ok(())
 }
 
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Limits how often "drbdsetup events2" gets restarted within a sliding time window, and how fast.
+pub struct RestartBudget {
+    max: u32,
+    window: Duration,
+    failures: VecDeque<Instant>,
+    delay: Duration,
+    max_delay: Duration,
+}
+
+impl RestartBudget {
+    /// Allows `max` restarts within `window`, 0 allows an unlimited number. The delay between restarts
+    /// doubles up to `max_delay`.
+    pub fn new(max: u32, window: Duration, max_delay: Duration) -> Self {
+        Self {
+            max,
+            window,
+            failures: VecDeque::new(),
+            delay: MIN_RESTART_DELAY,
+            max_delay,
+        }
+    }
+
+    /// The delay before the next restart (exponential backoff).
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.delay.min(self.max_delay);
+        self.delay = (self.delay * 2).min(self.max_delay);
+        delay
+    }
+
+    /// Records a failure of a run that started at `started`, true if the budget is exhausted.
+    fn failed(&mut self, started: Instant, now: Instant) -> bool {
+        // a run that lived longer than the window was a successful one, start over
+        if now.duration_since(started) >= self.window {
+            self.failures.clear();
+            self.delay = MIN_RESTART_DELAY;
+        }
+        while let Some(first) = self.failures.front() {
+            if now.duration_since(*first) < self.window {
+                break;
+            }
+            self.failures.pop_front();
+        }
+        self.failures.push_back(now);
+
+        self.max > 0 && self.failures.len() > self.max as usize
+    }
+}
+
 struct KillOnDrop(std::process::Child);
 impl Drop for KillOnDrop {
     fn drop(&mut self) {
@@ -42,21 +252,46 @@ impl Drop for KillOnDrop {
     }
 }
 
+// the child gets reaped by KillOnDrop, make sure nobody signals a potentially reused pid
+// has to be declared after KillOnDrop, so that it gets dropped first
+struct ForgetChildOnDrop<'a>(&'a Mutex<ResourceFilter>);
+impl Drop for ForgetChildOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut filter) = self.0.lock() {
+            filter.child = None;
+        }
+    }
+}
+
+fn lock_filter(filter: &Mutex<ResourceFilter>) -> Result<MutexGuard<'_, ResourceFilter>> {
+    filter
+        .lock()
+        .map_err(|_| anyhow::anyhow!("events: tried accessing a poisoned lock"))
+}
+
 fn process_events2(
     tx: &Sender<EventUpdate>,
     statistics_poll: Duration,
+    filter: &Mutex<ResourceFilter>,
     send_flush: bool,
 ) -> Result<()> {
-    let mut cmd = Command::new("drbdsetup")
-        .arg("events2")
-        .arg("--full")
-        .arg("--poll")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .with_context(|| {
-            "events: process_events2: could not spawn 'drbdsetup events2 --full --poll'"
-        })?;
+    let mut cmd = {
+        let mut filter = lock_filter(filter)?;
+        let mut cmd = Command::new("drbdsetup");
+        cmd.arg("events2").arg("--full").arg("--poll");
+        if let Some(resource) = filter.drbdsetup_arg() {
+            cmd.arg(resource);
+        }
+        let cmd = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| {
+                "events: process_events2: could not spawn 'drbdsetup events2 --full --poll'"
+            })?;
+        filter.child = Some(cmd.id());
+        cmd
+    };
 
     let mut stdin = cmd
         .stdin
@@ -76,6 +311,7 @@ fn process_events2(
         .expect("events: process_events2: stdout set to Stdio::piped()");
 
     let _kill_cmd = KillOnDrop(cmd);
+    let _forget_child = ForgetChildOnDrop(filter);
 
     // great, we established a successful events2 tracking
     if send_flush {
@@ -94,12 +330,26 @@ fn process_events2(
             continue;
         }
 
+        // cheap check before we parse the whole line
+        if let Some(name) = line
+            .split_whitespace()
+            .find_map(|w| w.strip_prefix("name:"))
+        {
+            if !lock_filter(filter)?.wants(name) {
+                buf.clear();
+                continue;
+            }
+        }
+
         match parse_events2_line(line) {
             Ok(update) => tx.send(update)?,
-            Err(e) => debug!(
-                "process_events2: could not parse line '{}', because {}",
-                line, e
-            ),
+            Err(e) => {
+                count_parse_error(line);
+                debug!(
+                    "process_events2: could not parse line '{}', because {}",
+                    line, e
+                )
+            }
         }
         buf.clear();
     }
@@ -108,6 +358,43 @@ fn process_events2(
     Err(anyhow::anyhow!("events: process_events2: exit"))
 }
 
+/// Reads events2 formatted lines from a file, a named pipe, or stdin ("-") instead of "drbdsetup events2".
+///
+/// See `replay` for the format. After the last line the core is stopped.
+pub fn events_from(tx: &Sender<EventUpdate>, path: &std::path::Path) -> Result<()> {
+    if path == std::path::Path::new("-") {
+        return replay(tx, std::io::stdin().lock());
+    }
+    let file =
+        File::open(path).with_context(|| format!("events: could not open '{}'", path.display()))?;
+    replay(tx, BufReader::new(file))
+}
+
+/// Replays a captured "drbdsetup events2" transcript, followed by a final Stop.
+///
+/// Empty lines and lines starting with '#' are skipped, as are timestamps (events2 --timestamps).
+pub fn replay(tx: &Sender<EventUpdate>, reader: impl BufRead) -> Result<()> {
+    for (nr, line) in reader.lines().enumerate() {
+        let line = line.context("events: replay: could not read transcript")?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line == "exists -" {
+            continue;
+        }
+        let line = match line.split_once(char::is_whitespace) {
+            Some((first, rest)) if first.starts_with(|c: char| c.is_ascii_digit()) => rest.trim(),
+            _ => line,
+        };
+
+        match parse_events2_line(line) {
+            Ok(update) => tx.send(update)?,
+            Err(e) => warn!("replay: could not parse line {} '{}': {}", nr + 1, line, e),
+        }
+    }
+
+    tx.send(EventUpdate::Stop)?;
+    Ok(())
+}
+
 fn parse_events2_line(line: &str) -> Result<EventUpdate> {
     let mut words = line.split_whitespace();
 
@@ -123,6 +410,14 @@ fn parse_events2_line(line: &str) -> Result<EventUpdate> {
     };
 
     let what = words.next().unwrap_or_default();
+    // "call"/"response" are only used for helpers, and helpers only use these
+    if matches!(et, EventType::Call | EventType::Response) != (what == "helper") {
+        return Err(anyhow::anyhow!(
+            "events: parse_events2_line: unexpected event '{} {}'",
+            verb,
+            what
+        ));
+    }
     let kvs = words.filter_map(parse_kv);
     if what == "resource" {
         let mut resource = Resource {
@@ -202,6 +497,8 @@ fn parse_events2_line(line: &str) -> Result<EventUpdate> {
             has_online_verify_details: false,
             ..Default::default()
         };
+        // only present while resyncing/verifying, and only with --statistics
+        let mut has_details = false;
         for (k, v) in kvs {
             match (k, v) {
                 ("name", v) => peerdevice.name = v.into(),
@@ -217,9 +514,18 @@ fn parse_events2_line(line: &str) -> Result<EventUpdate> {
                 ("out-of-sync", v) => peerdevice.out_of_sync = v.parse::<_>()?,
                 ("pending", v) => peerdevice.pending = v.parse::<_>()?,
                 ("unacked", v) => peerdevice.unacked = v.parse::<_>()?,
-                ("done", _) => (),
-                ("eta", _) => (),
-                ("dbdt1", _) => (),
+                ("done", v) => {
+                    peerdevice.resync_done = v.parse::<_>()?;
+                    has_details = true;
+                }
+                ("eta", v) => {
+                    peerdevice.resync_eta = v.parse::<_>()?;
+                    has_details = true;
+                }
+                ("dbdt1", v) => {
+                    peerdevice.resync_rate = v.parse::<_>()?;
+                    has_details = true;
+                }
                 _ => {
                     debug!(
                         "events: process_events2: peer-device: unknown keyword '{}'",
@@ -228,6 +534,12 @@ fn parse_events2_line(line: &str) -> Result<EventUpdate> {
                 }
             };
         }
+        match peerdevice.replication_state {
+            ReplicationState::VerifyS | ReplicationState::VerifyT => {
+                peerdevice.has_online_verify_details = has_details
+            }
+            _ => peerdevice.has_sync_details = has_details,
+        }
         return Ok(EventUpdate::PeerDevice(et, peerdevice));
     } else if what == "path" {
         let mut path = Path {
@@ -247,6 +559,24 @@ fn parse_events2_line(line: &str) -> Result<EventUpdate> {
             }
         }
         return Ok(EventUpdate::Path(et, path));
+    } else if what == "helper" {
+        let mut helper = Helper {
+            ..Default::default()
+        };
+        for (k, v) in kvs {
+            match (k, v) {
+                ("name", v) => helper.name = v.into(),
+                ("volume", v) => helper.volume = Some(v.parse::<_>()?),
+                ("peer-node-id", v) => helper.peer_node_id = Some(v.parse::<_>()?),
+                ("conn-name", v) => helper.conn_name = v.into(),
+                ("helper", v) => helper.helper = v.into(),
+                ("status", v) => helper.status = Some(v.parse::<_>()?),
+                _ => {
+                    debug!("events: process_events2: helper: unknown keyword '{}'", k)
+                }
+            }
+        }
+        return Ok(EventUpdate::Helper(et, helper));
     }
 
     Err(anyhow::anyhow!(
@@ -273,6 +603,83 @@ fn str_to_bool(s: &str) -> bool {
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_errors_by_category() {
+        count_parse_error("change device name:foo volume:0 disk:Bogus");
+        count_parse_error("change device name:foo volume:0 disk:Bogus");
+        count_parse_error("change bogus name:foo");
+        count_parse_error("");
+
+        let errors = events_parse_errors();
+        assert_eq!(errors.get("device"), Some(&2));
+        assert_eq!(errors.get("unknown"), Some(&2));
+        assert_eq!(errors.get("bogus"), None);
+    }
+
+    #[test]
+    fn resource_filter_patterns() {
+        let mut filter = ResourceFilter::new(None);
+        filter.set_patterns(vec![], vec!["backup-*".to_string()]);
+        assert!(filter.wants("foo"));
+        assert!(!filter.wants("backup-1"));
+
+        filter.set_patterns(vec!["db*".to_string()], vec![]);
+        assert!(filter.wants("db1"));
+        assert!(!filter.wants("foo"));
+
+        filter.set(Some(BTreeSet::from(["backup-1".to_string()])));
+        assert!(!filter.wants("backup-1"));
+    }
+
+    #[test]
+    fn resource_filter_intentional_restart() {
+        let mut filter = ResourceFilter::new(None);
+        // nothing running, nothing to restart
+        filter.set(Some(BTreeSet::from(["foo".to_string()])));
+        assert!(!filter.take_restart_requested());
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("60")
+            .spawn()
+            .expect("sleep must start");
+        filter.child = Some(child.id());
+
+        // unchanged filter does not restart
+        filter.set(Some(BTreeSet::from(["foo".to_string()])));
+        assert!(!filter.take_restart_requested());
+
+        filter.set_patterns(vec![], vec!["foo".to_string()]);
+        assert!(filter.take_restart_requested());
+        assert!(!filter.take_restart_requested());
+        assert!(!child.wait().expect("sleep must exit").success());
+    }
+
+    #[test]
+    fn replay_transcript() {
+        let transcript = "# captured on node1
+exists resource name:foo role:Secondary suspended:no write-ordering:flush may_promote:no promotion_score:0
+exists -
+2024-01-02T03:04:05.678901+01:00 change resource name:foo role:Primary
+change bogus name:foo
+
+";
+        let (tx, rx) = crossbeam_channel::unbounded();
+        replay(&tx, transcript.as_bytes()).expect("replay must work");
+        drop(tx);
+
+        let updates: Vec<EventUpdate> = rx.iter().collect();
+        assert_eq!(updates.len(), 3);
+        assert!(matches!(
+            &updates[0],
+            EventUpdate::Resource(EventType::Exists, r) if r.name == "foo"
+        ));
+        assert!(matches!(
+            &updates[1],
+            EventUpdate::Resource(EventType::Change, r) if r.role == Role::Primary
+        ));
+        assert!(matches!(updates[2], EventUpdate::Stop));
+    }
+
     #[test]
     fn string_to_bool() {
         assert!(str_to_bool("yes"));
@@ -402,6 +809,9 @@ mod tests {
                 out_of_sync: 1,
                 pending: 1,
                 unacked: 1,
+                resync_done: 0.0,
+                resync_eta: 0,
+                resync_rate: 0.0,
                 has_sync_details: false,
                 has_online_verify_details: false,
             },
@@ -411,6 +821,30 @@ mod tests {
         assert_eq!(up, expected);
     }
 
+    #[test]
+    fn peerdevice_sync_details() {
+        let up = parse_events2_line("change peer-device name:foo peer-node-id:1 conn-name:bar volume:0 replication:SyncSource peer-disk:Inconsistent received:0 sent:1 out-of-sync:2 pending:0 unacked:0 done:42.17 eta:120 dbdt1:1024").unwrap();
+        match up {
+            EventUpdate::PeerDevice(EventType::Change, pd) => {
+                assert!(pd.has_sync_details);
+                assert!(!pd.has_online_verify_details);
+                assert_eq!(pd.resync_done, 42.17);
+                assert_eq!(pd.resync_eta, 120);
+                assert_eq!(pd.resync_rate, 1024.0);
+            }
+            _ => panic!("expected a peer-device update"),
+        }
+
+        let up = parse_events2_line("change peer-device name:foo peer-node-id:1 conn-name:bar volume:0 replication:VerifyS done:1.00 eta:10").unwrap();
+        match up {
+            EventUpdate::PeerDevice(_, pd) => {
+                assert!(!pd.has_sync_details);
+                assert!(pd.has_online_verify_details);
+            }
+            _ => panic!("expected a peer-device update"),
+        }
+    }
+
     #[test]
     fn all_parsed_path_update() {
         let up = parse_events2_line("change path name:foo peer-node-id:3 conn-name:bar local:ipv4:1.2.3.4:7020 peer:ipv4:1.2.3.5:7020 established:yes").unwrap();
@@ -431,9 +865,92 @@ mod tests {
     #[test]
     fn wrong_et() {
         assert!(parse_events2_line("xxx resource name:foo").is_err());
-        // these will be implemented soon, but for now they are errors
-        assert!(parse_events2_line("call helper").is_err());
-        assert!(parse_events2_line("response helper").is_err());
+        assert!(parse_events2_line("call resource name:foo").is_err());
+        assert!(parse_events2_line("change helper name:foo").is_err());
+    }
+
+    #[test]
+    fn all_parsed_helper_update() {
+        let up = parse_events2_line(
+            "call helper name:foo peer-node-id:1 conn-name:bar volume:0 helper:before-resync-target",
+        )
+        .unwrap();
+        let expected = EventUpdate::Helper(
+            EventType::Call,
+            Helper {
+                name: "foo".to_string(),
+                volume: Some(0),
+                peer_node_id: Some(1),
+                conn_name: "bar".to_string(),
+                helper: "before-resync-target".to_string(),
+                status: None,
+            },
+        );
+        assert_eq!(up, expected);
+
+        // helpers DRBD might add in the future are just passed on
+        let up =
+            parse_events2_line("response helper name:foo helper:not-yet-known status:3").unwrap();
+        let expected = EventUpdate::Helper(
+            EventType::Response,
+            Helper {
+                name: "foo".to_string(),
+                helper: "not-yet-known".to_string(),
+                status: Some(3),
+                ..Default::default()
+            },
+        );
+        assert_eq!(up, expected);
+    }
+
+    #[test]
+    fn restart_budget() {
+        let window = Duration::from_secs(60);
+        let max_delay = Duration::from_secs(30);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // quick failures exhaust the budget
+        let mut budget = RestartBudget::new(2, window, max_delay);
+        assert!(!budget.failed(at(0), at(1)));
+        assert!(!budget.failed(at(1), at(2)));
+        assert!(budget.failed(at(2), at(3)));
+
+        // failures outside of the window do not count
+        let mut budget = RestartBudget::new(2, window, max_delay);
+        assert!(!budget.failed(at(0), at(1)));
+        assert!(!budget.failed(at(1), at(2)));
+        assert!(!budget.failed(at(60), at(61)));
+
+        // a long-lived run resets the failures
+        let mut budget = RestartBudget::new(2, window, max_delay);
+        assert!(!budget.failed(at(0), at(1)));
+        assert!(!budget.failed(at(1), at(2)));
+        assert!(!budget.failed(at(2), at(62)));
+        assert!(!budget.failed(at(62), at(63)));
+        assert!(budget.failed(at(63), at(64)));
+
+        // 0 never gives up
+        let mut budget = RestartBudget::new(0, window, max_delay);
+        for i in 0..100 {
+            assert!(!budget.failed(at(i), at(i)));
+        }
+    }
+
+    #[test]
+    fn restart_backoff() {
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let mut budget = RestartBudget::new(0, window, Duration::from_secs(5));
+        let delays: Vec<_> = (0..5).map(|_| budget.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+
+        // a long-lived run resets the delay
+        assert!(!budget.failed(at(0), at(60)));
+        assert_eq!(budget.next_delay(), Duration::from_secs(1));
+        assert_eq!(budget.next_delay(), Duration::from_secs(2));
     }
 
     #[test]
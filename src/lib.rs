@@ -5,5 +5,6 @@ pub mod config;
 pub mod drbd;
 pub mod events;
 pub mod plugin;
+pub mod runner;
 pub mod systemd;
 pub mod utils;
@@ -1,19 +1,22 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::read_to_string;
-use std::path::PathBuf;
-use std::time::Duration;
-use std::{io, sync, thread};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fmt, io, sync, thread};
 
 use anyhow::{Context, Result};
 
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 use signal_hook::iterator::Signals;
 use structopt::StructOpt;
 
 use drbd_reactor::drbd;
-use drbd_reactor::drbd::{EventType, EventUpdate, PluginUpdate, Resource};
-use drbd_reactor::events::events2;
-use drbd_reactor::{config, plugin};
+use drbd_reactor::drbd::{
+    EventObject, EventType, EventUpdate, HelperPluginUpdate, PluginUpdate, Resource,
+};
+use drbd_reactor::events::{events2, ResourceFilter, RestartBudget};
+use drbd_reactor::runner::SystemRunner;
+use drbd_reactor::{config, events, plugin, utils};
 
 /// Core handles DRBD events based on the provided configuration
 ///
@@ -28,8 +31,14 @@ use drbd_reactor::{config, plugin};
 ///   - the overall resource state
 struct Core {
     resources: HashMap<String, Resource>,
+    // glob patterns of resources that are tracked (empty tracks all) and that are ignored
+    included: Vec<String>,
+    ignored: Vec<String>,
 }
 
+// how often the core samples the plugin queue depths on busy nodes
+const QUEUE_DEPTH_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(PartialEq)]
 enum CoreExit {
     Stop,
@@ -44,6 +53,8 @@ impl Core {
     fn new() -> Core {
         Core {
             resources: HashMap::new(),
+            included: Vec::new(),
+            ignored: Vec::new(),
         }
     }
 
@@ -53,6 +64,46 @@ impl Core {
             .or_insert(Resource::with_name(name))
     }
 
+    /// Drop the state of resources that are not wanted anymore (e.g., after a reload changed the
+    /// resource filter). Plugins that get events are told that these resources are gone.
+    fn forget_resources<F: Fn(&str) -> bool>(
+        &mut self,
+        wanted: F,
+        started: &HashMap<plugin::PluginCfg, plugin::PluginStarted>,
+    ) -> Result<()> {
+        let unwanted: Vec<String> = self
+            .resources
+            .keys()
+            .filter(|name| !wanted(name))
+            .cloned()
+            .collect();
+        for name in unwanted {
+            if let Some(res) = self.resources.remove(&name) {
+                debug!("forget_resources: dropping state of '{}'", name);
+                let up = sync::Arc::new(PluginUpdate::ResourceOnly(
+                    EventType::Destroy,
+                    EventObject::Resource,
+                    res,
+                ));
+                for p in started.values() {
+                    if let plugin::PluginType::Event = p.ptype {
+                        p.tx.send(up.clone())?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Log the current view on all resources, sorted by name
+    fn dump_state(&self) {
+        let resources = self.resources.iter().collect::<BTreeMap<_, _>>();
+        match serde_json::to_string_pretty(&resources) {
+            Ok(state) => info!("dump_state: {} resources:\n{}", resources.len(), state),
+            Err(e) => warn!("dump_state: could not serialize state: {}", e),
+        }
+    }
+
     /// Start the core
     ///
     /// This will start listening for DRBD events, keeping track of any changes, updating the
@@ -65,6 +116,7 @@ impl Core {
         let _send_updates = |up: Option<PluginUpdate>,
                              res: &Resource,
                              et: &EventType,
+                             object: EventObject,
                              only_new: bool|
          -> Result<()> {
             if let Some(up) = up {
@@ -78,7 +130,7 @@ impl Core {
                     }
                 }
             }
-            let up = PluginUpdate::ResourceOnly(et.clone(), res.clone());
+            let up = PluginUpdate::ResourceOnly(et.clone(), object, res.clone());
             let up = sync::Arc::new(up);
             for p in started.values() {
                 if !p.new && only_new {
@@ -92,12 +144,13 @@ impl Core {
         };
         let send_updates = |up: Option<PluginUpdate>,
                             res: &Resource,
-                            et: &EventType|
-         -> Result<()> { _send_updates(up, res, et, false) };
-        let send_updates_only_new = |up: Option<PluginUpdate>,
-                                     res: &Resource,
-                                     et: &EventType|
-         -> Result<()> { _send_updates(up, res, et, true) };
+                            et: &EventType,
+                            object: EventObject|
+         -> Result<()> { _send_updates(up, res, et, object, false) };
+        let send_updates_only_new =
+            |up: Option<PluginUpdate>, res: &Resource, et: &EventType| -> Result<()> {
+                _send_updates(up, res, et, EventObject::Resource, true)
+            };
 
         // initial state, if there is one for new plugins
         for res in self.resources.values() {
@@ -108,12 +161,35 @@ impl Core {
             }
         }
 
+        plugin::record_queue_depths(started);
+        let mut depths_sampled = Instant::now();
+
         for r in e2rx {
+            if depths_sampled.elapsed() >= QUEUE_DEPTH_SAMPLE_INTERVAL {
+                plugin::record_queue_depths(started);
+                depths_sampled = Instant::now();
+            }
+            let name = r.resource_name().map(String::from);
+            // usually already filtered by the events2 thread, but not for other event sources
+            if name
+                .as_ref()
+                .is_some_and(|name| !config::tracks_resource(&self.included, &self.ignored, name))
+            {
+                continue;
+            }
+            if name.is_some() {
+                events::count_processed();
+            }
+            let was_healthy = name
+                .as_ref()
+                .and_then(|name| self.resources.get(name))
+                .is_some_and(|res| res.is_healthy());
+
             match r {
                 EventUpdate::Resource(et, r) => {
                     let res = self.get_or_create_resource(&r.name);
                     let up = res.get_resource_update(&et, &r);
-                    send_updates(up, res, &et)?;
+                    send_updates(up, res, &et, EventObject::Resource)?;
 
                     if et == EventType::Destroy {
                         self.resources.remove(&r.name);
@@ -122,26 +198,62 @@ impl Core {
                 EventUpdate::Device(et, d) => {
                     let res = self.get_or_create_resource(&d.name);
                     let up = res.get_device_update(&et, &d);
-                    send_updates(up, res, &EventType::Change)?;
+                    send_updates(up, res, &et, EventObject::Device)?;
                 }
                 EventUpdate::PeerDevice(et, pd) => {
                     let res = self.get_or_create_resource(&pd.name);
                     let up = res.get_peerdevice_update(&et, &pd);
-                    send_updates(up, res, &EventType::Change)?;
+                    send_updates(up, res, &et, EventObject::PeerDevice)?;
                 }
                 EventUpdate::Connection(et, c) => {
                     let res = self.get_or_create_resource(&c.name);
                     let up = res.get_connection_update(&et, &c);
-                    send_updates(up, res, &EventType::Change)?;
+                    send_updates(up, res, &et, EventObject::Connection)?;
                 }
                 EventUpdate::Path(et, p) => {
                     let res = self.get_or_create_resource(&p.name);
                     let up = res.get_path_update(&et, &p);
-                    send_updates(up, res, &EventType::Change)?;
+                    send_updates(up, res, &et, EventObject::Path)?;
+                }
+                EventUpdate::Helper(et, h) => {
+                    // helpers do not change the state, only forward them to plugins that react on changes
+                    let res = self
+                        .resources
+                        .get(&h.name)
+                        .cloned()
+                        .unwrap_or_else(|| Resource::with_name(&h.name));
+                    let up =
+                        sync::Arc::new(PluginUpdate::Helper(HelperPluginUpdate::new(et, &h, res)));
+                    for p in started.values() {
+                        if let plugin::PluginType::Change = p.ptype {
+                            p.tx.send(up.clone())?;
+                        }
+                    }
                 }
                 EventUpdate::Stop => return Ok(CoreExit::Stop),
-                EventUpdate::Reload => return Ok(CoreExit::Reload),
+                EventUpdate::Reload => {
+                    config::count_reload();
+                    return Ok(CoreExit::Reload);
+                }
                 EventUpdate::Flush => return Ok(CoreExit::Flush),
+                EventUpdate::DumpState => self.dump_state(),
+            }
+
+            // derived from the overall state, so only for plugins that get changes
+            if let Some(res) = name.and_then(|name| self.resources.get(&name)) {
+                if let Some(up) = res.get_health_update(was_healthy) {
+                    info!(
+                        "resource '{}' became {}",
+                        res.name,
+                        if was_healthy { "unhealthy" } else { "healthy" }
+                    );
+                    let up = sync::Arc::new(up);
+                    for p in started.values() {
+                        if let plugin::PluginType::Change = p.ptype {
+                            p.tx.send(up.clone())?;
+                        }
+                    }
+                }
             }
         }
 
@@ -149,24 +261,83 @@ impl Core {
     }
 }
 
-/// Initialize all configured loggers and set them up as global log sink
-fn init_loggers(log_cfgs: Vec<config::LogConfig>) -> Result<()> {
-    let mut central_dispatcher = fern::Dispatch::new().format(|out, message, record| {
-        out.finish(format_args!(
-            "{} [{}] {}",
+fn format_text(
+    out: fern::FormatCallback,
+    message: &fmt::Arguments,
+    record: &log::Record,
+    timestamps: bool,
+) {
+    let timestamp = if timestamps {
+        format!("{} ", utils::rfc3339(SystemTime::now()))
+    } else {
+        String::new()
+    };
+    plugin::with_log_context(|ctx| match ctx {
+        Some(ctx) => out.finish(format_args!(
+            "{}{} [{}] {}: {}",
+            timestamp,
+            record.level(),
+            record.target(),
+            ctx,
+            message,
+        )),
+        None => out.finish(format_args!(
+            "{}{} [{}] {}",
+            timestamp,
             record.level(),
             record.target(),
             message,
-        ))
+        )),
+    })
+}
+
+fn format_json(out: fern::FormatCallback, message: &fmt::Arguments, record: &log::Record) {
+    let mut line = serde_json::json!({
+        "timestamp": utils::rfc3339(SystemTime::now()),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": message.to_string(),
+    });
+    plugin::with_log_context(|ctx| {
+        if let Some(ctx) = ctx {
+            line["context"] = ctx.into();
+        }
     });
+    out.finish(format_args!("{}", line))
+}
+
+fn syslog_output(facility: config::SyslogFacility) -> fern::Output {
+    let facility = facility.code();
+    utils::openlog(facility);
+    fern::Output::call(move |record| {
+        utils::syslog(facility, record.level(), &record.args().to_string())
+    })
+}
+
+/// Initialize all configured loggers and set them up as global log sink
+fn init_loggers(log_cfgs: Vec<config::LogConfig>) -> Result<()> {
+    let mut central_dispatcher = fern::Dispatch::new();
 
     for log_cfg in log_cfgs {
-        let out: fern::Output = match log_cfg.file {
-            Some(path) => fern::log_file(path)?.into(),
-            None => io::stderr().into(),
+        let timestamps = log_cfg.with_timestamps();
+        let out: fern::Output = match (log_cfg.syslog, log_cfg.file) {
+            (true, Some(path)) => {
+                return Err(anyhow::anyhow!(
+                    "log: 'syslog' and 'file' ({}) are mutually exclusive",
+                    path.display()
+                ))
+            }
+            (true, None) => syslog_output(log_cfg.syslog_facility),
+            (false, Some(path)) => fern::log_file(path)?.into(),
+            (false, None) => io::stderr().into(),
         };
 
-        let dispatch_for_cfg = fern::Dispatch::new().level(log_cfg.level).chain(out);
+        let dispatch_for_cfg = match log_cfg.format {
+            config::LogFormat::Text => fern::Dispatch::new()
+                .format(move |out, message, record| format_text(out, message, record, timestamps)),
+            config::LogFormat::Json => fern::Dispatch::new().format(format_json),
+        };
+        let dispatch_for_cfg = dispatch_for_cfg.level(log_cfg.level).chain(out);
 
         central_dispatcher = central_dispatcher.chain(dispatch_for_cfg);
     }
@@ -181,6 +352,10 @@ fn init_loggers(log_cfgs: Vec<config::LogConfig>) -> Result<()> {
 fn main() -> Result<()> {
     let cli_opt = CliOpt::from_args();
 
+    if let Some(transcript) = &cli_opt.replay {
+        return replay(transcript);
+    }
+
     let tty = atty::is(atty::Stream::Stdin)
         || atty::is(atty::Stream::Stdout)
         || atty::is(atty::Stream::Stderr);
@@ -193,13 +368,36 @@ fn main() -> Result<()> {
     let mut cfg = get_config(&cli_opt.config)?;
     init_loggers(cfg.clone().log)?;
 
+    // set before any thread gets spawned, so that they inherit it
+    let mut process_title = cfg.process_title.clone();
+    if process_title.is_some() {
+        set_process_title(&process_title);
+    }
+
     let (e2tx, e2rx) = crossbeam_channel::unbounded();
 
     setup_signals(e2tx.clone())?;
 
     let statistics_poll = Duration::from_secs(cfg.statistics_poll_interval);
+    let mut resource_filter = ResourceFilter::new(cfg.plugins.resource_filter());
+    resource_filter.set_patterns(cfg.include_resources.clone(), cfg.ignore_resources.clone());
+    let filter = sync::Arc::new(sync::Mutex::new(resource_filter));
+    let events2_filter = filter.clone();
+    let events2_restarts = RestartBudget::new(
+        cfg.events2_max_restarts,
+        Duration::from_secs(cfg.events2_restart_window_secs),
+        Duration::from_secs(cfg.events2_max_restart_delay_secs),
+    );
+    let events_from = cli_opt.events_from.clone();
     thread::spawn(move || {
-        if let Err(e) = events2(e2tx, statistics_poll) {
+        let result = match events_from {
+            Some(path) => {
+                info!("main: reading events from '{}'", path.display());
+                events::events_from(&e2tx, &path)
+            }
+            None => events2(e2tx, statistics_poll, events2_filter, events2_restarts),
+        };
+        if let Err(e) = result {
             error!("main: events2 processing failed: {}", e);
             std::process::exit(1);
         }
@@ -213,8 +411,44 @@ fn main() -> Result<()> {
             Ok(new) => cfg = new,
             Err(e) => warn!("main: failed to reload config, reusing old: {}", e),
         };
+        // on reloads only the main thread gets renamed, running threads keep their names
+        if cfg.process_title != process_title {
+            process_title = cfg.process_title.clone();
+            set_process_title(&process_title);
+        }
         debug!("main: configuration: {:#?}", cfg);
+        if cfg.config_version > config::CONFIG_VERSION {
+            warn!(
+                "main: config-version {} is newer than the supported version {}",
+                cfg.config_version,
+                config::CONFIG_VERSION
+            );
+        }
+        let deprecated = config::deprecated_settings(&cfg);
+        if !deprecated.is_empty() {
+            warn!(
+                "main: config-version {} is older than {}, deprecated settings in use (see 'drbd-reactorctl migrate'): {}",
+                cfg.config_version,
+                config::CONFIG_VERSION,
+                deprecated.join(", ")
+            );
+        }
+        match config::hash(&cfg) {
+            Ok(hash) => config::set_config_hash(hash),
+            Err(e) => warn!("main: could not hash configuration: {}", e),
+        }
 
+        match filter.lock() {
+            Ok(mut filter) => {
+                filter.set(cfg.plugins.resource_filter());
+                filter.set_patterns(cfg.include_resources.clone(), cfg.ignore_resources.clone());
+                core.forget_resources(|name| filter.wants(name), &started)?;
+            }
+            Err(e) => warn!("main: could not update events2 resource filter: {}", e),
+        }
+
+        core.included = cfg.include_resources.clone();
+        core.ignored = cfg.ignore_resources.clone();
         plugin::start_from_config(cfg.plugins.clone(), &mut started)?;
         debug!("main: started.len()={}", started.len());
 
@@ -240,8 +474,42 @@ fn main() -> Result<()> {
     }
 }
 
+/// Runs the core with only the debugger plugin on a captured events2 transcript.
+fn replay(transcript: &Path) -> Result<()> {
+    init_loggers(vec![config::LogConfig {
+        level: log::LevelFilter::Debug,
+        file: None,
+        format: config::LogFormat::Text,
+        timestamps: Some(false),
+        syslog: false,
+        syslog_facility: config::SyslogFacility::Daemon,
+    }])?;
+
+    let (e2tx, e2rx) = crossbeam_channel::unbounded();
+    let transcript = transcript.to_path_buf();
+    let reader = thread::spawn(move || events::events_from(&e2tx, &transcript));
+
+    let plugins = plugin::PluginConfig {
+        debugger: vec![Default::default()],
+        ..Default::default()
+    };
+    let mut started = HashMap::new();
+    plugin::start_from_config(plugins, &mut started)?;
+
+    Core::new()
+        .run(&e2rx, &started)
+        .context("replay: core did not exit successfully")?;
+    for (_, plugin) in started.drain() {
+        plugin.stop()?;
+    }
+
+    reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("replay: transcript reader panicked"))?
+}
+
 fn setup_signals(events: crossbeam_channel::Sender<EventUpdate>) -> Result<()> {
-    let mut signals = Signals::new(&[libc::SIGHUP, libc::SIGINT, libc::SIGTERM])?;
+    let mut signals = Signals::new(&[libc::SIGHUP, libc::SIGINT, libc::SIGTERM, libc::SIGUSR1])?;
     debug!("signal-handler: set up done");
 
     thread::spawn(move || {
@@ -250,6 +518,7 @@ fn setup_signals(events: crossbeam_channel::Sender<EventUpdate>) -> Result<()> {
             let event = match signal as libc::c_int {
                 libc::SIGHUP => EventUpdate::Reload,
                 libc::SIGINT | libc::SIGTERM => EventUpdate::Stop,
+                libc::SIGUSR1 => EventUpdate::DumpState,
                 _ => unreachable!(),
             };
 
@@ -274,7 +543,7 @@ fn get_config(config_file: &PathBuf) -> Result<config::Config> {
 }
 
 fn min_drbd_versions() -> Result<()> {
-    let drbd_versions = drbd::get_drbd_versions()?;
+    let drbd_versions = drbd::get_drbd_versions(&SystemRunner)?;
 
     // check utils
     // no overrides for ExecCondition vs. ExecStart
@@ -299,11 +568,7 @@ fn min_drbd_versions() -> Result<()> {
             "Looks like the DRBD kernel module is not installed or not loaded"
         ));
     }
-    let want = drbd::Version {
-        major: 9,
-        minor: 1,
-        patch: 7,
-    };
+    let want = drbd::MIN_KMOD_SECONDARY_FORCE;
     if kmod < want {
         return Err(anyhow::anyhow!(
             "DRBD kernel module minimum version ('{}') not fulfilled by '{}'",
@@ -326,6 +591,21 @@ struct CliOpt {
     config: PathBuf,
     #[structopt(long)]
     allow_tty: bool,
+    /// Replay a captured "drbdsetup events2" transcript through the debugger plugin and exit
+    #[structopt(long, hidden = true, parse(from_os_str))]
+    replay: Option<PathBuf>,
+    /// Read events2 formatted lines from a file, a named pipe, or stdin ("-") instead of running
+    /// "drbdsetup events2"; drbd-reactor stops after the last event
+    #[structopt(long, parse(from_os_str))]
+    events_from: Option<PathBuf>,
+}
+
+// unset restores the binary name
+fn set_process_title(title: &Option<String>) {
+    let title = title.as_deref().unwrap_or(env!("CARGO_PKG_NAME"));
+    if let Err(e) = utils::set_process_title(title) {
+        warn!("main: could not set process title to '{}': {}", title, e);
+    }
 }
 
 fn read_config(config_file: &PathBuf) -> Result<config::Config> {
@@ -340,12 +620,11 @@ fn read_config(config_file: &PathBuf) -> Result<config::Config> {
         )
     })?;
 
-    let snippets_path = match config.snippets {
+    let snippets_paths = match &config.snippets {
         None => return Ok(config),
-        Some(path) => path,
+        Some(snippets) => snippets.files()?,
     };
 
-    let snippets_paths = config::files_with_extension_in(&snippets_path, "toml")?;
     let snippets = config::read_snippets(snippets_paths)
         .with_context(|| "Could not read config snippets".to_string())?;
     content.push_str("\n# Content from snippets:\n");
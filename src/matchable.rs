@@ -1,5 +1,14 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::slice;
+use std::sync::Mutex;
+
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+// compiled regexes of Regex patterns, by pattern; every pattern got validated on deserialization
+static REGEX_CACHE: Mutex<BTreeMap<String, Regex>> = Mutex::new(BTreeMap::new());
+
 /// A trait for matching structs from "partial" filters.
 ///
 /// This is meant as a very basic generic filter for a bunch of different structs or enums.
@@ -16,6 +25,14 @@ pub trait PartialMatchable {
 pub enum BasicPatternOperator {
     Equals,
     NotEquals,
+    // only match for ordered (i.e., numeric) values, never for others
+    GreaterThan,
+    LessThan,
+    GreaterOrEqual,
+    LessOrEqual,
+    // match if the value equals any/none of the values
+    In,
+    NotIn,
 }
 
 impl Default for BasicPatternOperator {
@@ -24,17 +41,164 @@ impl Default for BasicPatternOperator {
     }
 }
 
-#[derive(Serialize, Deserialize, Eq, Hash, Debug, Clone, Copy, PartialEq)]
-#[serde(untagged)]
+#[derive(Serialize, Deserialize, Eq, Hash, Debug, Clone, PartialEq)]
+#[serde(untagged, try_from = "RawBasicPattern<T>")]
 pub enum BasicPattern<T> {
     WithOperator {
         value: T,
         #[serde(default)]
         operator: BasicPatternOperator,
     },
+    /// Matches a set of values, `Equals`/`In` match if any value is equal, `NotEquals`/`NotIn` if none is.
+    WithValues {
+        values: Vec<T>,
+        #[serde(default = "default_set_operator")]
+        operator: BasicPatternOperator,
+    },
+    /// Matches if the regular expression matches (anywhere, use anchors for full matches).
+    /// Only matches string values, never others.
+    Regex {
+        pattern: String,
+    },
+    Default(T),
+}
+
+// BasicPattern as parsed, so that invalid regexes fail with a proper error instead of the generic
+// "did not match any variant" error of untagged enums.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawBasicPattern<T> {
+    WithOperator {
+        value: T,
+        #[serde(default)]
+        operator: BasicPatternOperator,
+    },
+    WithValues {
+        values: Vec<T>,
+        #[serde(default = "default_set_operator")]
+        operator: BasicPatternOperator,
+    },
+    Regex {
+        pattern: String,
+    },
     Default(T),
 }
 
+impl<T> TryFrom<RawBasicPattern<T>> for BasicPattern<T> {
+    type Error = String;
+
+    fn try_from(raw: RawBasicPattern<T>) -> Result<Self, Self::Error> {
+        Ok(match raw {
+            RawBasicPattern::WithOperator { value, operator } => {
+                Self::WithOperator { value, operator }
+            }
+            RawBasicPattern::WithValues { values, operator } => {
+                // such a pattern would never match
+                let comparison = matches!(
+                    operator,
+                    BasicPatternOperator::GreaterThan
+                        | BasicPatternOperator::LessThan
+                        | BasicPatternOperator::GreaterOrEqual
+                        | BasicPatternOperator::LessOrEqual
+                );
+                if comparison && values.len() != 1 {
+                    return Err(format!(
+                        "operator '{:?}' needs a single value, got {}",
+                        operator,
+                        values.len()
+                    ));
+                }
+                Self::WithValues { values, operator }
+            }
+            RawBasicPattern::Regex { pattern } => {
+                Regex::new(&pattern)
+                    .map_err(|e| format!("invalid regex pattern '{}': {}", pattern, e))?;
+                Self::Regex { pattern }
+            }
+            RawBasicPattern::Default(value) => Self::Default(value),
+        })
+    }
+}
+
+/// Test if the regular expression matches the value, compiling it at most once.
+///
+/// Patterns are validated when a BasicPattern is parsed, a pattern that does not compile never matches.
+pub fn regex_matches(pattern: &str, value: &str) -> bool {
+    let mut cache = REGEX_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if !cache.contains_key(pattern) {
+        match Regex::new(pattern) {
+            Ok(re) => cache.insert(pattern.to_string(), re),
+            Err(_) => return false,
+        };
+    }
+    cache[pattern].is_match(value)
+}
+
+fn default_set_operator() -> BasicPatternOperator {
+    BasicPatternOperator::In
+}
+
+impl<T> BasicPattern<T> {
+    /// Converts the value(s) of the pattern, keeping the operator.
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> BasicPattern<U> {
+        match self {
+            Self::WithOperator { value, operator } => BasicPattern::WithOperator {
+                value: f(value),
+                operator,
+            },
+            Self::WithValues { values, operator } => BasicPattern::WithValues {
+                values: values.into_iter().map(f).collect(),
+                operator,
+            },
+            Self::Regex { pattern } => BasicPattern::Regex { pattern },
+            Self::Default(value) => BasicPattern::Default(f(value)),
+        }
+    }
+
+    /// The values to compare to and the operator, None for Regex patterns.
+    pub fn values(&self) -> Option<(&[T], BasicPatternOperator)> {
+        match self {
+            Self::Default(value) => Some((slice::from_ref(value), BasicPatternOperator::Equals)),
+            Self::WithOperator { value, operator } => Some((slice::from_ref(value), *operator)),
+            Self::WithValues { values, operator } => Some((values, *operator)),
+            Self::Regex { .. } => None,
+        }
+    }
+}
+
+/// Pattern for lists (e.g., the devices of a resource)
+///
+/// `any` matches if at least one item matches, `all` if every item matches, and `len` matches the
+/// number of items. Unset fields match every list.
+#[derive(Serialize, Deserialize, Eq, Hash, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct ListPattern<P> {
+    pub any: Option<P>,
+    pub all: Option<P>,
+    pub len: Option<BasicPattern<usize>>,
+}
+
+impl<T: PartialMatchable> PartialMatchable for Vec<T> {
+    type Pattern = Option<ListPattern<T::Pattern>>;
+    fn matches(&self, pattern: &Self::Pattern) -> bool {
+        let pattern = match pattern {
+            Some(p) => p,
+            None => return true,
+        };
+        if let Some(any) = &pattern.any {
+            if !self.iter().any(|item| item.matches(any)) {
+                return false;
+            }
+        }
+        if let Some(all) = &pattern.all {
+            if !self.iter().all(|item| item.matches(all)) {
+                return false;
+            }
+        }
+        self.len().matches(&pattern.len)
+    }
+}
+
 #[macro_export]
 macro_rules! common_matchable {
     ($($ty:ty),*) => {
@@ -42,15 +206,18 @@ macro_rules! common_matchable {
             impl $crate::matchable::PartialMatchable for $ty {
                 type Pattern = ::core::option::Option<$crate::matchable::BasicPattern<$ty>>;
                 fn matches(&self, pattern: &Self::Pattern) -> bool {
-                    let (value, operator) = match pattern {
-                        Some($crate::matchable::BasicPattern::Default(v)) => (v, &$crate::matchable::BasicPatternOperator::Equals),
-                        Some($crate::matchable::BasicPattern::WithOperator{ value: v, operator: o}) => (v, o),
+                    let (values, operator) = match pattern.as_ref().map($crate::matchable::BasicPattern::values) {
+                        Some(Some(v)) => v,
+                        Some(None) => return false,
                         None => return true,
                     };
 
                     match operator {
-                        $crate::matchable::BasicPatternOperator::Equals => self == value,
-                        $crate::matchable::BasicPatternOperator::NotEquals => self != value,
+                        $crate::matchable::BasicPatternOperator::Equals
+                        | $crate::matchable::BasicPatternOperator::In => values.contains(self),
+                        $crate::matchable::BasicPatternOperator::NotEquals
+                        | $crate::matchable::BasicPatternOperator::NotIn => !values.contains(self),
+                        _ => false,
                     }
                 }
             }
@@ -58,13 +225,65 @@ macro_rules! common_matchable {
     };
 }
 
+/// Like common_matchable, but the types are ordered, so that the comparison operators match too.
+#[macro_export]
+macro_rules! ordered_matchable {
+    ($($ty:ty),*) => {
+        $(
+            impl $crate::matchable::PartialMatchable for $ty {
+                type Pattern = ::core::option::Option<$crate::matchable::BasicPattern<$ty>>;
+                fn matches(&self, pattern: &Self::Pattern) -> bool {
+                    let (values, operator) = match pattern.as_ref().map($crate::matchable::BasicPattern::values) {
+                        Some(Some(v)) => v,
+                        Some(None) => return false,
+                        None => return true,
+                    };
+
+                    // comparisons are only defined for a single value
+                    match (operator, values) {
+                        ($crate::matchable::BasicPatternOperator::Equals, _)
+                        | ($crate::matchable::BasicPatternOperator::In, _) => values.contains(self),
+                        ($crate::matchable::BasicPatternOperator::NotEquals, _)
+                        | ($crate::matchable::BasicPatternOperator::NotIn, _) => !values.contains(self),
+                        ($crate::matchable::BasicPatternOperator::GreaterThan, [value]) => self > value,
+                        ($crate::matchable::BasicPatternOperator::LessThan, [value]) => self < value,
+                        ($crate::matchable::BasicPatternOperator::GreaterOrEqual, [value]) => self >= value,
+                        ($crate::matchable::BasicPatternOperator::LessOrEqual, [value]) => self <= value,
+                        _ => false,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+// Strings additionally match Regex patterns
+impl PartialMatchable for String {
+    type Pattern = Option<BasicPattern<String>>;
+    fn matches(&self, pattern: &Self::Pattern) -> bool {
+        let (values, operator) = match pattern {
+            Some(BasicPattern::Regex { pattern }) => return regex_matches(pattern, self),
+            Some(p) => p.values().expect("only Regex patterns have no values"),
+            None => return true,
+        };
+
+        match operator {
+            BasicPatternOperator::Equals | BasicPatternOperator::In => values.contains(self),
+            BasicPatternOperator::NotEquals | BasicPatternOperator::NotIn => !values.contains(self),
+            _ => false,
+        }
+    }
+}
+
 // The generic impls for common types (used in the crate)
-common_matchable![String, bool, i32, u64];
+common_matchable![bool];
+ordered_matchable![i32, u64, usize];
 
 /// Implement PartialMatchable for structs and (unit) enums
 ///
 /// Creates an "all optional" copy of the given struct or enum and implements the
-/// PartialMatchable trait for the original.
+/// PartialMatchable trait for the original. Attributes following the pattern name are only applied
+/// to the pattern (e.g., derives the original can not have).
 ///
 /// # Example
 ///```rust
@@ -96,7 +315,7 @@ common_matchable![String, bool, i32, u64];
 ///```
 #[macro_export]
 macro_rules! make_matchable {
-    ($(#[$structattr:meta])* $svis:vis struct $name:ident { $($(#[$fieldattr:meta])* $fvis:vis $field:ident: $field_ty:ty),* $(,)? }, $pattern:ident) => {
+    ($(#[$structattr:meta])* $svis:vis struct $name:ident { $($(#[$fieldattr:meta])* $fvis:vis $field:ident: $field_ty:ty),* $(,)? }, $pattern:ident $(, $(#[$patternattr:meta])+)? $(,)?) => {
         $(
             #[$structattr]
         )*
@@ -112,6 +331,11 @@ macro_rules! make_matchable {
         $(
             #[$structattr]
         )*
+        $(
+            $(
+                #[$patternattr]
+            )+
+        )?
         $svis struct $pattern {
         $(
             $(
@@ -156,17 +380,26 @@ macro_rules! make_matchable {
         impl $crate::matchable::PartialMatchable for $name {
             type Pattern = ::core::option::Option<$crate::matchable::BasicPattern<$name>>;
             fn matches(&self, pattern: &Self::Pattern) -> bool {
-                let (value, operator) = match pattern {
-                        Some($crate::matchable::BasicPattern::Default(v)) => (v, &$crate::matchable::BasicPatternOperator::Equals),
-                        Some($crate::matchable::BasicPattern::WithOperator{ value: v, operator: o}) => (v, o),
+                let (values, operator) = match pattern.as_ref().map($crate::matchable::BasicPattern::values) {
+                        Some(Some(v)) => v,
+                        Some(None) => return false,
                         None => return true,
                 };
 
-                match (self, value) {
+                let equal = |value: &$name| match (self, value) {
                 $(
-                    ($name::$variant, $name::$variant) => &$crate::matchable::BasicPatternOperator::Equals == operator,
+                    ($name::$variant, $name::$variant) => true,
                 )*
-                    _ => &$crate::matchable::BasicPatternOperator::NotEquals == operator,
+                    #[allow(unreachable_patterns)]
+                    _ => false,
+                };
+
+                match operator {
+                    $crate::matchable::BasicPatternOperator::Equals
+                    | $crate::matchable::BasicPatternOperator::In => values.iter().any(equal),
+                    $crate::matchable::BasicPatternOperator::NotEquals
+                    | $crate::matchable::BasicPatternOperator::NotIn => !values.iter().any(equal),
+                    _ => false,
                 }
             }
         }
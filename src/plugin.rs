@@ -1,7 +1,8 @@
-use std::collections::{HashMap, HashSet};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::hash::Hash;
-use std::process::{Command, ExitStatus};
-use std::sync::Arc;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{any, thread};
 
@@ -10,6 +11,7 @@ use log::{error, info, trace, warn};
 use serde::{Deserialize, Serialize};
 
 use crate::drbd::{EventType, PluginUpdate};
+use crate::runner::{CommandRunner, SystemRunner};
 use crate::systemd;
 
 pub mod agentx;
@@ -21,6 +23,26 @@ pub mod umh;
 pub type PluginSender = crossbeam_channel::Sender<Arc<PluginUpdate>>;
 pub type PluginReceiver = crossbeam_channel::Receiver<Arc<PluginUpdate>>;
 
+thread_local! {
+    static LOG_CONTEXT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Sets the log context of the current thread and returns the previous one.
+///
+/// Plugins run in their own threads, so everything logged from a plugin thread carries the identity of that
+/// plugin instance.
+pub fn set_log_context(ctx: Option<String>) -> Option<String> {
+    LOG_CONTEXT.with(|c| c.replace(ctx))
+}
+
+/// Calls `f` with the log context of the current thread.
+pub fn with_log_context<F, R>(f: F) -> R
+where
+    F: FnOnce(Option<&str>) -> R,
+{
+    LOG_CONTEXT.with(|c| f(c.borrow().as_deref()))
+}
+
 trait Plugin: Send {
     fn run(&self, rx: PluginReceiver) -> anyhow::Result<()>;
     fn get_config(&self) -> PluginCfg;
@@ -54,16 +76,16 @@ pub fn map_status(status: std::result::Result<ExitStatus, std::io::Error>) -> Re
     }
 }
 
-pub fn system(action: &str) -> Result<()> {
+pub fn system(runner: &dyn CommandRunner, action: &str, timeout: Option<Duration>) -> Result<()> {
     info!("system: sh -c {}", action);
-    map_status(Command::new("sh").arg("-c").arg(action).status())
+    map_status(runner.status_timeout("sh", &["-c", action], timeout))
 }
 
 /// Central config for all available plugins.
 ///
 /// Each plugin can be configured multiple times (hence the Vec everywhere), and each config item is
 /// wrapped in a [crate::config::Component] to make it easy to disable plugins.
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct PluginConfig {
     #[serde(default)]
     pub promoter: Vec<promoter::PromoterConfig>,
@@ -77,6 +99,27 @@ pub struct PluginConfig {
     pub agentx: Vec<agentx::AgentXConfig>,
 }
 
+impl PluginConfig {
+    /// The DRBD resources the configured plugins are interested in.
+    ///
+    /// Returns None if at least one plugin needs to see every resource (e.g., prometheus).
+    pub fn resource_filter(&self) -> Option<BTreeSet<String>> {
+        if !self.debugger.is_empty() || !self.prometheus.is_empty() || !self.agentx.is_empty() {
+            return None;
+        }
+
+        let mut names = BTreeSet::new();
+        for promoter in &self.promoter {
+            names.extend(promoter.resources.keys().cloned());
+        }
+        for umh in &self.umh {
+            names.extend(umh.resource_names()?);
+        }
+
+        Some(names)
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
 pub enum PluginCfg {
     Promoter(promoter::PromoterConfig),
@@ -97,6 +140,33 @@ impl PluginCfg {
         }
     }
 
+    /// The type of the plugin as used in the configuration (e.g., "promoter").
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PluginCfg::Promoter(_) => "promoter",
+            PluginCfg::Debugger(_) => "debugger",
+            PluginCfg::UMH(_) => "umh",
+            PluginCfg::Prometheus(_) => "prometheus",
+            PluginCfg::AgentX(_) => "agentx",
+        }
+    }
+
+    /// Identifies a plugin instance in log messages.
+    ///
+    /// As `id` is deprecated, this is derived from the resources a plugin is interested in, or the address it
+    /// listens on/connects to.
+    pub fn log_context(&self) -> String {
+        match self {
+            PluginCfg::Promoter(cfg) => {
+                with_resources("promoter", Some(cfg.resources.keys().cloned().collect()))
+            }
+            PluginCfg::Debugger(_) => "debugger".to_string(),
+            PluginCfg::UMH(cfg) => with_resources("umh", cfg.resource_names()),
+            PluginCfg::Prometheus(cfg) => format!("prometheus[{}]", cfg.addresses_string()),
+            PluginCfg::AgentX(cfg) => format!("agentx[{}]", cfg.address),
+        }
+    }
+
     fn into_plugin(self) -> Result<Box<dyn Plugin>, anyhow::Error> {
         match self {
             PluginCfg::Debugger(cfg) => {
@@ -123,6 +193,17 @@ impl PluginCfg {
     }
 }
 
+fn with_resources(plugin: &str, names: Option<BTreeSet<String>>) -> String {
+    match names {
+        Some(names) if !names.is_empty() => format!(
+            "{}[{}]",
+            plugin,
+            names.into_iter().collect::<Vec<_>>().join(",")
+        ),
+        _ => plugin.to_string(),
+    }
+}
+
 pub struct PluginStarted {
     pub tx: PluginSender,
     pub handle: thread::JoinHandle<Result<()>>,
@@ -133,6 +214,43 @@ pub enum PluginType {
     Change, // important changes
     Event,  // every event line
 }
+// number of updates not yet consumed per plugin, sampled by the core
+static QUEUE_DEPTHS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Samples the number of updates queued for every started plugin.
+pub fn record_queue_depths(started: &HashMap<PluginCfg, PluginStarted>) {
+    let depths = started
+        .iter()
+        .map(|(cfg, p)| (cfg.log_context(), p.tx.len()))
+        .collect();
+    if let Ok(mut d) = QUEUE_DEPTHS.lock() {
+        *d = depths;
+    }
+}
+
+/// Returns the last sampled number of queued updates per plugin, keyed by the plugin's log context.
+pub fn queue_depths() -> BTreeMap<String, usize> {
+    QUEUE_DEPTHS.lock().map(|d| d.clone()).unwrap_or_default()
+}
+
+// (type, log context) of every running plugin, updated on every (re)start from config
+static INVENTORY: Mutex<BTreeSet<(String, String)>> = Mutex::new(BTreeSet::new());
+
+fn record_inventory(started: &HashMap<PluginCfg, PluginStarted>) {
+    let inventory = started
+        .keys()
+        .map(|cfg| (cfg.type_name().to_string(), cfg.log_context()))
+        .collect();
+    if let Ok(mut i) = INVENTORY.lock() {
+        *i = inventory;
+    }
+}
+
+/// Returns the type and log context of every running plugin.
+pub fn inventory() -> BTreeSet<(String, String)> {
+    INVENTORY.lock().map(|i| i.clone()).unwrap_or_default()
+}
+
 impl PluginStarted {
     pub fn stop(self) -> Result<()> {
         drop(self.tx);
@@ -193,8 +311,8 @@ pub fn start_from_config(
     *started = survive;
 
     let mut created_plugins = Vec::new();
-    // if we created new systemd units (i.e., promoter) remember that once and reload systemd once.
-    let mut systemd_reload = false;
+    // if we started a promoter remember that once, the first one is special (see below).
+    let mut promoter_started = false;
     // give every plugin 1 min to start
     // the first promoter is special, it might wait for the DRBD backing storage to show up
     // first promoter is handled in the loop below
@@ -209,18 +327,19 @@ pub fn start_from_config(
     for cfg in new_cfgs {
         deprecate_id(&cfg);
         if let PluginCfg::Promoter(_) = cfg {
-            if !systemd_reload {
+            if !promoter_started {
                 // the first promoter
                 extend = Duration::from_secs(60 * 10).as_micros();
             }
-            systemd_reload = true;
+            promoter_started = true;
         }
         systemd::notify(&format!("EXTEND_TIMEOUT_USEC={}\n", extend))?;
-        if systemd_reload {
+        if promoter_started {
             // reset, especially after the first promoter
             extend = extend_default;
         }
         trace!("start_from_config: starting new config '{:#?}'", cfg);
+        let prev_ctx = set_log_context(Some(cfg.log_context()));
         match cfg.into_plugin() {
             Ok(p) => created_plugins.push(p),
             Err(e) => error!(
@@ -228,12 +347,14 @@ pub fn start_from_config(
                 e
             ),
         }
+        set_log_context(prev_ctx);
     }
     systemd::notify("STATUS=Plugins started\n")?;
 
-    if systemd_reload {
+    // promoters only rewrite units that actually changed, reload systemd once if any did.
+    if promoter::take_units_changed() {
         info!("systemd_daemon_reload: reloading daemon");
-        systemd::daemon_reload()?;
+        systemd::daemon_reload(&SystemRunner)?;
     }
 
     systemd::notify("READY=1\n")?;
@@ -242,7 +363,11 @@ pub fn start_from_config(
         let cfg = p.get_config();
         let ptype = cfg.plugin_type();
         let (ptx, prx) = crossbeam_channel::unbounded();
-        let handle = thread::spawn(move || p.run(prx));
+        let ctx = cfg.log_context();
+        let handle = thread::spawn(move || {
+            set_log_context(Some(ctx));
+            p.run(prx)
+        });
         started.insert(
             cfg,
             PluginStarted {
@@ -253,6 +378,7 @@ pub fn start_from_config(
             },
         );
     }
+    record_inventory(started);
 
     Ok(())
 }
@@ -293,6 +419,116 @@ fn deprecate_id(cfg: &PluginCfg) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::Config;
+
+    fn filter_for(cfg: &str) -> Option<Vec<String>> {
+        let cfg: Config = toml::from_str(cfg).expect("cfg must parse");
+        cfg.plugins
+            .resource_filter()
+            .map(|names| names.into_iter().collect())
+    }
+
+    #[test]
+    fn test_resource_filter() {
+        assert_eq!(filter_for(""), Some(vec![]));
+
+        let promoters = r#"
+[[promoter]]
+[promoter.resources.foo]
+start = ["foo.service"]
+[[promoter]]
+[promoter.resources.bar]
+start = ["bar.service"]
+"#;
+        assert_eq!(
+            filter_for(promoters),
+            Some(vec!["bar".to_string(), "foo".to_string()])
+        );
+
+        let umh = r#"
+[[umh]]
+[[umh.resource]]
+command = "true"
+resource-name = "baz"
+[[umh.device]]
+command = "true"
+resource-name = { operator = "Equals", value = "foo" }
+"#;
+        assert_eq!(
+            filter_for(&format!("{}{}", promoters, umh)),
+            Some(vec![
+                "bar".to_string(),
+                "baz".to_string(),
+                "foo".to_string()
+            ])
+        );
+
+        // rules that might match any resource
+        let umh_any = r#"
+[[umh]]
+[[umh.connection]]
+command = "true"
+"#;
+        assert_eq!(filter_for(&format!("{}{}", promoters, umh_any)), None);
+        let umh_not = r#"
+[[umh]]
+[[umh.peerdevice]]
+command = "true"
+resource-name = { operator = "NotEquals", value = "foo" }
+"#;
+        assert_eq!(filter_for(&format!("{}{}", promoters, umh_not)), None);
+
+        // plugins that need to see everything
+        for all in &["[[prometheus]]", "[[agentx]]", "[[debugger]]"] {
+            assert_eq!(filter_for(&format!("{}\n{}", all, promoters)), None);
+        }
+    }
+
+    #[test]
+    fn test_inventory() {
+        let cfg: Config = toml::from_str(
+            r#"
+[[debugger]]
+[[umh]]
+[[umh.resource]]
+command = "true"
+resource-name = "foo"
+"#,
+        )
+        .expect("cfg must parse");
+        let mut started = HashMap::new();
+        for cfg in [
+            PluginCfg::Debugger(cfg.plugins.debugger[0].clone()),
+            PluginCfg::UMH(cfg.plugins.umh[0].clone()),
+        ] {
+            let (tx, _) = crossbeam_channel::unbounded();
+            let handle = thread::spawn(|| Ok(()));
+            let ptype = cfg.plugin_type();
+            started.insert(
+                cfg,
+                PluginStarted {
+                    tx,
+                    handle,
+                    new: true,
+                    ptype,
+                },
+            );
+        }
+
+        record_inventory(&started);
+        assert_eq!(
+            inventory().into_iter().collect::<Vec<_>>(),
+            vec![
+                ("debugger".to_string(), "debugger".to_string()),
+                ("umh".to_string(), "umh[foo]".to_string()),
+            ]
+        );
+
+        // e.g., a disabled snippet
+        started.retain(|cfg, _| cfg.type_name() != "umh");
+        record_inventory(&started);
+        assert_eq!(inventory().len(), 1);
+    }
 
     #[test]
     fn test_panic_error() {
@@ -309,4 +545,67 @@ mod tests {
             "plugin panicked: some String panic: 2"
         );
     }
+
+    #[test]
+    fn test_log_context() {
+        let contexts = |cfg: &str| -> Vec<String> {
+            let cfg: Config = toml::from_str(cfg).expect("cfg must parse");
+            let mut contexts = Vec::new();
+            for p in cfg.plugins.promoter {
+                contexts.push(PluginCfg::Promoter(p).log_context());
+            }
+            for p in cfg.plugins.umh {
+                contexts.push(PluginCfg::UMH(p).log_context());
+            }
+            for p in cfg.plugins.prometheus {
+                contexts.push(PluginCfg::Prometheus(p).log_context());
+            }
+            for p in cfg.plugins.debugger {
+                contexts.push(PluginCfg::Debugger(p).log_context());
+            }
+            contexts
+        };
+
+        let cfg = r#"
+[[promoter]]
+[promoter.resources.foo]
+start = ["a.service"]
+[promoter.resources.bar]
+start = ["b.service"]
+
+[[promoter]]
+
+[[umh]]
+[[umh.resource]]
+command = "true"
+resource-name = "foo"
+
+[[umh]]
+[[umh.resource]]
+command = "true"
+
+[[prometheus]]
+address = "127.0.0.1:9942"
+
+[[debugger]]
+"#;
+        assert_eq!(
+            contexts(cfg),
+            vec![
+                "promoter[bar,foo]",
+                "promoter",
+                "umh[foo]",
+                "umh",
+                "prometheus[127.0.0.1:9942]",
+                "debugger",
+            ]
+        );
+
+        assert_eq!(set_log_context(Some("foo".to_string())), None);
+        assert_eq!(
+            with_log_context(|c| c.map(String::from)),
+            Some("foo".to_string())
+        );
+        assert_eq!(set_log_context(None), Some("foo".to_string()));
+    }
 }
@@ -1,8 +1,12 @@
 use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
+use std::fmt;
+use std::io;
 use std::io::{Read, Write};
 use std::net::{Shutdown, TcpStream};
 use std::ops::Bound;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
@@ -15,8 +19,10 @@ use log::{debug, error, info, trace, warn};
 use serde::{Deserialize, Serialize};
 
 use crate::drbd;
-use crate::drbd::{DiskState, EventType, PluginUpdate, ReplicationState, Resource};
+use crate::drbd::{DiskState, EventObject, EventType, PluginUpdate, ReplicationState, Resource};
 use crate::plugin::PluginCfg;
+use crate::runner::SystemRunner;
+use crate::utils;
 
 static TERMINATE: AtomicBool = AtomicBool::new(false);
 const OIDPREFIX: [u32; 7] = [1, 3, 6, 1, 4, 1, 23302]; // enterprise + LINBIT
@@ -24,10 +30,111 @@ const OIDPREFIX: [u32; 7] = [1, 3, 6, 1, 4, 1, 23302]; // enterprise + LINBIT
 pub struct AgentX {
     cfg: AgentXConfig,
     metrics: Arc<Mutex<Metrics>>,
-    stream: Arc<RwLock<TcpStream>>,
+    stream: Arc<RwLock<Stream>>,
     thread_handle: Option<thread::JoinHandle<Result<()>>>,
 }
 
+// the master agent is either reachable via TCP or via a Unix socket.
+// all operations take &self, as reads happen under a shared lock, while the socket gets shut down on drop
+trait AgentXStream: Send + Sync {
+    fn read_exact(&self, buf: &mut [u8]) -> io::Result<()>;
+    fn write_all(&self, buf: &[u8]) -> io::Result<()>;
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+type Stream = Box<dyn AgentXStream>;
+
+impl AgentXStream for TcpStream {
+    fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        let mut s: &TcpStream = self;
+        Read::read_exact(&mut s, buf)
+    }
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let mut s: &TcpStream = self;
+        Write::write_all(&mut s, buf)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl AgentXStream for UnixStream {
+    fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        let mut s: &UnixStream = self;
+        Read::read_exact(&mut s, buf)
+    }
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let mut s: &UnixStream = self;
+        Write::write_all(&mut s, buf)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    // accepts the forms net-snmp uses for agentXSocket, e.g., "tcp:localhost:705" and "unix:/var/agentx/master",
+    // but also plain "localhost:705" and "/var/agentx/master"
+    fn parse(address: &str) -> Result<Self> {
+        let address = address.trim();
+        if let Some(path) = address.strip_prefix("unix:") {
+            return Self::parse_unix(path);
+        } else if address.starts_with('/') {
+            return Self::parse_unix(address);
+        }
+
+        let address = address.strip_prefix("tcp:").unwrap_or(address);
+        match address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Address::Tcp(address.to_string()))
+            }
+            _ => Err(anyhow::anyhow!(
+                "AgentX address '{}' is neither 'host:port' nor an absolute path to a Unix socket",
+                address
+            )),
+        }
+    }
+
+    fn parse_unix(path: &str) -> Result<Self> {
+        if !path.starts_with('/') {
+            return Err(anyhow::anyhow!(
+                "AgentX Unix socket '{}' has to be an absolute path",
+                path
+            ));
+        }
+        Ok(Address::Unix(PathBuf::from(path)))
+    }
+
+    // keepalive is (idle, interval), only used for TCP
+    fn connect(&self, keepalive: Option<(time::Duration, time::Duration)>) -> Result<Stream> {
+        Ok(match self {
+            Address::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                if let Some((idle, interval)) = keepalive {
+                    utils::set_tcp_keepalive(&stream, idle, interval)?;
+                }
+                Box::new(stream)
+            }
+            Address::Unix(path) => Box::new(UnixStream::connect(path)?),
+        })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "tcp:{}", address),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
 impl AgentX {
     pub fn new(cfg: AgentXConfig) -> Result<Self> {
         let cache_max = time::Duration::from_secs(cfg.cache_max);
@@ -37,21 +144,28 @@ impl AgentX {
             cfg.peer_states,
         )));
 
-        debug!("new: connecting to snmp daemon on address {}", cfg.address);
-        let stream = TcpStream::connect(&cfg.address).context(format!(
+        let address = Address::parse(&cfg.address)?;
+        let keepalive = cfg.keepalive();
+        debug!("new: connecting to snmp daemon on address {}", address);
+        let stream = address.connect(keepalive).context(format!(
             "Failed to connect to snmp daemon on address {}",
-            cfg.address
+            address
         ))?;
         let stream = Arc::new(RwLock::new(stream));
 
-        debug!("new: starting agentx tcp handler");
+        debug!("new: starting agentx handler");
         let thread_handle = {
             let stream_clone = stream.clone();
             let metrics_clone = metrics.clone();
-            let cfg = cfg.clone();
             let agent_timeout = time::Duration::from_secs(cfg.agent_timeout);
             thread::spawn(move || {
-                agentx_handler(stream_clone, &metrics_clone, &cfg.address, agent_timeout)
+                agentx_handler(
+                    stream_clone,
+                    &metrics_clone,
+                    &address,
+                    keepalive,
+                    agent_timeout,
+                )
             })
         };
 
@@ -69,17 +183,18 @@ impl super::Plugin for AgentX {
         trace!("run: start");
         for r in rx {
             match r.as_ref() {
-                PluginUpdate::ResourceOnly(EventType::Exists, u)
-                | PluginUpdate::ResourceOnly(EventType::Create, u)
-                | PluginUpdate::ResourceOnly(EventType::Change, u) => match self.metrics.lock() {
-                    Ok(mut m) => m.update(u),
-                    Err(e) => {
-                        error!("run: could not lock metrics: {}", e);
-                        return Err(anyhow::anyhow!("Tried accessing a poisoned lock"));
+                PluginUpdate::ResourceOnly(EventType::Destroy, EventObject::Resource, u) => {
+                    match self.metrics.lock() {
+                        Ok(mut m) => m.delete(&u.name),
+                        Err(e) => {
+                            error!("run: could not lock metrics: {}", e);
+                            return Err(anyhow::anyhow!("Tried accessing a poisoned lock"));
+                        }
                     }
-                },
-                PluginUpdate::ResourceOnly(EventType::Destroy, u) => match self.metrics.lock() {
-                    Ok(mut m) => m.delete(&u.name),
+                }
+                // the resource still exists if only one of its objects got destroyed
+                PluginUpdate::ResourceOnly(_, _, u) => match self.metrics.lock() {
+                    Ok(mut m) => m.update(u),
                     Err(e) => {
                         error!("run: could not lock metrics: {}", e);
                         return Err(anyhow::anyhow!("Tried accessing a poisoned lock"));
@@ -113,7 +228,7 @@ impl Drop for AgentX {
             loop {
                 {
                     let s = self.stream.read().unwrap();
-                    let _ = s.shutdown(Shutdown::Both);
+                    let _ = s.shutdown();
                 }
                 if !TERMINATE.load(Ordering::Relaxed) {
                     // handler reset it
@@ -134,7 +249,7 @@ impl Drop for AgentX {
 }
 
 fn agentx_handler_process_loop(
-    stream: &Arc<RwLock<TcpStream>>,
+    stream: &Arc<RwLock<Stream>>,
     metrics: &Arc<Mutex<Metrics>>,
     agent_timeout: time::Duration,
 ) -> Result<()> {
@@ -162,11 +277,13 @@ fn agentx_handler_process_loop(
         let (ty, bytes) = rx(stream)?;
         trace!("agentx_handler_process_loop:main: got request '{:?}'", ty);
 
-        // net-snmpd the defacto standard unfortunately does not implement GetBulk for agentx
+        // net-snmpd the defacto standard unfortunately does not forward GetBulk to agentx subagents
         // snmpbulk* still helps as it avoids all the "external" network back and forth, but even the bulk variants then degenerate to agentx GetNext
+        // other master agents might send GetBulk, which saves a lot of round trips for large tables
         let mut resp = match ty {
             pdu::Type::Get => get(&bytes, metrics)?,
             pdu::Type::GetNext => get_next(&bytes, metrics)?,
+            pdu::Type::GetBulk => get_bulk(&bytes, metrics)?,
             _ => {
                 return Err(anyhow::anyhow!(
                     "agentx_handler: main: header.ty={:?} unhandled",
@@ -182,9 +299,10 @@ fn agentx_handler_process_loop(
 // this thread never tries to continue until the main thread told it to terminate
 // for thread sync considerations please check AgentX::Drop
 fn agentx_handler(
-    stream: Arc<RwLock<TcpStream>>,
+    stream: Arc<RwLock<Stream>>,
     metrics: &Arc<Mutex<Metrics>>,
-    address: &str,
+    address: &Address,
+    keepalive: Option<(time::Duration, time::Duration)>,
     agent_timeout: time::Duration,
 ) -> Result<()> {
     let mut initially_connected = true;
@@ -202,11 +320,11 @@ fn agentx_handler(
                 let mut s = match stream.write() {
                     Ok(s) => s,
                     Err(e) => {
-                        warn!("agentx_handler: could not lock tcp stream: '{}'", e);
+                        warn!("agentx_handler: could not lock stream: '{}'", e);
                         continue;
                     }
                 };
-                *s = match TcpStream::connect(address) {
+                *s = match address.connect(keepalive) {
                     Ok(s) => s,
                     Err(e) => {
                         warn!("agentx_handler: could not connect stream '{}'", e);
@@ -262,33 +380,55 @@ fn get_next(bytes: &[u8], metrics: &Arc<Mutex<Metrics>>) -> Result<pdu::Response
     Ok(resp)
 }
 
+fn get_bulk(bytes: &[u8], metrics: &Arc<Mutex<Metrics>>) -> Result<pdu::Response> {
+    let pkg = pdu::GetBulk::from_bytes(bytes)?;
+    trace!(
+        "getbulk: sid: {}, tid: {}, non_repeaters: {}, max_repetitions: {}",
+        pkg.header.session_id,
+        pkg.header.transaction_id,
+        pkg.non_repeaters,
+        pkg.max_repetitions
+    );
+    let mut resp = pdu::Response::from_header(&pkg.header);
+    let vb = metrics
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Tried accessing a poisoned lock"))?
+        .get_bulk(
+            &pkg.sr,
+            pkg.non_repeaters as usize,
+            pkg.max_repetitions as usize,
+        );
+    trace!("getbulk: vbs: {:?}", vb);
+    resp.vb = Some(vb);
+
+    Ok(resp)
+}
+
 // for administrative messages where we send stuff and get a response pdu
-fn txrx(stream: &Arc<RwLock<TcpStream>>, bytes: &[u8]) -> Result<pdu::Response> {
+fn txrx(stream: &Arc<RwLock<Stream>>, bytes: &[u8]) -> Result<pdu::Response> {
     tx(stream, bytes)?;
     let (_, buf) = rx(stream)?;
     Ok(pdu::Response::from_bytes(&buf)?)
 }
 
-fn tx(stream: &Arc<RwLock<TcpStream>>, bytes: &[u8]) -> Result<()> {
-    let lock = match stream.read() {
+fn tx(stream: &Arc<RwLock<Stream>>, bytes: &[u8]) -> Result<()> {
+    let s = match stream.read() {
         Ok(l) => l,
         Err(_) => return Err(anyhow::anyhow!("txrx: could not lock stream")),
     };
-    let mut s: &TcpStream = &lock;
     s.write_all(bytes)?;
 
     Ok(())
 }
 
-fn rx(stream: &Arc<RwLock<TcpStream>>) -> Result<(pdu::Type, Vec<u8>)> {
+fn rx(stream: &Arc<RwLock<Stream>>) -> Result<(pdu::Type, Vec<u8>)> {
     let mut buf = vec![0u8; 20];
 
     // hold it till the end of the function, last s.read_exact() needs it anyways
-    let lock = match stream.read() {
+    let s = match stream.read() {
         Ok(s) => s,
         Err(_) => return Err(anyhow::anyhow!("rx: could not lock stream")),
     };
-    let mut s: &TcpStream = &lock;
     s.read_exact(&mut buf)?;
     let header = pdu::Header::from_bytes(&buf)?;
     buf.resize(20 + header.payload_length as usize, 0);
@@ -313,7 +453,7 @@ impl Metrics {
     fn new(cache_max: time::Duration, burst_max: time::Duration, peer_states: bool) -> Self {
         let now = time::Instant::now();
         let one_sec = time::Duration::from_secs(1);
-        let drbd_version = drbd::get_drbd_versions().unwrap_or_default();
+        let drbd_version = drbd::get_drbd_versions(&SystemRunner).unwrap_or_default();
         Self {
             mib: BTreeMap::new(),
             resources: HashMap::new(),
@@ -379,6 +519,10 @@ impl Metrics {
         resource_prefix.extend(&[1, 2, 1]);
         let resource_prefix = resource_prefix;
 
+        let mut connection_prefix = OIDPREFIX.to_vec();
+        connection_prefix.extend(&[1, 3, 1]);
+        let connection_prefix = connection_prefix;
+
         for (name, resource) in &self.resources {
             let mut vol_to_minor = HashMap::new();
 
@@ -504,6 +648,49 @@ impl Metrics {
                 );
             }
 
+            // connections are per resource, but like the resource columns in the device table we
+            // repeat them for every minor of the resource
+            for c in &resource.connections {
+                let peer = c.peer_node_id as u32;
+                for minor in vol_to_minor.values() {
+                    let minor = *minor as u32;
+                    let id =
+                        |col: MIBConnection| gen_id(&connection_prefix, &[col as u32, minor, peer]);
+
+                    self.mib.insert(
+                        id(MIBConnection::PeerNodeId),
+                        encodings::Value::Integer(c.peer_node_id),
+                    );
+                    self.mib.insert(
+                        id(MIBConnection::Name),
+                        encodings::Value::OctetString(encodings::OctetString(
+                            c.conn_name.to_string(),
+                        )),
+                    );
+                    self.mib.insert(
+                        id(MIBConnection::State),
+                        encodings::Value::OctetString(encodings::OctetString(
+                            c.connection.to_string(),
+                        )),
+                    );
+                    self.mib.insert(
+                        id(MIBConnection::Congested),
+                        encodings::Value::Integer(bool_to_truth(c.congested)),
+                    );
+                    // in flight data is bounded by the buffers, we can cap these...
+                    let ap = u32::try_from(sectors_to_bytes(c.ap_in_flight)).unwrap_or(u32::MAX);
+                    self.mib.insert(
+                        id(MIBConnection::ApInFlight),
+                        encodings::Value::Gauge32(ap), // fine, mib type is Unsigned32
+                    );
+                    let rs = u32::try_from(sectors_to_bytes(c.rs_in_flight)).unwrap_or(u32::MAX);
+                    self.mib.insert(
+                        id(MIBConnection::RsInFlight),
+                        encodings::Value::Gauge32(rs), // fine, mib type is Unsigned32
+                    );
+                }
+            }
+
             if self.peer_states {
                 let mut pd_states = HashMap::new();
                 // init 0 values
@@ -577,7 +764,8 @@ impl Metrics {
         encodings::VarBindList(vbs)
     }
 
-    fn get_next(&mut self, sr: &encodings::SearchRangeList) -> encodings::VarBindList {
+    // regenerates the MIB if the burst cache expired and rearms it, returns the rearm time
+    fn burst_generate_mib(&mut self) -> time::Instant {
         let now = time::Instant::now();
         let burst_expired = now - self.burst_last > self.burst_max;
         trace!("burst_expired: {}", burst_expired);
@@ -586,33 +774,94 @@ impl Metrics {
         }
         // rearm:
         self.burst_last = now;
+        now
+    }
 
-        let mut vbs = Vec::new();
+    // the walk is over, next GetNext/GetBulk has to regenerate the MIB (if dirty)
+    fn burst_expire(&mut self, now: time::Instant) {
+        self.burst_last = now - self.burst_max - time::Duration::from_secs(1);
+    }
 
-        let mut end_of_mibs = 0;
-        for s in sr {
-            trace!("get_next: s.start: {:?}", s.start);
-            trace!("get_next: s.end: {:?}", s.end);
-
-            let vb = if s.start.include == 0 {
-                let iter = self
-                    .mib
-                    .range((Bound::Excluded(&s.start), Bound::Unbounded));
-                generate_vb(iter, &s.start, &s.end)
-            } else {
-                let iter = self
-                    .mib
-                    .range((Bound::Included(&s.start), Bound::Unbounded));
-                generate_vb(iter, &s.start, &s.end)
-            };
-            if vb.data == encodings::Value::EndOfMibView {
-                end_of_mibs += 1;
+    fn next_vb(&self, start: &encodings::ID, end: &encodings::ID) -> encodings::VarBind {
+        trace!("next_vb: start: {:?}", start);
+        trace!("next_vb: end: {:?}", end);
+
+        if start.include == 0 {
+            let iter = self.mib.range((Bound::Excluded(start), Bound::Unbounded));
+            generate_vb(iter, start, end)
+        } else {
+            let iter = self.mib.range((Bound::Included(start), Bound::Unbounded));
+            generate_vb(iter, start, end)
+        }
+    }
+
+    fn get_next(&mut self, sr: &encodings::SearchRangeList) -> encodings::VarBindList {
+        let now = self.burst_generate_mib();
+
+        let vbs: Vec<_> = sr
+            .into_iter()
+            .map(|s| self.next_vb(&s.start, &s.end))
+            .collect();
+
+        if vbs
+            .iter()
+            .all(|vb| vb.data == encodings::Value::EndOfMibView)
+        {
+            self.burst_expire(now);
+        }
+
+        encodings::VarBindList(vbs)
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc2741#section-7.2.3.3
+    fn get_bulk(
+        &mut self,
+        sr: &encodings::SearchRangeList,
+        non_repeaters: usize,
+        max_repetitions: usize,
+    ) -> encodings::VarBindList {
+        let now = self.burst_generate_mib();
+        let non_repeaters = non_repeaters.min(sr.len());
+
+        // non-repeaters are handled exactly like GetNext
+        let mut vbs: Vec<_> = sr
+            .into_iter()
+            .take(non_repeaters)
+            .map(|s| self.next_vb(&s.start, &s.end))
+            .collect();
+        let mut end_of_mib = vbs
+            .iter()
+            .all(|vb| vb.data == encodings::Value::EndOfMibView);
+
+        // repeaters are interleaved, every repetition continues after the OID of the last one
+        let mut repeaters: Vec<_> = sr
+            .into_iter()
+            .skip(non_repeaters)
+            .map(|s| (s.start.clone(), &s.end))
+            .collect();
+        for _ in 0..max_repetitions {
+            if repeaters.is_empty() {
+                break;
+            }
+            let mut all_ended = true;
+            for (start, end) in repeaters.iter_mut() {
+                let vb = self.next_vb(start, end);
+                if vb.data != encodings::Value::EndOfMibView {
+                    all_ended = false;
+                }
+                *start = vb.name.clone();
+                start.include = 0;
+                vbs.push(vb);
+            }
+            end_of_mib = all_ended;
+            // the subagent may stop early if every repeater reached the end
+            if all_ended {
+                break;
             }
-            vbs.push(vb);
         }
 
-        if end_of_mibs == sr.len() {
-            self.burst_last = now - self.burst_max - time::Duration::from_secs(1);
+        if end_of_mib {
+            self.burst_expire(now);
         }
 
         encodings::VarBindList(vbs)
@@ -683,6 +932,17 @@ enum MIB {
     PeerReplBehind,
 }
 
+// columns of drbdConnEntry (OIDPREFIX.1.3.1.<column>.<minor>.<peer_node_id>), the objects in the
+// MIB file are prefixed with "Conn" (e.g., ConnCongested)
+enum MIBConnection {
+    PeerNodeId = 1, // INTEGER
+    Name,           // DisplayString
+    State,          // DisplayString
+    Congested,      // TruthValue
+    ApInFlight,     // Unsigned32, bytes of application requests in flight
+    RsInFlight,     // Unsigned32, bytes of resync requests in flight
+}
+
 impl MIB {
     fn from_disk_state(d: &DiskState) -> Self {
         match d {
@@ -719,6 +979,10 @@ impl MIB {
     }
 }
 
+fn sectors_to_bytes(sectors: u64) -> u64 {
+    sectors.saturating_mul(512)
+}
+
 fn bool_to_truth(b: bool) -> i32 {
     match b {
         true => 1,
@@ -773,6 +1037,22 @@ pub struct AgentXConfig {
     pub agent_timeout: u64,
     #[serde(default = "default_peer_states")]
     pub peer_states: bool,
+    #[serde(default = "default_tcp_keepalive_idle_secs")]
+    pub tcp_keepalive_idle_secs: u64,
+    #[serde(default = "default_tcp_keepalive_interval_secs")]
+    pub tcp_keepalive_interval_secs: u64,
+}
+
+impl AgentXConfig {
+    /// TCP keepalive (idle, interval), None if disabled
+    fn keepalive(&self) -> Option<(time::Duration, time::Duration)> {
+        (self.tcp_keepalive_idle_secs > 0).then(|| {
+            (
+                time::Duration::from_secs(self.tcp_keepalive_idle_secs),
+                time::Duration::from_secs(self.tcp_keepalive_interval_secs),
+            )
+        })
+    }
 }
 
 fn default_address() -> String {
@@ -790,3 +1070,235 @@ fn default_agent_timeout() -> u64 {
 fn default_peer_states() -> bool {
     true
 }
+
+fn default_tcp_keepalive_idle_secs() -> u64 {
+    60
+}
+
+fn default_tcp_keepalive_interval_secs() -> u64 {
+    10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc;
+
+    #[test]
+    fn address_parse() {
+        let tcp = |a: &str| Address::Tcp(a.to_string());
+        let unix = |p: &str| Address::Unix(PathBuf::from(p));
+
+        assert_eq!(
+            Address::parse("localhost:705").unwrap(),
+            tcp("localhost:705")
+        );
+        assert_eq!(
+            Address::parse("tcp:127.0.0.1:705").unwrap(),
+            tcp("127.0.0.1:705")
+        );
+        assert_eq!(Address::parse("[::1]:705").unwrap(), tcp("[::1]:705"));
+        assert_eq!(
+            Address::parse("/var/agentx/master").unwrap(),
+            unix("/var/agentx/master")
+        );
+        assert_eq!(
+            Address::parse("unix:/var/agentx/master").unwrap(),
+            unix("/var/agentx/master")
+        );
+
+        for invalid in [
+            "localhost",
+            "localhost:snmp",
+            ":705",
+            "unix:var/agentx/master",
+            "",
+        ] {
+            assert!(
+                Address::parse(invalid).is_err(),
+                "'{}' should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn tcp_keepalive_cfg() {
+        let cfg: AgentXConfig = toml::from_str("").expect("cfg must parse");
+        assert_eq!(
+            cfg.keepalive(),
+            Some((time::Duration::from_secs(60), time::Duration::from_secs(10)))
+        );
+
+        let cfg: AgentXConfig =
+            toml::from_str("tcp-keepalive-idle-secs = 0").expect("cfg must parse");
+        assert_eq!(cfg.keepalive(), None);
+    }
+
+    #[test]
+    fn unix_socket_handshake() {
+        let dir = tempfile::tempdir().expect("tempdir must work");
+        let path = dir.path().join("master");
+        let listener = UnixListener::bind(&path).expect("bind must work");
+
+        // mock master agent that answers the Open and Register PDUs
+        let (seen_tx, seen_rx) = mpsc::channel();
+        thread::spawn(move || -> Result<()> {
+            let (stream, _) = listener.accept()?;
+            let stream: Arc<RwLock<Stream>> = Arc::new(RwLock::new(Box::new(stream)));
+            for _ in 0..2 {
+                let (ty, bytes) = rx(&stream)?;
+                let header = pdu::Header::from_bytes(&bytes)?;
+                tx(&stream, &pdu::Response::from_header(&header).to_bytes()?)?;
+                seen_tx.send(ty)?;
+            }
+            // keep the connection open until the plugin shuts it down
+            let _ = rx(&stream);
+            Ok(())
+        });
+
+        let agentx = AgentX::new(AgentXConfig {
+            address: format!("unix:{}", path.display()),
+            agent_timeout: 60,
+            ..Default::default()
+        })
+        .expect("must connect to the mock master agent");
+
+        let timeout = time::Duration::from_secs(5);
+        assert_eq!(seen_rx.recv_timeout(timeout), Ok(pdu::Type::Open));
+        assert_eq!(seen_rx.recv_timeout(timeout), Ok(pdu::Type::Register));
+
+        drop(agentx);
+    }
+
+    #[test]
+    fn bulk_walk() {
+        let mut metrics = Metrics::new(time::Duration::ZERO, time::Duration::ZERO, true);
+        for (name, minor) in [("foo", 1000), ("bar", 1001)] {
+            metrics.update(&Resource {
+                name: name.to_string(),
+                devices: vec![drbd::Device {
+                    volume: 0,
+                    minor,
+                    ..Default::default()
+                }],
+                connections: vec![drbd::Connection {
+                    peer_node_id: 1,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            });
+        }
+        let prefix = gen_id(&OIDPREFIX, &[]);
+        let range = |start: &encodings::ID| {
+            encodings::SearchRangeList(vec![encodings::SearchRange::new(
+                start.clone(),
+                Default::default(),
+            )])
+        };
+
+        // what snmpwalk does: a series of GetNext requests
+        let mut walk = Vec::new();
+        let mut start = prefix.clone();
+        loop {
+            let vb = metrics.get_next(&range(&start)).0.remove(0);
+            if vb.data == encodings::Value::EndOfMibView {
+                break;
+            }
+            start = vb.name.clone();
+            walk.push(vb);
+        }
+        assert!(walk.len() > 100);
+
+        // the same via GetBulk, in chunks to test continuation
+        let mut bulk = Vec::new();
+        let mut start = prefix.clone();
+        'outer: loop {
+            for vb in metrics.get_bulk(&range(&start), 0, 7).0 {
+                if vb.data == encodings::Value::EndOfMibView {
+                    break 'outer;
+                }
+                start = vb.name.clone();
+                bulk.push(vb);
+            }
+        }
+        assert_eq!(walk, bulk);
+
+        // a non-repeater is answered once, the repeaters are interleaved
+        let minor = gen_id(&OIDPREFIX, &[1, 2, 1, MIB::Minor as u32]);
+        let name = gen_id(&OIDPREFIX, &[1, 2, 1, MIB::ResourceName as u32]);
+        let sr = encodings::SearchRangeList(vec![
+            encodings::SearchRange::new(prefix.clone(), Default::default()),
+            encodings::SearchRange::new(minor, Default::default()),
+            encodings::SearchRange::new(name, Default::default()),
+        ]);
+        let names: Vec<_> = metrics
+            .get_bulk(&sr, 1, 2)
+            .0
+            .into_iter()
+            .map(|vb| vb.name.to_string())
+            .collect();
+        let oid = |ext: &[u32]| gen_id(&OIDPREFIX, ext).to_string();
+        assert_eq!(
+            names,
+            vec![
+                oid(&[1, 1, 1]),
+                oid(&[1, 2, 1, MIB::Minor as u32, 1000]),
+                oid(&[1, 2, 1, MIB::ResourceName as u32, 1000]),
+                oid(&[1, 2, 1, MIB::Minor as u32, 1001]),
+                oid(&[1, 2, 1, MIB::ResourceName as u32, 1001]),
+            ]
+        );
+    }
+
+    #[test]
+    fn connection_table() {
+        let mut metrics = Metrics::new(time::Duration::ZERO, time::Duration::ZERO, false);
+        metrics.update(&Resource {
+            name: "foo".to_string(),
+            devices: vec![drbd::Device {
+                volume: 0,
+                minor: 1000,
+                ..Default::default()
+            }],
+            connections: vec![drbd::Connection {
+                peer_node_id: 2,
+                conn_name: "bar".to_string(),
+                congested: true,
+                ap_in_flight: 8,
+                rs_in_flight: u64::MAX,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        metrics.generate_mib();
+
+        let mut prefix = OIDPREFIX.to_vec();
+        prefix.extend(&[1, 3, 1]);
+        let get = |col: MIBConnection| metrics.mib.get(&gen_id(&prefix, &[col as u32, 1000, 2]));
+
+        assert_eq!(
+            get(MIBConnection::PeerNodeId),
+            Some(&encodings::Value::Integer(2))
+        );
+        assert_eq!(
+            get(MIBConnection::Name),
+            Some(&encodings::Value::OctetString(encodings::OctetString(
+                "bar".to_string()
+            )))
+        );
+        assert_eq!(
+            get(MIBConnection::Congested),
+            Some(&encodings::Value::Integer(bool_to_truth(true)))
+        );
+        assert_eq!(
+            get(MIBConnection::ApInFlight),
+            Some(&encodings::Value::Gauge32(4096))
+        );
+        assert_eq!(
+            get(MIBConnection::RsInFlight),
+            Some(&encodings::Value::Gauge32(u32::MAX))
+        );
+    }
+}
@@ -1,61 +1,107 @@
 use std::collections::HashMap;
 use std::fmt::Write;
-use std::io::Read;
+use std::fs::File;
 use std::io::Write as IOWrite;
+use std::io::{BufReader, BufWriter, Read};
 use std::net::{TcpListener, TcpStream};
 use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use log::{debug, error, trace, warn};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use serde::{Deserialize, Serialize};
 
-use crate::config::LocalAddress;
+use crate::config::{self, LocalAddress};
 use crate::drbd;
-use crate::drbd::{ConnectionState, DiskState, EventType, PluginUpdate, Resource, Role};
-use crate::plugin::PluginCfg;
+use crate::drbd::{
+    ConnectionState, DiskState, EventObject, EventType, PluginUpdate, ReplicationState, Resource,
+    Role,
+};
+use crate::events;
+use crate::plugin::{self, promoter, umh, PluginCfg};
+use crate::runner::SystemRunner;
+use crate::utils;
 
 pub struct Prometheus {
     cfg: PrometheusConfig,
-    listener: TcpListener,
-    thread_handle: Option<thread::JoinHandle<Result<()>>>,
+    listeners: Vec<TcpListener>,
+    thread_handles: Vec<thread::JoinHandle<Result<()>>>,
     metrics: Arc<Mutex<Metrics>>,
 }
 
 impl Prometheus {
     pub fn new(cfg: PrometheusConfig) -> Result<Self> {
-        let metrics = Arc::new(Mutex::new(Metrics::new(cfg.enums)));
+        let mut metrics = Metrics::new(cfg.enums);
+        metrics.only_established_peers = cfg.only_established_peers;
+        let metrics = Arc::new(Mutex::new(metrics));
+
+        let tls = match (&cfg.tls_cert, &cfg.tls_key) {
+            (Some(cert), Some(key)) => Some(tls_config(cert, key)?),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "'tls-cert' and 'tls-key' have to be set together"
+                ))
+            }
+        };
+
+        if cfg.max_connections == 0 {
+            return Err(anyhow::anyhow!("'max-connections' has to be at least 1"));
+        }
+        // without a timeout a client that never sends would block shutdown and reload
+        if cfg.timeout_secs == 0 {
+            return Err(anyhow::anyhow!("'timeout-secs' has to be at least 1"));
+        }
 
-        debug!("new: listening for connections on address {}", cfg.address);
-        let listener = TcpListener::bind(&cfg.address)
-            .context(format!("Failed to bind to {}", cfg.address))?;
+        if !cfg.addresses.is_empty() && cfg.address != default_address() {
+            warn!("new: 'address' is ignored if 'addresses' is set");
+        }
+
+        // bind all of them before starting any handler, so that a failing one does not leave handlers behind
+        let mut listeners = Vec::new();
+        for address in cfg.listen_addresses() {
+            debug!("new: listening for connections on address {}", address);
+            let listener =
+                TcpListener::bind(address).context(format!("Failed to bind to {}", address))?;
+            listeners.push(listener);
+        }
 
-        debug!("new: starting tcp listener");
-        let thread_handle = {
+        debug!("new: starting tcp listeners");
+        let mut thread_handles = Vec::new();
+        for listener in &listeners {
             let listener_clone = listener.try_clone().context("failed to clone socket")?;
             let metrics_clone = metrics.clone();
-            thread::spawn(move || tcp_handler(listener_clone, &metrics_clone))
-        };
+            let cfg_clone = cfg.clone();
+            let tls = tls.clone();
+            thread_handles.push(thread::spawn(move || {
+                tcp_handler(listener_clone, &metrics_clone, &cfg_clone, tls)
+            }));
+        }
 
         Ok(Prometheus {
             cfg,
-            listener,
+            listeners,
             metrics,
-            thread_handle: Some(thread_handle),
+            thread_handles,
         })
     }
 }
 
 impl Drop for Prometheus {
     fn drop(&mut self) {
-        unsafe {
-            // This is safe: self.listener is a separate FD from the one used by the HTTP handler.
-            // This means there is no chance for the FD to be already closed.
-            libc::shutdown(self.listener.as_raw_fd(), libc::SHUT_RD);
+        for listener in &self.listeners {
+            unsafe {
+                // This is safe: the listeners are separate FDs from the ones used by the HTTP handlers.
+                // This means there is no chance for the FD to be already closed.
+                libc::shutdown(listener.as_raw_fd(), libc::SHUT_RD);
+            }
         }
 
-        if let Some(handle) = self.thread_handle.take() {
+        for handle in self.thread_handles.drain(..) {
             trace!("drop: wait for server thread to shut down");
             let res = handle.join();
             trace!("drop: server thread shut down {:?}", res);
@@ -67,24 +113,14 @@ impl super::Plugin for Prometheus {
     fn run(&self, rx: super::PluginReceiver) -> Result<()> {
         trace!("run: start");
         for r in rx {
-            match r.as_ref() {
-                PluginUpdate::ResourceOnly(EventType::Exists, u)
-                | PluginUpdate::ResourceOnly(EventType::Create, u)
-                | PluginUpdate::ResourceOnly(EventType::Change, u) => match self.metrics.lock() {
-                    Ok(mut m) => m.update(u),
+            if let PluginUpdate::ResourceOnly(et, object, u) = r.as_ref() {
+                match self.metrics.lock() {
+                    Ok(mut m) => m.apply(et, *object, u),
                     Err(e) => {
                         error!("run: could not lock metrics: {}", e);
                         return Err(anyhow::anyhow!("Tried accessing a poisoned lock"));
                     }
-                },
-                PluginUpdate::ResourceOnly(EventType::Destroy, u) => match self.metrics.lock() {
-                    Ok(mut m) => m.delete(&u.name),
-                    Err(e) => {
-                        error!("run: could not lock metrics: {}", e);
-                        return Err(anyhow::anyhow!("Tried accessing a poisoned lock"));
-                    }
-                },
-                _ => (),
+                }
             }
         }
 
@@ -98,51 +134,339 @@ impl super::Plugin for Prometheus {
     }
 }
 
-fn tcp_handler(listener: TcpListener, metrics: &Arc<Mutex<Metrics>>) -> Result<()> {
-    for stream in listener.incoming() {
-        let stream = stream.context("closed socket")?;
+fn tls_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = {
+        let mut reader = BufReader::new(
+            File::open(cert).context(format!("Failed to open TLS certificate {:?}", cert))?,
+        );
+        rustls_pemfile::certs(&mut reader)
+            .context(format!("Failed to parse TLS certificate {:?}", cert))?
+    };
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificate found in {:?}", cert));
+    }
 
-        if let Err(e) = handle_connection(stream, metrics) {
-            // warn but continue processing
-            warn!("tcp_handler: could not handle connection: {}", e);
+    let key = {
+        let mut reader =
+            BufReader::new(File::open(key).context(format!("Failed to open TLS key {:?}", key))?);
+        loop {
+            match rustls_pemfile::read_one(&mut reader)
+                .context(format!("Failed to parse TLS key {:?}", key))?
+            {
+                Some(rustls_pemfile::Item::RSAKey(k))
+                | Some(rustls_pemfile::Item::PKCS8Key(k))
+                | Some(rustls_pemfile::Item::ECKey(k)) => break k,
+                Some(_) => continue,
+                None => return Err(anyhow::anyhow!("No private key found in {:?}", key)),
+            }
         }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(rustls::Certificate).collect(),
+            rustls::PrivateKey(key),
+        )
+        .context("Invalid TLS certificate/key")?;
+
+    Ok(Arc::new(config))
+}
+
+fn tcp_handler(
+    listener: TcpListener,
+    metrics: &Arc<Mutex<Metrics>>,
+    cfg: &PrometheusConfig,
+    tls: Option<Arc<ServerConfig>>,
+) -> Result<()> {
+    let tls = tls.as_ref();
+    // connections are handled by a fixed number of workers, accepted connections wait for a free one.
+    // if the listener gets shut down, the sender is dropped, and the scope waits for the workers to finish.
+    thread::scope(|s| {
+        let (tx, rx) = crossbeam_channel::bounded::<TcpStream>(cfg.max_connections);
+        for _ in 0..cfg.max_connections {
+            let rx = rx.clone();
+            s.spawn(move || {
+                for stream in rx {
+                    let result = match tls {
+                        Some(tls) => handle_tls_connection(stream, tls, metrics, cfg),
+                        None => handle_connection(stream, metrics, cfg),
+                    };
+                    if let Err(e) = result {
+                        // warn but continue processing
+                        warn!("tcp_handler: could not handle connection: {}", e);
+                    }
+                }
+            });
+        }
+
+        for stream in listener.incoming() {
+            let stream = stream.context("closed socket")?;
+            // responses are written in pieces, don't let Nagle delay them
+            if let Err(e) = stream.set_nodelay(true) {
+                warn!("tcp_handler: could not set TCP_NODELAY: {}", e);
+            }
+            // don't let clients that never send (or read) occupy a handler forever
+            let timeout = Some(Duration::from_secs(cfg.timeout_secs));
+            if let Err(e) = stream
+                .set_read_timeout(timeout)
+                .and_then(|_| stream.set_write_timeout(timeout))
+            {
+                warn!("tcp_handler: could not set timeouts: {}", e);
+            }
+            tx.send(stream)
+                .map_err(|_| anyhow::anyhow!("all connection handlers exited"))?;
+        }
+
+        Ok(())
+    })
+}
+
+fn handle_tls_connection(
+    mut stream: TcpStream,
+    tls: &Arc<ServerConfig>,
+    metrics: &Arc<Mutex<Metrics>>,
+    cfg: &PrometheusConfig,
+) -> Result<()> {
+    let mut conn = ServerConnection::new(tls.clone())?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)
+            .context("TLS handshake failed")?;
     }
 
+    let mut stream = StreamOwned::new(conn, stream);
+    handle_connection(&mut stream, metrics, cfg)?;
+    stream.conn.send_close_notify();
+    stream.flush()?;
+
     Ok(())
 }
 
-fn handle_connection(mut stream: TcpStream, metrics: &Arc<Mutex<Metrics>>) -> Result<()> {
-    // read request body
-    // we have to, otherwise we will get a connection reset by peer
-    let mut discard = [0u8; 4096];
-    let _ = stream.read(&mut discard)?;
+fn handle_connection<S: Read + IOWrite>(
+    mut stream: S,
+    metrics: &Arc<Mutex<Metrics>>,
+    cfg: &PrometheusConfig,
+) -> Result<()> {
+    // read the request, we have to, otherwise we will get a connection reset by peer
+    let request = read_request(&mut stream)?;
 
-    let content = metrics
-        .lock()
-        .map_err(|_| anyhow::anyhow!("Tried accessing a poisoned lock"))?
-        .get()?;
-    let mut response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: text/plain;version=0.0.4\r\nContent-Length: {}\r\n\r\n",
-        content.len()
-    );
-    response.push_str(&content);
+    // the lock is only held to get a reference to the sections, not while writing to a (slow) client
+    write_response(&mut stream, &request, cfg, || {
+        metrics
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Tried accessing a poisoned lock"))?
+            .sections()
+    })?;
+    stream.flush()?;
+    Ok(())
+}
+
+// scrapers send small requests, more than that is not worth reading
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// Reads the request until the end of its headers (i.e., the first empty line), a request line and
+/// headers might arrive in multiple reads. The read timeout of the stream bounds slow clients.
+fn read_request<R: Read>(stream: &mut R) -> Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !headers_complete(&request) {
+        if request.len() >= MAX_REQUEST_SIZE {
+            return Err(anyhow::anyhow!(
+                "request headers exceed {} bytes",
+                MAX_REQUEST_SIZE
+            ));
+        }
+        let n = stream.read(&mut buf).context("could not read request")?;
+        if n == 0 {
+            // client closed its side, answer what we got
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(request)
+}
+
+fn headers_complete(request: &[u8]) -> bool {
+    request.windows(4).any(|w| w == b"\r\n\r\n") || request.windows(2).any(|w| w == b"\n\n")
+}
+
+// only renders the metrics if they got requested, health checks on other paths should stay cheap
+fn write_response<W, F>(
+    stream: &mut W,
+    request: &[u8],
+    cfg: &PrometheusConfig,
+    metrics: F,
+) -> Result<()>
+where
+    W: IOWrite,
+    F: FnOnce() -> Result<MetricsBody>,
+{
+    let mut headers = String::new();
+    let (status, content_type, content) = match request_line(request) {
+        Some(("GET", path)) if path == cfg.metrics_path => {
+            if authorized(request, &cfg.bearer_token) {
+                ("200 OK", "text/plain;version=0.0.4", metrics()?)
+            } else {
+                headers.push_str("WWW-Authenticate: Bearer\r\n");
+                (
+                    "401 Unauthorized",
+                    "text/plain",
+                    MetricsBody::from("401 unauthorized\n".to_string()),
+                )
+            }
+        }
+        Some(("GET", "/")) => (
+            "200 OK",
+            "text/html",
+            MetricsBody::from(index(&cfg.metrics_path)),
+        ),
+        _ => (
+            "404 Not Found",
+            "text/plain",
+            MetricsBody::from("404 page not found\n".to_string()),
+        ),
+    };
 
-    stream.write_all(response.as_bytes())?;
+    let len = content.len();
+    if len <= cfg.max_body_size {
+        let response = format!(
+            "HTTP/1.1 {}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            headers,
+            content_type,
+            len,
+            content.concat()
+        );
+        stream.write_all(response.as_bytes())?;
+        return Ok(());
+    }
+
+    // too large to be assembled in memory, stream it section by section
+    let mut stream = BufWriter::new(stream);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n{}Content-Type: {}\r\nTransfer-Encoding: chunked\r\n\r\n",
+        status, headers, content_type
+    )?;
+    for section in content.iter().filter(|s| !s.is_empty()) {
+        write!(stream, "{:x}\r\n", section.len())?;
+        stream.write_all(section.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+    }
+    stream.write_all(b"0\r\n\r\n")?;
+    stream.flush()?;
     Ok(())
 }
 
+// rendered metrics, the (large) cached part is shared with the cache and not copied
+struct MetricsBody {
+    cached: Arc<Vec<String>>,
+    uncached: Vec<String>,
+}
+
+impl MetricsBody {
+    fn iter(&self) -> impl Iterator<Item = &String> {
+        self.cached.iter().chain(self.uncached.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.iter().map(String::len).sum()
+    }
+
+    fn concat(&self) -> String {
+        let mut content = String::with_capacity(self.len());
+        self.iter().for_each(|s| content.push_str(s));
+        content
+    }
+}
+
+impl From<String> for MetricsBody {
+    fn from(content: String) -> Self {
+        Self {
+            cached: Arc::new(vec![content]),
+            uncached: Vec::new(),
+        }
+    }
+}
+
+// unnamed UMH rules are labeled by their command, which might contain anything
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// the request has to contain all headers (see read_request), otherwise a token sent late is missed
+fn authorized(request: &[u8], bearer_token: &Option<String>) -> bool {
+    let expected = match bearer_token {
+        Some(token) => token,
+        None => return true,
+    };
+
+    let token = String::from_utf8_lossy(request)
+        .lines()
+        .skip(1) // request line
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| {
+            let (scheme, token) = value.trim().split_once(' ')?;
+            match scheme.eq_ignore_ascii_case("bearer") {
+                true => Some(token.trim().to_string()),
+                false => None,
+            }
+        });
+
+    match token {
+        Some(token) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+        None => false,
+    }
+}
+
+// compares every byte, so that the time it takes does not tell how much of a token was correct
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// returns method and path (without query) of the request line, e.g., "GET /metrics?foo=bar HTTP/1.1"
+fn request_line(request: &[u8]) -> Option<(&str, &str)> {
+    let line = request.split(|&b| b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?;
+    let target = parts.next()?;
+    let path = target.split('?').next()?;
+
+    Some((method, path))
+}
+
+fn index(metrics_path: &str) -> String {
+    format!(
+        "<html>\n<head><title>DRBD Reactor Exporter</title></head>\n<body>\n<h1>DRBD Reactor Exporter</h1>\n<p><a href=\"{}\">Metrics</a></p>\n</body>\n</html>\n",
+        metrics_path
+    )
+}
+
 #[derive(Default)]
 struct Metrics {
     resources: HashMap<String, Resource>,
     dirty: bool,
-    cache: String,
+    cache: Arc<Vec<String>>,
+    regenerated: Option<Instant>,
     enums: bool,
+    only_established_peers: bool,
     drbd_version: drbd::DRBDVersion,
 }
 
 impl Metrics {
     fn new(enums: bool) -> Self {
-        let drbd_version = drbd::get_drbd_versions().unwrap_or_default();
+        let drbd_version = drbd::get_drbd_versions(&SystemRunner).unwrap_or_default();
 
         Self {
             resources: HashMap::new(),
@@ -153,19 +477,181 @@ impl Metrics {
         }
     }
 
+    /// Applies the state of a resource after an event.
+    ///
+    /// Only destroying the resource itself removes it, if one of its objects (e.g., a device) got
+    /// destroyed, the resource is stored without it, so that its series vanish on regeneration.
+    fn apply(&mut self, et: &EventType, object: EventObject, resource: &Resource) {
+        match (et, object) {
+            (EventType::Destroy, EventObject::Resource) => self.delete(&resource.name),
+            _ => self.update(resource),
+        }
+    }
+
     fn update(&mut self, resource: &Resource) {
         self.dirty = true;
         self.resources
             .insert(resource.name.clone(), resource.clone());
     }
 
+    #[cfg(test)]
     fn get(&mut self) -> Result<String> {
-        if !self.dirty {
+        Ok(self.sections()?.concat())
+    }
+
+    fn sections(&mut self) -> Result<MetricsBody> {
+        if self.dirty {
+            self.regenerate()?;
+        } else {
             trace!("Metrics::get: serving from cache");
-            return Ok(self.cache.clone());
         }
 
-        trace!("Metrics::get: calculating metrics");
+        // changes without events, so never cached
+        let mut metrics = HashMap::new();
+        let (k, m) = type_gauge(
+            "drbdreactor_metrics_cache_age_seconds",
+            "Seconds since the metrics were last regenerated because of DRBD events",
+            &mut metrics,
+        );
+        let age = self
+            .regenerated
+            .map(|t| t.elapsed().as_secs_f64())
+            .unwrap_or_default();
+        writeln!(m, "{} {:.3}", k, age)?;
+
+        // event pipeline counters change without the resources changing, so never cached either
+        let (k, m) = type_counter(
+            "drbd_reactor_events_processed_total",
+            "Number of DRBD events processed",
+            &mut metrics,
+        );
+        writeln!(m, "{} {}", k, events::events_processed())?;
+        let (k, m) = type_counter(
+            "drbdreactor_events_total",
+            "Number of DRBD events processed by the core",
+            &mut metrics,
+        );
+        writeln!(m, "{} {}", k, events::events_processed())?;
+        let (k, m) = type_counter(
+            "drbd_reactor_events_parse_errors_total",
+            "Number of DRBD events that could not be parsed",
+            &mut metrics,
+        );
+        for (what, n) in events::events_parse_errors() {
+            writeln!(m, "{}{{what=\"{}\"}} {}", k, what, n)?;
+        }
+        let (k, m) = type_counter(
+            "drbdreactor_events2_restarts_total",
+            "Number of restarts of 'drbdsetup events2' after a failure",
+            &mut metrics,
+        );
+        writeln!(m, "{} {}", k, events::events2_restarts())?;
+        let (k, m) = type_counter(
+            "drbdreactor_reloads_total",
+            "Number of configuration reloads",
+            &mut metrics,
+        );
+        writeln!(m, "{} {}", k, config::reloads())?;
+        let (k, m) = type_gauge(
+            "drbdreactor_plugin_queue_depth",
+            "Number of updates queued for a plugin, sampled by the core",
+            &mut metrics,
+        );
+        for (plugin, depth) in plugin::queue_depths() {
+            writeln!(m, "{}{{plugin=\"{}\"}} {}", k, plugin, depth)?;
+        }
+        let (k, m) = type_gauge(
+            "drbdreactor_plugin_info",
+            "Plugins currently running. Always 1",
+            &mut metrics,
+        );
+        for (ptype, id) in plugin::inventory() {
+            writeln!(m, "{}{{type=\"{}\",id=\"{}\"}} 1", k, ptype, id)?;
+        }
+
+        // decided by the promoter, not by DRBD events
+        let intended_roles = promoter::intended_roles();
+        if !intended_roles.is_empty() {
+            let (k, m) = type_gauge(
+                "drbd_reactor_intended_role",
+                "Role the promoter intends for the resource on this node",
+                &mut metrics,
+            );
+            for (name, intended) in intended_roles {
+                for role in [Role::Primary, Role::Secondary].iter() {
+                    writeln!(
+                        m,
+                        "{}{{name=\"{}\",role=\"{}\"}} {}",
+                        k,
+                        name,
+                        role,
+                        (role == &intended) as i32
+                    )?;
+                }
+            }
+        }
+
+        let latency = promoter::promotion_latency();
+        if latency.count > 0 {
+            let (k, m) = type_histogram(
+                "drbd_promotion_seconds",
+                "Seconds from a resource becoming promotable until its services got started",
+                &mut metrics,
+            );
+            for (le, n) in promoter::PROMOTION_LATENCY_BUCKETS
+                .iter()
+                .zip(latency.buckets.iter())
+            {
+                writeln!(m, "{}_bucket{{le=\"{}\"}} {}", k, le, n)?;
+            }
+            writeln!(m, "{}_bucket{{le=\"+Inf\"}} {}", k, latency.count)?;
+            writeln!(m, "{}_sum {:.3}", k, latency.sum_secs)?;
+            writeln!(m, "{}_count {}", k, latency.count)?;
+        }
+
+        let handler_stats = umh::handler_stats();
+        if !handler_stats.is_empty() {
+            let (k, m) = type_counter(
+                "drbd_reactor_umh_handler_invocations_total",
+                "Number of executions of the command of a UMH rule",
+                &mut metrics,
+            );
+            for (rule, stats) in &handler_stats {
+                let rule = escape_label_value(rule);
+                writeln!(m, "{}{{rule=\"{}\"}} {}", k, rule, stats.invocations)?;
+            }
+            let (k, m) = type_counter(
+                "drbd_reactor_umh_handler_failures_total",
+                "Number of executions of the command of a UMH rule that did not exit successfully",
+                &mut metrics,
+            );
+            for (rule, stats) in &handler_stats {
+                let rule = escape_label_value(rule);
+                writeln!(m, "{}{{rule=\"{}\"}} {}", k, rule, stats.failures)?;
+            }
+        }
+
+        // set by the core on (re)load
+        if let Some(hash) = config::config_hash() {
+            let (k, m) = type_gauge(
+                "drbd_reactor_config_hash",
+                "Hash of the effective configuration, always 1",
+                &mut metrics,
+            );
+            writeln!(m, "{}{{hash=\"{}\"}} 1", k, hash)?;
+        }
+
+        // stable order, so that consecutive responses only differ in values
+        let mut uncached = metrics.into_iter().collect::<Vec<_>>();
+        uncached.sort();
+        Ok(MetricsBody {
+            cached: self.cache.clone(),
+            uncached: uncached.into_iter().map(|(_, v)| v).collect(),
+        })
+    }
+
+    fn regenerate(&mut self) -> Result<()> {
+        trace!("Metrics::regenerate: calculating metrics");
         let mut metrics = HashMap::new();
 
         // higher level metric
@@ -176,6 +662,19 @@ impl Metrics {
         );
         writeln!(m, "{} 1", k)?;
 
+        let (k, m) = type_gauge(
+            "drbdreactor_build_info",
+            "Version and git commit drbd-reactor was built from. Always 1",
+            &mut metrics,
+        );
+        writeln!(
+            m,
+            "{}{{version=\"{}\",git_sha=\"{}\"}} 1",
+            k,
+            utils::VERSION,
+            utils::GIT_SHA
+        )?;
+
         let (k, m) = type_gauge(
             "drbd_version",
             "Version of the loaded DRBD kernel module and DRBD utils",
@@ -228,6 +727,13 @@ impl Metrics {
             );
             writeln!(m, "{}{{name=\"{}\"}} {}", k, name, r.may_promote as i32)?;
 
+            let (k, m) = type_gauge(
+                "drbd_resource_healthy",
+                "Boolean whether the resource is Primary, has quorum, all devices are UpToDate, and all peers are connected",
+                &mut metrics,
+            );
+            writeln!(m, "{}{{name=\"{}\"}} {}", k, name, r.is_healthy() as i32)?;
+
             let (k, m) = type_gauge(
                 "drbd_resource_promotionscore",
                 "The promotion score (higher is better) for the resource",
@@ -274,7 +780,44 @@ impl Metrics {
                     }
                 }
 
+                for p in &c.paths {
+                    let (k, m) = type_gauge(
+                        "drbd_path_established",
+                        "Boolean whether this network path to the peer is established",
+                        &mut metrics,
+                    );
+                    writeln!(
+                        m,
+                        "{}{{{},local=\"{}\",peer=\"{}\"}} {}",
+                        k, common, p.local, p.peer, p.established as i32
+                    )?;
+                }
+
+                if self.only_established_peers && c.connection != ConnectionState::Connected {
+                    continue;
+                }
+
                 for pd in &c.peerdevices {
+                    if self.enums {
+                        let (k, m) = type_gauge(
+                            "drbd_peerdevice_replication_state",
+                            "DRBD replication state of the peer device",
+                            &mut metrics,
+                        );
+                        for rstate in ReplicationState::iterator() {
+                            writeln!(
+                                m,
+                                "{}{{{},volume=\"{}\",{}=\"{}\"}} {}",
+                                k,
+                                common,
+                                pd.volume,
+                                k,
+                                rstate,
+                                (rstate == &pd.replication_state) as i32
+                            )?;
+                        }
+                    }
+
                     let (k, m) = type_gauge("drbd_peerdevice_outofsync_bytes",
                                             "Number of bytes currently out of sync with this peer, according to the bitmap that DRBD has for it",
                                             &mut metrics);
@@ -284,11 +827,38 @@ impl Metrics {
                         k,
                         common,
                         pd.volume,
-                        pd.out_of_sync * 1024, // KiB
+                        kib_to_bytes(pd.out_of_sync),
+                    )?;
+                    let (k, m) = type_counter(
+                        "drbd_peerdevice_sent_bytes_total",
+                        "Net data sent to this peer",
+                        &mut metrics,
+                    );
+                    writeln!(
+                        m,
+                        "{}{{{},volume=\"{}\"}} {}",
+                        k,
+                        common,
+                        pd.volume,
+                        kib_to_bytes(pd.sent),
                     )?;
+                    // the name before it became a proper counter, to be removed in the future
                     let (k, m) = type_counter(
                         "drbd_peerdevice_sent_bytes",
-                        "Number of bytes currently sent to this peer",
+                        "Deprecated, use drbd_peerdevice_sent_bytes_total",
+                        &mut metrics,
+                    );
+                    writeln!(
+                        m,
+                        "{}{{{},volume=\"{}\"}} {}",
+                        k,
+                        common,
+                        pd.volume,
+                        kib_to_bytes(pd.sent),
+                    )?;
+                    let (k, m) = type_counter(
+                        "drbd_peerdevice_received_bytes_total",
+                        "Net data received from this peer",
                         &mut metrics,
                     );
                     writeln!(
@@ -297,11 +867,12 @@ impl Metrics {
                         k,
                         common,
                         pd.volume,
-                        pd.sent * 1024, // KiB
+                        kib_to_bytes(pd.received),
                     )?;
+                    // the name before it became a proper counter, to be removed in the future
                     let (k, m) = type_counter(
                         "drbd_peerdevice_received_bytes",
-                        "Number of bytes currently received from this peer",
+                        "Deprecated, use drbd_peerdevice_received_bytes_total",
                         &mut metrics,
                     );
                     writeln!(
@@ -310,8 +881,77 @@ impl Metrics {
                         k,
                         common,
                         pd.volume,
-                        pd.received * 1024, // KiB
+                        kib_to_bytes(pd.received),
                     )?;
+                    let (k, m) = type_gauge(
+                        "drbd_peerdevice_pending",
+                        "Number of requests sent to this peer, but that have not yet been answered by it",
+                        &mut metrics,
+                    );
+                    writeln!(
+                        m,
+                        "{}{{{},volume=\"{}\"}} {}",
+                        k, common, pd.volume, pd.pending,
+                    )?;
+                    let (k, m) = type_gauge(
+                        "drbd_peerdevice_unacked",
+                        "Number of requests received from this peer, but that have not yet been answered by us",
+                        &mut metrics,
+                    );
+                    writeln!(
+                        m,
+                        "{}{{{},volume=\"{}\"}} {}",
+                        k, common, pd.volume, pd.unacked,
+                    )?;
+
+                    if pd.has_sync_details {
+                        let (k, m) = type_gauge(
+                            "drbd_peerdevice_resync_done_percent",
+                            "Percentage of the resync with this peer that is done",
+                            &mut metrics,
+                        );
+                        writeln!(
+                            m,
+                            "{}{{{},volume=\"{}\"}} {}",
+                            k, common, pd.volume, pd.resync_done,
+                        )?;
+                        let (k, m) = type_gauge(
+                            "drbd_peerdevice_resync_done_ratio",
+                            "Ratio (0 to 1) of the resync with this peer that is done",
+                            &mut metrics,
+                        );
+                        writeln!(
+                            m,
+                            "{}{{{},volume=\"{}\"}} {}",
+                            k,
+                            common,
+                            pd.volume,
+                            pd.resync_done / 100.0,
+                        )?;
+                        let (k, m) = type_gauge(
+                            "drbd_peerdevice_resync_eta_seconds",
+                            "Estimated time until the resync with this peer is finished",
+                            &mut metrics,
+                        );
+                        writeln!(
+                            m,
+                            "{}{{{},volume=\"{}\"}} {}",
+                            k, common, pd.volume, pd.resync_eta,
+                        )?;
+                        let (k, m) = type_gauge(
+                            "drbd_peerdevice_resync_rate_bytes_per_second",
+                            "Current rate of the resync with this peer",
+                            &mut metrics,
+                        );
+                        writeln!(
+                            m,
+                            "{}{{{},volume=\"{}\"}} {}",
+                            k,
+                            common,
+                            pd.volume,
+                            pd.resync_rate * 1024.0,
+                        )?;
+                    }
                 }
 
                 let (k, m) = type_gauge("drbd_connection_congested",
@@ -325,14 +965,26 @@ impl Metrics {
                     "Number of application requests in flight (not completed)",
                     &mut metrics,
                 );
-                writeln!(m, "{}{{{}}} {}", k, common, c.ap_in_flight * 512)?; // 512 byte sectors
+                writeln!(
+                    m,
+                    "{}{{{}}} {}",
+                    k,
+                    common,
+                    sectors_to_bytes(c.ap_in_flight)
+                )?;
 
                 let (k, m) = type_gauge(
                     "drbd_connection_rsinflight_bytes",
                     "Number of resync requests in flight",
                     &mut metrics,
                 );
-                writeln!(m, "{}{{{}}} {}", k, common, c.rs_in_flight * 512)?; // 512 byte sectors
+                writeln!(
+                    m,
+                    "{}{{{}}} {}",
+                    k,
+                    common,
+                    sectors_to_bytes(c.rs_in_flight)
+                )?;
             }
 
             for d in &r.devices {
@@ -397,21 +1049,21 @@ impl Metrics {
                     "Device size in bytes",
                     &mut metrics,
                 );
-                writeln!(m, "{}{{{}}} {}", k, common, d.size * 1024)?; // KiB
+                writeln!(m, "{}{{{}}} {}", k, common, kib_to_bytes(d.size))?;
 
                 let (k, m) = type_counter(
                     "drbd_device_read_bytes_total",
                     "Net data read from local hard disk",
                     &mut metrics,
                 );
-                writeln!(m, "{}{{{}}} {}", k, common, d.read * 1024)?; // KiB
+                writeln!(m, "{}{{{}}} {}", k, common, kib_to_bytes(d.read))?;
 
                 let (k, m) = type_counter(
                     "drbd_device_written_bytes_total",
                     "Net data written on local disk",
                     &mut metrics,
                 );
-                writeln!(m, "{}{{{}}} {}", k, common, d.written * 1024)?; // KiB
+                writeln!(m, "{}{{{}}} {}", k, common, kib_to_bytes(d.written))?;
 
                 let (k, m) = type_counter(
                     "drbd_device_alwrites_total",
@@ -450,10 +1102,10 @@ impl Metrics {
             }
         }
 
-        self.cache.clear();
-        metrics.values().for_each(|v| self.cache.push_str(v));
+        self.cache = Arc::new(metrics.into_values().collect());
         self.dirty = false;
-        Ok(self.cache.clone())
+        self.regenerated = Some(Instant::now());
+        Ok(())
     }
 
     fn delete(&mut self, resource_name: &str) {
@@ -469,6 +1121,27 @@ fn header_generic(k: &str, help: &str, mtype: &str) -> (String, String) {
     )
 }
 
+fn kib_to_bytes(kib: u64) -> u64 {
+    to_bytes(kib, 1024)
+}
+
+fn sectors_to_bytes(sectors: u64) -> u64 {
+    to_bytes(sectors, 512)
+}
+
+// saturates instead of overflowing, a wrong (wrapped) value would be worse
+fn to_bytes(value: u64, unit: u64) -> u64 {
+    value.checked_mul(unit).unwrap_or_else(|| {
+        warn!(
+            "to_bytes: {} * {} overflows, saturating to {}",
+            value,
+            unit,
+            u64::MAX
+        );
+        u64::MAX
+    })
+}
+
 fn type_gauge<'a>(
     k: &'a str,
     help: &'a str,
@@ -479,6 +1152,16 @@ fn type_gauge<'a>(
     (k, m)
 }
 
+fn type_histogram<'a>(
+    k: &'a str,
+    help: &'a str,
+    metrics: &'a mut HashMap<String, String>,
+) -> (String, &'a mut String) {
+    let (k, t) = header_generic(k, help, "histogram");
+    let m = metrics.entry(k.clone()).or_insert(t);
+    (k, m)
+}
+
 fn type_counter<'a>(
     k: &'a str,
     help: &'a str,
@@ -490,14 +1173,783 @@ fn type_counter<'a>(
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
 pub struct PrometheusConfig {
     #[serde(default = "default_address")]
     pub address: LocalAddress,
     #[serde(default)]
+    pub addresses: Vec<LocalAddress>,
+    #[serde(default)]
     pub enums: bool,
+    #[serde(default = "default_metrics_path")]
+    pub metrics_path: String,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub bearer_token: Option<String>,
+    #[serde(default)]
+    pub only_established_peers: bool,
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
     pub id: Option<String>, // ! deprecated !
 }
 
+impl PrometheusConfig {
+    /// The addresses to listen on: `addresses` if set, `address` otherwise.
+    pub fn listen_addresses(&self) -> &[LocalAddress] {
+        if self.addresses.is_empty() {
+            std::slice::from_ref(&self.address)
+        } else {
+            &self.addresses
+        }
+    }
+
+    /// The addresses to listen on, comma separated.
+    pub fn addresses_string(&self) -> String {
+        self.listen_addresses()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
 fn default_address() -> LocalAddress {
     LocalAddress::Unspecified(9942)
 }
+
+fn default_metrics_path() -> String {
+    "/metrics".to_string()
+}
+
+fn default_max_body_size() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_max_connections() -> usize {
+    4
+}
+
+fn default_timeout_secs() -> u64 {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drbd::{Connection, Device, PeerDevice};
+
+    const PEER_LABELS: &str = "name=\"foo\",conn_name=\"bar\",peer_node_id=\"1\",volume=\"0\"";
+
+    fn peer_resource(sent: u64) -> Resource {
+        Resource {
+            name: "foo".to_string(),
+            connections: vec![Connection {
+                peer_node_id: 1,
+                conn_name: "bar".to_string(),
+                peerdevices: vec![PeerDevice {
+                    volume: 0,
+                    received: 2,
+                    sent,
+                    pending: 3,
+                    unacked: 4,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn huge_sizes_saturate() {
+        assert_eq!(kib_to_bytes(1), 1024);
+        assert_eq!(sectors_to_bytes(2), 1024);
+        assert_eq!(kib_to_bytes(u64::MAX / 1024), (u64::MAX / 1024) * 1024);
+        assert_eq!(kib_to_bytes(u64::MAX / 1024 + 1), u64::MAX);
+        assert_eq!(sectors_to_bytes(u64::MAX), u64::MAX);
+
+        let mut metrics = Metrics::new(false);
+        metrics.update(&Resource {
+            name: "foo".to_string(),
+            devices: vec![Device {
+                volume: 0,
+                minor: 1000,
+                size: u64::MAX - 1,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        let out = metrics.get().expect("metrics must render");
+        assert!(out
+            .lines()
+            .any(|l| l.starts_with("drbd_device_size_bytes{")
+                && l.ends_with(&format!(" {}", u64::MAX))));
+    }
+
+    #[test]
+    fn concurrent_connections() {
+        let prometheus = Prometheus::new(PrometheusConfig {
+            address: LocalAddress::Explicit("127.0.0.1:0".parse().unwrap()),
+            metrics_path: default_metrics_path(),
+            max_body_size: default_max_body_size(),
+            max_connections: 2,
+            timeout_secs: default_timeout_secs(),
+            ..Default::default()
+        })
+        .expect("prometheus must start");
+        let addr = prometheus.listeners[0].local_addr().unwrap();
+
+        // a scraper that never sends its request occupies one handler
+        let slow = TcpStream::connect(addr).unwrap();
+
+        let mut fast = TcpStream::connect(addr).unwrap();
+        fast.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        fast.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        fast.read_to_string(&mut response)
+            .expect("response must not be blocked by the slow scraper");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        drop(slow);
+        drop(prometheus);
+    }
+
+    #[test]
+    fn multiple_addresses() {
+        let cfg: PrometheusConfig =
+            toml::from_str(r#"addresses = ["127.0.0.1:0", "127.0.0.1:0"]"#).expect("must parse");
+        assert_eq!(cfg.addresses_string(), "127.0.0.1:0,127.0.0.1:0");
+        let prometheus = Prometheus::new(cfg).expect("prometheus must start");
+        assert_eq!(prometheus.listeners.len(), 2);
+
+        for listener in &prometheus.listeners {
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        }
+
+        // shuts down all listeners
+        drop(prometheus);
+
+        let cfg: PrometheusConfig = toml::from_str("").expect("must parse");
+        assert_eq!(cfg.addresses_string(), ":9942");
+    }
+
+    #[test]
+    fn read_timeout() {
+        let prometheus = Prometheus::new(PrometheusConfig {
+            address: LocalAddress::Explicit("127.0.0.1:0".parse().unwrap()),
+            max_connections: 1,
+            timeout_secs: 1,
+            ..Default::default()
+        })
+        .expect("prometheus must start");
+        let addr = prometheus.listeners[0].local_addr().unwrap();
+
+        // the only handler drops the connection after the timeout
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut response = Vec::new();
+        slow.read_to_end(&mut response)
+            .expect("connection must be closed by the server");
+        assert!(response.is_empty());
+
+        drop(prometheus);
+    }
+
+    #[test]
+    fn max_connections() {
+        let err = Prometheus::new(PrometheusConfig {
+            address: LocalAddress::Explicit("127.0.0.1:0".parse().unwrap()),
+            max_connections: 0,
+            ..Default::default()
+        });
+        assert!(err.is_err());
+
+        let err = Prometheus::new(PrometheusConfig {
+            address: LocalAddress::Explicit("127.0.0.1:0".parse().unwrap()),
+            timeout_secs: 0,
+            ..Default::default()
+        });
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn destroyed_device() {
+        let mut r = Resource {
+            name: "foo".to_string(),
+            devices: vec![
+                Device {
+                    volume: 0,
+                    minor: 1000,
+                    ..Default::default()
+                },
+                Device {
+                    volume: 1,
+                    minor: 1001,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let mut metrics = Metrics::new(false);
+        metrics.apply(&EventType::Exists, EventObject::Resource, &r);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("minor=\"1001\""));
+
+        r.devices.pop();
+        metrics.apply(&EventType::Destroy, EventObject::Device, &r);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("minor=\"1000\""));
+        assert!(!out.contains("minor=\"1001\""));
+
+        metrics.apply(&EventType::Destroy, EventObject::Resource, &r);
+        let out = metrics.get().expect("metrics must render");
+        assert!(!out.contains("name=\"foo\""));
+    }
+
+    #[test]
+    fn peerdevice_traffic() {
+        let mut metrics = Metrics::new(false);
+        metrics.update(&peer_resource(1));
+
+        let out = metrics.get().expect("metrics must render");
+        let labels = PEER_LABELS;
+        assert!(out.contains("drbd_resource_healthy{name=\"foo\"} 0\n"));
+        assert!(out.contains("# TYPE drbd_peerdevice_sent_bytes_total counter\n"));
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_sent_bytes_total{{{}}} 1024\n",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_received_bytes_total{{{}}} 2048\n",
+            labels
+        )));
+        // deprecated names are still exported
+        assert!(out.contains(&format!("drbd_peerdevice_sent_bytes{{{}}} 1024\n", labels)));
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_received_bytes{{{}}} 2048\n",
+            labels
+        )));
+        assert!(out.contains("# TYPE drbd_peerdevice_pending gauge\n"));
+        assert!(out.contains(&format!("drbd_peerdevice_pending{{{}}} 3\n", labels)));
+        assert!(out.contains(&format!("drbd_peerdevice_unacked{{{}}} 4\n", labels)));
+
+        // not dirty, so this has to come from the cache, even if the resource changed behind our back
+        let cached = metrics.cache.clone();
+        metrics
+            .resources
+            .insert("foo".to_string(), peer_resource(5));
+        let out = metrics.get().expect("metrics must render");
+        assert_eq!(metrics.cache, cached);
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_sent_bytes_total{{{}}} 1024\n",
+            labels
+        )));
+
+        metrics.update(&peer_resource(5));
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_sent_bytes_total{{{}}} 5120\n",
+            labels
+        )));
+    }
+
+    #[test]
+    fn only_established_peers() {
+        let mut r = peer_resource(1);
+        r.connections[0].connection = ConnectionState::StandAlone;
+
+        let mut metrics = Metrics::new(true);
+        metrics.update(&r);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("drbd_peerdevice_sent_bytes_total{"));
+
+        metrics.only_established_peers = true;
+        metrics.update(&r);
+        let out = metrics.get().expect("metrics must render");
+        assert!(!out.contains("drbd_peerdevice_"));
+        // the connection state itself is still exported
+        assert!(out.contains("drbd_connection_state{"));
+
+        r.connections[0].connection = ConnectionState::Connected;
+        metrics.update(&r);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_sent_bytes_total{{{}}} 1024\n",
+            PEER_LABELS
+        )));
+    }
+
+    #[test]
+    fn path_established() {
+        let mut r = peer_resource(1);
+        let path = |local: &str, established| drbd::Path {
+            name: "foo".to_string(),
+            peer_node_id: 1,
+            conn_name: "bar".to_string(),
+            local: local.to_string(),
+            peer: "ipv4:10.0.0.2:7000".to_string(),
+            established,
+        };
+        r.connections[0].paths = vec![
+            path("ipv4:10.0.0.1:7000", true),
+            path("ipv4:10.1.0.1:7000", false),
+        ];
+
+        let mut metrics = Metrics::new(false);
+        metrics.update(&r);
+        let out = metrics.get().expect("metrics must render");
+        let labels = "name=\"foo\",conn_name=\"bar\",peer_node_id=\"1\"";
+        assert!(out.contains("# TYPE drbd_path_established gauge\n"));
+        assert!(out.contains(&format!(
+            "drbd_path_established{{{},local=\"ipv4:10.0.0.1:7000\",peer=\"ipv4:10.0.0.2:7000\"}} 1\n",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "drbd_path_established{{{},local=\"ipv4:10.1.0.1:7000\",peer=\"ipv4:10.0.0.2:7000\"}} 0\n",
+            labels
+        )));
+    }
+
+    #[test]
+    fn peerdevice_replication_state() {
+        let mut metrics = Metrics::new(false);
+        let mut r = peer_resource(1);
+        r.connections[0].peerdevices[0].replication_state = ReplicationState::SyncTarget;
+        metrics.update(&r);
+        let out = metrics.get().expect("metrics must render");
+        assert!(!out.contains("drbd_peerdevice_replication_state"));
+
+        let mut metrics = Metrics::new(true);
+        metrics.update(&r);
+        let out = metrics.get().expect("metrics must render");
+        let k = "drbd_peerdevice_replication_state";
+        assert!(out.contains(&format!("# TYPE {} gauge\n", k)));
+        assert!(out.contains(&format!(
+            "{}{{{},{}=\"SyncTarget\"}} 1\n",
+            k, PEER_LABELS, k
+        )));
+        assert!(out.contains(&format!(
+            "{}{{{},{}=\"PausedSyncT\"}} 0\n",
+            k, PEER_LABELS, k
+        )));
+        assert_eq!(
+            out.lines()
+                .filter(|l| l.starts_with(&format!("{}{{", k)))
+                .count(),
+            ReplicationState::iterator().len()
+        );
+    }
+
+    fn response<F>(request: &[u8], cfg: &PrometheusConfig, metrics: F) -> Result<String>
+    where
+        F: FnOnce() -> Result<MetricsBody>,
+    {
+        let mut out = Vec::new();
+        write_response(&mut out, request, cfg, metrics)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    fn dechunk(mut body: &str) -> String {
+        let mut content = String::new();
+        loop {
+            let (len, rest) = body.split_once("\r\n").expect("chunk size must exist");
+            let len = usize::from_str_radix(len, 16).expect("chunk size must be hex");
+            if len == 0 {
+                assert_eq!(rest, "\r\n");
+                return content;
+            }
+            content.push_str(&rest[..len]);
+            assert_eq!(&rest[len..len + 2], "\r\n");
+            body = &rest[len + 2..];
+        }
+    }
+
+    #[test]
+    fn streamed_response() {
+        let mut metrics = Metrics::new(true);
+        for i in 0..500 {
+            let mut r = peer_resource(i);
+            r.name = format!("res{}", i);
+            metrics.update(&r);
+        }
+        let buffered_cfg = PrometheusConfig {
+            metrics_path: "/metrics".to_string(),
+            max_body_size: usize::MAX,
+            ..Default::default()
+        };
+        let streamed_cfg = PrometheusConfig {
+            max_body_size: 1024,
+            ..buffered_cfg.clone()
+        };
+        let request = b"GET /metrics HTTP/1.1\r\n\r\n";
+
+        let buffered = response(request, &buffered_cfg, || metrics.sections()).expect("must work");
+        let streamed = response(request, &streamed_cfg, || metrics.sections()).expect("must work");
+        assert!(buffered.contains("\r\nContent-Length: "));
+        assert!(!buffered.contains("Transfer-Encoding"));
+
+        let (headers, body) = streamed.split_once("\r\n\r\n").expect("must have headers");
+        assert!(headers.ends_with("\r\nTransfer-Encoding: chunked"));
+        assert!(!headers.contains("Content-Length"));
+        let (_, buffered_body) = buffered.split_once("\r\n\r\n").expect("must have headers");
+        // the uncached cache age might differ, everything else has to be identical
+        let without_age = |s: &str| {
+            s.lines()
+                .filter(|l| !l.starts_with("drbdreactor_metrics_cache_age_seconds "))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        assert!(buffered_body.len() > 100 * 1024);
+        assert_eq!(without_age(&dechunk(body)), without_age(buffered_body));
+
+        // small responses are never streamed
+        let r = response(b"GET / HTTP/1.1\r\n\r\n", &streamed_cfg, || {
+            panic!("metrics must not be rendered")
+        })
+        .expect("must work");
+        assert!(r.contains("\r\nContent-Length: "));
+    }
+
+    #[test]
+    fn request_paths() {
+        let render = || Ok(MetricsBody::from("drbdreactor_up 1\n".to_string()));
+        let nope = || -> Result<MetricsBody> { panic!("metrics must not be rendered") };
+        let cfg = |metrics_path: &str| PrometheusConfig {
+            metrics_path: metrics_path.to_string(),
+            max_body_size: default_max_body_size(),
+            ..Default::default()
+        };
+
+        let r = response(
+            b"GET /metrics HTTP/1.1\r\nHost: foo\r\n\r\n",
+            &cfg("/metrics"),
+            render,
+        )
+        .expect("must work");
+        assert!(r.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(r.ends_with("\r\n\r\ndrbdreactor_up 1\n"));
+
+        let r = response(
+            b"GET /metrics?x=y HTTP/1.1\r\n\r\n",
+            &cfg("/metrics"),
+            render,
+        )
+        .expect("must work");
+        assert!(r.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let r = response(b"GET / HTTP/1.1\r\n\r\n", &cfg("/metrics"), nope).expect("must work");
+        assert!(r.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n"));
+        assert!(r.contains("<a href=\"/metrics\">"));
+
+        for req in [
+            &b"GET /foo HTTP/1.1\r\n\r\n"[..],
+            b"POST /metrics HTTP/1.1\r\n\r\n",
+            b"garbage",
+            b"",
+        ] {
+            let r = response(req, &cfg("/metrics"), nope).expect("must work");
+            assert!(r.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        }
+
+        // metrics on the root path take precedence over the index
+        let r = response(b"GET / HTTP/1.1\r\n\r\n", &cfg("/"), render).expect("must work");
+        assert!(r.ends_with("drbdreactor_up 1\n"));
+    }
+
+    // returns one chunk per read
+    struct Chunked(Vec<&'static [u8]>);
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn request_in_pieces() {
+        let mut stream = Chunked(vec![
+            b"GET /met",
+            b"rics HTTP/1.1\r\nHost: foo\r\n",
+            b"\r\n",
+            b"must not be read",
+        ]);
+        let request = read_request(&mut stream).expect("must work");
+        assert_eq!(request, b"GET /metrics HTTP/1.1\r\nHost: foo\r\n\r\n");
+        assert_eq!(stream.0.len(), 1);
+
+        // client closed before finishing the headers
+        let mut stream = Chunked(vec![b"GET / HTTP/1.1\r\n"]);
+        let request = read_request(&mut stream).expect("must work");
+        assert_eq!(request, b"GET / HTTP/1.1\r\n");
+
+        // clients that never finish their headers are cut off
+        let mut stream = std::io::repeat(b'x');
+        assert!(read_request(&mut stream).is_err());
+    }
+
+    #[test]
+    fn tls_config_errors() {
+        let dir = tempfile::tempdir().expect("tempdir must work");
+        let empty = dir.path().join("empty.pem");
+        File::create(&empty).expect("file creation must work");
+        let missing = dir.path().join("missing.pem");
+
+        let e = tls_config(&missing, &missing).expect_err("must fail");
+        assert!(e.to_string().contains("Failed to open TLS certificate"));
+
+        let e = tls_config(&empty, &empty).expect_err("must fail");
+        assert!(e.to_string().starts_with("No certificate found in"));
+
+        let cfg = PrometheusConfig {
+            address: LocalAddress::Explicit("127.0.0.1:0".parse().expect("valid address")),
+            tls_cert: Some(empty),
+            ..Default::default()
+        };
+        assert!(Prometheus::new(cfg).is_err());
+    }
+
+    #[test]
+    fn bearer_token() {
+        let render = || Ok(MetricsBody::from("drbdreactor_up 1\n".to_string()));
+        let nope = || -> Result<MetricsBody> { panic!("metrics must not be rendered") };
+        let cfg = PrometheusConfig {
+            metrics_path: "/metrics".to_string(),
+            max_body_size: default_max_body_size(),
+            bearer_token: Some("s3cr3t".to_string()),
+            ..Default::default()
+        };
+
+        let r = response(
+            b"GET /metrics HTTP/1.1\r\nHost: foo\r\nauthorization: Bearer s3cr3t\r\n\r\n",
+            &cfg,
+            render,
+        )
+        .expect("must work");
+        assert!(r.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        for req in [
+            &b"GET /metrics HTTP/1.1\r\nHost: foo\r\n\r\n"[..],
+            b"GET /metrics HTTP/1.1\r\nAuthorization: Bearer s3cr3\r\n\r\n",
+            b"GET /metrics HTTP/1.1\r\nAuthorization: Bearer s3cr3tt\r\n\r\n",
+            b"GET /metrics HTTP/1.1\r\nAuthorization: Basic s3cr3t\r\n\r\n",
+            // headers end at the first empty line
+            b"GET /metrics HTTP/1.1\r\n\r\nAuthorization: Bearer s3cr3t\r\n",
+        ] {
+            let r = response(req, &cfg, nope).expect("must work");
+            assert!(r.starts_with("HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\n"));
+        }
+
+        // the index stays accessible for health checks
+        let r = response(b"GET / HTTP/1.1\r\n\r\n", &cfg, nope).expect("must work");
+        assert!(r.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // the token might arrive in a later read than the request line
+        let mut stream = Chunked(vec![
+            b"GET /metrics HTTP/1.1\r\nHost: foo\r\n",
+            b"Authorization: Bearer s3",
+            b"cr3t\r\n\r\n",
+        ]);
+        let request = read_request(&mut stream).expect("must work");
+        let r = response(&request, &cfg, render).expect("must work");
+        assert!(r.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        assert!(constant_time_eq(b"foo", b"foo"));
+        assert!(!constant_time_eq(b"foo", b"fob"));
+        assert!(!constant_time_eq(b"foo", b"fo"));
+    }
+
+    #[test]
+    fn peerdevice_resync_progress() {
+        let mut metrics = Metrics::new(false);
+        metrics.update(&peer_resource(1));
+        let out = metrics.get().expect("metrics must render");
+        assert!(!out.contains("drbd_peerdevice_resync_"));
+
+        let mut r = peer_resource(1);
+        r.connections[0].peerdevices[0].resync_done = 42.5;
+        r.connections[0].peerdevices[0].resync_eta = 120;
+        r.connections[0].peerdevices[0].resync_rate = 2048.0;
+        r.connections[0].peerdevices[0].has_sync_details = true;
+        metrics.update(&r);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("# TYPE drbd_peerdevice_resync_done_percent gauge\n"));
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_resync_done_percent{{{}}} 42.5\n",
+            PEER_LABELS
+        )));
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_resync_eta_seconds{{{}}} 120\n",
+            PEER_LABELS
+        )));
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_resync_done_ratio{{{}}} 0.425\n",
+            PEER_LABELS
+        )));
+        assert!(out.contains(&format!(
+            "drbd_peerdevice_resync_rate_bytes_per_second{{{}}} 2097152\n",
+            PEER_LABELS
+        )));
+    }
+
+    #[test]
+    fn build_info() {
+        let mut metrics = Metrics::new(false);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("# TYPE drbdreactor_build_info gauge\n"));
+        assert!(out.contains(&format!(
+            "drbdreactor_build_info{{version=\"{}\",git_sha=\"{}\"}} 1\n",
+            env!("CARGO_PKG_VERSION"),
+            utils::GIT_SHA
+        )));
+        assert!(!utils::GIT_SHA.is_empty());
+    }
+
+    #[test]
+    fn events_processed() {
+        let processed = |out: &str| -> u64 {
+            out.lines()
+                .find_map(|l| l.strip_prefix("drbd_reactor_events_processed_total "))
+                .expect("events processed must be rendered")
+                .parse()
+                .expect("events processed must be a number")
+        };
+
+        let mut metrics = Metrics::new(false);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("# TYPE drbd_reactor_events_processed_total counter\n"));
+        let before = processed(&out);
+
+        let n = 42;
+        for _ in 0..n {
+            events::count_processed();
+        }
+        // served from cache, the counter is still up to date
+        let out = metrics.get().expect("metrics must render");
+        assert_eq!(processed(&out), before + n);
+        assert!(out.contains("# TYPE drbdreactor_events_total counter\n"));
+        assert!(out.contains(&format!("drbdreactor_events_total {}\n", before + n)));
+    }
+
+    #[test]
+    fn cache_age() {
+        let age = |out: &str| -> f64 {
+            out.lines()
+                .find_map(|l| l.strip_prefix("drbdreactor_metrics_cache_age_seconds "))
+                .expect("cache age must be rendered")
+                .parse()
+                .expect("cache age must be a number")
+        };
+
+        let mut metrics = Metrics::new(false);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("# TYPE drbdreactor_metrics_cache_age_seconds gauge\n"));
+        assert!(age(&out) < 1.0);
+
+        // served from cache, but the age is still up to date
+        metrics.regenerated = Some(Instant::now() - Duration::from_secs(60));
+        let out = metrics.get().expect("metrics must render");
+        assert!(age(&out) >= 60.0);
+
+        metrics.update(&peer_resource(1));
+        let out = metrics.get().expect("metrics must render");
+        assert!(age(&out) < 1.0);
+    }
+
+    #[test]
+    fn intended_role() {
+        // the roles are global, use a name no other test uses
+        let name = "prometheus-intended-role-test";
+        let mut metrics = Metrics::new(false);
+        promoter::set_intended_role(name, Some(Role::Primary));
+
+        // served from cache, but still up to date
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("# TYPE drbd_reactor_intended_role gauge\n"));
+        assert!(out.contains(&format!(
+            "drbd_reactor_intended_role{{name=\"{}\",role=\"Primary\"}} 1\n",
+            name
+        )));
+        assert!(out.contains(&format!(
+            "drbd_reactor_intended_role{{name=\"{}\",role=\"Secondary\"}} 0\n",
+            name
+        )));
+
+        promoter::set_intended_role(name, None);
+        let out = metrics.get().expect("metrics must render");
+        assert!(!out.contains(name));
+    }
+
+    #[test]
+    fn reloads() {
+        let mut metrics = Metrics::new(false);
+        let reloads = |out: &str| -> u64 {
+            out.lines()
+                .find_map(|l| l.strip_prefix("drbdreactor_reloads_total "))
+                .expect("reloads must be exported")
+                .parse()
+                .expect("must be a number")
+        };
+        let before = reloads(&metrics.get().expect("metrics must render"));
+        config::count_reload();
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("# TYPE drbdreactor_reloads_total counter\n"));
+        assert!(out.contains("# TYPE drbdreactor_events2_restarts_total counter\n"));
+        assert!(reloads(&out) > before);
+    }
+
+    #[test]
+    fn promotion_latency() {
+        let mut metrics = Metrics::new(false);
+        promoter::set_promotable("prometheus-latency-test", true);
+        promoter::record_promotion("prometheus-latency-test");
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("# TYPE drbd_promotion_seconds histogram\n"));
+        assert!(out.contains("drbd_promotion_seconds_bucket{le=\"0.5\"} "));
+        assert!(out.contains("drbd_promotion_seconds_bucket{le=\"+Inf\"} "));
+        assert!(out.contains("drbd_promotion_seconds_count "));
+    }
+
+    #[test]
+    fn umh_handler_stats() {
+        let mut metrics = Metrics::new(false);
+        umh::record_handler("prometheus-test", true);
+        umh::record_handler("prometheus-test", false);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("# TYPE drbd_reactor_umh_handler_invocations_total counter\n"));
+        assert!(out
+            .contains("drbd_reactor_umh_handler_invocations_total{rule=\"prometheus-test\"} 2\n"));
+        assert!(
+            out.contains("drbd_reactor_umh_handler_failures_total{rule=\"prometheus-test\"} 1\n")
+        );
+
+        // unnamed rules are labeled by their command
+        umh::record_handler("echo \"$DRBD_RES_NAME\" >> /tmp/log", true);
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains(
+            "drbd_reactor_umh_handler_invocations_total{rule=\"echo \\\"$DRBD_RES_NAME\\\" >> /tmp/log\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn config_hash() {
+        let mut metrics = Metrics::new(false);
+        config::set_config_hash("0123456789abcdef".to_string());
+        let out = metrics.get().expect("metrics must render");
+        assert!(out.contains("# TYPE drbd_reactor_config_hash gauge\n"));
+        assert!(out.contains("drbd_reactor_config_hash{hash=\"0123456789abcdef\"} 1\n"));
+    }
+}
@@ -1,69 +1,255 @@
 use core::time;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::env;
 use std::fmt;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Write;
 use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use log::{debug, info, trace, warn};
+use log::{debug, error, info, trace, warn};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use tinytemplate::TinyTemplate;
 
-use crate::drbd::{get_primary, DiskState, EventType, PluginUpdate, PrimaryOn, Resource, Role};
+use crate::drbd;
+use crate::drbd::{
+    get_primary, ConnectionState, DiskState, EventType, PluginUpdate, PrimaryOn, Resource, Role,
+};
 use crate::plugin;
 use crate::plugin::PluginCfg;
+use crate::runner::{CommandRunner, SystemRunner};
 use crate::systemd;
 use crate::utils;
 
 pub struct Promoter {
     cfg: PromoterConfig,
+    // the resources actually managed, see 'managed-if'
+    managed: HashSet<String>,
+    // as configured, identifies the plugin on reload
+    configured: PromoterConfig,
+    runner: Box<dyn CommandRunner>,
+}
+
+// what the promoters intend per resource on this node, shared with the prometheus plugin
+static INTENDED_ROLES: Mutex<BTreeMap<String, Role>> = Mutex::new(BTreeMap::new());
+
+// set if a promoter (re)wrote any systemd unit, consumed by the plugin (re)start to decide on a daemon-reload
+static UNITS_CHANGED: AtomicBool = AtomicBool::new(false);
+
+// when a resource became promotable, until its start finished
+static PROMOTABLE_SINCE: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
+static PROMOTION_LATENCY: Mutex<PromotionLatency> = Mutex::new(PromotionLatency::new());
+
+/// Upper bounds (in seconds) of the promotion latency histogram buckets
+pub const PROMOTION_LATENCY_BUCKETS: [f64; 10] =
+    [0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Histogram of the time from a resource becoming promotable ("may_promote") until its services got started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromotionLatency {
+    /// Cumulative counts per bucket of PROMOTION_LATENCY_BUCKETS
+    pub buckets: [u64; PROMOTION_LATENCY_BUCKETS.len()],
+    pub count: u64,
+    pub sum_secs: f64,
+}
+
+impl PromotionLatency {
+    const fn new() -> Self {
+        Self {
+            buckets: [0; PROMOTION_LATENCY_BUCKETS.len()],
+            count: 0,
+            sum_secs: 0.0,
+        }
+    }
+
+    fn observe(&mut self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        for (bucket, le) in self
+            .buckets
+            .iter_mut()
+            .zip(PROMOTION_LATENCY_BUCKETS.iter())
+        {
+            if secs <= *le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum_secs += secs;
+    }
+}
+
+/// The promotion latencies observed by all promoters on this node.
+pub fn promotion_latency() -> PromotionLatency {
+    PROMOTION_LATENCY
+        .lock()
+        .map(|l| l.clone())
+        .unwrap_or_else(|_| PromotionLatency::new())
+}
+
+pub(crate) fn set_promotable(name: &str, promotable: bool) {
+    if let Ok(mut since) = PROMOTABLE_SINCE.lock() {
+        if !promotable {
+            since.remove(name);
+        } else if !since.contains_key(name) {
+            since.insert(name.to_string(), Instant::now());
+        }
+    }
+}
+
+pub(crate) fn record_promotion(name: &str) {
+    let since = match PROMOTABLE_SINCE
+        .lock()
+        .ok()
+        .and_then(|mut s| s.remove(name))
+    {
+        Some(since) => since,
+        None => return,
+    };
+    let latency = since.elapsed();
+    info!(
+        "resource '{}' started {:.3}s after it became promotable",
+        name,
+        latency.as_secs_f64()
+    );
+    if let Ok(mut l) = PROMOTION_LATENCY.lock() {
+        l.observe(latency);
+    }
+}
+
+/// Returns if any promoter changed a systemd unit since the last call and resets the flag.
+pub fn take_units_changed() -> bool {
+    UNITS_CHANGED.swap(false, Ordering::SeqCst)
+}
+
+/// The role the promoters intend for their resources on this node: Primary if the services got started,
+/// Secondary otherwise. This might differ from the actual DRBD role (e.g., a manual promotion).
+pub fn intended_roles() -> BTreeMap<String, Role> {
+    INTENDED_ROLES
+        .lock()
+        .map(|roles| roles.clone())
+        .unwrap_or_default()
+}
+
+pub(crate) fn set_intended_role(name: &str, role: Option<Role>) {
+    if let Ok(mut roles) = INTENDED_ROLES.lock() {
+        match role {
+            Some(role) => roles.insert(name.to_string(), role),
+            None => roles.remove(name),
+        };
+    }
 }
 
 impl Promoter {
     pub fn new(cfg: PromoterConfig) -> Result<Self> {
-        let names = cfg.resources.keys().cloned().collect::<Vec<String>>();
+        let configured = cfg.clone();
+        let runner = SystemRunner;
+
+        for (name, res) in &cfg.resources {
+            res.check_start_hooks()
+                .map_err(|e| anyhow::anyhow!("resource '{}': {}", name, e))?;
+        }
+
+        let names = cfg
+            .resources
+            .iter()
+            .filter(|(_, res)| !res.dry_run)
+            .map(|(name, res)| {
+                (
+                    name.clone(),
+                    res.backing_device_timeout_secs.map(Duration::from_secs),
+                )
+            })
+            .collect::<Vec<_>>();
+        // dry-run resources are not adjusted, a previous state would never get updated
+        for (name, _) in cfg.resources.iter().filter(|(_, res)| res.dry_run) {
+            set_adjust_state(Path::new(ADJUST_STATE_DIR), name, None);
+        }
         trace!("Executing adjust_resources({:?})'", &names);
         if let Err(e) = adjust_resources(&names) {
             warn!("Could not adjust '{:?}': {}", names, e);
         }
         trace!("Executed adjust_resources({:?})'", &names);
 
+        // only after the adjust, before the resource might not be known to DRBD
+        let mut managed = HashSet::new();
         for (name, res) in &cfg.resources {
             // deprecated settings
             if !res.on_stop_failure.is_empty() {
                 warn!("'on-stop-failure' is deprecated and ignored!; use 'on-drbd-demote-failure'");
             }
 
-            info!("Checking DRBD options for resource '{}'", name);
-            if let Err(e) = check_resource(name, &res.on_quorum_loss) {
-                warn!("Could not execute DRBD options check: {}", e);
+            if !resource_managed(&runner, name, &res.managed_if) {
+                continue;
             }
-
-            if res.runner == Runner::Systemd {
-                let systemd_settings = SystemdSettings {
-                    dependencies_as: res.dependencies_as.clone(),
-                    target_as: res.target_as.clone(),
-                    failure_action: res.on_drbd_demote_failure.clone(),
-                };
-                generate_systemd_templates(
-                    name,
-                    &res.start,
-                    &systemd_settings,
-                    res.secondary_force,
-                )?;
+            if prepare_resource(&runner, name, res)? {
+                UNITS_CHANGED.store(true, Ordering::SeqCst);
             }
+            managed.insert(name.clone());
         }
 
-        Ok(Self { cfg })
+        Ok(Self {
+            cfg,
+            managed,
+            configured,
+            runner: Box::new(runner),
+        })
+    }
+}
+
+/// Checks the DRBD options and OCF agents of a managed resource and writes its systemd units.
+/// Returns true if any unit changed.
+fn prepare_resource(
+    runner: &dyn CommandRunner,
+    name: &str,
+    res: &PromoterOptResource,
+) -> Result<bool> {
+    info!("Checking DRBD options for resource '{}'", name);
+    if let Err(e) = check_resource(name, &res.on_quorum_loss) {
+        warn!("Could not execute DRBD options check: {}", e);
+    }
+
+    let ocf_root = res
+        .ocf_root()
+        .map_err(|e| anyhow::anyhow!("resource '{}': {}", name, e))?;
+
+    if res.validate_ocf {
+        info!("Validating OCF agents for resource '{}'", name);
+        validate_ocf_agents(runner, name, &res.start_services(), &ocf_root)?;
+    }
+
+    if res.runner == Runner::Systemd {
+        let systemd_settings = SystemdSettings {
+            dependencies_as: res.dependencies_as.clone(),
+            target_as: res.target_as.clone(),
+            failure_action: res.on_drbd_demote_failure.clone(),
+            ownership_markers: res.ownership_markers.then(|| name.to_string()),
+            escalate_notify: res.on_escalate_notify.clone(),
+            service_shim: drbd_service_shim(&res.drbd_script_path, DRBD_SCRIPT_PATHS),
+            ocf_root,
+        };
+        let changed = generate_systemd_templates(
+            name,
+            &res.start,
+            &systemd_settings,
+            res.secondary_force,
+            res.dry_run,
+        )?;
+        if !changed {
+            debug!("systemd units for resource '{}' unchanged", name);
+        }
+        return Ok(changed);
     }
+    Ok(false)
 }
 
 const MIN_SECS_PROMOTE: u64 = 20;
@@ -72,14 +258,16 @@ impl super::Plugin for Promoter {
     fn run(&self, rx: super::PluginReceiver) -> Result<()> {
         trace!("run: start");
 
+        let runner = self.runner.as_ref();
         let names = self.cfg.resources.keys().cloned().collect::<Vec<String>>();
+        let mut managed = self.managed.clone();
 
         // set default stop actions (i.e., reversed start)
         let cfg = {
             let mut cfg = self.cfg.clone();
             for res in cfg.resources.values_mut() {
                 if res.stop.is_empty() {
-                    res.stop = res.start.clone();
+                    res.stop = res.start_services();
                     res.stop.reverse();
                 }
             }
@@ -92,8 +280,11 @@ impl super::Plugin for Promoter {
         // top of the promoted Primary.
         // inserting the names into may_promote HashSet is tempting, but the first update might delete
         // them before the first ticker, so just start them as usual and be done
+        for name in &managed {
+            set_intended_role(name, Some(Role::Secondary));
+        }
         for name in &names {
-            if !try_initial_target_start(name) {
+            if !managed.contains(name) || !try_initial_target_start(runner, name) {
                 continue;
             }
             if let Some(res) = cfg.resources.get(name) {
@@ -102,58 +293,97 @@ impl super::Plugin for Promoter {
                 let sleep_s = get_preferred_nodes_sleep_s(&res.preferred_nodes);
                 thread::sleep(time::Duration::from_secs(sleep_s));
 
-                try_start_stop_actions(name, &res.start, &res.stop, &res.runner);
+                try_start_stop_actions(runner, name, res);
             }
         }
 
         let names_filter = plugin::namefilter(&names);
         let type_exists = plugin::typefilter(&EventType::Exists);
         let type_change = plugin::typefilter(&EventType::Change);
+        let type_create = plugin::typefilter(&EventType::Create);
 
         let ticker = crossbeam_channel::tick(Duration::from_secs(MIN_SECS_PROMOTE));
-        let mut last_start = Instant::now() - Duration::from_secs(MIN_SECS_PROMOTE + 1);
+        let never_started = Instant::now() - Duration::from_secs(MIN_SECS_PROMOTE + 1);
+        let mut last_start = never_started;
         let mut may_promote: HashSet<String> = HashSet::new();
+        // promotion groups that already started a resource since the last tick
+        let mut groups_in_flight: HashSet<String> = HashSet::new();
+
+        let status = Mutex::new(StatusState::default());
+        let stop_status = AtomicBool::new(false);
+        let status_listener = match &cfg.status_socket {
+            Some(path) => match bind_status_socket(path) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    warn!("Could not bind status socket '{}': {}", path.display(), e);
+                    None
+                }
+            },
+            None => None,
+        };
 
-        loop {
-            crossbeam_channel::select! {
-                recv(ticker) -> _ => {
-                    for name in &may_promote {
-                        if let Ok(false) = systemd::is_active(&systemd::escaped_services_target(name)) {
-                            let res = cfg
-                                .resources
-                                .get(name)
-                                .expect("Can not happen, name filter is built from the cfg");
-
-                            last_start = Instant::now();
-                            // see start_actions comments in process_drbd_event()
-                            // we do not manipulate the may_promote state from here
-                            try_start_stop_actions(&name, &res.start, &res.stop, &res.runner);
-                        }
-                    }
-                },
-                recv(rx) -> msg => match msg {
-                    Ok(update) => {
-                        if (type_change(&update) || type_exists(&update)) && names_filter(&update) {
-                            process_drbd_event(&update, &cfg, &mut last_start, &mut may_promote);
-                        }
+        thread::scope(|s| {
+            if let Some(listener) = status_listener {
+                s.spawn(|| serve_status(listener, &stop_status, runner, &cfg, &status));
+            }
+
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => {
+                        process_ticker(runner, &cfg, &may_promote, &mut last_start, &mut groups_in_flight);
                     },
-                    Err(_) => break,
-                },
+                    recv(rx) -> msg => match msg {
+                        Ok(update) => {
+                            if (type_change(&update) || type_exists(&update) || type_create(&update))
+                                && names_filter(&update)
+                                && check_managed(runner, &update, &cfg, &mut managed)
+                            {
+                                process_drbd_event(runner, &update, &cfg, &mut last_start, &mut may_promote, &mut groups_in_flight);
+                            }
+                        },
+                        Err(_) => break,
+                    },
+                }
+
+                if let Ok(mut status) = status.lock() {
+                    status.may_promote = may_promote.iter().cloned().collect();
+                    status.last_start = (last_start != never_started).then_some(last_start);
+                }
             }
+
+            // wake up the listener, it checks for stop_status after every accept
+            stop_status.store(true, Ordering::Relaxed);
+            if let Some(path) = &cfg.status_socket {
+                let _ = UnixStream::connect(path);
+            }
+        });
+        if let Some(path) = &cfg.status_socket {
+            let _ = fs::remove_file(path);
         }
 
         // stop services if configured
         for (name, res) in cfg.resources {
+            // the resource might not be configured (or adjusted) by the next promoter
+            set_adjust_state(Path::new(ADJUST_STATE_DIR), &name, None);
+            if !managed.contains(&name) {
+                continue;
+            }
             if res.stop_services_on_exit {
                 let shutdown = || -> Result<()> {
-                    fs::remove_file(escaped_services_target_dir(&name).join(SYSTEMD_BEFORE_CONF))?;
-                    systemd::daemon_reload()?;
-                    stop_actions(&name, &res.stop, &res.runner)
+                    if !res.dry_run {
+                        fs::remove_file(
+                            escaped_services_target_dir(&name).join(SYSTEMD_BEFORE_CONF),
+                        )?;
+                        systemd::daemon_reload(runner)?;
+                    }
+                    stop_resource(runner, &name, &res)
                 };
                 if let Err(e) = shutdown() {
                     warn!("Stopping '{}' failed: {}", name, e);
                 }
             }
+            set_intended_role(&name, None);
+            set_promotable(&name, false);
         }
 
         trace!("run: exit");
@@ -161,25 +391,119 @@ impl super::Plugin for Promoter {
     }
 
     fn get_config(&self) -> PluginCfg {
-        PluginCfg::Promoter(self.cfg.clone())
+        PluginCfg::Promoter(self.configured.clone())
     }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
 pub struct PromoterConfig {
     #[serde(default)]
     pub resources: BTreeMap<String, PromoterOptResource>,
     pub id: Option<String>, // ! deprecated !
+    pub status_socket: Option<PathBuf>,
+}
+
+// state of the event loop, shared with the status socket listener
+#[derive(Default)]
+struct StatusState {
+    may_promote: BTreeSet<String>,
+    last_start: Option<Instant>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PromoterStatus {
+    may_promote: BTreeSet<String>,
+    last_start_secs_ago: Option<u64>,
+    resources: BTreeMap<String, ResourceStatus>,
+}
+
+#[derive(Serialize)]
+struct ResourceStatus {
+    // only known for the systemd runner
+    started: Option<bool>,
+}
+
+fn bind_status_socket(path: &Path) -> Result<UnixListener> {
+    // stale socket of a previous run
+    if let Ok(meta) = fs::metadata(path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+fn serve_status(
+    listener: UnixListener,
+    stop: &AtomicBool,
+    runner: &dyn CommandRunner,
+    cfg: &PromoterConfig,
+    state: &Mutex<StatusState>,
+) {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("serve_status: could not accept connection: {}", e);
+                continue;
+            }
+        };
+        let result =
+            status_json(runner, cfg, state).and_then(|json| Ok(writeln!(stream, "{}", json)?));
+        if let Err(e) = result {
+            warn!("serve_status: could not send status: {}", e);
+        }
+    }
+    trace!("serve_status: exit");
+}
+
+fn status_json(
+    runner: &dyn CommandRunner,
+    cfg: &PromoterConfig,
+    state: &Mutex<StatusState>,
+) -> Result<String> {
+    let (may_promote, last_start) = {
+        let state = state
+            .lock()
+            .map_err(|_| anyhow::anyhow!("status: tried accessing a poisoned lock"))?;
+        (state.may_promote.clone(), state.last_start)
+    };
+
+    let resources = cfg
+        .resources
+        .iter()
+        .map(|(name, res)| {
+            let started = match res.runner {
+                Runner::Systemd => {
+                    systemd::is_active(runner, &systemd::escaped_services_target(name)).ok()
+                }
+                Runner::Shell => None,
+            };
+            (name.clone(), ResourceStatus { started })
+        })
+        .collect();
+
+    let status = PromoterStatus {
+        may_promote,
+        last_start_secs_ago: last_start.map(|t| t.elapsed().as_secs()),
+        resources,
+    };
+    Ok(serde_json::to_string(&status)?)
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct PromoterOptResource {
     #[serde(default)]
-    pub start: Vec<String>,
+    pub start: Vec<StartEntry>,
     #[serde(default)]
     pub stop: Vec<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub on_stop_failure: String, // ! deprecated !
     #[serde(default)]
     pub stop_services_on_exit: bool,
@@ -193,12 +517,164 @@ pub struct PromoterOptResource {
     pub on_drbd_demote_failure: SystemdFailureAction,
     #[serde(default = "default_promote_sleep")]
     pub sleep_before_promote_factor: u32,
+    // upper bound of the (scaled) sleep before promotion
+    pub max_sleep_before_promote_secs: Option<u64>,
+    // how long to wait for backing devices on start, forever if unset
+    pub backing_device_timeout_secs: Option<u64>,
     #[serde(default)]
-    pub preferred_nodes: Vec<String>,
+    pub preferred_nodes: Vec<PreferredNode>,
     #[serde(default = "default_secondary_force")]
     pub secondary_force: bool,
     #[serde(default)]
     pub on_quorum_loss: QuorumLossPolicy,
+    // None: keep the services running, IO fails (or blocks) as configured in DRBD
+    pub on_no_data_accessible: Option<QuorumLossPolicy>,
+    #[serde(default)]
+    pub ownership_markers: bool,
+    pub on_escalate_notify: Option<String>,
+    #[serde(default)]
+    pub start_retries: u32,
+    #[serde(default = "default_start_retry_interval")]
+    pub start_retry_interval_secs: u64,
+    pub start_timeout_secs: Option<u64>,
+    pub stop_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub managed_if: BTreeMap<String, String>,
+    pub on_promote: Option<String>,
+    pub on_stop: Option<String>,
+    #[serde(default)]
+    pub min_connected_peers: u32,
+    #[serde(default)]
+    pub dry_run: bool,
+    pub candidate_script: Option<String>,
+    pub promotion_group: Option<String>,
+    #[serde(default)]
+    pub validate_ocf: bool,
+    // directory containing drbd-service-shim.sh, autodetected if unset
+    pub drbd_script_path: Option<PathBuf>,
+    // OCF_ROOT of the OCF agents, autodetected if unset
+    pub ocf_root: Option<PathBuf>,
+}
+
+/// An item of the start list: a systemd unit or an OCF agent, optionally with additional ordering
+/// dependencies for its generated unit.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum StartEntry {
+    Plain(String),
+    #[serde(rename_all = "kebab-case")]
+    Extended {
+        service: String,
+        #[serde(default)]
+        after: Vec<String>,
+        #[serde(default)]
+        before: Vec<String>,
+        #[serde(default)]
+        exec_start_pre: Vec<String>,
+        #[serde(default)]
+        exec_stop_post: Vec<String>,
+    },
+}
+
+impl StartEntry {
+    /// The unit or OCF agent to start.
+    pub fn service(&self) -> &str {
+        match self {
+            Self::Plain(service) => service,
+            Self::Extended { service, .. } => service,
+        }
+    }
+
+    fn after(&self) -> &[String] {
+        match self {
+            Self::Plain(_) => &[],
+            Self::Extended { after, .. } => after,
+        }
+    }
+
+    fn before(&self) -> &[String] {
+        match self {
+            Self::Plain(_) => &[],
+            Self::Extended { before, .. } => before,
+        }
+    }
+
+    fn exec_start_pre(&self) -> &[String] {
+        match self {
+            Self::Plain(_) => &[],
+            Self::Extended { exec_start_pre, .. } => exec_start_pre,
+        }
+    }
+
+    fn exec_stop_post(&self) -> &[String] {
+        match self {
+            Self::Plain(_) => &[],
+            Self::Extended { exec_stop_post, .. } => exec_stop_post,
+        }
+    }
+}
+
+impl From<&str> for StartEntry {
+    fn from(service: &str) -> Self {
+        Self::Plain(service.to_string())
+    }
+}
+
+impl fmt::Display for StartEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.service())
+    }
+}
+
+impl PromoterOptResource {
+    /// The units and OCF agents of the start list.
+    pub fn start_services(&self) -> Vec<String> {
+        self.start.iter().map(|s| s.service().to_string()).collect()
+    }
+
+    /// The OCF root containing the OCF agents of the start list: 'ocf-root', $OCF_ROOT, or the
+    /// first of the standard locations that contains all of them.
+    pub fn ocf_root(&self) -> Result<PathBuf> {
+        let ocf_pattern = Regex::new(OCF_PATTERN)?;
+        let agents: Vec<(String, String)> = self
+            .start
+            .iter()
+            .filter_map(|s| ocf_pattern.captures(s.service().trim()))
+            .map(|ocf| (ocf[1].to_string(), ocf[2].to_string()))
+            .collect();
+        let configured = self
+            .ocf_root
+            .clone()
+            .or_else(|| env::var_os("OCF_ROOT").map(PathBuf::from));
+        systemd::ocf_root(configured.as_deref(), systemd::OCF_ROOTS, &agents)
+    }
+
+    /// Rejects exec hooks on start entries that do not get a service unit (e.g., mount units), there
+    /// is no [Service] section they could go to.
+    pub fn check_start_hooks(&self) -> Result<()> {
+        let ocf_pattern = Regex::new(OCF_PATTERN)?;
+        for entry in &self.start {
+            if entry.exec_start_pre().is_empty() && entry.exec_stop_post().is_empty() {
+                continue;
+            }
+            let service = entry.service().trim();
+            if !service.ends_with(".service") && !ocf_pattern.is_match(service) {
+                return Err(anyhow::anyhow!(
+                    "'{}': 'exec-start-pre' and 'exec-stop-post' are only supported for services and OCF agents",
+                    service
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn start_timeout(&self) -> Option<Duration> {
+        self.start_timeout_secs.map(Duration::from_secs)
+    }
+
+    fn stop_timeout(&self) -> Option<Duration> {
+        self.stop_timeout_secs.map(Duration::from_secs)
+    }
 }
 
 fn default_promote_sleep() -> u32 {
@@ -207,23 +683,117 @@ fn default_promote_sleep() -> u32 {
 fn default_secondary_force() -> bool {
     true
 }
+fn default_start_retry_interval() -> u64 {
+    5
+}
 
-fn systemd_stop(unit: &str) -> Result<()> {
+fn systemd_stop(runner: &dyn CommandRunner, unit: &str, timeout: Option<Duration>) -> Result<()> {
     info!("systemd_stop: systemctl stop {}", unit);
-    plugin::map_status(
-        Command::new("systemctl")
-            .stdin(Stdio::null())
-            .arg("stop")
-            .arg(unit)
-            .status(),
-    )
+    plugin::map_status(runner.status_timeout("systemctl", &["stop", unit], timeout))
+}
+
+fn process_ticker(
+    runner: &dyn CommandRunner,
+    cfg: &PromoterConfig,
+    may_promote: &HashSet<String>,
+    last_start: &mut Instant,
+    groups_in_flight: &mut HashSet<String>,
+) {
+    // starts are synchronous, so whatever got started since the last tick is done by now
+    groups_in_flight.clear();
+
+    for name in may_promote {
+        if let Ok(false) = systemd::is_active(runner, &systemd::escaped_services_target(name)) {
+            let res = cfg
+                .resources
+                .get(name)
+                .expect("Can not happen, name filter is built from the cfg");
+            if !candidate_allows(runner, name, res) {
+                continue;
+            }
+            if !claim_promotion_group(name, res, groups_in_flight) {
+                continue;
+            }
+
+            *last_start = Instant::now();
+            // see start_actions comments in process_drbd_event()
+            // we do not manipulate the may_promote state from here
+            try_start_stop_actions(runner, name, res);
+        }
+    }
+}
+
+/// Returns true if the resource of the update is managed. A resource that is not managed (yet) gets
+/// 'managed-if' evaluated again when DRBD reports it (again), e.g., because it did not exist when the
+/// plugin started.
+fn check_managed(
+    runner: &dyn CommandRunner,
+    update: &PluginUpdate,
+    cfg: &PromoterConfig,
+    managed: &mut HashSet<String>,
+) -> bool {
+    let name = update.get_name();
+    if managed.contains(&name) {
+        return true;
+    }
+    let res = match (update, cfg.resources.get(&name)) {
+        (PluginUpdate::Resource(u), Some(res))
+            if matches!(u.event_type, EventType::Exists | EventType::Create) =>
+        {
+            res
+        }
+        _ => return false,
+    };
+    if !resource_managed(runner, &name, &res.managed_if) {
+        return false;
+    }
+    match prepare_resource(runner, &name, res) {
+        Ok(true) if !res.dry_run => {
+            if let Err(e) = systemd::daemon_reload(runner) {
+                warn!("resource '{}': could not reload systemd: {}", name, e);
+                return false;
+            }
+        }
+        Ok(_) => (),
+        Err(e) => {
+            warn!("resource '{}': not managing it: {}", name, e);
+            return false;
+        }
+    }
+    info!("resource '{}': managing it from now on", name);
+    set_intended_role(&name, Some(Role::Secondary));
+    managed.insert(name);
+    true
+}
+
+// returns false if another resource of the same promotion group was started since the last tick,
+// the resource then stays in may_promote and the ticker tries again
+fn claim_promotion_group(
+    name: &str,
+    res: &PromoterOptResource,
+    groups_in_flight: &mut HashSet<String>,
+) -> bool {
+    let group = match &res.promotion_group {
+        Some(group) => group,
+        None => return true,
+    };
+    if !groups_in_flight.insert(group.clone()) {
+        info!(
+            "resource '{}' may promote, but promotion group '{}' is busy, deferring",
+            name, group
+        );
+        return false;
+    }
+    true
 }
 
 fn process_drbd_event(
+    runner: &dyn CommandRunner,
     r: &Arc<PluginUpdate>,
     cfg: &PromoterConfig,
     last_start: &mut Instant,
     may_promote: &mut HashSet<String>,
+    groups_in_flight: &mut HashSet<String>,
 ) {
     let name = r.get_name();
     let res = cfg
@@ -233,16 +803,39 @@ fn process_drbd_event(
 
     match r.as_ref() {
         PluginUpdate::Resource(u) => {
-            match u.new.may_promote {
+            // only holds back the promotion, the other reactions (e.g., a forced demote) still apply
+            let mut promotable = u.new.may_promote;
+            if promotable && !enough_connected_peers(&u.resource, res.min_connected_peers) {
+                info!(
+                    "run: resource '{}' may promote, but has less than {} connected peers, not promoting",
+                    name, res.min_connected_peers
+                );
+                promotable = false;
+            }
+            match promotable {
                 true => may_promote.insert(name.clone()),
                 false => may_promote.remove(&name),
             };
-            if !u.old.may_promote && u.new.may_promote {
+            set_promotable(&name, promotable);
+            if !u.old.may_promote && promotable {
+                if has_candidate_script(res) {
+                    // the script replaces the built-in stagger, if it defers, the ticker
+                    // consults it again
+                    if candidate_allows(runner, &name, res)
+                        && claim_promotion_group(&name, res, groups_in_flight)
+                    {
+                        *last_start = Instant::now();
+                        try_start_stop_actions(runner, &name, res);
+                    }
+                    return;
+                }
+
                 let sleep_millis = get_sleep_before_promote_ms(
                     &u.resource,
                     &res.preferred_nodes,
                     &res.on_quorum_loss,
                     res.sleep_before_promote_factor,
+                    res.max_sleep_before_promote_secs,
                 );
 
                 // no saturating_sub on old rust
@@ -258,6 +851,9 @@ fn process_drbd_event(
                     debug!("got may_promote but start interval for '{}' too fast", name);
                     return;
                 }
+                if !claim_promotion_group(&name, res, groups_in_flight) {
+                    return;
+                }
 
                 info!(
                     "run: resource '{}' may promote after {}ms",
@@ -272,7 +868,7 @@ fn process_drbd_event(
                 // - start_actions is inherently racy
                 // - it really does not improve things a lot
                 // - better have only one source here that reflects events2 and only events2 at the time
-                try_start_stop_actions(&name, &res.start, &res.stop, &res.runner);
+                try_start_stop_actions(runner, &name, res);
             } else if u.old.role == Role::Primary
                 && u.new.role == Role::Secondary
                 && res.on_quorum_loss == QuorumLossPolicy::Freeze
@@ -287,22 +883,43 @@ fn process_drbd_event(
                     "resource '{}' got forced to Secondary while frozen, stopping services",
                     name
                 );
-                if let Err(e) = stop_actions(&name, &res.stop, &res.runner) {
+                if let Err(e) = stop_resource(runner, &name, res) {
                     warn!("Stopping '{}' failed: {}", name, e);
                 }
             }
         }
         PluginUpdate::Device(u) => {
+            // whatever the published adjust progress was, DRBD got the device attached now
+            if u.old.disk_state != u.new.disk_state
+                && !matches!(
+                    u.new.disk_state,
+                    DiskState::Diskless
+                        | DiskState::Attaching
+                        | DiskState::Detaching
+                        | DiskState::Failed
+                )
+            {
+                set_adjust_state(Path::new(ADJUST_STATE_DIR), &name, None);
+            }
+
+            if let Some(policy) = &res.on_no_data_accessible {
+                let mut before = u.resource.clone();
+                if let Some(d) = before.devices.iter_mut().find(|d| d.volume == u.volume) {
+                    d.disk_state = u.old.disk_state.clone();
+                }
+                no_data_accessible_transition(runner, &name, res, policy, &before, &u.resource);
+            }
+
             if u.old.quorum && !u.new.quorum {
                 info!("run: resource '{}' lost quorum", name);
                 match res.on_quorum_loss {
                     QuorumLossPolicy::Freeze => {
-                        if let Err(e) = freeze_actions(&name, State::Freeze, &res.runner) {
+                        if let Err(e) = freeze_actions(runner, &name, State::Freeze, res) {
                             warn!("Freezing '{}' failed: {}", name, e);
                         }
                     }
                     QuorumLossPolicy::Shutdown => {
-                        if let Err(e) = stop_actions(&name, &res.stop, &res.runner) {
+                        if let Err(e) = stop_resource(runner, &name, res) {
                             warn!("Stopping '{}' failed: {}", name, e);
                         }
                     }
@@ -313,12 +930,20 @@ fn process_drbd_event(
                 && u.resource.role == Role::Primary
             {
                 info!("resource '{}' gained quorum, thawing Primary", name);
-                if let Err(e) = freeze_actions(&name, State::Thaw, &res.runner) {
+                if let Err(e) = freeze_actions(runner, &name, State::Thaw, res) {
                     warn!("Thawing '{}' failed: {}", name, e);
                 }
             }
         }
         PluginUpdate::PeerDevice(u) => {
+            if let Some(policy) = &res.on_no_data_accessible {
+                let mut before = u.resource.clone();
+                if let Some(pd) = before.get_peerdevice_mut(u.peer_node_id, u.volume) {
+                    pd.peer_disk_state = u.old.peer_disk_state.clone();
+                }
+                no_data_accessible_transition(runner, &name, res, policy, &before, &u.resource);
+            }
+
             #[allow(clippy::if_same_then_else)]
             if res.preferred_nodes.is_empty() {
                 return;
@@ -337,8 +962,9 @@ fn process_drbd_event(
                     return;
                 }
             };
-            let peer_pos = match res.preferred_nodes.iter().position(|n| n == &peer_name) {
-                Some(pos) => pos,
+            // compared like the delays before the promotion
+            let peer_sleep = match preferred_node_position(&res.preferred_nodes, &peer_name) {
+                Some(_) => preferred_nodes_sleep_s(&res.preferred_nodes, &peer_name),
                 None => {
                     // not in the list, it can not be better
                     debug!(
@@ -356,46 +982,64 @@ fn process_drbd_event(
                     return;
                 }
             };
-            let node_pos = match res.preferred_nodes.iter().position(|n| n == &node_name) {
-                Some(pos) => pos,
-                None => res.preferred_nodes.len(),
-            };
+            let node_sleep = preferred_nodes_sleep_s(&res.preferred_nodes, &node_name);
 
-            if peer_pos < node_pos {
+            if peer_sleep < node_sleep {
                 info!("run: resource '{}' has a new preferred node ('{}'), stopping services locally ('{}')", name, peer_name, node_name);
-                if let Err(e) = stop_actions(&name, &res.stop, &res.runner) {
+                if let Err(e) = stop_resource(runner, &name, res) {
                     warn!("Stopping '{}' failed: {}", name, e);
                 }
             }
         }
+        PluginUpdate::Connection(u) => {
+            if u.old.peer_role != Role::Primary && u.new.peer_role == Role::Primary {
+                // another node won the race, don't let the ticker try it again. If the peer gives
+                // it up again, a may_promote change makes it a candidate again.
+                if may_promote.remove(&name) {
+                    info!(
+                        "run: peer '{}' became Primary for resource '{}', standing down",
+                        u.new.conn_name, name
+                    );
+                }
+                set_promotable(&name, false);
+                return;
+            }
+            if res.min_connected_peers == 0 {
+                return;
+            }
+
+            // the resource might have been held back because of missing peers, if so, the
+            // ticker starts it
+            if u.resource.may_promote
+                && enough_connected_peers(&u.resource, res.min_connected_peers)
+            {
+                may_promote.insert(name);
+            } else {
+                may_promote.remove(&name);
+            }
+        }
         _ => (),
     }
 }
 
-fn systemd_start(unit: &str) -> Result<()> {
-    // we need to make sure that stdin is null, otherwise systemd wants to add some
-    // watches for password files. https://github.com/systemd/systemd/blob/fc5037e7d7b35d234720dcf06701a89c66c73adc/src/tty-ask-password-agent/tty-ask-password-agent.c#L367
+fn enough_connected_peers(resource: &Resource, min_connected_peers: u32) -> bool {
+    let connected = resource
+        .connections
+        .iter()
+        .filter(|c| c.connection == ConnectionState::Connected)
+        .count();
+    connected >= min_connected_peers as usize
+}
 
-    // we really don't care
-    let _ = Command::new("systemctl")
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .arg("reset-failed")
-        .arg(unit)
-        .status();
+fn systemd_start(runner: &dyn CommandRunner, unit: &str, timeout: Option<Duration>) -> Result<()> {
+    // we really don't care, output is captured to keep it quiet
+    let _ = runner.output("systemctl", &["reset-failed", unit]);
 
     info!("systemd_start: systemctl start {}", unit);
-    plugin::map_status(
-        Command::new("systemctl")
-            .stdin(Stdio::null())
-            .arg("start")
-            .arg(unit)
-            .status(),
-    )?;
+    plugin::map_status(runner.status_timeout("systemctl", &["start", unit], timeout))?;
     // this is inherently racy, systemd might take some time to "propagate" the actual state
     // still, we might catch it already here, otherwise we will check for the actual state in the "ticker"
-    if !systemd::is_active(unit)? {
+    if !systemd::is_active(runner, unit)? {
         return Err(anyhow::anyhow!(
             "systemd_start: unit '{}' is not active",
             unit
@@ -405,8 +1049,8 @@ fn systemd_start(unit: &str) -> Result<()> {
     Ok(())
 }
 
-fn systemd_freeze_thaw(unit: &str, to: State) -> Result<()> {
-    let services = get_target_services(unit)?;
+fn systemd_freeze_thaw(runner: &dyn CommandRunner, unit: &str, to: State) -> Result<()> {
+    let services = get_target_services(runner, unit)?;
     if services.is_empty() {
         return Err(anyhow::anyhow!("services list empty"));
     }
@@ -424,13 +1068,7 @@ fn systemd_freeze_thaw(unit: &str, to: State) -> Result<()> {
     );
 
     for service_name in services.iter().filter(|x| !x.ends_with(".mount")) {
-        if let Err(e) = plugin::map_status(
-            Command::new("systemctl")
-                .stdin(Stdio::null())
-                .arg(action)
-                .arg(service_name.clone())
-                .status(),
-        ) {
+        if let Err(e) = plugin::map_status(runner.status("systemctl", &[action, service_name])) {
             warn!("systemd_freeze_thaw: 'systemctl {} {}' failed ('{}'), this might be fine if there is no process in that unit", action, service_name, e);
         }
     }
@@ -438,69 +1076,322 @@ fn systemd_freeze_thaw(unit: &str, to: State) -> Result<()> {
     Ok(())
 }
 
-fn persist_journal() {
-    let _ = Command::new("journalctl")
-        .stdin(Stdio::null())
-        .arg("--flush")
-        .arg("--sync")
-        .status();
+fn persist_journal(runner: &dyn CommandRunner) {
+    let _ = runner.status("journalctl", &["--flush", "--sync"]);
 }
 
-fn action(what: &str, to: State, how: &Runner) -> Result<()> {
+fn action(
+    runner: &dyn CommandRunner,
+    what: &str,
+    to: State,
+    how: &Runner,
+    timeout: Option<Duration>,
+) -> Result<()> {
     match how {
-        Runner::Shell => plugin::system(what),
+        Runner::Shell => plugin::system(runner, what, timeout),
         Runner::Systemd => match to {
-            State::Start => systemd_start(what),
-            State::Stop => systemd_stop(what),
-            State::Freeze | State::Thaw => systemd_freeze_thaw(what, to),
+            State::Start => systemd_start(runner, what, timeout),
+            State::Stop => systemd_stop(runner, what, timeout),
+            State::Freeze | State::Thaw => systemd_freeze_thaw(runner, what, to),
         },
     }
 }
 
-fn try_start_stop_actions(name: &str, start: &[String], stop: &[String], how: &Runner) {
-    if let Err(e) = start_actions(name, start, how) {
-        warn!("Starting '{}' failed: {}", name, e);
-        if let Err(e) = stop_actions(name, stop, how) {
-            warn!("Stopping '{}' failed: {}", name, e);
-        }
+fn try_start_stop_actions(runner: &dyn CommandRunner, name: &str, res: &PromoterOptResource) {
+    // failures reset this to Secondary via stop_resource()
+    set_intended_role(name, Some(Role::Primary));
+    if res.dry_run {
+        dry_run_actions(name, "start", &res.start_services(), &res.runner);
+        return;
     }
-}
 
-fn start_actions(name: &str, actions: &[String], how: &Runner) -> Result<()> {
-    match how {
-        Runner::Shell => {
-            for a in actions {
-                action(a, State::Start, how)?;
+    let retry_interval = Duration::from_secs(res.start_retry_interval_secs);
+    match start_actions(
+        runner,
+        name,
+        &res.start_services(),
+        &res.runner,
+        res.start_retries,
+        retry_interval,
+        res.start_timeout(),
+    ) {
+        Ok(()) => {
+            record_promotion(name);
+            run_hook(runner, "on-promote", &res.on_promote, name)
+        }
+        Err(e) => {
+            warn!("Starting '{}' failed: {}", name, e);
+            if let Err(e) = stop_resource(runner, name, res) {
+                warn!("Stopping '{}' failed: {}", name, e);
             }
-            Ok(())
         }
-        Runner::Systemd => action(&systemd::escaped_services_target(name), State::Start, how),
     }
 }
 
-fn stop_actions(name: &str, actions: &[String], how: &Runner) -> Result<()> {
-    info!(
-        "stop_actions (could trigger failure actions (e.g., reboot)): {}",
-        name
-    );
+fn stop_resource(runner: &dyn CommandRunner, name: &str, res: &PromoterOptResource) -> Result<()> {
+    set_intended_role(name, Some(Role::Secondary));
+    if res.dry_run {
+        dry_run_actions(name, "stop", &res.stop, &res.runner);
+        return Ok(());
+    }
+
+    stop_actions(runner, name, &res.stop, &res.runner, res.stop_timeout())?;
+    if res.runner == Runner::Systemd {
+        ensure_demoted(runner, name, res.secondary_force);
+    }
+    run_hook(runner, "on-stop", &res.on_stop, name);
+    Ok(())
+}
+
+/// Demotes the resource directly if it is still Primary after its services were stopped.
+///
+/// Stopping the target demotes via the ExecStop of drbd-promote@.service, if that is broken (e.g., the shim is
+/// missing), the demote would otherwise fail silently and the node stays Primary.
+fn ensure_demoted(runner: &dyn CommandRunner, name: &str, secondary_force: bool) {
+    match get_primary(runner, name) {
+        Ok(PrimaryOn::Local) => (),
+        Ok(_) => return,
+        Err(e) => {
+            warn!(
+                "ensure_demoted: could not verify that '{}' got demoted: {}",
+                name, e
+            );
+            return;
+        }
+    }
+
+    let force = secondary_force
+        && drbd::get_drbd_versions(runner).is_ok_and(|v| v.kmod >= drbd::MIN_KMOD_SECONDARY_FORCE);
+    let args = if force {
+        vec!["secondary", "--force", name]
+    } else {
+        vec!["secondary", name]
+    };
+    error!(
+        "ensure_demoted: '{}' is still Primary after stopping its services, falling back to 'drbdadm {}'",
+        name,
+        args.join(" ")
+    );
+    if let Err(e) = plugin::map_status(runner.status("drbdadm", &args)) {
+        error!("ensure_demoted: demoting '{}' failed: {}", name, e);
+    }
+}
+
+// hooks and candidate scripts must not hang forever, the latter even block failover
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn run_hook(runner: &dyn CommandRunner, hook: &str, cmd: &Option<String>, name: &str) {
+    let cmd = match cmd {
+        Some(cmd) if !cmd.is_empty() => cmd,
+        _ => return,
+    };
+
+    info!("run_hook: executing {} hook for '{}'", hook, name);
+    // like umh handlers, hooks run in the background and only their exit status gets logged
+    let cmd = with_resource_env(name, cmd);
+    let (hook_name, res_name) = (hook.to_string(), name.to_string());
+    let done = Box::new(move |status| {
+        if let Err(e) = plugin::map_status(status) {
+            warn!("run_hook: {} for '{}' failed: {}", hook_name, res_name, e);
+        }
+    });
+    if let Err(e) = runner.spawn_timeout("sh", &["-c", &cmd], Some(HOOK_TIMEOUT), done) {
+        warn!("run_hook: could not spawn {} for '{}': {}", hook, name, e);
+    }
+}
+
+fn has_candidate_script(res: &PromoterOptResource) -> bool {
+    matches!(&res.candidate_script, Some(script) if !script.is_empty())
+}
+
+/// Asks the candidate script whether the resource should be promoted on this node, true if there
+/// is no script.
+fn candidate_allows(runner: &dyn CommandRunner, name: &str, res: &PromoterOptResource) -> bool {
+    if !has_candidate_script(res) {
+        return true;
+    }
+    let script = res.candidate_script.as_deref().unwrap_or_default();
+
+    match plugin::system(runner, &with_resource_env(name, script), Some(HOOK_TIMEOUT)) {
+        Ok(()) => true,
+        Err(e) => {
+            info!(
+                "candidate_allows: candidate script deferred promotion of '{}': {}",
+                name, e
+            );
+            false
+        }
+    }
+}
+
+// exports the resource and node name for shell commands
+fn with_resource_env(name: &str, cmd: &str) -> String {
+    let node_name = utils::uname_n().unwrap_or_default();
+    format!(
+        "export DRBD_RES_NAME={} DRBD_NODE_NAME={}; {}",
+        shell_words::quote(name),
+        shell_words::quote(&node_name),
+        cmd
+    )
+}
+
+fn start_actions(
+    runner: &dyn CommandRunner,
+    name: &str,
+    actions: &[String],
+    how: &Runner,
+    retries: u32,
+    retry_interval: Duration,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let attempts = retries + 1;
+    let mut attempt = 1;
+    loop {
+        info!(
+            "start_actions: starting '{}' (attempt {}/{})",
+            name, attempt, attempts
+        );
+        match start_actions_once(runner, name, actions, how, timeout) {
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "start_actions: starting '{}' failed (attempt {}/{}), retrying in {}s: {}",
+                    name,
+                    attempt,
+                    attempts,
+                    retry_interval.as_secs(),
+                    e
+                );
+                thread::sleep(retry_interval);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn start_actions_once(
+    runner: &dyn CommandRunner,
+    name: &str,
+    actions: &[String],
+    how: &Runner,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    match how {
+        Runner::Shell => {
+            for a in actions {
+                action(runner, a, State::Start, how, timeout)?;
+            }
+            Ok(())
+        }
+        Runner::Systemd => action(
+            runner,
+            &systemd::escaped_services_target(name),
+            State::Start,
+            how,
+            timeout,
+        ),
+    }
+}
+
+fn stop_actions(
+    runner: &dyn CommandRunner,
+    name: &str,
+    actions: &[String],
+    how: &Runner,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    info!(
+        "stop_actions (could trigger failure actions (e.g., reboot)): {}",
+        name
+    );
 
     match how {
         Runner::Shell => {
             for a in actions {
-                action(a, State::Stop, how)?;
+                action(runner, a, State::Stop, how, timeout)?;
             }
             Ok(())
         }
         Runner::Systemd => {
             let target = systemd::escaped_services_target(name);
             info!("stop_actions: stopping '{}'", target);
-            persist_journal();
-            action(&target, State::Stop, how)
+            persist_journal(runner);
+            action(runner, &target, State::Stop, how, timeout)
+        }
+    }
+}
+
+// a volume has accessible data if it has a local disk or an UpToDate peer that can serve it
+fn data_accessible(resource: &Resource) -> bool {
+    resource.devices.iter().all(|d| {
+        !matches!(
+            d.disk_state,
+            DiskState::Diskless | DiskState::Detaching | DiskState::Failed
+        ) || resource
+            .connections
+            .iter()
+            .flat_map(|c| &c.peerdevices)
+            .any(|pd| pd.volume == d.volume && pd.peer_disk_state == DiskState::UpToDate)
+    })
+}
+
+// freezes/stops the services of a Primary that lost access to its data, and thaws them once it is back
+fn no_data_accessible_transition(
+    runner: &dyn CommandRunner,
+    name: &str,
+    res: &PromoterOptResource,
+    policy: &QuorumLossPolicy,
+    before: &Resource,
+    after: &Resource,
+) {
+    if after.role != Role::Primary {
+        return;
+    }
+    match (data_accessible(before), data_accessible(after)) {
+        (true, false) => {
+            info!("run: resource '{}' has no accessible data", name);
+            match policy {
+                QuorumLossPolicy::Freeze => {
+                    if let Err(e) = freeze_actions(runner, name, State::Freeze, res) {
+                        warn!("Freezing '{}' failed: {}", name, e);
+                    }
+                }
+                QuorumLossPolicy::Shutdown => {
+                    if let Err(e) = stop_resource(runner, name, res) {
+                        warn!("Stopping '{}' failed: {}", name, e);
+                    }
+                }
+            }
+        }
+        (false, true) if *policy == QuorumLossPolicy::Freeze => {
+            info!(
+                "resource '{}' has accessible data again, thawing Primary",
+                name
+            );
+            if let Err(e) = freeze_actions(runner, name, State::Thaw, res) {
+                warn!("Thawing '{}' failed: {}", name, e);
+            }
         }
+        _ => (),
     }
 }
 
-fn freeze_actions(name: &str, to: State, how: &Runner) -> Result<()> {
+fn freeze_actions(
+    runner: &dyn CommandRunner,
+    name: &str,
+    to: State,
+    res: &PromoterOptResource,
+) -> Result<()> {
+    let how = &res.runner;
+    if res.dry_run && *how == Runner::Systemd {
+        let what = match to {
+            State::Thaw => "thaw",
+            _ => "freeze",
+        };
+        dry_run_actions(name, what, &[], how);
+        return Ok(());
+    }
+
     match how {
         Runner::Shell => Err(anyhow::anyhow!(
             "Shell runner can not not freeze/thaw services, use systemd"
@@ -511,8 +1402,24 @@ fn freeze_actions(name: &str, to: State, how: &Runner) -> Result<()> {
                 "freeze_actions: freezing/thawing services in target '{}'",
                 target
             );
-            action(&target, to, how)
+            action(runner, &target, to, how, None)
+        }
+    }
+}
+
+fn dry_run_actions(name: &str, what: &str, actions: &[String], how: &Runner) {
+    match how {
+        Runner::Shell => {
+            for a in actions {
+                info!("dry-run: '{}': would {}: sh -c {}", name, what, a);
+            }
         }
+        Runner::Systemd => info!(
+            "dry-run: '{}': would {} '{}'",
+            name,
+            what,
+            systemd::escaped_services_target(name)
+        ),
     }
 }
 
@@ -536,45 +1443,71 @@ fn get_backing_devices(resname: &str) -> Result<Vec<String>> {
     Ok(devices)
 }
 
-fn get_target_services(target: &str) -> Result<Vec<String>> {
-    let deps = Command::new("systemctl")
-        .stdin(Stdio::null())
-        .arg("list-dependencies")
-        .arg("--no-pager")
-        .arg("--plain")
-        .arg(target)
-        .output()?;
-    if !deps.status.success() {
-        return Err(anyhow::anyhow!(
-            "'systemctl list-dependencies --no-pager --plain {}' not executed successfully, stdout: '{}', stderr: '{}'",
-            target,
-            String::from_utf8(deps.stdout).unwrap_or("<Could not convert stdout>".to_string()),
-            String::from_utf8(deps.stderr).unwrap_or("<Could not convert stderr>".to_string())
-        ));
-    }
-
-    let deps = String::from_utf8(deps.stdout)?;
-    let services: Vec<String> = deps
-        .lines()
+fn get_target_services(runner: &dyn CommandRunner, target: &str) -> Result<Vec<String>> {
+    let services = systemd::list_dependencies(runner, target)?
+        .into_iter()
         .skip(2) // target itself is printed, + implicit promote unit (has no running process, freeze complains)
-        .map(str::trim)
-        .map(ToString::to_string)
         .collect();
     Ok(services)
 }
 
-fn adjust_resources(to_start: &[String]) -> Result<()> {
-    for res in to_start {
+/// Directory the promoter publishes the progress of adjusting resources in (one file per resource), so
+/// that `drbd-reactorctl status` can show why a resource is not coming up.
+pub const ADJUST_STATE_DIR: &str = "/run/drbd-reactor/adjust";
+
+// publishing is best effort, it only improves the status output
+fn set_adjust_state(dir: &Path, res: &str, state: Option<&str>) {
+    let path = dir.join(res);
+    let result = match state {
+        Some(state) => fs::create_dir_all(dir).and_then(|_| fs::write(&path, state)),
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            x => x,
+        },
+    };
+    if let Err(e) = result {
+        debug!(
+            "set_adjust_state: could not update '{}': {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// The published adjust progress of a resource (e.g., waiting for a backing device), if any.
+pub fn adjust_state(dir: &Path, res: &str) -> Option<String> {
+    fs::read_to_string(dir.join(res))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+// adjusts the given resources, after waiting (at most the optional timeout) for their backing devices
+fn adjust_resources(to_start: &[(String, Option<Duration>)]) -> Result<()> {
+    let state_dir = Path::new(ADJUST_STATE_DIR);
+    'resources: for (res, timeout) in to_start {
         for dev in get_backing_devices(res)? {
             info!(
                 "adjust_resources: waiting for backing device '{}' to become ready",
                 dev
             );
-            while !drbd_backing_device_ready(&dev) {
-                thread::sleep(Duration::from_secs(2));
+            if !drbd_backing_device_ready(&dev) {
+                let waiting = format!("waiting for backing device '{}'", dev);
+                set_adjust_state(state_dir, res, Some(&waiting));
+            }
+            if !wait_backing_device(&dev, *timeout, Duration::from_secs(2)) {
+                let timeout = timeout.unwrap_or_default().as_secs();
+                error!(
+                    "adjust_resources: backing device '{}' of '{}' not ready after {}s, not adjusting '{}'",
+                    dev, res, timeout, res
+                );
+                let failed = format!("backing device '{}' not ready after {}s", dev, timeout);
+                set_adjust_state(state_dir, res, Some(&failed));
+                continue 'resources;
             }
             info!("adjust_resources: backing device '{}' now ready", dev);
         }
+        set_adjust_state(state_dir, res, None);
 
         plugin::map_status(
             Command::new("drbdadm")
@@ -587,6 +1520,18 @@ fn adjust_resources(to_start: &[String]) -> Result<()> {
     Ok(())
 }
 
+// returns false if the device did not become ready within the timeout (if any)
+fn wait_backing_device(dev: &str, timeout: Option<Duration>, poll: Duration) -> bool {
+    let started = Instant::now();
+    while !drbd_backing_device_ready(dev) {
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            return false;
+        }
+        thread::sleep(poll);
+    }
+    true
+}
+
 fn drbd_backing_device_ready(dev: &str) -> bool {
     dev == "none"
         || match fs::metadata(dev) {
@@ -595,46 +1540,57 @@ fn drbd_backing_device_ready(dev: &str) -> bool {
         }
 }
 
-const SYSTEMD_PREFIX: &str = "/run/systemd/system";
+pub const SYSTEMD_PREFIX: &str = "/run/systemd/system";
 const SYSTEMD_CONF: &str = "reactor.conf";
 const SYSTEMD_BEFORE_CONF: &str = "reactor-50-before.conf";
+// a hanging on-escalate-notify command must not hold back the escalation
+const ESCALATE_NOTIFY_TIMEOUT_SECS: u64 = 10;
 pub const OCF_PATTERN: &str = r"^ocf:(\S+):(\S+)\s+((?s).*)$";
 
 fn generate_systemd_templates(
     name: &str,
-    actions: &[String],
+    actions: &[StartEntry],
     systemd_settings: &SystemdSettings,
     secondary_force: bool,
-) -> Result<()> {
+    dry_run: bool,
+) -> Result<bool> {
     let escaped_name = systemd::escape_name(name);
+    let mut changed = false;
+    let mut write_unit = |prefix: PathBuf, unit: &str, content: String| -> Result<()> {
+        if dry_run {
+            info!(
+                "dry-run: '{}': would create {:?}:\n{}",
+                name,
+                prefix.join(unit),
+                content
+            );
+            Ok(())
+        } else {
+            changed |= systemd_write_unit(prefix, unit, content)?;
+            Ok(())
+        }
+    };
 
     if let Some(content) = drbd_promote(systemd_settings, secondary_force)? {
         let prefix =
             Path::new(SYSTEMD_PREFIX).join(format!("drbd-promote@{}.service.d", escaped_name));
-        systemd_write_unit(prefix, SYSTEMD_CONF, content)?;
+        write_unit(prefix, SYSTEMD_CONF, content)?;
     }
 
-    if systemd_settings.failure_action != SystemdFailureAction::None {
+    if let Some(content) = drbd_demote_or_escalate(systemd_settings, secondary_force) {
         let prefix = Path::new(SYSTEMD_PREFIX).join(format!(
             "drbd-demote-or-escalate@{}.service.d",
             escaped_name
         ));
-        let mut content = format!(
-            "[Unit]\nFailureAction={}\nConflicts=drbd-promote@%i.service\n",
-            systemd_settings.failure_action
-        );
-        if secondary_force {
-            content.push_str("\n[Service]\nExecStart=\nExecStart=/lib/drbd/scripts/drbd-service-shim.sh secondary-secondary-force-or-escalate %I\n")
-        }
-        systemd_write_unit(prefix, SYSTEMD_CONF, content)?;
+        write_unit(prefix, SYSTEMD_CONF, content)?;
     }
 
     let mut target_requires: Vec<String> = Vec::new();
 
     let ocf_pattern = Regex::new(OCF_PATTERN)?;
 
-    for action in actions {
-        let action = action.trim();
+    for entry in actions {
+        let action = entry.service().trim();
         let deps = match target_requires.last() {
             Some(prev) => vec![
                 format!("drbd-promote@{}.service", escaped_name),
@@ -646,7 +1602,13 @@ fn generate_systemd_templates(
         let (service_name, env) = match ocf_pattern.captures(action) {
             Some(ocf) => {
                 let (vendor, agent, args) = (&ocf[1], &ocf[2], &ocf[3]);
-                systemd::escaped_ocf_parse_to_env(name, vendor, agent, args)?
+                systemd::escaped_ocf_parse_to_env(
+                    name,
+                    vendor,
+                    agent,
+                    args,
+                    &systemd_settings.ocf_root,
+                )?
             }
             _ => (action.to_string(), Vec::new()),
         };
@@ -665,16 +1627,16 @@ fn generate_systemd_templates(
 
         let prefix = Path::new(SYSTEMD_PREFIX).join(format!("{}.d", service_name));
         if service_name.ends_with(".mount") {
-            systemd_write_unit(
+            write_unit(
                 prefix.clone(),
                 "reactor-50-mount.conf",
                 "[Unit]\nDefaultDependencies=no\n".to_string(),
             )?;
         }
-        systemd_write_unit(
+        write_unit(
             prefix,
             SYSTEMD_CONF,
-            systemd_unit(&escaped_name, &deps, systemd_settings, &env)?,
+            systemd_unit(&escaped_name, &deps, systemd_settings, &env, entry)?,
         )?;
 
         // we would not need to keep the order here, as it does not matter
@@ -700,13 +1662,52 @@ fn generate_systemd_templates(
 
     // target and the extra Before= override
     if let Some(content) = systemd_target_requires(&target_requires, systemd_settings)? {
-        systemd_write_unit(escaped_services_target_dir(name), SYSTEMD_CONF, content)?;
+        write_unit(escaped_services_target_dir(name), SYSTEMD_CONF, content)?;
     }
-    systemd_write_unit(
+    write_unit(
         escaped_services_target_dir(name),
         SYSTEMD_BEFORE_CONF,
         "[Unit]\nBefore=drbd-reactor.service\n".to_string(),
-    )
+    )?;
+
+    Ok(changed)
+}
+
+fn drbd_demote_or_escalate(
+    systemd_settings: &SystemdSettings,
+    secondary_force: bool,
+) -> Option<String> {
+    if systemd_settings.failure_action == SystemdFailureAction::None {
+        return None;
+    }
+
+    let mut content = format!(
+        "[Unit]\nFailureAction={}\nConflicts=drbd-promote@%i.service\n",
+        systemd_settings.failure_action
+    );
+    if let Some(resource) = &systemd_settings.ownership_markers {
+        content.push_str(&ownership_markers(resource));
+    }
+
+    let mut service = String::new();
+    // '-': best effort, a failing notification must not prevent the escalation
+    if let Some(notify) = &systemd_settings.escalate_notify {
+        service.push_str(&format!(
+            "ExecStartPre=-/usr/bin/timeout {} {}\n",
+            ESCALATE_NOTIFY_TIMEOUT_SECS, notify
+        ));
+    }
+    if secondary_force {
+        service.push_str(&format!(
+            "ExecStart=\nExecStart={} secondary-secondary-force-or-escalate %I\n",
+            systemd_settings.service_shim
+        ));
+    }
+    if !service.is_empty() {
+        content.push_str(&format!("\n[Service]\n{}", service));
+    }
+
+    Some(content)
 }
 
 fn drbd_promote(
@@ -714,28 +1715,36 @@ fn drbd_promote(
     secondary_force: bool,
 ) -> Result<Option<String>> {
     #[derive(Serialize)]
-    struct Context {
+    struct Context<'a> {
         needs_on_failure: bool,
         secondary_force: bool,
+        resource: &'a Option<String>,
+        shim: &'a str,
     }
     let context = Context {
         needs_on_failure: systemd_settings.failure_action != SystemdFailureAction::None,
         secondary_force,
+        resource: &systemd_settings.ownership_markers,
+        shim: &systemd_settings.service_shim,
     };
 
-    if !context.needs_on_failure && !context.secondary_force {
+    if !context.needs_on_failure && !context.secondary_force && context.resource.is_none() {
         return Ok(None);
     }
 
-    const PROMOTE_TEMPLATE: &str = r"[Service]
-{{ if secondary_force -}}
+    const PROMOTE_TEMPLATE: &str = r"{{ if secondary_force -}}
+[Service]
 ExecStop=
-ExecStop=/lib/drbd/scripts/drbd-service-shim.sh secondary-secondary-force %I
+ExecStop={shim | unescaped} secondary-secondary-force %I
 {{ endif -}}
 [Unit]
 {{ if needs_on_failure -}}
 OnFailure=drbd-demote-or-escalate@%i.service
 OnFailureJobMode=replace-irreversibly
+{{ endif -}}
+{{ if resource -}}
+Documentation=man:drbd-reactor.promoter(5)
+X-DrbdReactor-Resource={resource | unescaped}
 {{ endif -}}";
 
     let mut tt = TinyTemplate::new();
@@ -752,39 +1761,74 @@ fn systemd_unit(
     deps: &[String],
     systemd_settings: &SystemdSettings,
     env: &[String],
+    entry: &StartEntry,
 ) -> Result<String> {
     const UNIT_TEMPLATE: &str = r"[Unit]
 Description=drbd-reactor controlled %N
 PartOf = drbd-services@{name}.target
+{{ if resource -}}
+Documentation=man:drbd-reactor.promoter(5)
+X-DrbdReactor-Resource={resource | unescaped}
+{{ endif -}}
 {{ for dep in deps }}
 {strictness} = {dep | unescaped}
 After = {dep}
 {{- endfor -}}
+{{ for unit in after }}
+After = {unit | unescaped}
+{{- endfor -}}
+{{ for unit in before }}
+Before = {unit | unescaped}
+{{- endfor -}}
 
-{{ for e in env }}
+{{ for line in service }}
 {{ if @first  }}
 [Service]
 {{ endif -}}
-Environment= {e | unescaped}
+{line | unescaped}
 {{- endfor -}}";
 
     let mut tt = TinyTemplate::new();
     tt.add_template("unit", UNIT_TEMPLATE)?;
 
+    // hooks are added to the ones of the unit (if any), a failing ExecStartPre fails the start
+    let service = env
+        .iter()
+        .map(|e| format!("Environment= {}", e))
+        .chain(
+            entry
+                .exec_start_pre()
+                .iter()
+                .map(|c| format!("ExecStartPre={}", c)),
+        )
+        .chain(
+            entry
+                .exec_stop_post()
+                .iter()
+                .map(|c| format!("ExecStopPost={}", c)),
+        )
+        .collect();
+
     #[derive(Serialize)]
     struct Context<'a> {
         name: String,
         deps: &'a [String],
-        env: &'a [String],
+        service: Vec<String>,
         strictness: String,
+        resource: &'a Option<String>,
+        after: &'a [String],
+        before: &'a [String],
     }
     let result = tt.render(
         "unit",
         &Context {
             name: name.to_string(),
             deps,
-            env,
+            service,
+            after: entry.after(),
+            before: entry.before(),
             strictness: systemd_settings.dependencies_as.to_string(),
+            resource: &systemd_settings.ownership_markers,
         },
     )?;
     Ok(result)
@@ -799,6 +1843,10 @@ fn systemd_target_requires(
     }
 
     const WANTS_TEMPLATE: &str = r"[Unit]
+{{- if resource }}
+Documentation=man:drbd-reactor.promoter(5)
+X-DrbdReactor-Resource={resource | unescaped}
+{{- endif }}
 {{- for require in requires }}
 {strictness} = {require | unescaped}
 {{- endfor -}}";
@@ -810,33 +1858,60 @@ fn systemd_target_requires(
     struct Context<'a> {
         requires: &'a [String],
         strictness: String,
+        resource: &'a Option<String>,
     }
     let result = tt.render(
         "requires",
         &Context {
             requires,
             strictness: systemd_settings.target_as.to_string(),
+            resource: &systemd_settings.ownership_markers,
         },
     )?;
     Ok(Some(result))
 }
 
-fn systemd_write_unit(prefix: PathBuf, unit: &str, content: String) -> Result<()> {
-    let content = format!("# Auto-generated by drbd-reactor, DO NOT EDIT\n{}", content);
+// returns false if the unit already existed with the same content and was left alone
+fn systemd_write_unit(prefix: PathBuf, unit: &str, content: String) -> Result<bool> {
+    let content = format!(
+        "# Auto-generated by drbd-reactor, DO NOT EDIT\n{}\n",
+        content
+    );
 
     let path = prefix.join(unit);
+    if fs::read_to_string(&path).is_ok_and(|existing| existing == content) {
+        trace!("systemd_write_unit: {:?} unchanged", path);
+        return Ok(false);
+    }
     let tmp_path = prefix.join(format!("{}.tmp", unit));
     info!("systemd_write_unit: creating {:?}", path);
 
-    fs::create_dir_all(&prefix)?;
-    {
-        let mut f = File::create(&tmp_path)?;
-        f.write_all(content.as_bytes())?;
-        f.write_all("\n".as_bytes())?;
+    let write = || -> io::Result<()> {
+        fs::create_dir_all(&prefix)?;
+        {
+            let mut f = File::create(&tmp_path)?;
+            f.write_all(content.as_bytes())?;
+        }
+        fs::rename(&tmp_path, &path)
+    };
+
+    write().map_err(|e| systemd_write_error(&path, e))?;
+    Ok(true)
+}
+
+fn systemd_write_error(path: &Path, e: io::Error) -> anyhow::Error {
+    let not_writable =
+        e.kind() == io::ErrorKind::PermissionDenied || e.raw_os_error() == Some(libc::EROFS);
+    if !not_writable {
+        return anyhow::anyhow!("systemd_write_unit: could not write {:?}: {}", path, e);
     }
-    fs::rename(tmp_path, path)?;
 
-    Ok(())
+    anyhow::anyhow!(
+        "systemd_write_unit: could not write {:?}: {}; '{}' has to be writable (not read-only) for the \"systemd\" runner, if systemd is not available on this system, consider setting runner = \"shell\"",
+        path,
+        e,
+        SYSTEMD_PREFIX
+    )
 }
 
 enum State {
@@ -907,6 +1982,92 @@ struct SystemdSettings {
     dependencies_as: SystemdDependency,
     target_as: SystemdDependency,
     failure_action: SystemdFailureAction,
+    // name of the DRBD resource if generated units should be marked as owned by drbd-reactor
+    ownership_markers: Option<String>,
+    // command executed before a demote that escalates to the failure action is tried
+    escalate_notify: Option<String>,
+    // path of drbd-service-shim.sh
+    service_shim: String,
+    // OCF_ROOT of the OCF agents
+    ocf_root: PathBuf,
+}
+
+const DRBD_SERVICE_SHIM: &str = "drbd-service-shim.sh";
+// where distributions install the DRBD scripts, the first one is the default
+const DRBD_SCRIPT_PATHS: &[&str] = &["/lib/drbd/scripts", "/usr/lib/drbd/scripts"];
+
+/// Returns the path of drbd-service-shim.sh in the configured directory, or in the first of the
+/// `candidates` that contains it.
+fn drbd_service_shim(configured: &Option<PathBuf>, candidates: &[&str]) -> String {
+    if let Some(dir) = configured {
+        let shim = dir.join(DRBD_SERVICE_SHIM);
+        if !shim.exists() {
+            warn!(
+                "'{}' does not exist, check 'drbd-script-path'",
+                shim.display()
+            );
+        }
+        return shim.display().to_string();
+    }
+
+    let shim = candidates
+        .iter()
+        .map(|dir| Path::new(dir).join(DRBD_SERVICE_SHIM))
+        .find(|shim| shim.exists());
+    match shim {
+        Some(shim) => shim.display().to_string(),
+        None => {
+            let default = Path::new(candidates[0]).join(DRBD_SERVICE_SHIM);
+            warn!(
+                "could not find '{}' in any of {:?}, using '{}'; consider setting 'drbd-script-path'",
+                DRBD_SERVICE_SHIM,
+                candidates,
+                default.display()
+            );
+            default.display().to_string()
+        }
+    }
+}
+
+fn ownership_markers(resource: &str) -> String {
+    format!(
+        "Documentation=man:drbd-reactor.promoter(5)\nX-DrbdReactor-Resource={}\n",
+        resource
+    )
+}
+
+/// An entry in `preferred-nodes`, either just the node name, or a node name with a weight.
+///
+/// The weight is the delay (in seconds) the entries after this one wait additionally.
+#[derive(Serialize, Deserialize, Hash, Debug, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+pub enum PreferredNode {
+    Name(String),
+    Weighted {
+        node: String,
+        #[serde(default = "default_preferred_node_weight")]
+        weight: u32,
+    },
+}
+
+impl PreferredNode {
+    fn name(&self) -> &str {
+        match self {
+            Self::Name(node) => node,
+            Self::Weighted { node, .. } => node,
+        }
+    }
+
+    fn weight(&self) -> u32 {
+        match self {
+            Self::Name(_) => 1,
+            Self::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+fn default_preferred_node_weight() -> u32 {
+    1
 }
 
 #[derive(Serialize, Deserialize, Hash, Debug, PartialEq, Eq, Clone)]
@@ -937,9 +2098,10 @@ impl Default for Runner {
 
 fn get_sleep_before_promote_ms(
     resource: &Resource,
-    preferred_nodes: &[String],
+    preferred_nodes: &[PreferredNode],
     on_quorum_loss: &QuorumLossPolicy,
     factor: u32,
+    max_secs: Option<u64>,
 ) -> u64 {
     let mut max_sleep_s: u64 = resource
         .devices
@@ -974,60 +2136,254 @@ fn get_sleep_before_promote_ms(
     }
 
     // convert to ms and scale by factor
-    max_sleep_s * 1000 * (factor as u64)
-}
-
-fn escaped_services_target_dir(name: &str) -> PathBuf {
-    Path::new(SYSTEMD_PREFIX).join(format!("{}.d", systemd::escaped_services_target(name)))
+    let sleep_ms = max_sleep_s
+        .saturating_mul(1000)
+        .saturating_mul(factor as u64);
+    match max_secs {
+        Some(max_secs) => sleep_ms.min(max_secs.saturating_mul(1000)),
+        None => sleep_ms,
+    }
 }
 
-fn check_resource(name: &str, on_quorum_loss: &QuorumLossPolicy) -> Result<()> {
-    #[derive(Serialize, Deserialize)]
-    struct Resource {
-        resource: String,
-        options: Options,
-        connections: Vec<Connection>,
-    }
+/// Returns the per resource drop-in directories in `prefix` (e.g., "drbd-services@foo.target.d") of resources that
+/// are not in `resources`, i.e., left over from promoters that got removed.
+pub fn orphaned_unit_dirs(prefix: &Path, resources: &BTreeSet<String>) -> Result<Vec<PathBuf>> {
+    let escaped = resources
+        .iter()
+        .map(|r| systemd::escape_name(r))
+        .collect::<HashSet<_>>();
+    let per_resource = Regex::new(
+        r"^(?:drbd-services@(.+)\.target|drbd-promote@(.+)\.service|drbd-demote-or-escalate@(.+)\.service)\.d$",
+    )?;
 
-    #[derive(Serialize, Deserialize)]
-    #[serde(rename_all = "kebab-case")]
-    struct Options {
-        auto_promote: bool,
-        quorum: String,
-        on_no_quorum: String,
-        on_suspended_primary_outdated: String,
-        on_no_data_accessible: String,
+    let mut orphaned = Vec::new();
+    for entry in fs::read_dir(prefix)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let file_name = entry.file_name();
+        let escaped_name = per_resource
+            .captures(&file_name.to_string_lossy())
+            .and_then(|c| {
+                c.iter()
+                    .skip(1)
+                    .flatten()
+                    .next()
+                    .map(|m| m.as_str().to_string())
+            });
+        match escaped_name {
+            Some(name) if !escaped.contains(&name) => orphaned.push(entry.path()),
+            _ => (),
+        }
     }
+    orphaned.sort();
+    Ok(orphaned)
+}
 
-    #[derive(Serialize, Deserialize)]
-    #[serde(rename_all = "kebab-case")]
-    struct Connection {
-        // even if we expect the net options to be set globally, they are
-        // "inherited" downwards to the individual connections
-        net: Net,
-    }
+fn escaped_services_target_dir(name: &str) -> PathBuf {
+    Path::new(SYSTEMD_PREFIX).join(format!("{}.d", systemd::escaped_services_target(name)))
+}
 
-    #[derive(Serialize, Deserialize)]
-    #[serde(rename_all = "kebab-case")]
-    struct Net {
-        rr_conflict: String,
+/// Returns true if the promoter should manage the resource, i.e., if all the DRBD resource options in
+/// `managed_if` have the expected values.
+fn resource_managed(
+    runner: &dyn CommandRunner,
+    name: &str,
+    managed_if: &BTreeMap<String, String>,
+) -> bool {
+    if managed_if.is_empty() {
+        return true;
     }
 
-    let check_for = |res: &str, what: &str, expected: &str, is: &str| {
-        if expected != is {
+    let options = match drbd_resource_options(runner, name) {
+        Ok(options) => options,
+        Err(e) => {
             warn!(
-                "resource '{}': DRBD option '{}' should be '{}', but is '{}'",
-                res, what, expected, is
+                "resource '{}': could not get DRBD options, not managing it: {}",
+                name, e
             );
+            return false;
         }
     };
 
-    let output = Command::new("drbdsetup")
-        .stdin(Stdio::null())
-        .arg("show")
-        .arg("--show-defaults")
-        .arg("--json")
-        .arg(name)
+    for (key, expected) in managed_if {
+        let is = options.get(key).map(String::as_str).unwrap_or_default();
+        if is != expected {
+            info!(
+                "resource '{}': not managing it, DRBD option '{}' is '{}' instead of '{}'",
+                name, key, is, expected
+            );
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Executes the `validate-all` action of every OCF agent in `actions`, with the environment the agent
+/// would get from its ocf.rs@ unit.
+fn validate_ocf_agents(
+    runner: &dyn CommandRunner,
+    name: &str,
+    actions: &[String],
+    ocf_root: &Path,
+) -> Result<()> {
+    let ocf_pattern = Regex::new(OCF_PATTERN)?;
+    for action in actions {
+        let ocf = match ocf_pattern.captures(action.trim()) {
+            Some(ocf) => ocf,
+            None => continue,
+        };
+        let (vendor, agent, args) = (&ocf[1], &ocf[2], &ocf[3]);
+        let args = shell_words::split(args)?;
+        let instance = args.first().ok_or_else(|| {
+            anyhow::anyhow!(
+                "OCF agent '{}:{}' needs at least one argument (its name)",
+                vendor,
+                agent
+            )
+        })?;
+
+        let agent_path = format!("{}/resource.d/{}/{}", ocf_root.display(), vendor, agent);
+        let mut cmd = vec![
+            format!("OCF_ROOT={}", ocf_root.display()),
+            format!("OCF_RESOURCE_INSTANCE={}_{}", instance, name),
+        ];
+        for item in &args[1..] {
+            match item.split_once('=') {
+                Some((k, v)) => cmd.push(format!("OCF_RESKEY_{}={}", k, v)),
+                None if !item.is_empty() => cmd.push(format!("OCF_RESKEY_{}=", item)),
+                None => (),
+            }
+        }
+        cmd.push(agent_path.clone());
+        cmd.push("validate-all".to_string());
+
+        let cmd: Vec<&str> = cmd.iter().map(String::as_str).collect();
+        let status = runner.status("env", &cmd)?;
+        match status.code() {
+            Some(0) => debug!("resource '{}': OCF agent '{}' validated", name, agent_path),
+            // env could not find/execute it
+            Some(126) | Some(127) => {
+                return Err(anyhow::anyhow!(
+                    "resource '{}': OCF agent '{}' does not exist or is not executable",
+                    name,
+                    agent_path
+                ))
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "resource '{}': OCF agent '{}' failed to validate '{}' ({})",
+                    name,
+                    agent_path,
+                    instance,
+                    status
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn drbd_resource_options(
+    runner: &dyn CommandRunner,
+    name: &str,
+) -> Result<BTreeMap<String, String>> {
+    let output = runner.output("drbdsetup", &["show", "--show-defaults", "--json", name])?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "'drbdsetup show' not executed successfully"
+        ));
+    }
+
+    parse_resource_options(name, &String::from_utf8(output.stdout)?)
+}
+
+/// Returns the "options" section of `drbdsetup show --json` for the resource, values are
+/// formatted like in a DRBD resource file (e.g., "yes" instead of true).
+fn parse_resource_options(name: &str, json: &str) -> Result<BTreeMap<String, String>> {
+    #[derive(Deserialize)]
+    struct Resource {
+        resource: String,
+        #[serde(default)]
+        options: BTreeMap<String, serde_json::Value>,
+    }
+
+    let resources: Vec<Resource> = serde_json::from_str(json)?;
+    let resource = match resources.into_iter().find(|r| r.resource == name) {
+        Some(resource) => resource,
+        None => {
+            return Err(anyhow::anyhow!(
+                "resource '{}' not found in drbdsetup show output",
+                name
+            ))
+        }
+    };
+
+    Ok(resource
+        .options
+        .into_iter()
+        .map(|(k, v)| {
+            let v = match v {
+                serde_json::Value::Bool(true) => "yes".to_string(),
+                serde_json::Value::Bool(false) => "no".to_string(),
+                serde_json::Value::String(s) => s,
+                v => v.to_string(),
+            };
+            (k, v)
+        })
+        .collect())
+}
+
+fn check_resource(name: &str, on_quorum_loss: &QuorumLossPolicy) -> Result<()> {
+    #[derive(Serialize, Deserialize)]
+    struct Resource {
+        resource: String,
+        options: Options,
+        connections: Vec<Connection>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    struct Options {
+        auto_promote: bool,
+        quorum: String,
+        on_no_quorum: String,
+        on_suspended_primary_outdated: String,
+        on_no_data_accessible: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    struct Connection {
+        // even if we expect the net options to be set globally, they are
+        // "inherited" downwards to the individual connections
+        net: Net,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    struct Net {
+        rr_conflict: String,
+    }
+
+    let check_for = |res: &str, what: &str, expected: &str, is: &str| {
+        if expected != is {
+            warn!(
+                "resource '{}': DRBD option '{}' should be '{}', but is '{}'",
+                res, what, expected, is
+            );
+        }
+    };
+
+    let output = Command::new("drbdsetup")
+        .stdin(Stdio::null())
+        .arg("show")
+        .arg("--show-defaults")
+        .arg("--json")
+        .arg(name)
         .output()?;
     if !output.status.success() {
         return Err(anyhow::anyhow!(
@@ -1097,25 +2453,34 @@ fn check_resource(name: &str, on_quorum_loss: &QuorumLossPolicy) -> Result<()> {
     Ok(())
 }
 
-fn get_preferred_nodes_sleep_s(preferred_nodes: &[String]) -> u64 {
-    let sleep = match utils::uname_n() {
-        Ok(node_name) => match preferred_nodes.iter().position(|n| n == &node_name) {
-            Some(pos) => pos,
-            None => preferred_nodes.len(),
-        },
+fn get_preferred_nodes_sleep_s(preferred_nodes: &[PreferredNode]) -> u64 {
+    match utils::uname_n() {
+        Ok(node_name) => preferred_nodes_sleep_s(preferred_nodes, &node_name),
         Err(e) => {
             warn!("Could not determine 'uname -n': {}", e);
             0
         }
+    }
+}
+
+// the sum of the weights of the entries before the node, so the delay grows with the position in the list
+// nodes not in the list wait longer than any node in the list
+fn preferred_nodes_sleep_s(preferred_nodes: &[PreferredNode], node_name: &str) -> u64 {
+    let before = match preferred_node_position(preferred_nodes, node_name) {
+        Some(pos) => &preferred_nodes[..pos],
+        None => preferred_nodes,
     };
+    before.iter().map(|n| n.weight() as u64).sum()
+}
 
-    sleep as u64
+fn preferred_node_position(preferred_nodes: &[PreferredNode], node_name: &str) -> Option<usize> {
+    preferred_nodes.iter().position(|n| n.name() == node_name)
 }
 
-fn try_initial_target_start(name: &str) -> bool {
+fn try_initial_target_start(runner: &dyn CommandRunner, name: &str) -> bool {
     // if we know for sure that a remote is Primary, then we don't try
     // in all other cases, even if unsure, we can try
-    match get_primary(name) {
+    match get_primary(runner, name) {
         Ok(PrimaryOn::Remote(_)) => false,
         _ => true,
     }
@@ -1125,6 +2490,7 @@ fn try_initial_target_start(name: &str) -> bool {
 mod tests {
     use super::*;
     use crate::drbd::Device;
+    use crate::runner::MockRunner;
 
     #[test]
     fn sleep_before_promote_ms() {
@@ -1148,17 +2514,17 @@ mod tests {
             ..Default::default()
         };
         assert_eq!(
-            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Shutdown, 1),
+            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Shutdown, 1, None),
             6000
         );
 
         r.role = Role::Secondary;
         assert_eq!(
-            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Freeze, 1),
+            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Freeze, 1, None),
             6000 + 2000
         );
         assert_eq!(
-            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Shutdown, 2),
+            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Shutdown, 2, None),
             12000
         );
         if let Ok(node_name) = utils::uname_n() {
@@ -1166,28 +2532,140 @@ mod tests {
                 get_sleep_before_promote_ms(
                     &r,
                     &[
-                        "".to_string(),
-                        "".to_string(),
-                        node_name.clone(),
-                        "".to_string()
+                        PreferredNode::Name("".to_string()),
+                        PreferredNode::Name("".to_string()),
+                        PreferredNode::Name(node_name.clone()),
+                        PreferredNode::Name("".to_string())
                     ],
                     &QuorumLossPolicy::Shutdown,
-                    1
+                    1,
+                    None
                 ),
                 6000 + 2000
             );
             assert_eq!(
                 get_sleep_before_promote_ms(
                     &r,
-                    &["".to_string(), "".to_string(), "".to_string()],
+                    &[
+                        PreferredNode::Name("".to_string()),
+                        PreferredNode::Name("".to_string()),
+                        PreferredNode::Name("".to_string())
+                    ],
                     &QuorumLossPolicy::Shutdown,
-                    1
+                    1,
+                    None
                 ),
                 6000 + 3000
             );
         }
     }
 
+    #[test]
+    fn test_adjust_state() {
+        let dir = tempfile::tempdir().expect("tempdir must work");
+        let dir = dir.path().join("adjust");
+        assert_eq!(adjust_state(&dir, "foo"), None);
+
+        set_adjust_state(&dir, "foo", Some("waiting for backing device '/dev/foo'"));
+        assert_eq!(
+            adjust_state(&dir, "foo"),
+            Some("waiting for backing device '/dev/foo'".to_string())
+        );
+        assert_eq!(adjust_state(&dir, "bar"), None);
+
+        set_adjust_state(&dir, "foo", None);
+        assert_eq!(adjust_state(&dir, "foo"), None);
+        // removing twice is fine
+        set_adjust_state(&dir, "foo", None);
+    }
+
+    #[test]
+    fn backing_device_timeout() {
+        let poll = Duration::from_millis(10);
+        assert!(wait_backing_device("none", Some(Duration::ZERO), poll));
+
+        let started = Instant::now();
+        assert!(!wait_backing_device(
+            "/dev/drbd-reactor-does-not-exist",
+            Some(Duration::from_millis(50)),
+            poll
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn sleep_before_promote_ms_capped() {
+        let r = Resource {
+            name: "test".to_string(),
+            devices: vec![Device {
+                disk_state: DiskState::Inconsistent,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        // below the cap
+        assert_eq!(
+            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Shutdown, 1, Some(10)),
+            3000
+        );
+        // scaled beyond the cap
+        assert_eq!(
+            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Shutdown, 100, Some(10)),
+            10000
+        );
+        assert_eq!(
+            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Shutdown, 100, None),
+            300000
+        );
+        // 0 disables the sleep
+        assert_eq!(
+            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Shutdown, 100, Some(0)),
+            0
+        );
+        // a huge cap does not overflow
+        assert_eq!(
+            get_sleep_before_promote_ms(&r, &[], &QuorumLossPolicy::Shutdown, 100, Some(u64::MAX)),
+            300000
+        );
+    }
+
+    #[test]
+    fn preferred_nodes_weights() {
+        let res: PromoterOptResource = toml::from_str(
+            r#"preferred-nodes = ["a", { node = "b", weight = 10 }, { node = "c", weight = 5 }, { node = "d" }]"#,
+        )
+        .expect("cfg must parse");
+        let nodes = &res.preferred_nodes;
+        assert_eq!(nodes[0], PreferredNode::Name("a".to_string()));
+        assert_eq!(nodes[3].weight(), 1);
+
+        assert_eq!(preferred_nodes_sleep_s(nodes, "a"), 0);
+        assert_eq!(preferred_nodes_sleep_s(nodes, "b"), 1);
+        assert_eq!(preferred_nodes_sleep_s(nodes, "c"), 1 + 10);
+        assert_eq!(preferred_nodes_sleep_s(nodes, "d"), 1 + 10 + 5);
+        assert_eq!(preferred_nodes_sleep_s(nodes, "e"), 1 + 10 + 5 + 1);
+        assert_eq!(preferred_node_position(nodes, "c"), Some(2));
+
+        // less preferred nodes never wait less, a weight on the first entry delays all others
+        let res: PromoterOptResource = toml::from_str(
+            r#"preferred-nodes = [{ node = "a", weight = 10 }, "b", { node = "c", weight = 0 }, "d"]"#,
+        )
+        .expect("cfg must parse");
+        let sleeps: Vec<u64> = ["a", "b", "c", "d", "x"]
+            .iter()
+            .map(|n| preferred_nodes_sleep_s(&res.preferred_nodes, n))
+            .collect();
+        assert_eq!(sleeps, vec![0, 10, 11, 11, 12]);
+
+        // plain names keep the positional delay
+        let nodes = [
+            PreferredNode::Name("a".to_string()),
+            PreferredNode::Name("b".to_string()),
+        ];
+        assert_eq!(preferred_nodes_sleep_s(&nodes, "b"), 1);
+        assert_eq!(preferred_nodes_sleep_s(&nodes, "x"), 2);
+    }
+
     #[test]
     fn test_drbd_promote() {
         let empty = drbd_promote(
@@ -1195,6 +2673,10 @@ mod tests {
                 target_as: SystemdDependency::Wants,
                 dependencies_as: SystemdDependency::Wants,
                 failure_action: SystemdFailureAction::None,
+                ownership_markers: None,
+                escalate_notify: None,
+                service_shim: "/lib/drbd/scripts/drbd-service-shim.sh".to_string(),
+                ocf_root: PathBuf::from(systemd::OCF_ROOT),
             },
             false,
         )
@@ -1206,6 +2688,10 @@ mod tests {
                 target_as: SystemdDependency::Wants,
                 dependencies_as: SystemdDependency::Wants,
                 failure_action: SystemdFailureAction::Reboot,
+                ownership_markers: None,
+                escalate_notify: None,
+                service_shim: "/lib/drbd/scripts/drbd-service-shim.sh".to_string(),
+                ocf_root: PathBuf::from(systemd::OCF_ROOT),
             },
             true,
         )
@@ -1220,4 +2706,1373 @@ OnFailureJobMode=replace-irreversibly
 ";
         assert_eq!(Some(expected.to_string()), on_failure);
     }
+
+    #[test]
+    fn test_ownership_markers() {
+        let settings = SystemdSettings {
+            target_as: SystemdDependency::Requires,
+            dependencies_as: SystemdDependency::Requires,
+            failure_action: SystemdFailureAction::Reboot,
+            ownership_markers: Some("res1".to_string()),
+            escalate_notify: None,
+            service_shim: "/lib/drbd/scripts/drbd-service-shim.sh".to_string(),
+            ocf_root: PathBuf::from(systemd::OCF_ROOT),
+        };
+        let markers = "Documentation=man:drbd-reactor.promoter(5)\nX-DrbdReactor-Resource=res1\n";
+
+        let promote = drbd_promote(&settings, false)
+            .expect("should work")
+            .expect("should generate content");
+        assert!(promote.contains(markers));
+
+        // markers only
+        let promote = drbd_promote(
+            &SystemdSettings {
+                target_as: SystemdDependency::Requires,
+                dependencies_as: SystemdDependency::Requires,
+                failure_action: SystemdFailureAction::None,
+                ownership_markers: Some("res1".to_string()),
+                escalate_notify: None,
+                service_shim: "/lib/drbd/scripts/drbd-service-shim.sh".to_string(),
+                ocf_root: PathBuf::from(systemd::OCF_ROOT),
+            },
+            false,
+        )
+        .expect("should work");
+        assert_eq!(promote, Some(format!("[Unit]\n{}", markers)));
+
+        let unit = systemd_unit(
+            "res1",
+            &["drbd-promote@res1.service".to_string()],
+            &settings,
+            &[],
+            &StartEntry::from("foo.service"),
+        )
+        .expect("should work");
+        let expected = r"[Unit]
+Description=drbd-reactor controlled %N
+PartOf = drbd-services@res1.target
+Documentation=man:drbd-reactor.promoter(5)
+X-DrbdReactor-Resource=res1
+
+Requires = drbd-promote@res1.service
+After = drbd-promote@res1.service";
+        assert_eq!(unit, expected);
+
+        let target = systemd_target_requires(&["foo.service".to_string()], &settings)
+            .expect("should work")
+            .expect("should generate content");
+        assert_eq!(target, format!("[Unit]\n{}Requires = foo.service", markers));
+
+        // no markers, no changes
+        let settings = SystemdSettings {
+            ownership_markers: None,
+            escalate_notify: None,
+            service_shim: "/lib/drbd/scripts/drbd-service-shim.sh".to_string(),
+            ocf_root: PathBuf::from(systemd::OCF_ROOT),
+            ..settings
+        };
+        let unit = systemd_unit(
+            "res1",
+            &[],
+            &settings,
+            &[],
+            &StartEntry::from("foo.service"),
+        )
+        .expect("should work");
+        assert!(!unit.contains("X-DrbdReactor-Resource"));
+        let target = systemd_target_requires(&["foo.service".to_string()], &settings)
+            .expect("should work")
+            .expect("should generate content");
+        assert_eq!(target, "[Unit]\nRequires = foo.service");
+    }
+
+    #[test]
+    fn test_start_entry_ordering() {
+        let res: PromoterOptResource = toml::from_str(
+            r#"start = ["foo.service", { service = "bar.mount", after = ["network-online.target"], before = ["x.service", "y.service"] }]"#,
+        )
+        .expect("must parse");
+        assert_eq!(res.start_services(), vec!["foo.service", "bar.mount"]);
+        assert_eq!(res.start[0], StartEntry::from("foo.service"));
+
+        let settings = SystemdSettings {
+            target_as: SystemdDependency::Requires,
+            dependencies_as: SystemdDependency::Requires,
+            failure_action: SystemdFailureAction::None,
+            ownership_markers: None,
+            escalate_notify: None,
+            service_shim: "/lib/drbd/scripts/drbd-service-shim.sh".to_string(),
+            ocf_root: PathBuf::from(systemd::OCF_ROOT),
+        };
+        let unit = systemd_unit(
+            "res1",
+            &["drbd-promote@res1.service".to_string()],
+            &settings,
+            &[],
+            &res.start[1],
+        )
+        .expect("should work");
+        let expected = r"[Unit]
+Description=drbd-reactor controlled %N
+PartOf = drbd-services@res1.target
+
+Requires = drbd-promote@res1.service
+After = drbd-promote@res1.service
+After = network-online.target
+Before = x.service
+Before = y.service";
+        assert_eq!(unit, expected);
+    }
+
+    #[test]
+    fn test_drbd_service_shim() {
+        let dir = tempfile::tempdir().expect("tempdir must work");
+        let usr = dir.path().join("usr");
+        fs::create_dir(&usr).expect("must create dir");
+        fs::write(usr.join(DRBD_SERVICE_SHIM), "").expect("must write shim");
+        let missing = dir.path().join("missing");
+        let (missing, usr) = (missing.to_str().unwrap(), usr.to_str().unwrap());
+
+        assert_eq!(
+            drbd_service_shim(&None, &[missing, usr]),
+            format!("{}/drbd-service-shim.sh", usr)
+        );
+        // not found: the first candidate
+        assert_eq!(
+            drbd_service_shim(&None, &[missing]),
+            format!("{}/drbd-service-shim.sh", missing)
+        );
+        // configured wins
+        assert_eq!(
+            drbd_service_shim(&Some(PathBuf::from("/opt/drbd")), &[usr]),
+            "/opt/drbd/drbd-service-shim.sh"
+        );
+    }
+
+    #[test]
+    fn test_start_entry_hooks() {
+        let res: PromoterOptResource = toml::from_str(
+            r#"start = [{ service = "db.service", exec-start-pre = ["/usr/local/bin/check-db"], exec-stop-post = ["/usr/local/bin/cleanup-db --all"] }]"#,
+        )
+        .expect("must parse");
+        res.check_start_hooks()
+            .expect("hooks on services must work");
+
+        for start in [
+            r#"start = ["mnt-data.mount", { service = "ocf:heartbeat:IPaddr2 ip ip=10.43.7.223", exec-start-pre = ["/bin/true"] }]"#,
+            r#"start = [{ service = "mnt-data.mount" }]"#,
+        ] {
+            let ok: PromoterOptResource = toml::from_str(start).expect("must parse");
+            ok.check_start_hooks().expect("must work");
+        }
+        for start in [
+            r#"start = [{ service = "mnt-data.mount", exec-start-pre = ["/bin/true"] }]"#,
+            r#"start = [{ service = "app.target", exec-stop-post = ["/bin/true"] }]"#,
+        ] {
+            let bad: PromoterOptResource = toml::from_str(start).expect("must parse");
+            assert!(bad.check_start_hooks().is_err());
+        }
+
+        let settings = SystemdSettings {
+            target_as: SystemdDependency::Requires,
+            dependencies_as: SystemdDependency::Requires,
+            failure_action: SystemdFailureAction::None,
+            ownership_markers: None,
+            escalate_notify: None,
+            service_shim: "/lib/drbd/scripts/drbd-service-shim.sh".to_string(),
+            ocf_root: PathBuf::from(systemd::OCF_ROOT),
+        };
+        let unit = systemd_unit(
+            "res1",
+            &["drbd-promote@res1.service".to_string()],
+            &settings,
+            &["OCF_RESKEY_ip=10.43.7.223".to_string()],
+            &res.start[0],
+        )
+        .expect("should work");
+        let expected = r"[Unit]
+Description=drbd-reactor controlled %N
+PartOf = drbd-services@res1.target
+
+Requires = drbd-promote@res1.service
+After = drbd-promote@res1.service
+
+[Service]
+Environment= OCF_RESKEY_ip=10.43.7.223
+ExecStartPre=/usr/local/bin/check-db
+ExecStopPost=/usr/local/bin/cleanup-db --all";
+        assert_eq!(unit, expected);
+    }
+
+    #[test]
+    fn test_systemd_write_error() {
+        let path = Path::new(SYSTEMD_PREFIX).join("foo.service.d/reactor.conf");
+
+        let e = systemd_write_error(&path, io::Error::from_raw_os_error(libc::EROFS));
+        let msg = e.to_string();
+        assert!(msg.contains("\"/run/systemd/system/foo.service.d/reactor.conf\""));
+        assert!(msg.contains("'/run/systemd/system' has to be writable"));
+        assert!(msg.contains("runner = \"shell\""));
+
+        let e = systemd_write_error(&path, io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(e.to_string().contains("runner = \"shell\""));
+
+        let e = systemd_write_error(&path, io::Error::from(io::ErrorKind::WriteZero));
+        assert!(!e.to_string().contains("runner"));
+    }
+
+    #[test]
+    fn test_drbd_demote_or_escalate() {
+        let mut settings = SystemdSettings {
+            target_as: SystemdDependency::Requires,
+            dependencies_as: SystemdDependency::Requires,
+            failure_action: SystemdFailureAction::None,
+            ownership_markers: None,
+            escalate_notify: Some("/usr/local/bin/alert reboot %I".to_string()),
+            service_shim: "/lib/drbd/scripts/drbd-service-shim.sh".to_string(),
+            ocf_root: PathBuf::from(systemd::OCF_ROOT),
+        };
+        assert_eq!(drbd_demote_or_escalate(&settings, true), None);
+
+        settings.failure_action = SystemdFailureAction::Reboot;
+        let content = drbd_demote_or_escalate(&settings, true).expect("should generate content");
+        assert_eq!(
+            content,
+            r"[Unit]
+FailureAction=reboot
+Conflicts=drbd-promote@%i.service
+
+[Service]
+ExecStartPre=-/usr/bin/timeout 10 /usr/local/bin/alert reboot %I
+ExecStart=
+ExecStart=/lib/drbd/scripts/drbd-service-shim.sh secondary-secondary-force-or-escalate %I
+"
+        );
+
+        let content = drbd_demote_or_escalate(&settings, false).expect("should generate content");
+        assert!(content.ends_with(
+            "\n[Service]\nExecStartPre=-/usr/bin/timeout 10 /usr/local/bin/alert reboot %I\n"
+        ));
+
+        settings.escalate_notify = None;
+        let content = drbd_demote_or_escalate(&settings, false).expect("should generate content");
+        assert!(!content.contains("[Service]"));
+    }
+
+    #[test]
+    fn test_start_actions_retries() {
+        let dir = tempfile::tempdir().expect("tempdir must work");
+        let marker = dir.path().join("failed-once");
+        // fails the first time it gets called, succeeds afterwards
+        let actions = vec![format!(
+            "test -e {0} || {{ touch {0}; false; }}",
+            marker.display()
+        )];
+        let no_sleep = Duration::from_secs(0);
+
+        assert!(start_actions(
+            &SystemRunner,
+            "foo",
+            &actions,
+            &Runner::Shell,
+            0,
+            no_sleep,
+            None
+        )
+        .is_err());
+        assert!(start_actions(
+            &SystemRunner,
+            "foo",
+            &actions,
+            &Runner::Shell,
+            0,
+            no_sleep,
+            None
+        )
+        .is_ok());
+
+        fs::remove_file(&marker).expect("marker must exist");
+        assert!(start_actions(
+            &SystemRunner,
+            "foo",
+            &actions,
+            &Runner::Shell,
+            1,
+            no_sleep,
+            None
+        )
+        .is_ok());
+
+        let actions = vec!["false".to_string()];
+        assert!(start_actions(
+            &SystemRunner,
+            "foo",
+            &actions,
+            &Runner::Shell,
+            2,
+            no_sleep,
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_promotion_sequence() {
+        let mut res: PromoterOptResource =
+            toml::from_str(r#"start = ["foo.mount", "foo.service"]"#).expect("cfg must parse");
+
+        // a remote Primary prevents the initial start
+        let remote_primary =
+            r#"[{"role": "Secondary", "connections": [{"name": "peer", "peer-role": "Primary"}]}]"#;
+        let runner = MockRunner::new().respond("drbdsetup status --json foo", 0, remote_primary);
+        assert!(!try_initial_target_start(&runner, "foo"));
+        assert_eq!(runner.calls(), vec!["drbdsetup status --json foo"]);
+
+        let runner = MockRunner::new().respond(
+            "systemctl show --property=ActiveState",
+            0,
+            "ActiveState=active\n",
+        );
+        try_start_stop_actions(&runner, "foo", &res);
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "systemctl reset-failed drbd-services@foo.target",
+                "systemctl start drbd-services@foo.target",
+                "systemctl show --property=ActiveState drbd-services@foo.target",
+            ]
+        );
+
+        // failed start, services get stopped again
+        let runner = MockRunner::new().respond("systemctl start", 1, "");
+        try_start_stop_actions(&runner, "foo", &res);
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "systemctl reset-failed drbd-services@foo.target",
+                "systemctl start drbd-services@foo.target",
+                "journalctl --flush --sync",
+                "systemctl stop drbd-services@foo.target",
+                "drbdsetup status --json foo",
+            ]
+        );
+
+        // start that never becomes active, retried once
+        res.start_retries = 1;
+        res.start_retry_interval_secs = 0;
+        let runner = MockRunner::new().respond(
+            "systemctl show --property=ActiveState",
+            0,
+            "ActiveState=failed\n",
+        );
+        try_start_stop_actions(&runner, "foo", &res);
+        let calls = runner.calls();
+        assert_eq!(
+            calls
+                .iter()
+                .filter(|c| *c == "systemctl start drbd-services@foo.target")
+                .count(),
+            2
+        );
+        assert!(calls.contains(&"systemctl stop drbd-services@foo.target".to_string()));
+    }
+
+    #[test]
+    fn test_start_timeout() {
+        let res: PromoterOptResource = toml::from_str(
+            r#"
+            start = ["foo.service"]
+            start-timeout-secs = 30
+            "#,
+        )
+        .expect("cfg must parse");
+        assert_eq!(res.start_timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(res.stop_timeout(), None);
+
+        // hanging start, services get stopped again
+        let runner = MockRunner::new().hang("systemctl start");
+        try_start_stop_actions(&runner, "foo", &res);
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "systemctl reset-failed drbd-services@foo.target",
+                "systemctl start drbd-services@foo.target",
+                "journalctl --flush --sync",
+                "systemctl stop drbd-services@foo.target",
+                "drbdsetup status --json foo",
+            ]
+        );
+
+        // without a timeout the start is waited for
+        let res: PromoterOptResource =
+            toml::from_str(r#"start = ["foo.service"]"#).expect("cfg must parse");
+        let runner = MockRunner::new().hang("systemctl start").respond(
+            "systemctl show --property=ActiveState",
+            0,
+            "ActiveState=active\n",
+        );
+        try_start_stop_actions(&runner, "foo", &res);
+        assert!(!runner
+            .calls()
+            .contains(&"systemctl stop drbd-services@foo.target".to_string()));
+    }
+
+    #[test]
+    fn test_validate_ocf_agents() {
+        let actions = vec![
+            "ocf:heartbeat:IPaddr2 ip0 cidr_netmask=24 ip=10.43.7.223".to_string(),
+            "foo.service".to_string(),
+        ];
+        let runner = MockRunner::new();
+        validate_ocf_agents(&runner, "res", &actions, Path::new(systemd::OCF_ROOT))
+            .expect("must validate");
+        assert_eq!(
+            runner.calls(),
+            vec!["env OCF_ROOT=/usr/lib/ocf OCF_RESOURCE_INSTANCE=ip0_res OCF_RESKEY_cidr_netmask=24 OCF_RESKEY_ip=10.43.7.223 /usr/lib/ocf/resource.d/heartbeat/IPaddr2 validate-all"]
+        );
+
+        let actions = vec!["ocf:heartbeat:Filesystm fs0 device=/dev/drbd1000".to_string()];
+        let runner = MockRunner::new().respond("env", 127, "");
+        let err = validate_ocf_agents(&runner, "res", &actions, Path::new(systemd::OCF_ROOT))
+            .expect_err("must fail");
+        assert!(err.to_string().contains("does not exist"));
+
+        let runner = MockRunner::new().respond("env", 6, "");
+        let err = validate_ocf_agents(&runner, "res", &actions, Path::new(systemd::OCF_ROOT))
+            .expect_err("must fail");
+        assert!(err.to_string().contains("failed to validate 'fs0'"));
+    }
+
+    #[test]
+    fn test_resource_managed() {
+        let show = r#"[
+    {
+        "resource": "foo",
+        "_this_host": {
+            "node-id": 0
+        },
+        "options": {
+            "auto-promote": false,
+            "quorum": "majority",
+            "on-no-quorum": "io-error",
+            "peer-ack-window": 4096
+        },
+        "connections": []
+    }
+]"#;
+        let options = parse_resource_options("foo", show).expect("show output must parse");
+        assert_eq!(options.get("auto-promote").map(String::as_str), Some("no"));
+        assert_eq!(options.get("quorum").map(String::as_str), Some("majority"));
+        assert_eq!(
+            options.get("peer-ack-window").map(String::as_str),
+            Some("4096")
+        );
+        assert!(parse_resource_options("bar", show).is_err());
+
+        let managed_if = |kvs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            kvs.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let runner = MockRunner::new().respond("drbdsetup show", 0, show);
+
+        // no condition, drbdsetup not even asked
+        assert!(resource_managed(&runner, "foo", &managed_if(&[])));
+        assert!(runner.calls().is_empty());
+
+        assert!(resource_managed(
+            &runner,
+            "foo",
+            &managed_if(&[("auto-promote", "no"), ("quorum", "majority")])
+        ));
+        assert_eq!(
+            runner.calls(),
+            vec!["drbdsetup show --show-defaults --json foo"]
+        );
+        assert!(!resource_managed(
+            &runner,
+            "foo",
+            &managed_if(&[("quorum", "off")])
+        ));
+        assert!(!resource_managed(
+            &runner,
+            "foo",
+            &managed_if(&[("on-suspended-primary-outdated", "force-secondary")])
+        ));
+
+        // resource unknown to DRBD
+        let runner = MockRunner::new().respond("drbdsetup show", 10, "");
+        assert!(!resource_managed(
+            &runner,
+            "foo",
+            &managed_if(&[("quorum", "majority")])
+        ));
+    }
+
+    #[test]
+    fn test_check_managed() {
+        use crate::drbd::{ResourcePluginUpdate, ResourceUpdateState};
+
+        let cfg: PromoterConfig = toml::from_str(
+            r#"
+            [resources.foo]
+            start = ["foo.service"]
+            runner = "shell"
+            managed-if = { "auto-promote" = "no" }
+            "#,
+        )
+        .expect("cfg must parse");
+        let show = r#"[{"resource": "foo", "options": {"auto-promote": false}}]"#;
+        let update = |event_type: EventType| {
+            let state = ResourceUpdateState {
+                role: Role::Secondary,
+                may_promote: false,
+                promotion_score: 0,
+                healthy: false,
+            };
+            PluginUpdate::Resource(ResourcePluginUpdate {
+                event_type,
+                resource_name: "foo".to_string(),
+                old: state.clone(),
+                new: state,
+                resource: Resource::with_name("foo"),
+            })
+        };
+        // not known to DRBD when the plugin started, but created later
+        let runner = MockRunner::new()
+            .respond_times("drbdsetup show", 1, 10, "")
+            .respond("drbdsetup show", 0, show);
+        let mut managed = HashSet::new();
+
+        assert!(!check_managed(
+            &runner,
+            &update(EventType::Create),
+            &cfg,
+            &mut managed
+        ));
+        // changes do not evaluate 'managed-if' again
+        assert!(!check_managed(
+            &runner,
+            &update(EventType::Change),
+            &cfg,
+            &mut managed
+        ));
+        assert_eq!(runner.calls().len(), 1);
+
+        assert!(check_managed(
+            &runner,
+            &update(EventType::Exists),
+            &cfg,
+            &mut managed
+        ));
+        assert!(managed.contains("foo"));
+        assert!(check_managed(
+            &runner,
+            &update(EventType::Change),
+            &cfg,
+            &mut managed
+        ));
+        assert_eq!(runner.calls().len(), 2);
+        set_intended_role("foo", None);
+    }
+
+    #[test]
+    fn test_status_socket() {
+        let cfg: PromoterConfig = toml::from_str(
+            r#"
+            status-socket = "/run/drbd-reactor/promoter.sock"
+            [resources.foo]
+            start = ["foo.service"]
+            [resources.bar]
+            runner = "shell"
+            start = ["true"]
+            "#,
+        )
+        .expect("cfg must parse");
+        assert_eq!(
+            cfg.status_socket,
+            Some(PathBuf::from("/run/drbd-reactor/promoter.sock"))
+        );
+
+        let dir = tempfile::tempdir().expect("tempdir must work");
+        let path = dir.path().join("promoter.sock");
+        let runner = MockRunner::new().respond(
+            "systemctl show --property=ActiveState drbd-services@foo.target",
+            0,
+            "ActiveState=active\n",
+        );
+        let state = Mutex::new(StatusState {
+            may_promote: BTreeSet::from(["foo".to_string()]),
+            last_start: Some(Instant::now()),
+        });
+        let stop = AtomicBool::new(false);
+        let listener = bind_status_socket(&path).expect("socket must bind");
+
+        thread::scope(|s| {
+            s.spawn(|| serve_status(listener, &stop, &runner, &cfg, &state));
+
+            let mut out = String::new();
+            io::Read::read_to_string(
+                &mut UnixStream::connect(&path).expect("must connect"),
+                &mut out,
+            )
+            .expect("must read status");
+            let status: serde_json::Value = serde_json::from_str(&out).expect("must be JSON");
+            assert_eq!(status["may-promote"], serde_json::json!(["foo"]));
+            assert_eq!(status["last-start-secs-ago"], serde_json::json!(0));
+            assert_eq!(
+                status["resources"]["foo"]["started"],
+                serde_json::json!(true)
+            );
+            assert_eq!(
+                status["resources"]["bar"]["started"],
+                serde_json::Value::Null
+            );
+
+            stop.store(true, Ordering::Relaxed);
+            let _ = UnixStream::connect(&path);
+        });
+
+        // a stale socket gets replaced
+        assert!(bind_status_socket(&path).is_ok());
+    }
+
+    #[test]
+    fn test_hooks() {
+        let res: PromoterOptResource = toml::from_str(
+            r#"
+            start = ["foo.service"]
+            on-promote = "notify promoted"
+            on-stop = ""
+            "#,
+        )
+        .expect("cfg must parse");
+
+        let runner = MockRunner::new().respond(
+            "systemctl show --property=ActiveState",
+            0,
+            "ActiveState=active\n",
+        );
+        try_start_stop_actions(&runner, "foo", &res);
+        let calls = runner.calls();
+        let hook = calls.last().expect("hook must be executed");
+        assert!(hook.starts_with("sh -c export DRBD_RES_NAME=foo DRBD_NODE_NAME="));
+        assert!(hook.ends_with("; notify promoted"));
+
+        // a failing hook does not matter, an empty one is not executed
+        let runner = MockRunner::new().respond("sh -c", 1, "");
+        assert!(stop_resource(&runner, "foo", &res).is_ok());
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "journalctl --flush --sync",
+                "systemctl stop drbd-services@foo.target",
+                "drbdsetup status --json foo",
+            ]
+        );
+
+        // failed start, no on-promote
+        let runner = MockRunner::new().respond("systemctl start", 1, "");
+        try_start_stop_actions(&runner, "foo", &res);
+        assert!(!runner.calls().iter().any(|c| c.starts_with("sh -c")));
+    }
+
+    #[test]
+    fn test_no_data_accessible() {
+        use crate::drbd::{Connection, DevicePluginUpdate, DeviceUpdateState, PeerDevice};
+
+        let cfg: PromoterConfig = toml::from_str(
+            r#"
+            [resources.foo]
+            start = ["foo.service"]
+            on-no-data-accessible = "shutdown"
+            "#,
+        )
+        .expect("cfg must parse");
+        let mut resource = Resource {
+            name: "foo".to_string(),
+            role: Role::Primary,
+            devices: vec![Device {
+                disk_state: DiskState::Diskless,
+                ..Default::default()
+            }],
+            connections: vec![Connection {
+                peer_node_id: 1,
+                peerdevices: vec![PeerDevice {
+                    peer_node_id: 1,
+                    peer_disk_state: DiskState::UpToDate,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        // diskless, but served by the peer
+        assert!(data_accessible(&resource));
+        resource.connections[0].peerdevices[0].peer_disk_state = DiskState::DUnknown;
+        assert!(!data_accessible(&resource));
+        resource.devices[0].disk_state = DiskState::UpToDate;
+        assert!(data_accessible(&resource));
+
+        let detached = |resource: &Resource| {
+            Arc::new(PluginUpdate::Device(DevicePluginUpdate {
+                event_type: EventType::Change,
+                resource_name: "foo".to_string(),
+                volume: 0,
+                old: DeviceUpdateState {
+                    disk_state: DiskState::UpToDate,
+                    quorum: true,
+                    ..Default::default()
+                },
+                new: DeviceUpdateState {
+                    disk_state: DiskState::Failed,
+                    quorum: true,
+                    ..Default::default()
+                },
+                resource: resource.clone(),
+            }))
+        };
+        let process = |runner: &MockRunner, cfg: &PromoterConfig, update| {
+            process_drbd_event(
+                runner,
+                &update,
+                cfg,
+                &mut Instant::now(),
+                &mut HashSet::new(),
+                &mut HashSet::new(),
+            );
+        };
+
+        // the last UpToDate disk is gone
+        resource.devices[0].disk_state = DiskState::Failed;
+        let runner = MockRunner::new();
+        process(&runner, &cfg, detached(&resource));
+        assert!(runner
+            .calls()
+            .contains(&"systemctl stop drbd-services@foo.target".to_string()));
+
+        // not configured
+        let mut unset = cfg.clone();
+        unset
+            .resources
+            .get_mut("foo")
+            .unwrap()
+            .on_no_data_accessible = None;
+        let runner = MockRunner::new();
+        process(&runner, &unset, detached(&resource));
+        assert!(runner.calls().is_empty());
+
+        // a peer still has the data
+        resource.connections[0].peerdevices[0].peer_disk_state = DiskState::UpToDate;
+        let runner = MockRunner::new();
+        process(&runner, &cfg, detached(&resource));
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn test_min_connected_peers() {
+        use crate::drbd::{
+            Connection, ConnectionPluginUpdate, ConnectionUpdateState, ResourcePluginUpdate,
+            ResourceUpdateState,
+        };
+
+        let cfg: PromoterConfig = toml::from_str(
+            r#"
+            [resources.foo]
+            start = ["foo.service"]
+            min-connected-peers = 2
+            "#,
+        )
+        .expect("cfg must parse");
+        let mut resource = Resource {
+            name: "foo".to_string(),
+            may_promote: true,
+            devices: vec![Device {
+                disk_state: DiskState::UpToDate,
+                ..Default::default()
+            }],
+            connections: vec![
+                Connection {
+                    peer_node_id: 1,
+                    connection: ConnectionState::Connected,
+                    ..Default::default()
+                },
+                Connection {
+                    peer_node_id: 2,
+                    connection: ConnectionState::Connecting,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(!enough_connected_peers(&resource, 2));
+        assert!(enough_connected_peers(&resource, 1));
+        assert!(enough_connected_peers(&resource, 0));
+
+        let may_promote_update = |resource: &Resource| {
+            Arc::new(PluginUpdate::Resource(ResourcePluginUpdate {
+                event_type: EventType::Change,
+                resource_name: "foo".to_string(),
+                old: ResourceUpdateState {
+                    role: Role::Secondary,
+                    may_promote: false,
+                    promotion_score: 0,
+                    healthy: false,
+                },
+                new: ResourceUpdateState {
+                    role: Role::Secondary,
+                    may_promote: true,
+                    promotion_score: 0,
+                    healthy: false,
+                },
+                resource: resource.clone(),
+            }))
+        };
+
+        // only one peer connected, held back
+        let runner = MockRunner::new();
+        let mut last_start = Instant::now() - Duration::from_secs(MIN_SECS_PROMOTE + 1);
+        let mut may_promote = HashSet::new();
+        process_drbd_event(
+            &runner,
+            &may_promote_update(&resource),
+            &cfg,
+            &mut last_start,
+            &mut may_promote,
+            &mut HashSet::new(),
+        );
+        assert!(runner.calls().is_empty());
+        assert!(may_promote.is_empty());
+
+        // the second peer connects, the ticker may start it now
+        resource.connections[1].connection = ConnectionState::Connected;
+        let connected = Arc::new(PluginUpdate::Connection(ConnectionPluginUpdate {
+            event_type: EventType::Change,
+            resource_name: "foo".to_string(),
+            peer_node_id: 2,
+            old: ConnectionUpdateState {
+                connection_state: ConnectionState::Connecting,
+                ..Default::default()
+            },
+            new: ConnectionUpdateState {
+                connection_state: ConnectionState::Connected,
+                ..Default::default()
+            },
+            resource: resource.clone(),
+        }));
+        process_drbd_event(
+            &runner,
+            &connected,
+            &cfg,
+            &mut last_start,
+            &mut may_promote,
+            &mut HashSet::new(),
+        );
+        assert!(may_promote.contains("foo"));
+
+        // enough peers, promoted right away
+        let runner = MockRunner::new().respond(
+            "systemctl show --property=ActiveState",
+            0,
+            "ActiveState=active\n",
+        );
+        let mut may_promote = HashSet::new();
+        process_drbd_event(
+            &runner,
+            &may_promote_update(&resource),
+            &cfg,
+            &mut last_start,
+            &mut may_promote,
+            &mut HashSet::new(),
+        );
+        assert!(runner
+            .calls()
+            .contains(&"systemctl start drbd-services@foo.target".to_string()));
+    }
+
+    #[test]
+    fn test_min_connected_peers_forced_secondary() {
+        use crate::drbd::{Connection, ResourcePluginUpdate, ResourceUpdateState};
+
+        let cfg: PromoterConfig = toml::from_str(
+            r#"
+            [resources.foo]
+            start = ["foo.service"]
+            min-connected-peers = 2
+            on-quorum-loss = "freeze"
+            "#,
+        )
+        .expect("cfg must parse");
+        let resource = Resource {
+            name: "foo".to_string(),
+            may_promote: true,
+            connections: vec![Connection {
+                peer_node_id: 1,
+                connection: ConnectionState::Connected,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        // forced to Secondary while frozen, and may promote again right away
+        let update = Arc::new(PluginUpdate::Resource(ResourcePluginUpdate {
+            event_type: EventType::Change,
+            resource_name: "foo".to_string(),
+            old: ResourceUpdateState {
+                role: Role::Primary,
+                may_promote: false,
+                promotion_score: 0,
+                healthy: false,
+            },
+            new: ResourceUpdateState {
+                role: Role::Secondary,
+                may_promote: true,
+                promotion_score: 0,
+                healthy: false,
+            },
+            resource,
+        }));
+
+        let runner = MockRunner::new();
+        let mut may_promote = HashSet::new();
+        process_drbd_event(
+            &runner,
+            &update,
+            &cfg,
+            &mut Instant::now(),
+            &mut may_promote,
+            &mut HashSet::new(),
+        );
+        // not promoted, but the services still get stopped
+        assert!(may_promote.is_empty());
+        assert!(runner
+            .calls()
+            .contains(&"systemctl stop drbd-services@foo.target".to_string()));
+        set_intended_role("foo", None);
+    }
+
+    #[test]
+    fn test_promotion_group() {
+        use crate::drbd::{ResourcePluginUpdate, ResourceUpdateState};
+
+        let cfg: PromoterConfig = toml::from_str(
+            r#"
+            [resources.foo]
+            start = ["foo.service"]
+            promotion-group = "vg0"
+            [resources.bar]
+            start = ["bar.service"]
+            promotion-group = "vg0"
+            "#,
+        )
+        .expect("cfg must parse");
+        let update = |name: &str| {
+            Arc::new(PluginUpdate::Resource(ResourcePluginUpdate {
+                event_type: EventType::Change,
+                resource_name: name.to_string(),
+                old: ResourceUpdateState {
+                    role: Role::Secondary,
+                    may_promote: false,
+                    promotion_score: 0,
+                    healthy: false,
+                },
+                new: ResourceUpdateState {
+                    role: Role::Secondary,
+                    may_promote: true,
+                    promotion_score: 0,
+                    healthy: false,
+                },
+                resource: Resource::with_name(name),
+            }))
+        };
+        let started = |runner: &MockRunner, name: &str| {
+            runner
+                .calls()
+                .contains(&format!("systemctl start drbd-services@{}.target", name))
+        };
+        let long_ago = || Instant::now() - Duration::from_secs(MIN_SECS_PROMOTE + 1);
+
+        let runner = MockRunner::new().respond(
+            "systemctl show --property=ActiveState",
+            0,
+            "ActiveState=active\n",
+        );
+        let mut may_promote = HashSet::new();
+        let mut groups_in_flight = HashSet::new();
+        let mut last_start = long_ago();
+        process_drbd_event(
+            &runner,
+            &update("foo"),
+            &cfg,
+            &mut last_start,
+            &mut may_promote,
+            &mut groups_in_flight,
+        );
+        assert!(started(&runner, "foo"));
+
+        // independent of the global start interval, bar has to wait for the group
+        let mut last_start = long_ago();
+        process_drbd_event(
+            &runner,
+            &update("bar"),
+            &cfg,
+            &mut last_start,
+            &mut may_promote,
+            &mut groups_in_flight,
+        );
+        assert!(!started(&runner, "bar"));
+        assert!(may_promote.contains("bar"));
+
+        // the next tick starts the queued one
+        let runner = MockRunner::new()
+            .respond_times(
+                "systemctl show --property=ActiveState drbd-services@bar.target",
+                1,
+                0,
+                "ActiveState=inactive\n",
+            )
+            .respond(
+                "systemctl show --property=ActiveState",
+                0,
+                "ActiveState=active\n",
+            );
+        process_ticker(
+            &runner,
+            &cfg,
+            &may_promote,
+            &mut last_start,
+            &mut groups_in_flight,
+        );
+        assert!(started(&runner, "bar"));
+        assert!(!started(&runner, "foo"));
+
+        // resources without a group are not affected
+        let res = cfg.resources.get("foo").expect("foo must exist").clone();
+        let res = PromoterOptResource {
+            promotion_group: None,
+            ..res
+        };
+        assert!(claim_promotion_group("foo", &res, &mut groups_in_flight));
+        assert!(claim_promotion_group("foo", &res, &mut groups_in_flight));
+    }
+
+    #[test]
+    fn test_peer_became_primary() {
+        use crate::drbd::{ConnectionPluginUpdate, ConnectionUpdateState};
+
+        let cfg: PromoterConfig = toml::from_str(
+            r#"
+            [resources.standdown]
+            start = ["foo.service"]
+            "#,
+        )
+        .expect("cfg must parse");
+        let update = |old: Role, new: Role| {
+            Arc::new(PluginUpdate::Connection(ConnectionPluginUpdate {
+                event_type: EventType::Change,
+                resource_name: "standdown".to_string(),
+                peer_node_id: 1,
+                old: ConnectionUpdateState {
+                    conn_name: "peer".to_string(),
+                    peer_role: old,
+                    ..Default::default()
+                },
+                new: ConnectionUpdateState {
+                    conn_name: "peer".to_string(),
+                    peer_role: new,
+                    ..Default::default()
+                },
+                resource: Resource::with_name("standdown"),
+            }))
+        };
+
+        let runner = MockRunner::new();
+        let mut last_start = Instant::now() - Duration::from_secs(MIN_SECS_PROMOTE + 1);
+        let mut may_promote = HashSet::from(["standdown".to_string()]);
+        set_promotable("standdown", true);
+        let promotable = || PROMOTABLE_SINCE.lock().unwrap().contains_key("standdown");
+
+        // unrelated changes keep the candidate
+        process_drbd_event(
+            &runner,
+            &update(Role::Secondary, Role::Secondary),
+            &cfg,
+            &mut last_start,
+            &mut may_promote,
+            &mut HashSet::new(),
+        );
+        assert!(may_promote.contains("standdown"));
+        assert!(promotable());
+
+        process_drbd_event(
+            &runner,
+            &update(Role::Secondary, Role::Primary),
+            &cfg,
+            &mut last_start,
+            &mut may_promote,
+            &mut HashSet::new(),
+        );
+        assert!(may_promote.is_empty());
+        assert!(!promotable());
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let res: PromoterOptResource = toml::from_str(
+            r#"
+            start = ["dry-run-test.service"]
+            dry-run = true
+            "#,
+        )
+        .expect("cfg must parse");
+
+        let runner = MockRunner::new();
+        try_start_stop_actions(&runner, "dry-run-test", &res);
+        assert!(stop_resource(&runner, "dry-run-test", &res).is_ok());
+        assert!(freeze_actions(&runner, "dry-run-test", State::Freeze, &res).is_ok());
+        assert!(runner.calls().is_empty());
+
+        let settings = SystemdSettings {
+            target_as: SystemdDependency::Requires,
+            dependencies_as: SystemdDependency::Requires,
+            failure_action: SystemdFailureAction::None,
+            ownership_markers: None,
+            escalate_notify: None,
+            service_shim: "/lib/drbd/scripts/drbd-service-shim.sh".to_string(),
+            ocf_root: PathBuf::from(systemd::OCF_ROOT),
+        };
+        assert!(
+            generate_systemd_templates("dry-run-test", &res.start, &settings, true, true).is_ok()
+        );
+        assert!(!escaped_services_target_dir("dry-run-test").exists());
+        assert!(!Path::new(SYSTEMD_PREFIX)
+            .join("dry-run-test.service.d")
+            .exists());
+    }
+
+    #[test]
+    fn test_candidate_script() {
+        use crate::drbd::{ResourcePluginUpdate, ResourceUpdateState};
+
+        let cfg: PromoterConfig = toml::from_str(
+            r#"
+            [resources.foo]
+            start = ["foo.service"]
+            candidate-script = "ask-policy-engine"
+            "#,
+        )
+        .expect("cfg must parse");
+        let update = Arc::new(PluginUpdate::Resource(ResourcePluginUpdate {
+            event_type: EventType::Change,
+            resource_name: "foo".to_string(),
+            old: ResourceUpdateState {
+                role: Role::Secondary,
+                may_promote: false,
+                promotion_score: 0,
+                healthy: false,
+            },
+            new: ResourceUpdateState {
+                role: Role::Secondary,
+                may_promote: true,
+                promotion_score: 0,
+                healthy: false,
+            },
+            resource: Resource::with_name("foo"),
+        }));
+        let started = |runner: &MockRunner| {
+            runner
+                .calls()
+                .contains(&"systemctl start drbd-services@foo.target".to_string())
+        };
+
+        // non-zero defers, but the resource stays a candidate for the ticker
+        let runner = MockRunner::new().respond("sh -c", 1, "");
+        let mut last_start = Instant::now() - Duration::from_secs(MIN_SECS_PROMOTE + 1);
+        let mut may_promote = HashSet::new();
+        process_drbd_event(
+            &runner,
+            &update,
+            &cfg,
+            &mut last_start,
+            &mut may_promote,
+            &mut HashSet::new(),
+        );
+        assert!(runner.calls()[0].ends_with("; ask-policy-engine"));
+        assert!(!started(&runner));
+        assert!(may_promote.contains("foo"));
+
+        // zero promotes
+        let runner = MockRunner::new().respond(
+            "systemctl show --property=ActiveState",
+            0,
+            "ActiveState=active\n",
+        );
+        let mut may_promote = HashSet::new();
+        process_drbd_event(
+            &runner,
+            &update,
+            &cfg,
+            &mut last_start,
+            &mut may_promote,
+            &mut HashSet::new(),
+        );
+        assert!(started(&runner));
+
+        // without a script it is always allowed
+        let res = cfg.resources.get("foo").expect("foo must exist");
+        assert!(!candidate_allows(
+            &MockRunner::new().respond("sh -c", 1, ""),
+            "foo",
+            res
+        ));
+        let mut res = res.clone();
+        res.candidate_script = Some("".to_string());
+        assert!(candidate_allows(
+            &MockRunner::new().respond("sh -c", 1, ""),
+            "foo",
+            &res
+        ));
+    }
+
+    #[test]
+    fn test_intended_role() {
+        // the roles are global, use a name no other test uses
+        let name = "intended-role-test";
+        let intended = || intended_roles().get(name).cloned();
+        let res: PromoterOptResource =
+            toml::from_str(r#"start = ["foo.service"]"#).expect("cfg must parse");
+
+        let runner = MockRunner::new().respond(
+            "systemctl show --property=ActiveState",
+            0,
+            "ActiveState=active\n",
+        );
+        try_start_stop_actions(&runner, name, &res);
+        assert_eq!(intended(), Some(Role::Primary));
+
+        assert!(stop_resource(&runner, name, &res).is_ok());
+        assert_eq!(intended(), Some(Role::Secondary));
+
+        let runner = MockRunner::new().respond("systemctl start", 1, "");
+        try_start_stop_actions(&runner, name, &res);
+        assert_eq!(intended(), Some(Role::Secondary));
+
+        set_intended_role(name, None);
+        assert_eq!(intended(), None);
+    }
+
+    #[test]
+    fn test_fallback_demote() {
+        let res: PromoterOptResource =
+            toml::from_str(r#"start = ["foo.service"]"#).expect("cfg must parse");
+        let primary = r#"[{"role": "Primary", "connections": []}]"#;
+        let secondary = r#"[{"role": "Secondary", "connections": []}]"#;
+        let version = "DRBDADM_VERSION_CODE=0x091d00\nDRBD_KERNEL_VERSION_CODE=0x090200\n";
+
+        // demoted by the target stop, nothing to do
+        let runner = MockRunner::new().respond("drbdsetup status --json foo", 0, secondary);
+        assert!(stop_resource(&runner, "foo", &res).is_ok());
+        assert!(!runner.calls().iter().any(|c| c.starts_with("drbdadm")));
+
+        // still Primary, the fallback forces the demote
+        let runner = MockRunner::new()
+            .respond("drbdsetup status --json foo", 0, primary)
+            .respond("drbdadm --version", 0, version);
+        assert!(stop_resource(&runner, "foo", &res).is_ok());
+        assert_eq!(
+            runner.calls().last().map(String::as_str),
+            Some("drbdadm secondary --force foo")
+        );
+
+        // too old for --force
+        let old_version = "DRBDADM_VERSION_CODE=0x091d00\nDRBD_KERNEL_VERSION_CODE=0x090106\n";
+        let runner = MockRunner::new()
+            .respond("drbdsetup status --json foo", 0, primary)
+            .respond("drbdadm --version", 0, old_version);
+        assert!(stop_resource(&runner, "foo", &res).is_ok());
+        assert_eq!(
+            runner.calls().last().map(String::as_str),
+            Some("drbdadm secondary foo")
+        );
+
+        // only for the systemd runner, the shell runner has no demote shim
+        let mut res = res;
+        res.runner = Runner::Shell;
+        res.stop = vec!["true".to_string()];
+        let runner = MockRunner::new().respond("drbdsetup status --json foo", 0, primary);
+        assert!(stop_resource(&runner, "foo", &res).is_ok());
+        assert!(!runner.calls().iter().any(|c| c.starts_with("drbd")));
+    }
+
+    #[test]
+    fn test_systemd_write_unit_unchanged() {
+        let dir = tempfile::tempdir().expect("tempdir must work");
+        let prefix = dir.path().join("foo.service.d");
+        let unit = || "[Unit]\nBefore=drbd-reactor.service\n".to_string();
+
+        assert!(systemd_write_unit(prefix.clone(), SYSTEMD_CONF, unit()).expect("must write"));
+        assert!(!systemd_write_unit(prefix.clone(), SYSTEMD_CONF, unit()).expect("must write"));
+        assert!(
+            systemd_write_unit(prefix.clone(), SYSTEMD_CONF, "[Unit]\n".to_string())
+                .expect("must write")
+        );
+        assert_eq!(
+            fs::read_to_string(prefix.join(SYSTEMD_CONF)).expect("must read"),
+            "# Auto-generated by drbd-reactor, DO NOT EDIT\n[Unit]\n\n"
+        );
+    }
+
+    #[test]
+    fn test_promotion_latency() {
+        let mut l = PromotionLatency::new();
+        l.observe(Duration::from_millis(300));
+        l.observe(Duration::from_secs(7));
+        l.observe(Duration::from_secs(1000));
+        assert_eq!(l.buckets, [1, 1, 1, 1, 2, 2, 2, 2, 2, 2]);
+        assert_eq!(l.count, 3);
+        assert!((l.sum_secs - 1007.3).abs() < 0.001);
+
+        // only starts of resources that became promotable are recorded, the first start wins
+        let name = "promoter-latency-test";
+        let promotable = || PROMOTABLE_SINCE.lock().unwrap().contains_key(name);
+        set_promotable(name, true);
+        set_promotable(name, false);
+        assert!(!promotable());
+        set_promotable(name, true);
+        assert!(promotable());
+        let before = promotion_latency().count;
+        record_promotion(name);
+        assert!(!promotable());
+        assert!(promotion_latency().count > before);
+    }
+
+    #[test]
+    fn test_orphaned_unit_dirs() {
+        let dir = tempfile::tempdir().expect("tempdir must work");
+        for d in [
+            "drbd-services@foo.target.d",
+            "drbd-promote@foo.service.d",
+            "drbd-services@bar.target.d",
+            "drbd-promote@bar.service.d",
+            "drbd-demote-or-escalate@bar.service.d",
+            "drbd-services@a\\x2db.target.d",
+            "a.service.d",
+        ] {
+            fs::create_dir(dir.path().join(d)).expect("must create dir");
+        }
+        // not a directory
+        File::create(dir.path().join("drbd-services@baz.target")).expect("must create file");
+
+        let resources = BTreeSet::from(["foo".to_string(), "a-b".to_string()]);
+        let orphaned = orphaned_unit_dirs(dir.path(), &resources).expect("must list");
+        assert_eq!(
+            orphaned,
+            vec![
+                dir.path().join("drbd-demote-or-escalate@bar.service.d"),
+                dir.path().join("drbd-promote@bar.service.d"),
+                dir.path().join("drbd-services@bar.target.d"),
+            ]
+        );
+    }
 }
@@ -1,56 +1,129 @@
 use anyhow::Result;
 use log::{debug, info, trace, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
-use std::process::{Command, Stdio};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::io::{self, Read};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::drbd::{
     ConnectionPluginUpdatePattern, ConnectionUpdateStatePattern, DevicePluginUpdatePattern,
-    DeviceUpdateStatePattern, EventType, PeerDevicePluginUpdatePattern,
-    PeerDeviceUpdateStatePattern, PluginUpdate, ResourcePluginUpdatePattern,
-    ResourceUpdateStatePattern,
+    DeviceUpdateStatePattern, EventType, HealthPluginUpdatePattern, HealthUpdateStatePattern,
+    HelperPluginUpdatePattern, PeerDevicePluginUpdatePattern, PeerDeviceUpdateStatePattern,
+    PluginUpdate, ResourcePattern, ResourcePluginUpdatePattern, ResourceUpdateStatePattern,
 };
-use crate::matchable::{BasicPattern, PartialMatchable};
+use crate::matchable::{BasicPattern, BasicPatternOperator, PartialMatchable};
 use crate::plugin::PluginCfg;
+use crate::runner;
+use crate::utils;
 
 pub struct UMH {
     resource_rules: Vec<(CommonRule, Option<ResourcePluginUpdatePattern>)>,
     device_rules: Vec<(CommonRule, Option<DevicePluginUpdatePattern>)>,
     peer_device_rules: Vec<(CommonRule, Option<PeerDevicePluginUpdatePattern>)>,
     connection_rules: Vec<(CommonRule, Option<ConnectionPluginUpdatePattern>)>,
+    health_rules: Vec<(CommonRule, Option<HealthPluginUpdatePattern>)>,
+    helper_rules: Vec<(CommonRule, Option<HelperPluginUpdatePattern>)>,
     cfg: UMHConfig,
 }
 
+/// Number of executions of a rule's command and how many of them failed
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerStats {
+    pub invocations: u64,
+    pub failures: u64,
+}
+
+// per rule (see rule_key), shared with the prometheus plugin
+static HANDLER_STATS: Mutex<BTreeMap<String, HandlerStats>> = Mutex::new(BTreeMap::new());
+
+/// Handler statistics of all UMH rules that got executed at least once, by rule name (or command for
+/// unnamed rules)
+pub fn handler_stats() -> BTreeMap<String, HandlerStats> {
+    HANDLER_STATS
+        .lock()
+        .map(|stats| stats.clone())
+        .unwrap_or_default()
+}
+
+pub(crate) fn record_handler(rule: &str, success: bool) {
+    if let Ok(mut stats) = HANDLER_STATS.lock() {
+        let stats = stats.entry(rule.to_string()).or_default();
+        stats.invocations += 1;
+        if !success {
+            stats.failures += 1;
+        }
+    }
+}
+
 impl UMH {
     pub fn new(cfg: UMHConfig) -> Result<Self> {
         let cfg_clone = cfg.clone();
         Ok(Self {
-            resource_rules: cfg.resource.into_iter().map(Into::into).collect(),
-            device_rules: cfg.device.into_iter().map(Into::into).collect(),
-            peer_device_rules: cfg.peerdevice.into_iter().map(Into::into).collect(),
-            connection_rules: cfg.connection.into_iter().map(Into::into).collect(),
+            resource_rules: rules(cfg.resource),
+            device_rules: rules(cfg.device),
+            peer_device_rules: rules(cfg.peerdevice),
+            connection_rules: rules(cfg.connection),
+            health_rules: rules(cfg.health),
+            helper_rules: rules(cfg.helper),
             cfg: cfg_clone,
         })
     }
 }
 
+/// Converts config rules to (rule, pattern) pairs with `${NAME}` references in the commands expanded
+fn rules<R, P>(rules: Vec<R>) -> Vec<(CommonRule, P)>
+where
+    R: Into<(CommonRule, P)>,
+{
+    rules
+        .into_iter()
+        .map(Into::into)
+        .map(|(mut common, pattern): (CommonRule, P)| {
+            // the user defined env takes precedence over the one drbd-reactor got started with
+            common.command = utils::interpolate_env(&common.command, |name| {
+                common
+                    .env
+                    .get(name)
+                    .cloned()
+                    .or_else(|| env::var(name).ok())
+            });
+            (common, pattern)
+        })
+        .collect()
+}
+
 impl super::Plugin for UMH {
     fn run(&self, rx: super::PluginReceiver) -> Result<()> {
         trace!("run: start");
 
+        // rule key -> last time its command got executed
+        let mut last_fired: HashMap<String, Instant> = HashMap::new();
+
         for r in rx.into_iter() {
             let handlers = match r.as_ref() {
                 PluginUpdate::Resource(r) => get_handlers_by_pattern(r, &self.resource_rules),
                 PluginUpdate::Device(d) => get_handlers_by_pattern(d, &self.device_rules),
                 PluginUpdate::PeerDevice(p) => get_handlers_by_pattern(p, &self.peer_device_rules),
                 PluginUpdate::Connection(c) => get_handlers_by_pattern(c, &self.connection_rules),
+                PluginUpdate::Health(h) => get_handlers_by_pattern(h, &self.health_rules),
+                PluginUpdate::Helper(h) => get_handlers_by_pattern(h, &self.helper_rules),
                 _ => continue,
             };
 
             for handler in handlers {
+                if rate_limited(handler, &mut last_fired, Instant::now()) {
+                    debug!(
+                        "run: match for rule: {}, but dropped due to min-interval-secs",
+                        handler.name
+                    );
+                    continue;
+                }
                 info!("run: match for rule: {}", handler.name);
-                spawn_command(&handler.command, &r.get_env(), &handler.env)
+                spawn_command(handler, &r.get_env())
             }
         }
 
@@ -79,22 +152,50 @@ where
     Box::new(iter)
 }
 
-fn spawn_command(
-    cmd: &str,
-    filter_env: &HashMap<String, String>,
-    user_env: &BTreeMap<String, String>,
-) {
-    debug!("spawn_command: starting handler '{}'", cmd);
+// names are optional, unnamed rules are tracked by their command
+fn rule_key(rule: &CommonRule) -> &str {
+    if rule.name.is_empty() {
+        &rule.command
+    } else {
+        &rule.name
+    }
+}
+
+/// Returns true if the rule fired within its min_interval_secs, otherwise records it as fired at now
+fn rate_limited(
+    rule: &CommonRule,
+    last_fired: &mut HashMap<String, Instant>,
+    now: Instant,
+) -> bool {
+    if rule.min_interval_secs == 0 {
+        return false;
+    }
+
+    let key = rule_key(rule);
+    let min_interval = Duration::from_secs(rule.min_interval_secs);
+    match last_fired.get(key) {
+        Some(last) if now.duration_since(*last) < min_interval => true,
+        _ => {
+            last_fired.insert(key.to_string(), now);
+            false
+        }
+    }
+}
+
+/// Executes the rule's command, for synchronous rules this waits until the command finished or the
+/// rule's timeout expired (in which case the command gets killed)
+fn spawn_command(rule: &CommonRule, filter_env: &HashMap<String, String>) {
+    debug!("spawn_command: starting handler '{}'", rule.command);
 
     let common_env = common_env();
 
     let child = match Command::new("sh")
         .stdin(Stdio::null())
         .arg("-c")
-        .arg(cmd)
+        .arg(&rule.command)
         .env_clear()
         .envs(filter_env)
-        .envs(user_env)
+        .envs(&rule.env)
         .envs(common_env)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -102,25 +203,84 @@ fn spawn_command(
     {
         Ok(c) => c,
         Err(e) => {
-            warn!("spawn_command: could not execute handler: {}", e);
+            warn!(
+                "spawn_command: could not execute handler for rule '{}': {}",
+                rule_key(rule),
+                e
+            );
+            record_handler(rule_key(rule), false);
             return;
         }
     };
-    thread::spawn(move || match child.wait_with_output() {
-        Ok(output) => {
-            if !output.status.success() {
-                warn!("spawn_command: handler did not not exit successfully")
-            }
-            let out = std::str::from_utf8(&output.stdout).unwrap_or("<Could not convert stdout>");
-            let err = std::str::from_utf8(&output.stderr).unwrap_or("<Could not convert stderr>");
-            if !out.is_empty() || !err.is_empty() {
-                debug!(
-                    "spawn_command: handler stdout: '{}'; stderr: '{}'",
-                    out, err
-                );
+    let name = rule_key(rule).to_string();
+
+    if !rule.synchronous {
+        thread::spawn(move || wait_command(&name, child, None));
+        return;
+    }
+    // 0 disables the timeout
+    let timeout = match rule.timeout_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    wait_command(&name, child, timeout);
+}
+
+/// Waits for the handler (and kills it after `timeout`), records and logs its exit status
+fn wait_command(rule: &str, mut child: Child, timeout: Option<Duration>) {
+    // the output is logged in the background, a handler that backgrounds children which keep
+    // stdout/stderr open must not block us
+    if let Some(stdout) = child.stdout.take() {
+        log_output(rule, "stdout", stdout);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        log_output(rule, "stderr", stderr);
+    }
+
+    let status = match timeout {
+        Some(timeout) => runner::wait_timeout(&mut child, rule, timeout),
+        None => child.wait(),
+    };
+    match status {
+        Ok(status) => {
+            record_handler(rule, status.success());
+            if !status.success() {
+                warn!(
+                    "wait_command: handler for rule '{}' did not exit successfully ({})",
+                    rule, status
+                )
             }
         }
-        Err(e) => warn!("spawn_command: could not execute handler: {}", e),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            record_handler(rule, false);
+            warn!(
+                "wait_command: handler for rule '{}' did not finish within {}s, killed it",
+                rule,
+                timeout.unwrap_or_default().as_secs()
+            )
+        }
+        Err(e) => {
+            record_handler(rule, false);
+            warn!(
+                "wait_command: could not execute handler for rule '{}': {}",
+                rule, e
+            )
+        }
+    }
+}
+
+fn log_output(rule: &str, stream: &'static str, mut reader: impl Read + Send + 'static) {
+    let rule = rule.to_string();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if reader.read_to_end(&mut output).is_ok() && !output.is_empty() {
+            debug!(
+                "wait_command: handler for rule '{}' {}: '{}'",
+                rule,
+                stream,
+                String::from_utf8_lossy(&output)
+            );
+        }
     });
 }
 
@@ -141,16 +301,64 @@ pub struct UMHConfig {
     device: Vec<DeviceRule>,
     peerdevice: Vec<PeerDeviceRule>,
     connection: Vec<ConnectionRule>,
+    health: Vec<HealthRule>,
+    helper: Vec<HelperRule>,
     pub id: Option<String>, // ! deprecated !
 }
 
+impl UMHConfig {
+    /// Resource names any of the rules can match, None if at least one rule can match any resource
+    pub fn resource_names(&self) -> Option<BTreeSet<String>> {
+        let patterns = self
+            .resource
+            .iter()
+            .map(|r| &r.resource_name)
+            .chain(self.device.iter().map(|r| &r.resource_name))
+            .chain(self.peerdevice.iter().map(|r| &r.resource_name))
+            .chain(self.connection.iter().map(|r| &r.resource_name))
+            .chain(self.health.iter().map(|r| &r.resource_name))
+            .chain(self.helper.iter().map(|r| &r.resource_name));
+
+        let mut names = BTreeSet::new();
+        for pattern in patterns {
+            match pattern {
+                Some(BasicPattern::Default(name))
+                | Some(BasicPattern::WithOperator {
+                    value: name,
+                    operator: BasicPatternOperator::Equals,
+                }) => {
+                    names.insert(name.clone());
+                }
+                Some(BasicPattern::WithValues {
+                    values,
+                    operator: BasicPatternOperator::Equals | BasicPatternOperator::In,
+                }) => names.extend(values.iter().cloned()),
+                _ => return None,
+            };
+        }
+
+        Some(names)
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
 struct CommonRule {
     command: String,
     #[serde(default)]
     name: String,
     #[serde(default)]
     env: BTreeMap<String, String>,
+    #[serde(default)]
+    min_interval_secs: u64,
+    #[serde(default)]
+    synchronous: bool,
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    30
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
@@ -164,6 +372,7 @@ struct DeviceRule {
     volume: Option<BasicPattern<i32>>,
     old: Option<DeviceUpdateStatePattern>,
     new: Option<DeviceUpdateStatePattern>,
+    resource: Option<ResourcePattern>,
 }
 
 impl From<DeviceRule> for (CommonRule, Option<DevicePluginUpdatePattern>) {
@@ -176,7 +385,7 @@ impl From<DeviceRule> for (CommonRule, Option<DevicePluginUpdatePattern>) {
                 volume: val.volume,
                 old: val.old,
                 new: val.new,
-                resource: None,
+                resource: val.resource,
             }),
         )
     }
@@ -192,6 +401,7 @@ pub struct ResourceRule {
     resource_name: Option<BasicPattern<String>>,
     old: Option<ResourceUpdateStatePattern>,
     new: Option<ResourceUpdateStatePattern>,
+    resource: Option<ResourcePattern>,
 }
 
 impl From<ResourceRule> for (CommonRule, Option<ResourcePluginUpdatePattern>) {
@@ -203,7 +413,7 @@ impl From<ResourceRule> for (CommonRule, Option<ResourcePluginUpdatePattern>) {
                 resource_name: val.resource_name,
                 old: val.old,
                 new: val.new,
-                resource: None,
+                resource: val.resource,
             }),
         )
     }
@@ -221,6 +431,7 @@ pub struct PeerDeviceRule {
     peer_node_id: Option<BasicPattern<i32>>,
     old: Option<PeerDeviceUpdateStatePattern>,
     new: Option<PeerDeviceUpdateStatePattern>,
+    resource: Option<ResourcePattern>,
 }
 
 impl From<PeerDeviceRule> for (CommonRule, Option<PeerDevicePluginUpdatePattern>) {
@@ -234,7 +445,7 @@ impl From<PeerDeviceRule> for (CommonRule, Option<PeerDevicePluginUpdatePattern>
                 peer_node_id: val.peer_node_id,
                 old: val.old,
                 new: val.new,
-                resource: None,
+                resource: val.resource,
             }),
         )
     }
@@ -251,6 +462,7 @@ pub struct ConnectionRule {
     peer_node_id: Option<BasicPattern<i32>>,
     old: Option<ConnectionUpdateStatePattern>,
     new: Option<ConnectionUpdateStatePattern>,
+    resource: Option<ResourcePattern>,
 }
 
 impl From<ConnectionRule> for (CommonRule, Option<ConnectionPluginUpdatePattern>) {
@@ -263,8 +475,150 @@ impl From<ConnectionRule> for (CommonRule, Option<ConnectionPluginUpdatePattern>
                 peer_node_id: val.peer_node_id,
                 old: val.old,
                 new: val.new,
-                resource: None,
+                resource: val.resource,
+            }),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthRule {
+    #[serde(flatten)]
+    common: CommonRule,
+
+    resource_name: Option<BasicPattern<String>>,
+    old: Option<HealthUpdateStatePattern>,
+    new: Option<HealthUpdateStatePattern>,
+    resource: Option<ResourcePattern>,
+}
+
+impl From<HealthRule> for (CommonRule, Option<HealthPluginUpdatePattern>) {
+    fn from(val: HealthRule) -> Self {
+        (
+            val.common,
+            Some(HealthPluginUpdatePattern {
+                event_type: None,
+                resource_name: val.resource_name,
+                old: val.old,
+                new: val.new,
+                resource: val.resource,
+            }),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct HelperRule {
+    #[serde(flatten)]
+    common: CommonRule,
+
+    event_type: Option<BasicPattern<EventType>>,
+    resource_name: Option<BasicPattern<String>>,
+    volume: Option<BasicPattern<i32>>,
+    peer_node_id: Option<BasicPattern<i32>>,
+    helper: Option<BasicPattern<String>>,
+    status: Option<BasicPattern<i32>>,
+    resource: Option<ResourcePattern>,
+}
+
+impl From<HelperRule> for (CommonRule, Option<HelperPluginUpdatePattern>) {
+    fn from(val: HelperRule) -> Self {
+        (
+            val.common,
+            Some(HelperPluginUpdatePattern {
+                event_type: val.event_type,
+                resource_name: val.resource_name,
+                // helpers without the value (e.g., the status of a call) are never equal to the one of a rule
+                volume: val.volume.map(|p| p.map(Some)),
+                peer_node_id: val.peer_node_id.map(|p| p.map(Some)),
+                helper: val.helper,
+                status: val.status.map(|p| p.map(Some)),
+                resource: val.resource,
             }),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync_rule(name: &str, command: &str, timeout_secs: u64) -> CommonRule {
+        CommonRule {
+            command: command.to_string(),
+            name: name.to_string(),
+            env: BTreeMap::new(),
+            min_interval_secs: 0,
+            synchronous: true,
+            timeout_secs,
+        }
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let mut rule = sync_rule("", "notify", 30);
+        let mut last_fired = HashMap::new();
+        let now = Instant::now();
+
+        // disabled
+        assert!(!rate_limited(&rule, &mut last_fired, now));
+        assert!(!rate_limited(&rule, &mut last_fired, now));
+        assert!(last_fired.is_empty());
+
+        rule.min_interval_secs = 10;
+        assert!(!rate_limited(&rule, &mut last_fired, now));
+        assert!(rate_limited(
+            &rule,
+            &mut last_fired,
+            now + Duration::from_secs(9)
+        ));
+        // suppressed invocations do not extend the interval
+        assert!(!rate_limited(
+            &rule,
+            &mut last_fired,
+            now + Duration::from_secs(10)
+        ));
+        assert!(rate_limited(
+            &rule,
+            &mut last_fired,
+            now + Duration::from_secs(11)
+        ));
+
+        // tracked by command if unnamed, otherwise by name
+        assert!(last_fired.contains_key("notify"));
+        rule.name = "other".to_string();
+        assert!(!rate_limited(
+            &rule,
+            &mut last_fired,
+            now + Duration::from_secs(11)
+        ));
+    }
+
+    #[test]
+    fn test_synchronous_timeout() {
+        let rule = sync_rule("umh-test-timeout", "sleep 10", 1);
+        let start = Instant::now();
+        spawn_command(&rule, &HashMap::new());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            handler_stats().get("umh-test-timeout"),
+            Some(&HandlerStats {
+                invocations: 1,
+                failures: 1
+            })
+        );
+
+        // 0 does not kill the handler right away
+        let rule = sync_rule("umh-test-no-timeout", "sleep 0.2", 0);
+        spawn_command(&rule, &HashMap::new());
+        assert_eq!(
+            handler_stats().get("umh-test-no-timeout"),
+            Some(&HandlerStats {
+                invocations: 1,
+                failures: 0
+            })
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Execution of external commands (e.g., `systemctl`, `drbdsetup`).
+//!
+//! Code that decides what gets executed takes a [CommandRunner] instead of using [Command]
+//! directly. Production code uses [SystemRunner], tests can use a `MockRunner` to check the exact
+//! invocations without a real DRBD/systemd.
+
+use std::io;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait CommandRunner: Send + Sync {
+    /// Executes `program` with `args`, stdout and stderr are inherited.
+    fn status(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus>;
+    /// Executes `program` with `args`, stdout and stderr are captured.
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<Output>;
+    /// Like [CommandRunner::status], but kills `program` if it did not finish within `timeout`
+    /// and returns an error of kind [io::ErrorKind::TimedOut].
+    fn status_timeout(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+    ) -> io::Result<ExitStatus>;
+    /// Like [CommandRunner::status_timeout], but does not wait for `program`. `done` gets called
+    /// with the result once `program` finished (or was killed).
+    fn spawn_timeout(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+        done: Box<dyn FnOnce(io::Result<ExitStatus>) + Send>,
+    ) -> io::Result<()>;
+}
+
+/// Executes commands on the local system.
+///
+/// stdin is always `/dev/null`, otherwise systemd wants to add some watches for password files.
+/// https://github.com/systemd/systemd/blob/fc5037e7d7b35d234720dcf06701a89c66c73adc/src/tty-ask-password-agent/tty-ask-password-agent.c#L367
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn status(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus> {
+        Command::new(program)
+            .stdin(Stdio::null())
+            .args(args)
+            .status()
+    }
+
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        Command::new(program)
+            .stdin(Stdio::null())
+            .args(args)
+            .output()
+    }
+
+    fn status_timeout(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+    ) -> io::Result<ExitStatus> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.status(program, args),
+        };
+
+        let mut child = Command::new(program)
+            .stdin(Stdio::null())
+            .args(args)
+            .spawn()?;
+        wait_timeout(&mut child, &cmdline(program, args), timeout)
+    }
+
+    fn spawn_timeout(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+        done: Box<dyn FnOnce(io::Result<ExitStatus>) + Send>,
+    ) -> io::Result<()> {
+        let mut child = Command::new(program)
+            .stdin(Stdio::null())
+            .args(args)
+            .spawn()?;
+        let cmdline = cmdline(program, args);
+        thread::spawn(move || {
+            let result = match timeout {
+                Some(timeout) => wait_timeout(&mut child, &cmdline, timeout),
+                None => child.wait(),
+            };
+            done(result)
+        });
+        Ok(())
+    }
+}
+
+fn cmdline(program: &str, args: &[&str]) -> String {
+    [&[program], args].concat().join(" ")
+}
+
+/// Waits for `child` at most `timeout`, kills it afterwards. As we own the [Child], it can not
+/// have been reaped yet, so there is no race with a reused PID.
+pub fn wait_timeout(child: &mut Child, cmdline: &str, timeout: Duration) -> io::Result<ExitStatus> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("'{}' did not finish within {}s", cmdline, timeout.as_secs()),
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+pub use mock::MockRunner;
+
+#[cfg(test)]
+mod mock {
+    use std::io;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::CommandRunner;
+
+    struct Response {
+        cmdline: String,
+        code: i32,
+        stdout: String,
+        // None: unlimited
+        times: Option<usize>,
+    }
+
+    /// Records all invocations as "program arg1 arg2..." instead of executing them.
+    ///
+    /// Invocations succeed with empty output, unless a response was registered for a command line
+    /// they start with.
+    #[derive(Default)]
+    pub struct MockRunner {
+        calls: Mutex<Vec<String>>,
+        responses: Mutex<Vec<Response>>,
+        hangs: Vec<String>,
+    }
+
+    impl MockRunner {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Lets invocations starting with `cmdline` exit with `code`, printing `stdout`.
+        pub fn respond(self, cmdline: &str, code: i32, stdout: &str) -> Self {
+            self.add_response(cmdline, code, stdout, None)
+        }
+
+        /// Like [MockRunner::respond], but only for the next `times` matching invocations.
+        pub fn respond_times(self, cmdline: &str, times: usize, code: i32, stdout: &str) -> Self {
+            self.add_response(cmdline, code, stdout, Some(times))
+        }
+
+        fn add_response(
+            mut self,
+            cmdline: &str,
+            code: i32,
+            stdout: &str,
+            times: Option<usize>,
+        ) -> Self {
+            self.responses
+                .get_mut()
+                .expect("lock must not be poisoned")
+                .push(Response {
+                    cmdline: cmdline.to_string(),
+                    code,
+                    stdout: stdout.to_string(),
+                    times,
+                });
+            self
+        }
+
+        /// Lets invocations starting with `cmdline` time out if they are executed with a timeout.
+        pub fn hang(mut self, cmdline: &str) -> Self {
+            self.hangs.push(cmdline.to_string());
+            self
+        }
+
+        pub fn calls(&self) -> Vec<String> {
+            self.calls
+                .lock()
+                .expect("lock must not be poisoned")
+                .clone()
+        }
+
+        fn run(&self, program: &str, args: &[&str]) -> Output {
+            let mut cmdline = vec![program];
+            cmdline.extend_from_slice(args);
+            let cmdline = cmdline.join(" ");
+
+            let (code, stdout) = self
+                .responses
+                .lock()
+                .expect("lock must not be poisoned")
+                .iter_mut()
+                .find(|r| cmdline.starts_with(r.cmdline.as_str()) && r.times != Some(0))
+                .map(|r| {
+                    if let Some(times) = r.times.as_mut() {
+                        *times -= 1;
+                    }
+                    (r.code, r.stdout.clone())
+                })
+                .unwrap_or_default();
+
+            self.calls
+                .lock()
+                .expect("lock must not be poisoned")
+                .push(cmdline);
+
+            Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+            }
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn status(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus> {
+            Ok(self.run(program, args).status)
+        }
+
+        fn output(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+            Ok(self.run(program, args))
+        }
+
+        fn status_timeout(
+            &self,
+            program: &str,
+            args: &[&str],
+            timeout: Option<Duration>,
+        ) -> io::Result<ExitStatus> {
+            let status = self.run(program, args).status;
+            let cmdline = [&[program], args].concat().join(" ");
+            if timeout.is_some() && self.hangs.iter().any(|h| cmdline.starts_with(h.as_str())) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "mock timeout"));
+            }
+            Ok(status)
+        }
+
+        fn spawn_timeout(
+            &self,
+            program: &str,
+            args: &[&str],
+            timeout: Option<Duration>,
+            done: Box<dyn FnOnce(io::Result<ExitStatus>) + Send>,
+        ) -> io::Result<()> {
+            done(self.status_timeout(program, args, timeout));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_runner_timeout() {
+        let timeout = Some(Duration::from_millis(200));
+
+        let status = SystemRunner
+            .status_timeout("true", &[], timeout)
+            .expect("must not time out");
+        assert!(status.success());
+
+        let start = Instant::now();
+        let err = SystemRunner
+            .status_timeout("sleep", &["10"], timeout)
+            .expect_err("must time out");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn system_runner_spawn_timeout() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        SystemRunner
+            .spawn_timeout(
+                "sleep",
+                &["10"],
+                Some(Duration::from_millis(200)),
+                Box::new(move |status| tx.send(status).expect("receiver must exist")),
+            )
+            .expect("must spawn");
+        // spawning does not wait for the command
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let err = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("done must be called")
+            .expect_err("must time out");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
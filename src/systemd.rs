@@ -2,16 +2,20 @@ use std::env;
 use std::fmt;
 use std::io::{Error, ErrorKind};
 use std::os::unix::net::UnixDatagram;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
 
 use anyhow::Result;
 use colored::Colorize;
+use log::warn;
+use serde::Serialize;
 use shell_words;
 
 use crate::plugin;
+use crate::runner::CommandRunner;
 
 static NOTIFY_SOCKET_CELL: OnceLock<Option<PathBuf>> = OnceLock::new();
 fn notify_socket() -> &'static Option<PathBuf> {
@@ -42,37 +46,60 @@ pub fn notify(msg: &str) -> Result<()> {
     }
 }
 
-pub fn daemon_reload() -> Result<()> {
-    plugin::map_status(
-        Command::new("systemctl")
-            .stdin(Stdio::null())
-            .arg("daemon-reload")
-            .status(),
-    )
+const DAEMON_RELOAD_ATTEMPTS: u32 = 3;
+
+/// Executes `systemctl daemon-reload`, retried a few times as it can fail transiently if systemd is
+/// busy.
+pub fn daemon_reload(runner: &dyn CommandRunner) -> Result<()> {
+    daemon_reload_retry(runner, DAEMON_RELOAD_ATTEMPTS, Duration::from_secs(1))
+}
+
+// the backoff doubles after every failed attempt
+fn daemon_reload_retry(runner: &dyn CommandRunner, attempts: u32, backoff: Duration) -> Result<()> {
+    let mut backoff = backoff;
+    let mut attempt = 1;
+    loop {
+        match plugin::map_status(runner.status("systemctl", &["daemon-reload"])) {
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "daemon_reload: attempt {}/{} failed, retrying in {}ms: {}",
+                    attempt,
+                    attempts,
+                    backoff.as_millis(),
+                    e
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
 }
 
-pub fn show_property(unit: &str, property: &str) -> Result<String> {
-    let output = Command::new("systemctl")
-        .stdin(Stdio::null())
-        .arg("show")
-        .arg(format!("--property={}", property))
-        .arg(unit)
-        .output()?;
+pub fn show_property(runner: &dyn CommandRunner, unit: &str, property: &str) -> Result<String> {
+    let output = runner.output(
+        "systemctl",
+        &["show", &format!("--property={}", property), unit],
+    )?;
     let output = std::str::from_utf8(&output.stdout)?;
-    // split_once('=') would be more elegant, but we want to support old rustc (e.g., bullseye)
-    let mut split = output.splitn(2, '=');
-    match (split.next(), split.next()) {
-        (Some(k), Some(v)) if k == property => Ok(v.trim().to_string()),
-        (Some(_), Some(_)) => Err(anyhow::anyhow!(
-            "Property did not start with '{}='",
-            property
-        )),
-        _ => Err(anyhow::anyhow!("Could not get property '{}'", property)),
+    // some (patched) systemd versions print additional lines, look for the one we asked for
+    let prefix = format!("{}=", property);
+    match output
+        .lines()
+        .find_map(|line| line.trim_start().strip_prefix(&prefix))
+    {
+        Some(v) => Ok(v.trim().to_string()),
+        None if output.contains('=') => {
+            Err(anyhow::anyhow!("No line started with '{}='", property))
+        }
+        None => Err(anyhow::anyhow!("Could not get property '{}'", property)),
     }
 }
 
 // most of that inspired by systemc/src/basic/unit-def.c
-#[derive(PartialEq)]
+#[derive(PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum UnitActiveState {
     Active,
     Reloading,
@@ -115,17 +142,91 @@ impl fmt::Display for UnitActiveState {
     }
 }
 
-pub fn is_active(unit: &str) -> Result<bool> {
-    let prop = show_property(unit, "ActiveState")?;
+/// Returns `unit` followed by all the units it depends on, as listed by "systemctl list-dependencies".
+pub fn list_dependencies(runner: &dyn CommandRunner, unit: &str) -> Result<Vec<String>> {
+    let deps = runner.output(
+        "systemctl",
+        &["list-dependencies", "--no-pager", "--plain", unit],
+    )?;
+    if !deps.status.success() {
+        return Err(anyhow::anyhow!(
+            "'systemctl list-dependencies --no-pager --plain {}' not executed successfully, stdout: '{}', stderr: '{}'",
+            unit,
+            String::from_utf8(deps.stdout).unwrap_or("<Could not convert stdout>".to_string()),
+            String::from_utf8(deps.stderr).unwrap_or("<Could not convert stderr>".to_string())
+        ));
+    }
+
+    let deps = String::from_utf8(deps.stdout)?;
+    Ok(deps
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(ToString::to_string)
+        .collect())
+}
+
+pub fn is_active(runner: &dyn CommandRunner, unit: &str) -> Result<bool> {
+    let prop = show_property(runner, unit, "ActiveState")?;
     let state = UnitActiveState::from_str(&prop)?;
     Ok(state == UnitActiveState::Active)
 }
 
+/// OCF_ROOT as set in the ocf.rs@ template, agents are expected in its "resource.d" subdirectory
+pub const OCF_ROOT: &str = "/usr/lib/ocf";
+/// Where distributions install OCF resource agents, the first one is the default
+pub const OCF_ROOTS: &[&str] = &[OCF_ROOT, "/usr/libexec/ocf"];
+
+/// Returns the OCF root that contains all of the given (vendor, agent) pairs.
+///
+/// A configured root is used as is, but has to contain the agents. Otherwise the first of the
+/// `candidates` that contains all agents is used. Without any agents that is the first candidate.
+pub fn ocf_root(
+    configured: Option<&Path>,
+    candidates: &[&str],
+    agents: &[(String, String)],
+) -> Result<PathBuf> {
+    let missing = |root: &Path| {
+        agents
+            .iter()
+            .map(|(vendor, agent)| root.join("resource.d").join(vendor).join(agent))
+            .find(|path| !path.exists())
+    };
+
+    if let Some(root) = configured {
+        if let Some(agent) = missing(root) {
+            return Err(anyhow::anyhow!(
+                "OCF agent '{}' does not exist in OCF root '{}'",
+                agent.display(),
+                root.display()
+            ));
+        }
+        return Ok(root.to_path_buf());
+    }
+
+    match candidates
+        .iter()
+        .map(Path::new)
+        .find(|root| missing(root).is_none())
+    {
+        Some(root) => Ok(root.to_path_buf()),
+        None => Err(anyhow::anyhow!(
+            "could not find OCF agents {:?} in any of {:?}; consider setting 'ocf-root'",
+            agents
+                .iter()
+                .map(|(vendor, agent)| format!("{}:{}", vendor, agent))
+                .collect::<Vec<_>>(),
+            candidates
+        )),
+    }
+}
+
 pub fn escaped_ocf_parse_to_env(
     name: &str,
     vendor: &str,
     agent: &str,
     args: &str,
+    ocf_root: &Path,
 ) -> Result<(String, Vec<String>)> {
     let args = shell_words::split(args)?;
 
@@ -148,8 +249,16 @@ pub fn escaped_ocf_parse_to_env(
         env.push(add)
     }
 
+    // the ocf.rs@ template sets the default, only override it if needed
+    if ocf_root != Path::new(OCF_ROOT) {
+        env.push(format!(
+            "OCF_ROOT={}",
+            escape_env(&ocf_root.display().to_string())
+        ));
+    }
     env.push(format!(
-        "AGENT=/usr/lib/ocf/resource.d/{}/{}",
+        "AGENT={}/resource.d/{}/{}",
+        escape_env(&ocf_root.display().to_string()),
         escape_env(vendor),
         escape_env(agent)
     ));
@@ -215,6 +324,7 @@ fn test_ocf_parse_to_env() {
         "vendor1",
         "agent1",
         "name1\nk1=v1 \nk2=\"with whitespace\" k3=with\\ different\\ whitespace foo empty='' pass='*pass/'",
+        Path::new(OCF_ROOT),
     )
     .expect("should work");
 
@@ -233,8 +343,127 @@ fn test_ocf_parse_to_env() {
     );
 
     // escaping
-    let (name, _env) = escaped_ocf_parse_to_env("res-1", "vendor1", "agent1", "name-1 do not care")
-        .expect("should work");
+    let (name, _env) = escaped_ocf_parse_to_env(
+        "res-1",
+        "vendor1",
+        "agent1",
+        "name-1 do not care",
+        Path::new(OCF_ROOT),
+    )
+    .expect("should work");
 
     assert_eq!(name, "ocf.rs@name\\x2d1_res\\x2d1.service");
+
+    // non-default root
+    let (_name, env) = escaped_ocf_parse_to_env(
+        "res1",
+        "vendor1",
+        "agent1",
+        "name1",
+        Path::new("/usr/libexec/ocf"),
+    )
+    .expect("should work");
+    assert_eq!(
+        &env[..],
+        &[
+            "OCF_ROOT=/usr/libexec/ocf",
+            "AGENT=/usr/libexec/ocf/resource.d/vendor1/agent1"
+        ]
+    );
+}
+
+#[test]
+fn test_ocf_root() {
+    let dir = tempfile::tempdir().expect("tempdir must work");
+    let lib = dir.path().join("lib");
+    let libexec = dir.path().join("libexec");
+    let agent = libexec.join("resource.d").join("heartbeat");
+    std::fs::create_dir_all(&agent).expect("mkdir must work");
+    std::fs::write(agent.join("IPaddr2"), "").expect("write must work");
+    let candidates = [lib.to_str().unwrap(), libexec.to_str().unwrap()];
+    let agents = vec![("heartbeat".to_string(), "IPaddr2".to_string())];
+
+    // autodetected
+    assert_eq!(ocf_root(None, &candidates, &agents).unwrap(), libexec);
+    // no agents, first candidate
+    assert_eq!(ocf_root(None, &candidates, &[]).unwrap(), lib);
+    // configured
+    assert_eq!(
+        ocf_root(Some(&libexec), &candidates, &agents).unwrap(),
+        libexec
+    );
+    let err = ocf_root(Some(&lib), &candidates, &agents).expect_err("must fail");
+    assert!(err.to_string().contains("does not exist in OCF root"));
+    // not found anywhere
+    let agents = vec![("heartbeat".to_string(), "Filesystem".to_string())];
+    let err = ocf_root(None, &candidates, &agents).expect_err("must fail");
+    assert!(err.to_string().contains("heartbeat:Filesystem"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+
+    #[test]
+    fn daemon_reload_retries() {
+        let no_sleep = Duration::from_secs(0);
+        let reload = "systemctl daemon-reload";
+
+        let runner = MockRunner::new().respond_times(reload, 1, 1, "");
+        assert!(daemon_reload_retry(&runner, 3, no_sleep).is_ok());
+        assert_eq!(runner.calls(), vec![reload, reload]);
+
+        let runner = MockRunner::new().respond(reload, 1, "");
+        assert!(daemon_reload_retry(&runner, 3, no_sleep).is_err());
+        assert_eq!(runner.calls(), vec![reload, reload, reload]);
+    }
+
+    #[test]
+    fn test_show_property() {
+        let cmd = "systemctl show --property=ActiveState foo.service";
+        let runner = MockRunner::new().respond(cmd, 0, "ActiveState=active\n");
+        assert_eq!(
+            show_property(&runner, "foo.service", "ActiveState").expect("must work"),
+            "active"
+        );
+
+        // additional lines before and after the property
+        let runner = MockRunner::new().respond(
+            cmd,
+            0,
+            "Warning: foo.service changed on disk\nSubState=running\nActiveState=active\nX=y\n",
+        );
+        assert_eq!(
+            show_property(&runner, "foo.service", "ActiveState").expect("must work"),
+            "active"
+        );
+
+        // only a prefix of another property
+        let runner = MockRunner::new().respond(cmd, 0, "ActiveStateX=active\n");
+        let err = show_property(&runner, "foo.service", "ActiveState").expect_err("must fail");
+        assert!(err
+            .to_string()
+            .contains("No line started with 'ActiveState='"));
+
+        let runner = MockRunner::new().respond(cmd, 0, "");
+        assert!(show_property(&runner, "foo.service", "ActiveState").is_err());
+    }
+
+    #[test]
+    fn test_list_dependencies() {
+        let target = "drbd-services@foo.target";
+        let runner = MockRunner::new().respond(
+            "systemctl list-dependencies --no-pager --plain drbd-services@foo.target",
+            0,
+            "drbd-services@foo.target\n  drbd-promote@foo.service\n  a.service\n",
+        );
+        assert_eq!(
+            list_dependencies(&runner, target).expect("must list"),
+            vec![target, "drbd-promote@foo.service", "a.service"]
+        );
+
+        let runner = MockRunner::new().respond("systemctl list-dependencies", 1, "");
+        assert!(list_dependencies(&runner, target).is_err());
+    }
 }
@@ -1,7 +1,13 @@
 use anyhow::Result;
-use libc::c_char;
-use std::ffi::CStr;
+use libc::{c_char, c_int};
+use std::ffi::{CStr, CString};
 use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// set by build.rs, "unknown" if it could not be determined
+pub const GIT_SHA: &str = env!("GIT_SHA");
 
 // inspired by https://crates.io/crates/uname
 // inlined because currently not packaged in Ubuntu Focal
@@ -18,3 +24,246 @@ pub fn uname_n() -> Result<String> {
         Err(anyhow::anyhow!(io::Error::last_os_error()))
     }
 }
+
+// the kernel limits a task's comm name to 16 bytes, including the terminating NUL byte
+const TASK_COMM_LEN: usize = 16;
+
+/// Builds a process title of the form "name[instance]" that is easy to spot in `ps`.
+pub fn process_title(name: &str, instance: &str) -> String {
+    format!("{}[{}]", name, instance)
+}
+
+/// Sets the name of the calling thread (i.e., what `ps -o comm` shows for the main thread).
+///
+/// Threads spawned afterwards inherit the name. Titles longer than 15 bytes get truncated.
+pub fn set_process_title(title: &str) -> Result<()> {
+    let mut comm = [0u8; TASK_COMM_LEN];
+    let title = title.as_bytes();
+    let len = title.len().min(TASK_COMM_LEN - 1);
+    comm[..len].copy_from_slice(&title[..len]);
+
+    let r = unsafe { libc::prctl(libc::PR_SET_NAME, comm.as_ptr() as libc::c_ulong, 0, 0, 0) };
+    if r == 0 {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(io::Error::last_os_error()))
+    }
+}
+
+/// Opens the connection to the local syslog, messages are tagged with "drbd-reactor" and the PID.
+pub fn openlog(facility: c_int) {
+    // openlog(3) keeps the pointer, so it has to be static
+    static IDENT: &[u8] = b"drbd-reactor\0";
+    unsafe { libc::openlog(IDENT.as_ptr() as *const c_char, libc::LOG_PID, facility) };
+}
+
+/// Sends `message` to the local syslog, log levels are mapped to the according syslog priorities.
+pub fn syslog(facility: c_int, level: log::Level, message: &str) {
+    let priority = match level {
+        log::Level::Error => libc::LOG_ERR,
+        log::Level::Warn => libc::LOG_WARNING,
+        log::Level::Info => libc::LOG_INFO,
+        log::Level::Debug | log::Level::Trace => libc::LOG_DEBUG,
+    };
+    // interior NULs would truncate the message anyways
+    let message = match CString::new(message.replace('\0', "")) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+    unsafe {
+        libc::syslog(
+            facility | priority,
+            b"%s\0".as_ptr() as *const c_char,
+            message.as_ptr(),
+        )
+    };
+}
+
+/// Enables TCP keepalive on a socket: the first probe is sent after the connection was `idle`
+/// for that long, further probes every `interval`.
+pub fn set_tcp_keepalive(socket: &impl AsRawFd, idle: Duration, interval: Duration) -> Result<()> {
+    // the kernel wants at least 1s for both
+    let secs = |d: Duration| d.as_secs().clamp(1, c_int::MAX as u64) as c_int;
+
+    setsockopt(socket, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    setsockopt(socket, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs(idle))?;
+    setsockopt(
+        socket,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPINTVL,
+        secs(interval),
+    )?;
+    Ok(())
+}
+
+/// Formats `time` as RFC 3339 timestamp in UTC with millisecond precision (e.g., "2024-01-31T12:00:00.123Z").
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Expands `${NAME}` references in `s` via `lookup`, `$$` is a literal `$`.
+///
+/// References `lookup` does not know about are kept as they are (e.g., for a shell to expand them).
+pub fn interpolate_env<F>(s: &str, lookup: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            result.push('$');
+            rest = after;
+            continue;
+        }
+        let name = rest
+            .strip_prefix("${")
+            .and_then(|r| r.find('}').map(|end| &r[..end]))
+            .filter(|n| is_env_name(n));
+        match name {
+            Some(name) => {
+                match lookup(name) {
+                    Some(value) => result.push_str(&value),
+                    None => result.push_str(&rest[..name.len() + 3]),
+                }
+                rest = &rest[name.len() + 3..];
+            }
+            None => {
+                result.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn setsockopt(socket: &impl AsRawFd, level: c_int, name: c_int, value: c_int) -> Result<()> {
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const c_int as *const libc::c_void,
+            std::mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if r == 0 {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(io::Error::last_os_error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    fn getsockopt(socket: &impl AsRawFd, level: c_int, name: c_int) -> c_int {
+        let mut value: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(r, 0, "getsockopt failed: {}", io::Error::last_os_error());
+        value
+    }
+
+    #[test]
+    fn test_tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("must bind");
+        let stream = TcpStream::connect(listener.local_addr().expect("must have an address"))
+            .expect("must connect");
+        assert_eq!(getsockopt(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+
+        set_tcp_keepalive(&stream, Duration::from_secs(42), Duration::from_millis(10))
+            .expect("must set keepalive");
+        assert_eq!(getsockopt(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        assert_eq!(
+            getsockopt(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+            42
+        );
+        assert_eq!(
+            getsockopt(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL),
+            1
+        );
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let lookup = |name: &str| match name {
+            "HOSTNAME" => Some("alpha".to_string()),
+            "EMPTY" => Some("".to_string()),
+            _ => None,
+        };
+        let expand = |s| interpolate_env(s, lookup);
+
+        assert_eq!(expand("echo ${HOSTNAME}"), "echo alpha");
+        assert_eq!(expand("${HOSTNAME}-${HOSTNAME}"), "alpha-alpha");
+        assert_eq!(expand("a${EMPTY}b"), "ab");
+        assert_eq!(expand("echo $${HOSTNAME}"), "echo ${HOSTNAME}");
+        assert_eq!(expand("echo $$$$"), "echo $$");
+        // unknown references and everything that is not a reference stays as is
+        assert_eq!(expand("echo ${DRBD_RES_NAME}"), "echo ${DRBD_RES_NAME}");
+        assert_eq!(expand("echo $HOSTNAME $"), "echo $HOSTNAME $");
+        assert_eq!(expand("echo ${HOSTNAME"), "echo ${HOSTNAME");
+        assert_eq!(expand("echo ${1x} ${}"), "echo ${1x} ${}");
+        assert_eq!(expand("grüße ${HOSTNAME}"), "grüße alpha");
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        let at = |secs: u64, millis: u64| {
+            rfc3339(UNIX_EPOCH + Duration::from_millis(secs * 1000 + millis))
+        };
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951782400, 7), "2000-02-29T00:00:00.007Z");
+        assert_eq!(at(1706702400, 123), "2024-01-31T12:00:00.123Z");
+        assert_eq!(at(1798761599, 999), "2026-12-31T23:59:59.999Z");
+    }
+
+    #[test]
+    fn test_process_title() {
+        assert_eq!(process_title("ocf-rs", "fs_res1"), "ocf-rs[fs_res1]");
+        assert_eq!(process_title("drbd-reactor", ""), "drbd-reactor[]");
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use drbd_reactor::drbd::{parse_device_statistics, DeviceStatistics};
+
+#[test]
+fn parse_statistics() {
+    let json = r#"[{"name": "foo", "role": "Primary", "devices": [
+        {"volume": 0, "minor": 1000, "disk-state": "UpToDate", "read": 10, "written": 20},
+        {"volume": 1, "minor": 1001, "disk-state": "Diskless"}
+    ], "connections": []},
+    {"name": "bar", "role": "Secondary", "devices": [], "connections": []}]"#;
+
+    let stats = parse_device_statistics(json.as_bytes()).expect("must parse");
+    assert_eq!(
+        stats,
+        vec![
+            DeviceStatistics {
+                resource: "foo".to_string(),
+                volume: 0,
+                minor: 1000,
+                read: 10,
+                written: 20,
+            },
+            DeviceStatistics {
+                resource: "foo".to_string(),
+                volume: 1,
+                minor: 1001,
+                read: 0,
+                written: 0,
+            },
+        ]
+    );
+
+    assert!(parse_device_statistics(b"not json").is_err());
+}
+
+#[test]
+fn rates() {
+    let before = DeviceStatistics {
+        resource: "foo".to_string(),
+        volume: 0,
+        minor: 1000,
+        read: 100,
+        written: 1000,
+    };
+    let after = DeviceStatistics {
+        read: 300,
+        written: 500, // re-attached, counter reset
+        ..before.clone()
+    };
+
+    assert_eq!(before.rates(&after, Duration::from_secs(2)), (100.0, 0.0));
+    assert_eq!(before.rates(&after, Duration::ZERO), (0.0, 0.0));
+}
@@ -0,0 +1,64 @@
+use drbd_reactor::drbd::{
+    EventType, Helper, HelperPluginUpdate, HelperPluginUpdatePattern, Resource,
+};
+use drbd_reactor::matchable::{BasicPattern, PartialMatchable};
+
+fn update(et: EventType, status: Option<i32>) -> HelperPluginUpdate {
+    let helper = Helper {
+        name: "foo".to_string(),
+        volume: Some(0),
+        peer_node_id: Some(1),
+        conn_name: "bar".to_string(),
+        helper: "before-resync-target".to_string(),
+        status,
+    };
+    HelperPluginUpdate::new(et, &helper, Resource::with_name("foo"))
+}
+
+#[test]
+fn match_helper_name() {
+    let pattern = Some(HelperPluginUpdatePattern {
+        event_type: Some(BasicPattern::Default(EventType::Call)),
+        resource_name: None,
+        volume: None,
+        peer_node_id: None,
+        helper: Some(BasicPattern::Default("before-resync-target".to_string())),
+        status: None,
+        resource: None,
+    });
+
+    assert!(update(EventType::Call, None).matches(&pattern));
+    assert!(!update(EventType::Response, Some(0)).matches(&pattern));
+
+    let mut other = update(EventType::Call, None);
+    other.helper = "after-resync-target".to_string();
+    assert!(!other.matches(&pattern));
+}
+
+#[test]
+fn match_helper_status() {
+    let pattern = Some(HelperPluginUpdatePattern {
+        event_type: None,
+        resource_name: None,
+        volume: None,
+        peer_node_id: None,
+        helper: None,
+        status: Some(BasicPattern::Default(Some(0))),
+        resource: None,
+    });
+
+    assert!(update(EventType::Response, Some(0)).matches(&pattern));
+    assert!(!update(EventType::Response, Some(1)).matches(&pattern));
+    // calls do not have a status
+    assert!(!update(EventType::Call, None).matches(&pattern));
+
+    let env = update(EventType::Response, Some(1)).get_env();
+    assert_eq!(
+        env.get("DRBD_HELPER").map(String::as_str),
+        Some("before-resync-target")
+    );
+    assert_eq!(env.get("DRBD_HELPER_STATUS").map(String::as_str), Some("1"));
+    assert!(!update(EventType::Call, None)
+        .get_env()
+        .contains_key("DRBD_HELPER_STATUS"));
+}
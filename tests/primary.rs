@@ -0,0 +1,63 @@
+use drbd_reactor::drbd::{parse_primary, PrimaryOn};
+
+#[test]
+fn primary_local_and_remote() {
+    let json = r#"[{"name": "foo", "node-id": 1, "role": "Primary", "connections": []}]"#;
+    assert_eq!(
+        parse_primary(json.as_bytes(), "foo").unwrap(),
+        PrimaryOn::Local
+    );
+
+    let json = r#"[{"name": "foo", "role": "Secondary", "connections": [
+        {"peer-node-id": 2, "name": "alpha", "peer-role": "Secondary"},
+        {"peer-node-id": 3, "name": "bravo", "peer-role": "Primary"}
+    ]}]"#;
+    assert_eq!(
+        parse_primary(json.as_bytes(), "foo").unwrap(),
+        PrimaryOn::Remote("bravo".to_string())
+    );
+}
+
+#[test]
+fn primary_tolerant_schema() {
+    // unknown fields and roles, missing connections
+    let json = r#"[{"name": "foo", "role": "Standby", "new-field": {"x": 1}}]"#;
+    assert_eq!(
+        parse_primary(json.as_bytes(), "foo").unwrap(),
+        PrimaryOn::None
+    );
+
+    let json = r#"[{"name": "foo", "role": null}]"#;
+    assert_eq!(
+        parse_primary(json.as_bytes(), "foo").unwrap(),
+        PrimaryOn::None
+    );
+
+    // no name, but a single resource
+    let json = r#"[{"role": "Primary"}]"#;
+    assert_eq!(
+        parse_primary(json.as_bytes(), "foo").unwrap(),
+        PrimaryOn::Local
+    );
+}
+
+#[test]
+fn primary_multiple_resources() {
+    let json = r#"[
+        {"name": "bar", "role": "Primary", "connections": []},
+        {"name": "foo", "role": "Secondary", "connections": []}
+    ]"#;
+    assert_eq!(
+        parse_primary(json.as_bytes(), "foo").unwrap(),
+        PrimaryOn::None
+    );
+    assert_eq!(
+        parse_primary(json.as_bytes(), "bar").unwrap(),
+        PrimaryOn::Local
+    );
+    assert!(parse_primary(json.as_bytes(), "baz").is_err());
+    // a single, but different resource
+    let json = r#"[{"name": "bar", "role": "Primary"}]"#;
+    assert!(parse_primary(json.as_bytes(), "foo").is_err());
+    assert!(parse_primary(b"[]", "foo").is_err());
+}
@@ -102,6 +102,16 @@ fn get_connection_update() {
     //check that updated did not delete existing pd
     assert!(r.connections[0].peerdevices.len() == 1);
 
+    // statistics are not part of the update state, but still need to be stored
+    let mut s = u.clone();
+    s.ap_in_flight = 42;
+    s.rs_in_flight = 23;
+    assert!(r.get_connection_update(&EventType::Change, &s).is_none());
+    assert_eq!(r.connections[0].ap_in_flight, 42);
+    assert_eq!(r.connections[0].rs_in_flight, 23);
+    assert!(r.connections[0].congested);
+    assert!(r.connections[0].peerdevices.len() == 1);
+
     // destroy still needs to be an update
     assert!(r.get_connection_update(&EventType::Destroy, &u).is_some());
 }
@@ -0,0 +1,160 @@
+use drbd_reactor::drbd::{
+    Connection, ConnectionState, Device, DiskState, EventType, PluginUpdate, Resource,
+    ResourcePluginUpdatePattern, Role,
+};
+use drbd_reactor::matchable::PartialMatchable;
+
+fn healthy_resource() -> Resource {
+    Resource {
+        name: "foo".to_string(),
+        role: Role::Primary,
+        devices: vec![
+            Device {
+                volume: 0,
+                disk_state: DiskState::UpToDate,
+                quorum: true,
+                ..Default::default()
+            },
+            Device {
+                volume: 1,
+                disk_state: DiskState::UpToDate,
+                quorum: true,
+                ..Default::default()
+            },
+        ],
+        connections: vec![
+            Connection {
+                peer_node_id: 1,
+                connection: ConnectionState::Connected,
+                ..Default::default()
+            },
+            Connection {
+                peer_node_id: 2,
+                connection: ConnectionState::Connected,
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn is_healthy() {
+    let r = healthy_resource();
+    assert!(r.is_healthy());
+
+    // no peers at all is fine
+    let mut u = r.clone();
+    u.connections.clear();
+    assert!(u.is_healthy());
+
+    let mut u = r.clone();
+    u.role = Role::Secondary;
+    assert!(!u.is_healthy());
+
+    let mut u = r.clone();
+    u.suspended = true;
+    assert!(!u.is_healthy());
+
+    let mut u = r.clone();
+    u.devices.clear();
+    assert!(!u.is_healthy());
+
+    let mut u = r.clone();
+    u.devices[1].quorum = false;
+    assert!(!u.is_healthy());
+
+    for disk_state in [
+        DiskState::Diskless,
+        DiskState::Outdated,
+        DiskState::Inconsistent,
+        DiskState::Consistent,
+    ] {
+        let mut u = r.clone();
+        u.devices[0].disk_state = disk_state;
+        assert!(!u.is_healthy());
+    }
+
+    for connection in [
+        ConnectionState::Connecting,
+        ConnectionState::StandAlone,
+        ConnectionState::NetworkFailure,
+    ] {
+        let mut u = r.clone();
+        u.connections[1].connection = connection;
+        assert!(!u.is_healthy());
+    }
+}
+
+#[test]
+fn get_health_update() {
+    let r = healthy_resource();
+    assert!(r.get_health_update(true).is_none());
+
+    match r.get_health_update(false) {
+        Some(PluginUpdate::Health(u)) => {
+            assert_eq!(u.resource_name, "foo");
+            assert!(!u.old.healthy);
+            assert!(u.new.healthy);
+            assert_eq!(
+                u.get_env().get("DRBD_NEW_HEALTHY").map(String::as_str),
+                Some("true")
+            );
+        }
+        _ => panic!("not a health update"),
+    }
+
+    let mut u = r;
+    u.role = Role::Secondary;
+    assert!(u.get_health_update(false).is_none());
+    match u.get_health_update(true) {
+        Some(PluginUpdate::Health(u)) => {
+            assert!(u.old.healthy);
+            assert!(!u.new.healthy);
+        }
+        _ => panic!("not a health update"),
+    }
+}
+
+#[test]
+fn resource_update_healthy() {
+    let mut r = healthy_resource();
+    r.role = Role::Secondary;
+    assert!(!r.is_healthy());
+
+    // the resource event itself only carries the resource level fields
+    let mut u = Resource::with_name("foo");
+    u.role = Role::Primary;
+    let up = match r.get_resource_update(&EventType::Change, &u) {
+        Some(PluginUpdate::Resource(up)) => up,
+        _ => panic!("not a resource update"),
+    };
+    assert!(!up.old.healthy);
+    assert!(up.new.healthy);
+    assert_eq!(
+        up.get_env().get("DRBD_NEW_HEALTHY").map(String::as_str),
+        Some("true")
+    );
+
+    let pattern: Option<ResourcePluginUpdatePattern> =
+        Some(toml::from_str("new.healthy = true").expect("pattern must parse"));
+    assert!(up.matches(&pattern));
+    let pattern: Option<ResourcePluginUpdatePattern> =
+        Some(toml::from_str("new.healthy = false").expect("pattern must parse"));
+    assert!(!up.matches(&pattern));
+
+    // same state again, nothing changed
+    assert!(r.get_resource_update(&EventType::Change, &u).is_none());
+
+    // losing a disk does not generate a resource update, but the next one sees it
+    r.devices[0].disk_state = DiskState::Outdated;
+    u.may_promote = true;
+    match r.get_resource_update(&EventType::Change, &u) {
+        Some(PluginUpdate::Resource(up)) => {
+            assert!(up.new.may_promote);
+            assert!(!up.old.healthy);
+            assert!(!up.new.healthy);
+        }
+        _ => panic!("not a resource update"),
+    }
+}
@@ -0,0 +1,175 @@
+use drbd_reactor::drbd::{
+    Connection, ConnectionState, Device, DiskState, Resource, ResourcePattern, Role,
+};
+use drbd_reactor::matchable::PartialMatchable;
+
+fn resource() -> Resource {
+    Resource {
+        name: "foo".to_string(),
+        role: Role::Primary,
+        devices: vec![
+            Device {
+                volume: 0,
+                disk_state: DiskState::UpToDate,
+                ..Default::default()
+            },
+            Device {
+                volume: 1,
+                disk_state: DiskState::Inconsistent,
+                ..Default::default()
+            },
+        ],
+        connections: vec![Connection {
+            peer_node_id: 1,
+            connection: ConnectionState::Connected,
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+fn pattern(s: &str) -> Option<ResourcePattern> {
+    Some(toml::from_str(s).expect("pattern must parse"))
+}
+
+#[test]
+fn match_any_device() {
+    let p = pattern(
+        r#"
+        role = "Primary"
+        devices = { any = { disk-state = "Inconsistent" } }
+        "#,
+    );
+    let r = resource();
+    assert!(r.matches(&p));
+
+    let mut u = r.clone();
+    u.role = Role::Secondary;
+    assert!(!u.matches(&p));
+
+    let mut u = r.clone();
+    u.devices[1].disk_state = DiskState::UpToDate;
+    assert!(!u.matches(&p));
+}
+
+#[test]
+fn match_all_devices() {
+    let p = pattern(r#"devices = { all = { disk-state = "UpToDate" } }"#);
+    let mut r = resource();
+    assert!(!r.matches(&p));
+
+    r.devices[1].disk_state = DiskState::UpToDate;
+    assert!(r.matches(&p));
+
+    // vacuously true
+    r.devices.clear();
+    assert!(r.matches(&p));
+}
+
+#[test]
+fn match_connections() {
+    let p = pattern(r#"connections = { len = 2 }"#);
+    let mut r = resource();
+    assert!(!r.matches(&p));
+
+    r.connections.push(Connection {
+        peer_node_id: 2,
+        connection: ConnectionState::Connecting,
+        ..Default::default()
+    });
+    assert!(r.matches(&p));
+
+    let p = pattern(
+        r#"connections = { any = { connection = { value = "Connected", operator = "NotEquals" } } }"#,
+    );
+    assert!(r.matches(&p));
+    r.connections.pop();
+    assert!(!r.matches(&p));
+
+    assert!(r.matches(&None));
+}
+
+#[test]
+fn match_numeric_comparisons() {
+    let mut r = resource();
+    r.promotion_score = 9000;
+
+    let p = pattern(r#"promotion-score = { value = 10000, operator = "LessThan" }"#);
+    assert!(r.matches(&p));
+    r.promotion_score = 10000;
+    assert!(!r.matches(&p));
+
+    let p = pattern(r#"promotion-score = { value = 10000, operator = "GreaterOrEqual" }"#);
+    assert!(r.matches(&p));
+    let p = pattern(r#"promotion-score = { value = 10000, operator = "LessOrEqual" }"#);
+    assert!(r.matches(&p));
+    let p = pattern(r#"promotion-score = { value = 10000, operator = "GreaterThan" }"#);
+    assert!(!r.matches(&p));
+
+    let p = pattern(r#"connections = { len = { value = 0, operator = "GreaterThan" } }"#);
+    assert!(r.matches(&p));
+
+    // unordered values never match comparisons
+    let p = pattern(r#"name = { value = "a", operator = "GreaterThan" }"#);
+    assert!(!r.matches(&p));
+    let p = pattern(r#"role = { value = "Secondary", operator = "GreaterThan" }"#);
+    assert!(!r.matches(&p));
+}
+
+#[test]
+fn match_regex() {
+    let mut r = resource();
+    r.name = "pg_main".to_string();
+
+    let p = pattern(r#"name = { pattern = "^pg_" }"#);
+    assert!(r.matches(&p));
+    r.name = "mysql".to_string();
+    assert!(!r.matches(&p));
+
+    let p = pattern(r#"connections = { any = { conn-name = { pattern = "^peer[0-9]$" } } }"#);
+    assert!(!r.matches(&p));
+
+    // only strings match regexes
+    let p = pattern(r#"role = { pattern = "Secondary" }"#);
+    assert!(!r.matches(&p));
+
+    let err = toml::from_str::<ResourcePattern>(r#"name = { pattern = "(pg_" }"#).unwrap_err();
+    assert!(err.to_string().contains("invalid regex pattern '(pg_'"));
+}
+
+#[test]
+fn match_sets() {
+    let r = resource();
+
+    let p = pattern(
+        r#"devices = { any = { disk-state = { operator = "In", values = ["Failed", "Inconsistent"] } } }"#,
+    );
+    assert!(r.matches(&p));
+    let p = pattern(
+        r#"devices = { all = { disk-state = { operator = "NotIn", values = ["Failed", "Diskless"] } } }"#,
+    );
+    assert!(r.matches(&p));
+    let p = pattern(
+        r#"devices = { all = { disk-state = { operator = "NotIn", values = ["Failed", "Inconsistent"] } } }"#,
+    );
+    assert!(!r.matches(&p));
+
+    // In is the default operator for sets
+    let p = pattern(r#"name = { values = ["bar", "foo"] }"#);
+    assert!(r.matches(&p));
+    let p = pattern(r#"name = { operator = "NotIn", values = ["bar", "foo"] }"#);
+    assert!(!r.matches(&p));
+    let p = pattern(r#"devices = { any = { volume = { values = [2, 3] } } }"#);
+    assert!(!r.matches(&p));
+
+    // comparisons need a single value
+    let err = toml::from_str::<ResourcePattern>(
+        r#"promotion-score = { operator = "GreaterOrEqual", values = [0, 1] }"#,
+    )
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("operator 'GreaterOrEqual' needs a single value, got 2"));
+    let p = pattern(r#"promotion-score = { operator = "GreaterOrEqual", values = [0] }"#);
+    assert!(r.matches(&p));
+}